@@ -1,19 +1,62 @@
+// Where a texture is drawn and which part of it
+struct Params {
+   // Top left corner and size of the destination in clip space
+   pos: vec2<f32>,
+   size: vec2<f32>,
+   // Top left corner and size of the part shown, in texture coordinates
+   uv_pos: vec2<f32>,
+   uv_size: vec2<f32>,
+   // Opacity of the whole texture
+   alpha: f32,
+   // Rotated clockwise in quarter turns, flipped horizontally first if 4 or
+   // more, as in `Transform`
+   transform: u32,
+   // Non-zero if the texture's alpha channel is padding, to be read as 1
+   opaque: u32,
+}
 struct VertexOutput {
    @builtin(position) position: vec4<f32>,
    @location(0) tex_coords: vec2<f32>,
 }
+@group(0) @binding(0) var tex: texture_2d<f32>;
+@group(0) @binding(1) var tex_sampler: sampler;
+@group(1) @binding(0) var<uniform> params: Params;
+// Where the destination corner `corner` samples the texture
+fn transformed(corner: vec2<f32>, transform: u32) -> vec2<f32> {
+   var c = corner;
+   switch transform & 3u {
+      case 1u: {
+         c = vec2<f32>(corner.y, 1.0 - corner.x);
+      }
+      case 2u: {
+         c = vec2<f32>(1.0 - corner.x, 1.0 - corner.y);
+      }
+      case 3u: {
+         c = vec2<f32>(1.0 - corner.y, corner.x);
+      }
+      default: {
+      }
+   }
+   if transform >= 4u {
+      c.x = 1.0 - c.x;
+   }
+   return c;
+}
+// Drawn as a 4 vertex triangle strip
 @vertex
 fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
    var out: VertexOutput;
-   let x = f32((vertex_index & 1u) << 2u) - 1.0;
-   let x = f32((vertex_index & 2u) << 1u) - 1.0;
-   out.position = vec4<f32>(x, y, 0.0, 1.0);
-   out.tex_coords = vec2<f32>(x + 1.0, 1.0 - y) * 0.5;
+   let corner = vec2<f32>(f32(vertex_index & 1u), f32(vertex_index >> 1u));
+   out.position = vec4<f32>(params.pos + corner * params.size, 0.0, 1.0);
+   out.tex_coords = params.uv_pos + transformed(corner, params.transform) * params.uv_size;
    return out;
 }
-@group(0) @binding(0) var tex: texture_2d<f32>;
-@group(0) @binding(1) var tex_sampler: sampler;
+// Textures are premultiplied, so opacity scales every channel
 @fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<32> {
-   return textureSample(tex, tex_sampler, in.tex_coords);
-}
\ No newline at end of file
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+   var color = textureSample(tex, tex_sampler, in.tex_coords);
+   if params.opaque != 0u {
+      color.a = 1.0;
+   }
+   return color * params.alpha;
+}
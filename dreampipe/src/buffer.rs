@@ -4,21 +4,30 @@ use ash::vk;
 use crate::context::Card;
 use crate::error::CompositorError;
 use crate::error::CompositorResult;
+use crate::formats::FormatTable;
 use crate::fourcc::FourCc;
+use crate::kms::AtomicRequest;
+use crate::kms::KmsBackend;
+use crate::kms::PropertyMap;
+use crate::kms::Resources;
 use drm::buffer::DrmFourcc;
-use drm::control::AtomicCommitFlags;
+use drm::buffer::DrmModifier;
+use drm::control::FbCmd2Flags;
 use drm::control::Device as ControlDevice;
 use drm::control::PlaneType;
-use drm::control::ResourceHandles;
-use drm::control::atomic;
 use drm::control::crtc;
 use drm::control::framebuffer;
 use drm::control::plane;
 use drm::control::property;
 use gbm::BufferObjectFlags;
-use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
 use std::os::fd::IntoRawFd;
+use std::str::FromStr;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
 use wgpu::Extent3d;
 use wgpu::TextureDescriptor;
 use wgpu::TextureDimension;
@@ -29,47 +38,57 @@ use wgpu_hal::MemoryFlags;
 
 pub const CURSOR_DIM: u32 = 128;
 pub const DRM_FORMAT: DrmFourcc = DrmFourcc::Xrgb8888;
+/// Cursors need alpha. The memory layout is the same as `DRM_FORMAT`.
+pub const CURSOR_FORMAT: DrmFourcc = DrmFourcc::Argb8888;
 pub const VK_FORMAT: ash::vk::Format = ash::vk::Format::B8G8R8A8_UNORM;
+/// Formats with more than 8 bits per channel, most preferred first
+pub const DEEP_FORMATS: [ScanoutFormat; 3] =
+  [ScanoutFormat::XBGR2101010, ScanoutFormat::XRGB2101010, ScanoutFormat::XBGR16161616F];
+/// Buffers per plane unless configured otherwise
+pub const DEFAULT_BUFFERS: usize = 3;
+
+// Made up framebuffer IDs for buffers that never reach a real card. Counting
+// down from the top keeps them well clear of the IDs a mock device hands out.
+static OFFSCREEN_FB_IDS: AtomicU32 = AtomicU32::new(u32::MAX);
 
 fn is_plane_compatible_with_crtc(
-  card: &Card,
-  resources: &ResourceHandles,
+  kms: &dyn KmsBackend,
+  resources: &Resources,
   plane: plane::Handle,
   crtc: crtc::Handle,
 ) -> bool {
-  let plane_info = card.get_plane(plane).unwrap();
-  resources.filter_crtcs(plane_info.possible_crtcs()).contains(&crtc)
+  kms
+    .plane(plane)
+    .map(|info| resources.filter_crtcs(info.possible_crtcs).contains(&crtc))
+    .unwrap_or(false)
 }
 
-fn find_compatible_plane(
-  card: &Card,
-  resources: &ResourceHandles,
+/// Take a plane of `planetype` that can be used with `crtc` out of `planes`
+pub fn find_compatible_plane(
+  kms: &dyn KmsBackend,
+  resources: &Resources,
   crtc: crtc::Handle,
   planes: &mut HashSet<plane::Handle>,
   planetype: PlaneType,
 ) -> Option<plane::Handle> {
-  let mut compatible = Vec::new();
-  for &plane in planes.iter() {
-    if is_plane_compatible_with_crtc(card, &resources, plane, crtc) {
-      compatible.push(plane);
-    }
-  }
-  let mut plane = None;
-  'outer: for p in compatible.into_iter() {
-    if let Ok(plane_props) = card.get_properties(p) {
-      for (&id, &val) in plane_props.iter() {
-        if let Ok(info) = card.get_property(id) {
-          if info.name().to_str().map(|x| x == "type").unwrap_or(false) {
-            if val == planetype as u64 {
-              plane = Some(p);
-              break 'outer;
-            }
-          }
-        }
-      }
-    }
-  }
-  plane
+  let mut compatible =
+    planes
+      .iter()
+      .copied()
+      .filter(|&plane| is_plane_compatible_with_crtc(kms, resources, plane, crtc))
+      .collect::<Vec<_>>();
+
+  // Keep the choice stable between runs
+  compatible.sort_by_key(|&plane| u32::from(plane));
+  let plane = compatible.into_iter().find(|&plane| {
+    kms
+      .properties(plane.into())
+      .ok()
+      .and_then(|props| props.get("type").map(|prop| prop.value()))
+      .is_some_and(|val| val == planetype as u64)
+  })?;
+  planes.remove(&plane);
+  Some(plane)
 }
 
 #[allow(unused)]
@@ -77,23 +96,33 @@ fn make_buffer(
   card: &Card,
   gbm: &gbm::Device<&Card>,
   planetype: PlaneType,
+  format: DrmFourcc,
+  modifiers: &[DrmModifier],
   size: (u32, u32),
 ) -> Result<gbm::BufferObject<()>, CompositorError> {
   let planeflag = match planetype {
     PlaneType::Overlay | PlaneType::Primary => BufferObjectFlags::SCANOUT,
     PlaneType::Cursor => BufferObjectFlags::CURSOR,
   };
+  let flags = planeflag | BufferObjectFlags::RENDERING;
+
+  // gbm picks the best of the modifiers we give it. With none known to work
+  // for both the plane and the GPU the driver has to guess.
+  let explicit =
+    modifiers.iter().copied().filter(|modifier| *modifier != DrmModifier::Invalid);
   let buffer =
-    gbm
-      .create_buffer_object::<()>(
+    match explicit.clone().next() {
+      Some(_) => gbm.create_buffer_object_with_modifiers2::<()>(
         size.0,
         size.1,
-        DRM_FORMAT,
-        planeflag | BufferObjectFlags::RENDERING,
-      )
-      .map_err(|err| {
-        CompositorError::GbmCreation(err)
-      })?;
+        format,
+        explicit,
+        flags,
+      ),
+      None => gbm.create_buffer_object::<()>(size.0, size.1, format, flags),
+    }.map_err(|err| {
+      CompositorError::GbmCreation(err)
+    })?;
   Ok(buffer)
 }
 
@@ -115,6 +144,8 @@ fn create_vulkan_image_from_dmabuf<
 >(
   hal_device: &'a <api::Vulkan as wgpu::hal::Api>::Device,
   bo: &gbm::BufferObject<()>,
+  format: vk::Format,
+  modifiers: &[DrmModifier],
   (width, height): (u32, u32),
 ) -> CompositorResult<(vk::Image, vk::DeviceMemory)> {
   // Validate dimensions
@@ -126,8 +157,12 @@ fn create_vulkan_image_from_dmabuf<
   let device = hal_device.raw_device();
   let _instance = hal_device.shared_instance().raw_instance();
 
-  // Get the modifier
+  // Get the modifier, which has to be one the GPU listed if it listed any
   let modifier = bo.modifier();
+  let listed = modifiers.iter().any(|modifier| *modifier != DrmModifier::Invalid);
+  if listed && !modifiers.contains(&modifier) {
+    return Err(CompositorError::VulkanModifier(modifier));
+  }
 
   // Get plane info
   let plane_count = bo.plane_count();
@@ -155,7 +190,7 @@ fn create_vulkan_image_from_dmabuf<
   let image_create_info =
     vk::ImageCreateInfo::default()
       .image_type(vk::ImageType::TYPE_2D)
-      .format(VK_FORMAT)
+      .format(format)
       .extent(vk::Extent3D {
         width,
         height,
@@ -178,7 +213,25 @@ fn create_vulkan_image_from_dmabuf<
         .map_err(|e| CompositorError::VulkanImageCreate(e))?
     };
 
-  // Import memory from DMA-BUF
+  // Nothing owns the image yet, so it is ours to clean up
+  match import_bo_memory(hal_device, bo, image) {
+    Ok(device_memory) => Ok((image, device_memory)),
+    Err(e) => {
+      unsafe {
+        device.destroy_image(image, None)
+      };
+      Err(e)
+    },
+  }
+}
+
+// Import the memory of `bo` and bind it to `image`
+fn import_bo_memory(
+  hal_device: &<api::Vulkan as wgpu::hal::Api>::Device,
+  bo: &gbm::BufferObject<()>,
+  image: vk::Image,
+) -> CompositorResult<vk::DeviceMemory> {
+  let device = hal_device.raw_device();
   let memory_requirements = unsafe {
     device.get_image_memory_requirements(image)
   };
@@ -217,188 +270,531 @@ fn create_vulkan_image_from_dmabuf<
     };
 
   // Bind memory to image
-  unsafe {
-    device
-      .bind_image_memory(image, device_memory, 0)
-      .map_err(|e| CompositorError::VulkanBindMemory(e))?;
+  let bound = unsafe {
+    device.bind_image_memory(image, device_memory, 0)
+  };
+  if let Err(e) = bound {
+    unsafe {
+      device.free_memory(device_memory, None)
+    };
+    return Err(CompositorError::VulkanBindMemory(e));
+  }
+  Ok(device_memory)
+}
+
+/// A buffer format as KMS, Vulkan and wgpu each name it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScanoutFormat {
+  pub drm: DrmFourcc,
+  pub vk: vk::Format,
+  pub wgpu: TextureFormat,
+  /// wgpu has no format with red in the high bits of a 10 bit channel
+  /// layout, so red and blue are stored swapped and whoever draws into it
+  /// swaps them back
+  pub swap_red_blue: bool,
+}
+
+impl ScanoutFormat {
+  pub const XRGB8888: Self = Self {
+    drm: DRM_FORMAT,
+    vk: VK_FORMAT,
+    wgpu: TextureFormat::Bgra8Unorm,
+    swap_red_blue: false,
+  };
+  pub const ARGB8888: Self = Self {
+    drm: CURSOR_FORMAT,
+    vk: VK_FORMAT,
+    wgpu: TextureFormat::Bgra8Unorm,
+    swap_red_blue: false,
+  };
+  pub const XBGR2101010: Self = Self {
+    drm: DrmFourcc::Xbgr2101010,
+    vk: vk::Format::A2B10G10R10_UNORM_PACK32,
+    wgpu: TextureFormat::Rgb10a2Unorm,
+    swap_red_blue: false,
+  };
+  pub const XRGB2101010: Self = Self {
+    drm: DrmFourcc::Xrgb2101010,
+    vk: vk::Format::A2B10G10R10_UNORM_PACK32,
+    wgpu: TextureFormat::Rgb10a2Unorm,
+    swap_red_blue: true,
+  };
+  pub const XBGR16161616F: Self = Self {
+    drm: DrmFourcc::Xbgr16161616f,
+    vk: vk::Format::R16G16B16A16_SFLOAT,
+    wgpu: TextureFormat::Rgba16Float,
+    swap_red_blue: false,
+  };
+
+  /// Bits per colour channel
+  pub fn bits(&self) -> u32 {
+    match self.drm {
+      DrmFourcc::Xbgr16161616f => 16,
+      DrmFourcc::Xbgr2101010 | DrmFourcc::Xrgb2101010 => 10,
+      _ => 8,
+    }
+  }
+
+  /// The format closest to `bits` per channel that both the GPU and a plane
+  /// taking `formats` can use, or 8 bit if there is none
+  pub fn pick(bits: u32, formats: &FormatTable) -> Self {
+    DEEP_FORMATS
+      .into_iter()
+      .filter(|format| bits > 8 && formats.contains(format.drm))
+      .min_by_key(|format| format.bits().abs_diff(bits))
+      .unwrap_or(Self::XRGB8888)
+  }
+}
+
+// Buffers with an explicit modifier need it passed along, or the display
+// reads them as linear. The legacy call only knows formats by depth and bpp,
+// which stops being enough past 8 bits per channel.
+fn add_framebuffer(
+  card: &Card,
+  bo: &gbm::BufferObject<()>,
+  format: DrmFourcc,
+) -> std::io::Result<framebuffer::Handle> {
+  match (bo.modifier(), format) {
+    (DrmModifier::Invalid, DrmFourcc::Xrgb8888 | DrmFourcc::Argb8888) => {
+      card.add_framebuffer(bo, format.depth(), format.bpp())
+    },
+    (DrmModifier::Invalid, _) => card.add_planar_framebuffer(bo, FbCmd2Flags::empty()),
+    _ => card.add_planar_framebuffer(bo, FbCmd2Flags::MODIFIERS),
+  }
+}
+
+/// Where a buffer is on its way to the screen and back
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum BufferState {
+  #[default]
+  Free,
+  /// Being drawn into
+  Drawing,
+  /// Drawn, waiting to be committed
+  Ready,
+  /// Committed, waiting for the page flip
+  Queued,
+  /// On screen
+  Scanout,
+}
+
+/// What happens to a drawn frame when an older one is still waiting to be
+/// committed
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PresentMode {
+  /// Every frame is shown, in order
+  #[default]
+  Fifo,
+  /// The newest frame replaces the waiting one
+  Mailbox,
+}
+
+impl FromStr for PresentMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim() {
+      "fifo" => Ok(Self::Fifo),
+      "mailbox" => Ok(Self::Mailbox),
+      other => Err(format!["Unknown present mode '{other}', expected fifo or mailbox"]),
+    }
+  }
+}
+
+/// One buffer of a swapchain. Its GBM buffer is dropped once the framebuffer
+/// and the Vulkan memory are made, as those keep the allocation alive.
+#[derive(Debug)]
+pub struct SwapchainBuffer {
+  pub vk_memory: vk::DeviceMemory,
+  pub wgpu_texture: wgpu::Texture,
+  pub fb: framebuffer::Handle,
+}
+
+// What a swapchain knows about each buffer, apart from the buffer itself
+#[derive(Clone, Copy, Debug, Default)]
+struct Slot {
+  state: BufferState,
+  // Number of the frame last drawn into it, 0 if none
+  frame: u64,
+}
+
+/// The buffers a swapchain is made of
+#[derive(Clone, Copy, Debug)]
+pub struct SwapchainSpec<'a> {
+  pub format: ScanoutFormat,
+  /// What the plane can scan out that the GPU can render to, `format`
+  /// among them
+  pub formats: &'a FormatTable,
+  pub size: (u32, u32),
+  /// At least 2
+  pub count: usize,
+}
+
+// What `Swapchain::new` made so far, freed if making the rest fails
+struct MadeBuffers<'a> {
+  card: &'a Card,
+  device: ash::Device,
+  buffers: Vec<SwapchainBuffer>,
+  // Of the buffer being made
+  fb: Option<framebuffer::Handle>,
+}
+
+impl Drop for MadeBuffers<'_> {
+  fn drop(&mut self) {
+    let fbs = self.buffers.iter().map(|buffer| buffer.fb).chain(self.fb);
+    for fb in fbs.collect::<Vec<_>>() {
+      self.card.remove_framebuffer(fb).ok();
+    }
+    for buffer in self.buffers.drain(..) {
+      // wgpu owns the image and destroys it with the texture, but leaves the
+      // memory to us
+      drop(buffer.wgpu_texture);
+      unsafe {
+        self.device.free_memory(buffer.vk_memory, None);
+      }
+    }
   }
-  Ok((image, device_memory))
 }
 
 #[derive(Debug)]
-pub struct TripleBuffer {
-  pub draw: usize,
-  pub scan: usize,
-  pub vk_images: [vk::Image; 3],
-  pub vk_memories: [vk::DeviceMemory; 3],
-  pub wgpu_textures: [wgpu::Texture; 3],
-  pub bos: [gbm::BufferObject<()>; 3],
-  pub fbs: [framebuffer::Handle; 3],
+pub struct Swapchain {
+  pub buffers: Vec<SwapchainBuffer>,
+  pub mode: PresentMode,
+  // One for each buffer
+  slots: Vec<Slot>,
+  // Frames presented so far
+  frame: u64,
+  // Drawn buffers in the order they are to be committed
+  ready: VecDeque<usize>,
 }
 
-impl TripleBuffer {
+impl Swapchain {
   pub fn new(
-    card: &Card,
     gbm: &gbm::Device<&Card>,
     gpu: &wgpu::Device,
     planetype: PlaneType,
-    size @ (width, height): (u32, u32),
+    spec: SwapchainSpec,
   ) -> CompositorResult<Self> {
-    let [a, b, c] =
-      std::array::from_fn(|_| make_buffer(card, gbm, planetype, size));
-    let buffers = [a?, b?, c?];
-    let [a, b, c] =
-      std::array::from_fn(
-        |i| card
-          .add_framebuffer(&buffers[i], DRM_FORMAT.depth(), DRM_FORMAT.bpp())
-          .map_err(|e| CompositorError::AddFrameBuffer(e)),
-      );
-    let framebuffers = [a?, b?, c?];
+    let SwapchainSpec { format, formats, size: size @ (width, height), count } = spec;
+    let card: &Card = gbm;
     let chain_id: u64 = rand::random::<u64>();
-    let [a, b, c] =
-      std::array::from_fn(
-        |i| unsafe {
-          let hal_device_guard = gpu.as_hal::<api::Vulkan>();
-          let Some(hal_device) = hal_device_guard else {
-            return Err(CompositorError::VulkanApi);
-          };
-          let (vk_image, vk_memory) =
-            create_vulkan_image_from_dmabuf(&hal_device, &buffers[i], size)?;
-          let hal_texture =
-            <api::Vulkan as wgpu::hal::Api>::Device::texture_from_raw(
-              &hal_device,
-              vk_image,
-              &wgpu::hal::TextureDescriptor {
-                label: Some(&format!["DMA-BUF Texture {chain_id}-{i}"]),
-                size: Extent3d {
-                  width,
-                  height,
-                  depth_or_array_layers: 1,
-                },
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: TextureDimension::D2,
-                format: TextureFormat::Bgra8Unorm,
-                // unknown if correct for a compositor using dma buf
-                usage: TextureUses::COLOR_TARGET,
-                // Don't know what to put here
-                memory_flags: MemoryFlags::empty(),
-                view_formats: vec![],
+    let hal_device_guard = unsafe {
+      gpu.as_hal::<api::Vulkan>()
+    };
+    let Some(hal_device) = hal_device_guard else {
+      return Err(CompositorError::VulkanApi);
+    };
+    let mut made = MadeBuffers {
+      card,
+      device: hal_device.raw_device().clone(),
+      buffers: Vec::with_capacity(count),
+      fb: None,
+    };
+    for i in 0 .. count {
+      let bo = make_buffer(card, gbm, planetype, format.drm, formats.modifiers(format.drm), size)?;
+      let fb =
+        add_framebuffer(card, &bo, format.drm).map_err(|e| CompositorError::AddFrameBuffer(e))?;
+      made.fb = Some(fb);
+      let (wgpu_texture, vk_memory) = unsafe {
+        let (vk_image, vk_memory) =
+          create_vulkan_image_from_dmabuf(
+            &hal_device,
+            &bo,
+            format.vk,
+            formats.modifiers(format.drm),
+            size,
+          )?;
+        let hal_texture =
+          <api::Vulkan as wgpu::hal::Api>::Device::texture_from_raw(
+            &hal_device,
+            vk_image,
+            &wgpu::hal::TextureDescriptor {
+              label: Some(&format!["DMA-BUF Texture {chain_id}-{i}"]),
+              size: Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
               },
-              None,
-            );
-          let wgpu_texture =
-            gpu.create_texture_from_hal::<api::Vulkan>(
-              hal_texture,
-              &TextureDescriptor {
-                label: Some(&format!["DMA-BUF Texture {chain_id}-{i}"]),
-                size: Extent3d {
-                  width,
-                  height,
-                  depth_or_array_layers: 1,
-                },
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: TextureDimension::D2,
-                format: TextureFormat::Bgra8Unorm,
-                usage: wgpu::TextureUsages::RENDER_ATTACHMENT |
-                  wgpu::TextureUsages::TEXTURE_BINDING,
-                view_formats: &[],
+              mip_level_count: 1,
+              sample_count: 1,
+              dimension: TextureDimension::D2,
+              format: format.wgpu,
+              // unknown if correct for a compositor using dma buf
+              usage: TextureUses::COLOR_TARGET | TextureUses::COPY_SRC | TextureUses::COPY_DST,
+              // Don't know what to put here
+              memory_flags: MemoryFlags::empty(),
+              view_formats: vec![],
+            },
+            None,
+          );
+        let wgpu_texture =
+          gpu.create_texture_from_hal::<api::Vulkan>(
+            hal_texture,
+            &TextureDescriptor {
+              label: Some(&format!["DMA-BUF Texture {chain_id}-{i}"]),
+              size: Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
               },
-            );
-          Ok((wgpu_texture, vk_image, vk_memory))
-        },
-      );
-    let [(a0, a1, a2), (b0, b1, b2), (c0, c1, c2)] = [a?, b?, c?];
-    let vk_memories = [a2, b2, c2];
-    let vk_images = [a1, b1, c1];
-    let wgpu_textures = [a0, b0, c0];
-    Ok(Self {
-      scan: 0,
-      draw: 0,
-      vk_memories,
-      vk_images,
-      wgpu_textures,
-      bos: buffers,
-      fbs: framebuffers,
-    })
+              mip_level_count: 1,
+              sample_count: 1,
+              dimension: TextureDimension::D2,
+              format: format.wgpu,
+              usage: wgpu::TextureUsages::RENDER_ATTACHMENT |
+                wgpu::TextureUsages::TEXTURE_BINDING |
+                wgpu::TextureUsages::COPY_SRC |
+                wgpu::TextureUsages::COPY_DST,
+              view_formats: &[],
+            },
+          );
+        (wgpu_texture, vk_memory)
+      };
+      made.fb = None;
+      made.buffers.push(SwapchainBuffer {
+        vk_memory,
+        wgpu_texture,
+        fb,
+      });
+    }
+    Ok(Self::from_buffers(std::mem::take(&mut made.buffers)))
+  }
+
+  /// Plain GPU textures for a device without GBM, eg a headless one. The
+  /// framebuffer IDs only mean something to the device they are committed to.
+  pub fn offscreen(gpu: &wgpu::Device, spec: SwapchainSpec) -> Self {
+    let SwapchainSpec { format, size: (width, height), count, .. } = spec;
+    let chain_id: u64 = rand::random::<u64>();
+    let buffers =
+      (0 .. count)
+        .map(|i| SwapchainBuffer {
+          vk_memory: vk::DeviceMemory::null(),
+          wgpu_texture: gpu.create_texture(&TextureDescriptor {
+            label: Some(&format!["Offscreen Texture {chain_id}-{i}"]),
+            size: Extent3d {
+              width,
+              height,
+              depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: format.wgpu,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT |
+              wgpu::TextureUsages::TEXTURE_BINDING |
+              wgpu::TextureUsages::COPY_SRC |
+              wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+          }),
+          fb: drm::control::from_u32(OFFSCREEN_FB_IDS.fetch_sub(1, Ordering::Relaxed)).unwrap(),
+        })
+        .collect();
+    Self::from_buffers(buffers)
+  }
+
+  fn from_buffers(buffers: Vec<SwapchainBuffer>) -> Self {
+    let mut swapchain = Self::with_slots(buffers.len());
+    swapchain.buffers = buffers;
+    swapchain
+  }
+
+  // The bookkeeping for `count` buffers, without the buffers. The first one
+  // is what a modeset shows.
+  fn with_slots(count: usize) -> Self {
+    let mut slots = vec![Slot::default(); count];
+    slots[0].state = BufferState::Scanout;
+    Self {
+      buffers: Vec::new(),
+      mode: PresentMode::default(),
+      slots,
+      frame: 0,
+      ready: VecDeque::new(),
+    }
+  }
+
+  fn find(&self, state: BufferState) -> Option<usize> {
+    self.slots.iter().position(|slot| slot.state == state)
+  }
+
+  /// The buffer being drawn into, taking a free one if there is none. The
+  /// free buffer drawn most recently is picked so its age is low. In mailbox
+  /// mode the oldest waiting frame is given up if nothing is free. None if
+  /// every buffer is in use.
+  pub fn acquire(&mut self) -> Option<usize> {
+    if let Some(i) = self.find(BufferState::Drawing) {
+      return Some(i);
+    }
+    let free =
+      self
+        .slots
+        .iter()
+        .enumerate()
+        .filter(|(_, slot)| slot.state == BufferState::Free)
+        .max_by_key(|(_, slot)| slot.frame)
+        .map(|(i, _)| i);
+    let i = match (free, self.mode) {
+      (Some(i), _) => i,
+      (None, PresentMode::Mailbox) => self.ready.pop_front()?,
+      (None, PresentMode::Fifo) => return None,
+    };
+    self.slots[i].state = BufferState::Drawing;
+    Some(i)
+  }
+
+  /// How many frames ago the contents of the draw buffer were presented, 1
+  /// being the last one. 0 if they are undefined and everything has to be
+  /// redrawn.
+  pub fn age(&self) -> u64 {
+    self
+      .find(BufferState::Drawing)
+      .map(|i| self.slots[i].frame)
+      .filter(|frame| *frame != 0)
+      .map(|frame| self.frame - frame + 1)
+      .unwrap_or(0)
+  }
+
+  /// The draw buffer is done, queue it to be committed. In mailbox mode it
+  /// replaces any frame still waiting.
+  pub fn present(&mut self) {
+    let Some(i) = self.find(BufferState::Drawing) else {
+      return;
+    };
+    if self.mode == PresentMode::Mailbox {
+      for dropped in self.ready.drain(..) {
+        self.slots[dropped].state = BufferState::Free;
+      }
+    }
+    self.frame += 1;
+    self.slots[i].frame = self.frame;
+    self.slots[i].state = BufferState::Ready;
+    self.ready.push_back(i);
+  }
+
+  /// The frame to commit next, None if nothing new was presented
+  pub fn next_ready(&self) -> Option<usize> {
+    self.ready.front().copied()
+  }
+
+  /// The frame from `next_ready` was committed
+  pub fn queued(&mut self) {
+    if let Some(i) = self.ready.pop_front() {
+      self.slots[i].state = BufferState::Queued;
+    }
+  }
+
+  /// A page flip completed: the queued buffer is on screen and the one it
+  /// replaced is free again
+  pub fn flipped(&mut self) {
+    let Some(queued) = self.find(BufferState::Queued) else {
+      return;
+    };
+    if let Some(scanout) = self.find(BufferState::Scanout) {
+      self.slots[scanout].state = BufferState::Free;
+    }
+    self.slots[queued].state = BufferState::Scanout;
   }
 
-  pub fn swap(&mut self) {
-    self.scan = self.draw;
-    self.draw = (self.draw + 1) % 3;
+  /// The buffer most recently committed, whether or not it is on screen yet
+  pub fn latest(&self) -> usize {
+    self.find(BufferState::Queued).or_else(|| self.find(BufferState::Scanout)).unwrap_or(0)
   }
 }
 
 #[derive(Debug)]
 pub struct DrmCtx {
   pub plane: plane::Handle,
-  pub plane_props: HashMap<String, property::Info>,
+  pub plane_props: PropertyMap,
   pub size: (u32, u32),
-  pub buffers: TripleBuffer,
+  pub format: ScanoutFormat,
+  /// What the plane can scan out
+  pub formats: FormatTable,
+  pub buffers: Swapchain,
 }
 
 impl DrmCtx {
+  /// `formats` is all the plane can scan out. Without `gbm` the buffers are
+  /// offscreen textures.
   pub fn new(
-    card: &Card,
-    gbm: &gbm::Device<&'static Card>,
+    kms: &dyn KmsBackend,
+    gbm: Option<&gbm::Device<&'static Card>>,
     gpu: &wgpu::Device,
     plane: plane::Handle,
     planetype: PlaneType,
-    size: (u32, u32),
+    formats: FormatTable,
+    spec: SwapchainSpec,
   ) -> CompositorResult<Self> {
     let plane_props =
-      card
-        .get_properties(plane)
-        .map_err(|err| CompositorError::GetPlaneProperties(plane, err))?
-        .as_hashmap(card)
-        .map_err(|err| CompositorError::PropsToHashMap(err))?;
-    let buffers = TripleBuffer::new(card, gbm, gpu, planetype, size)?;
+      kms
+        .properties(plane.into())
+        .map_err(|err| CompositorError::GetPlaneProperties(plane, err))?;
+    let buffers =
+      match gbm {
+        Some(gbm) => Swapchain::new(gbm, gpu, planetype, spec)?,
+        None => Swapchain::offscreen(gpu, spec),
+      };
     Ok(Self {
       plane,
       plane_props,
-      size,
+      size: spec.size,
+      format: spec.format,
+      formats,
       buffers,
     })
   }
 
-  pub fn from_connector(
-    card: &Card,
-    gbm: &gbm::Device<&'static Card>,
-    gpu: &wgpu::Device,
-    resources: &ResourceHandles,
-    crtc: crtc::Handle,
-    planes: &mut HashSet<plane::Handle>,
-    planetype: PlaneType,
-    size: (u32, u32),
-  ) -> CompositorResult<Self> {
-    let plane = find_compatible_plane(card, resources, crtc, planes, planetype);
-    if let Some(plane) = plane {
-      Self::new(card, gbm, gpu, plane, planetype, size)
-    } else {
-      Err(
-        CompositorError::NoCompatiblePrimaryPlane(
-          card.get_crtc(crtc).map_err(|e| CompositorError::GetCrtcInfo(crtc, e))?,
-        ),
-      )
+  pub fn destroy_framebuffers(&self, kms: &dyn KmsBackend) {
+    for buffer in self.buffers.buffers.iter() {
+      kms.remove_framebuffer(buffer.fb).ok();
     }
   }
 
-  fn get_draw_fb(&self) -> framebuffer::Handle {
-    self.buffers.fbs[self.buffers.draw]
+  /// The texture most recently committed
+  pub fn scan_texture(&self) -> &wgpu::Texture {
+    &self.buffers.buffers[self.buffers.latest()].wgpu_texture
+  }
+
+  /// The texture to draw the next frame into, None if every buffer is busy
+  pub fn draw_texture(&mut self) -> Option<&wgpu::Texture> {
+    let i = self.buffers.acquire()?;
+    Some(&self.buffers.buffers[i].wgpu_texture)
+  }
+
+  /// The frame to commit: the next one presented, or the latest one again if
+  /// nothing new was
+  pub fn next_fb(&self) -> framebuffer::Handle {
+    let i = self.buffers.next_ready().unwrap_or_else(|| self.buffers.latest());
+    self.buffers.buffers[i].fb
   }
 
   pub fn init_req(
     &self,
-    atomic_req: &mut atomic::AtomicModeReq,
+    atomic_req: &mut AtomicRequest,
     crtc: crtc::Handle,
   ) -> CompositorResult<()> {
+    self.plane_req(atomic_req, crtc, self.buffers.buffers[0].fb, (0, 0));
+    Ok(())
+  }
+
+  /// Queue the next presented frame. Call `buffers.queued` once it is
+  /// committed.
+  pub fn flip_req(&self, atomic_req: &mut AtomicRequest, crtc: crtc::Handle) {
+    self.plane_req(atomic_req, crtc, self.next_fb(), (0, 0));
+  }
+
+  /// Show all of `fb` unscaled with its top left corner at `pos` on `crtc`
+  pub fn plane_req(
+    &self,
+    atomic_req: &mut AtomicRequest,
+    crtc: crtc::Handle,
+    fb: framebuffer::Handle,
+    (x, y): (i32, i32),
+  ) {
     let plane = self.plane;
     let props = &self.plane_props;
     atomic_req.add_property(
       plane,
       props["FB_ID"].handle(),
-      property::Value::Framebuffer(Some(self.buffers.fbs[0])),
+      property::Value::Framebuffer(Some(fb)),
     );
     atomic_req.add_property(
       plane,
@@ -428,12 +824,12 @@ impl DrmCtx {
     atomic_req.add_property(
       plane,
       props["CRTC_X"].handle(),
-      property::Value::SignedRange(0),
+      property::Value::SignedRange(x as i64),
     );
     atomic_req.add_property(
       plane,
       props["CRTC_Y"].handle(),
-      property::Value::SignedRange(0),
+      property::Value::SignedRange(y as i64),
     );
     atomic_req.add_property(
       plane,
@@ -445,75 +841,110 @@ impl DrmCtx {
       props["CRTC_H"].handle(),
       property::Value::UnsignedRange(self.size.1 as u64),
     );
-    Ok(())
   }
 
-  pub unsafe fn swap(
-    &mut self,
-    card: &Card,
-    crtc: crtc::Handle,
-  ) -> CompositorResult<()> {
-    let plane = self.plane;
+  /// Have the plane wait for `fence` before showing the buffer, if the
+  /// driver supports it
+  pub fn fence_req(&self, atomic_req: &mut AtomicRequest, fence: BorrowedFd) {
+    if let Some(prop) = self.plane_props.get("IN_FENCE_FD") {
+      atomic_req.add_property(
+        self.plane,
+        prop.handle(),
+        property::Value::SignedRange(fence.as_raw_fd() as i64),
+      );
+    }
+  }
 
-    // Queue a page flip
-    let mut atomic_req = atomic::AtomicModeReq::new();
+  /// Turn the plane off
+  pub fn disable_req(&self, atomic_req: &mut AtomicRequest) {
     atomic_req.add_property(
-      plane,
+      self.plane,
       self.plane_props["FB_ID"].handle(),
-      property::Value::Framebuffer(Some(self.get_draw_fb())),
+      property::Value::Framebuffer(None),
     );
     atomic_req.add_property(
-      plane,
+      self.plane,
       self.plane_props["CRTC_ID"].handle(),
-      property::Value::CRTC(Some(crtc)),
-    );
-    atomic_req.add_property(
-      plane,
-      self.plane_props["SRC_X"].handle(),
-      property::Value::UnsignedRange(0),
-    );
-    atomic_req.add_property(
-      plane,
-      self.plane_props["SRC_Y"].handle(),
-      property::Value::UnsignedRange(0),
-    );
-    atomic_req.add_property(
-      plane,
-      self.plane_props["SRC_W"].handle(),
-      property::Value::UnsignedRange((self.size.0 << 16) as u64),
-    );
-    atomic_req.add_property(
-      plane,
-      self.plane_props["SRC_H"].handle(),
-      property::Value::UnsignedRange((self.size.1 << 16) as u64),
-    );
-    atomic_req.add_property(
-      plane,
-      self.plane_props["CRTC_X"].handle(),
-      property::Value::SignedRange(0),
+      property::Value::CRTC(None),
     );
-    atomic_req.add_property(
-      plane,
-      self.plane_props["CRTC_Y"].handle(),
-      property::Value::SignedRange(0),
-    );
-    atomic_req.add_property(
-      plane,
-      self.plane_props["CRTC_W"].handle(),
-      property::Value::UnsignedRange(self.size.0 as u64),
-    );
-    atomic_req.add_property(
-      plane,
-      self.plane_props["CRTC_H"].handle(),
-      property::Value::UnsignedRange(self.size.1 as u64),
-    );
-    card
-      .atomic_commit(
-        AtomicCommitFlags::NONBLOCK | AtomicCommitFlags::PAGE_FLIP_EVENT,
-        atomic_req,
-      )
-      .map_err(|err| CompositorError::AtomicCommitFailed(err))?;
-    self.buffers.swap();
-    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn states(swapchain: &Swapchain) -> Vec<BufferState> {
+    swapchain.slots.iter().map(|slot| slot.state).collect()
+  }
+
+  #[test]
+  fn presents_every_frame_in_fifo_mode() {
+    use BufferState::*;
+    let mut swapchain = Swapchain::with_slots(3);
+    assert_eq!(swapchain.age(), 0);
+
+    // Two frames wait in turn, after which nothing is free
+    assert_eq!(swapchain.acquire(), Some(2));
+    assert_eq!(swapchain.age(), 0);
+    swapchain.present();
+    assert_eq!(swapchain.acquire(), Some(1));
+    swapchain.present();
+    assert_eq!(states(&swapchain), [Scanout, Ready, Ready]);
+    assert_eq!(swapchain.acquire(), None);
+
+    assert_eq!(swapchain.next_ready(), Some(2));
+    swapchain.queued();
+    assert_eq!(swapchain.latest(), 2);
+    assert_eq!(swapchain.next_ready(), Some(1));
+    swapchain.flipped();
+    assert_eq!(states(&swapchain), [Free, Ready, Scanout]);
+
+    // The first buffer was never drawn into
+    assert_eq!(swapchain.acquire(), Some(0));
+    assert_eq!(swapchain.age(), 0);
+    swapchain.present();
+    swapchain.queued();
+    swapchain.flipped();
+    assert_eq!(states(&swapchain), [Ready, Scanout, Free]);
+
+    // Drawn three frames ago, and still the draw buffer until presented
+    assert_eq!(swapchain.acquire(), Some(2));
+    assert_eq!(swapchain.age(), 3);
+    assert_eq!(swapchain.acquire(), Some(2));
+  }
+
+  #[test]
+  fn replaces_the_waiting_frame_in_mailbox_mode() {
+    use BufferState::*;
+    let mut swapchain = Swapchain::with_slots(3);
+    swapchain.mode = PresentMode::Mailbox;
+    assert_eq!(swapchain.acquire(), Some(2));
+    swapchain.present();
+
+    // A new frame frees the one still waiting
+    assert_eq!(swapchain.acquire(), Some(1));
+    assert_eq!(swapchain.age(), 0);
+    swapchain.present();
+    assert_eq!(states(&swapchain), [Scanout, Ready, Free]);
+    assert_eq!(swapchain.next_ready(), Some(1));
+
+    assert_eq!(swapchain.acquire(), Some(2));
+    assert_eq!(swapchain.age(), 2);
+    swapchain.present();
+    swapchain.queued();
+    assert_eq!(swapchain.acquire(), Some(1));
+    assert_eq!(swapchain.age(), 2);
+    swapchain.present();
+    assert_eq!(states(&swapchain), [Scanout, Ready, Queued]);
+
+    // With nothing free the waiting frame is drawn over
+    assert_eq!(swapchain.acquire(), Some(1));
+    assert_eq!(swapchain.age(), 1);
+    swapchain.present();
+    assert_eq!(swapchain.next_ready(), Some(1));
+    swapchain.flipped();
+    assert_eq!(states(&swapchain), [Free, Ready, Scanout]);
+    assert_eq!(swapchain.latest(), 2);
   }
 }
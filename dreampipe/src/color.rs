@@ -0,0 +1,587 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+/// Colour temperature that leaves colours as they are, in kelvin
+pub const NEUTRAL_TEMPERATURE: u32 = 6500;
+/// Night light colour temperature unless configured otherwise
+pub const DEFAULT_NIGHT_TEMPERATURE: u32 = 4000;
+/// Ramp size of the shader fallback, one entry per 8 bit value
+pub const FALLBACK_LUT_SIZE: usize = 256;
+/// How bright SDR white is in HDR by default, in nits, as in ITU-R BT.2408
+pub const DEFAULT_SDR_WHITE: f64 = 203.0;
+// Minutes taken to fade between day and night
+const TRANSITION: f64 = 30.0;
+const DAY: f64 = 24.0 * 60.0;
+// Temperatures are rounded to this so a fade doesn't update every frame
+const TEMPERATURE_STEP: u32 = 10;
+
+/// Row major 3x3 matrix on linear RGB
+pub type Matrix = [[f64; 3]; 3];
+
+pub const IDENTITY: Matrix = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+// Machado et al. 2009 simulations of full dichromacy, in linear RGB
+const PROTANOPIA: Matrix = [
+  [0.152286, 1.052583, -0.204868],
+  [0.114503, 0.786281, 0.099216],
+  [-0.003882, -0.048116, 1.051998],
+];
+const DEUTERANOPIA: Matrix = [
+  [0.367322, 0.860646, -0.227968],
+  [0.280085, 0.672501, 0.047413],
+  [-0.011820, 0.042940, 0.968881],
+];
+const TRITANOPIA: Matrix = [
+  [1.255528, -0.076749, -0.178779],
+  [-0.078411, 0.930809, 0.147602],
+  [0.004733, 0.691367, 0.303900],
+];
+// Where the colour lost to red-green blindness is moved to
+const RED_GREEN_SHIFT: Matrix = [[0.0, 0.0, 0.0], [0.7, 1.0, 0.0], [0.7, 0.0, 1.0]];
+// Where the colour lost to blue-yellow blindness is moved to
+const BLUE_YELLOW_SHIFT: Matrix = [[1.0, 0.0, 0.7], [0.0, 1.0, 0.7], [0.0, 0.0, 0.0]];
+// Rec. 709 luminance
+const GRAYSCALE: Matrix = [
+  [0.2126, 0.7152, 0.0722],
+  [0.2126, 0.7152, 0.0722],
+  [0.2126, 0.7152, 0.0722],
+];
+
+pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+  std::array::from_fn(|row| {
+    std::array::from_fn(|col| (0 .. 3).map(|i| a[row][i] * b[i][col]).sum())
+  })
+}
+
+fn subtract(a: &Matrix, b: &Matrix) -> Matrix {
+  std::array::from_fn(|row| std::array::from_fn(|col| a[row][col] - b[row][col]))
+}
+
+fn add(a: &Matrix, b: &Matrix) -> Matrix {
+  std::array::from_fn(|row| std::array::from_fn(|col| a[row][col] + b[row][col]))
+}
+
+// Daltonization: colour a dichromat can't tell apart is moved by `shift` into
+// channels they can
+fn daltonize(simulation: &Matrix, shift: &Matrix) -> Matrix {
+  add(&IDENTITY, &multiply(shift, &subtract(&IDENTITY, simulation)))
+}
+
+/// Colour matrix filters, mostly to make colours tell apart for colour
+/// blindness
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ColorFilter {
+  #[default]
+  None,
+  /// Corrects for missing red cones
+  Protanopia,
+  /// Corrects for missing green cones
+  Deuteranopia,
+  /// Corrects for missing blue cones
+  Tritanopia,
+  Grayscale,
+}
+
+impl FromStr for ColorFilter {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim() {
+      "none" => Ok(Self::None),
+      "protanopia" => Ok(Self::Protanopia),
+      "deuteranopia" => Ok(Self::Deuteranopia),
+      "tritanopia" => Ok(Self::Tritanopia),
+      "grayscale" => Ok(Self::Grayscale),
+      other => Err(format![
+        "Unknown color filter '{other}', expected none, protanopia, deuteranopia, tritanopia or \
+         grayscale"
+      ]),
+    }
+  }
+}
+
+impl ColorFilter {
+  /// The matrix to apply to linear RGB, None for no filter
+  pub fn matrix(&self) -> Option<Matrix> {
+    match self {
+      ColorFilter::None => None,
+      ColorFilter::Protanopia => Some(daltonize(&PROTANOPIA, &RED_GREEN_SHIFT)),
+      ColorFilter::Deuteranopia => Some(daltonize(&DEUTERANOPIA, &RED_GREEN_SHIFT)),
+      ColorFilter::Tritanopia => Some(daltonize(&TRITANOPIA, &BLUE_YELLOW_SHIFT)),
+      ColorFilter::Grayscale => Some(GRAYSCALE),
+    }
+  }
+}
+
+/// Local wall clock time, as needed for night light
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LocalTime {
+  /// 1 on January 1st
+  pub day_of_year: u32,
+  /// Since local midnight
+  pub minutes: f64,
+  /// Local time minus UTC, in minutes
+  pub utc_offset: f64,
+}
+
+/// Sunrise and sunset of one day, in minutes since local midnight
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Daylight {
+  Sun {
+    rise: f64,
+    set: f64,
+  },
+  /// The sun doesn't set
+  PolarDay,
+  /// The sun doesn't rise
+  PolarNight,
+}
+
+/// When the sun rises and sets at `latitude` and `longitude` (degrees, north
+/// and east positive) on `day_of_year`, using NOAA's approximation
+pub fn daylight(latitude: f64, longitude: f64, day_of_year: u32, utc_offset: f64) -> Daylight {
+  let year = 2.0 * PI / 365.0 * (day_of_year as f64 - 1.0);
+  let equation_of_time =
+    229.18 *
+      (0.000075 + 0.001868 * year.cos() - 0.032077 * year.sin() - 0.014615 * (2.0 * year).cos() -
+        0.040849 * (2.0 * year).sin());
+  let declination =
+    0.006918 - 0.399912 * year.cos() + 0.070257 * year.sin() - 0.006758 * (2.0 * year).cos() +
+      0.000907 * (2.0 * year).sin() - 0.002697 * (3.0 * year).cos() + 0.00148 * (3.0 * year).sin();
+
+  // The sun's centre 0.833° below the horizon, for refraction and its radius
+  let latitude = latitude.to_radians();
+  let cos_hour_angle =
+    90.833f64.to_radians().cos() / (latitude.cos() * declination.cos()) -
+      latitude.tan() * declination.tan();
+  if cos_hour_angle > 1.0 {
+    return Daylight::PolarNight;
+  }
+  if cos_hour_angle < -1.0 {
+    return Daylight::PolarDay;
+  }
+  let hour_angle = cos_hour_angle.acos().to_degrees();
+  let noon = 720.0 - 4.0 * longitude - equation_of_time + utc_offset;
+  Daylight::Sun {
+    rise: (noon - 4.0 * hour_angle).rem_euclid(DAY),
+    set: (noon + 4.0 * hour_angle).rem_euclid(DAY),
+  }
+}
+
+// How far into the night `minutes` is: 0.0 by day, 1.0 by night, fading in
+// after `start` and out before `end`
+fn night_amount(minutes: f64, start: f64, end: f64) -> f64 {
+  let since_start = (minutes - start).rem_euclid(DAY);
+  let length = (end - start).rem_euclid(DAY);
+  if since_start >= length {
+    return 0.0;
+  }
+  let fade_in = since_start / TRANSITION;
+  let fade_out = (length - since_start) / TRANSITION;
+  fade_in.min(fade_out).min(1.0)
+}
+
+// Minutes since midnight from `HH:MM`
+fn parse_clock(s: &str) -> Result<f64, String> {
+  let (hours, minutes) =
+    s.trim().split_once(':').ok_or_else(|| format!["Expected HH:MM, got '{s}'"])?;
+  let hours = hours.parse::<u32>().map_err(|e| format!["Bad hour in '{s}': {e}"])?;
+  let minutes = minutes.parse::<u32>().map_err(|e| format!["Bad minute in '{s}': {e}"])?;
+  if hours > 23 || minutes > 59 {
+    return Err(format!["'{s}' is not a time of day"]);
+  }
+  Ok((hours * 60 + minutes) as f64)
+}
+
+/// When night light is on
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NightSchedule {
+  #[default]
+  Off,
+  On,
+  /// From and until, in minutes since local midnight
+  Fixed(f64, f64),
+  /// From sunset until sunrise at a latitude and longitude
+  Sun(f64, f64),
+}
+
+impl FromStr for NightSchedule {
+  type Err = String;
+
+  /// `off`, `on`, `HH:MM-HH:MM`, or `sun LATITUDE,LONGITUDE`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim();
+    if let Some(position) = s.strip_prefix("sun") {
+      let (latitude, longitude) =
+        position
+          .split_once(',')
+          .ok_or_else(|| format!["Expected 'sun LATITUDE,LONGITUDE', got '{s}'"])?;
+      let latitude = latitude.trim().parse::<f64>().map_err(|e| format!["Bad latitude: {e}"])?;
+      let longitude = longitude.trim().parse::<f64>().map_err(|e| format!["Bad longitude: {e}"])?;
+      return Ok(Self::Sun(latitude.clamp(-90.0, 90.0), longitude));
+    }
+    match s {
+      "off" => Ok(Self::Off),
+      "on" => Ok(Self::On),
+      range => {
+        let (start, end) =
+          range
+            .split_once('-')
+            .ok_or_else(|| format!["Expected off, on, HH:MM-HH:MM or 'sun LAT,LON', got '{s}'"])?;
+        Ok(Self::Fixed(parse_clock(start)?, parse_clock(end)?))
+      },
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NightLight {
+  pub schedule: NightSchedule,
+  /// Colour temperature at night, in kelvin
+  pub temperature: u32,
+}
+
+impl Default for NightLight {
+  fn default() -> Self {
+    Self {
+      schedule: NightSchedule::Off,
+      temperature: DEFAULT_NIGHT_TEMPERATURE,
+    }
+  }
+}
+
+impl NightLight {
+  /// Colour temperature at `time`, fading between day and night over half an
+  /// hour
+  pub fn temperature_at(&self, time: &LocalTime) -> u32 {
+    let (start, end) =
+      match self.schedule {
+        NightSchedule::Off => return NEUTRAL_TEMPERATURE,
+        NightSchedule::On => return self.temperature,
+        NightSchedule::Fixed(start, end) => (start, end),
+        NightSchedule::Sun(latitude, longitude) => {
+          match daylight(latitude, longitude, time.day_of_year, time.utc_offset) {
+            Daylight::Sun { rise, set } => (set, rise),
+            Daylight::PolarDay => return NEUTRAL_TEMPERATURE,
+            Daylight::PolarNight => return self.temperature,
+          }
+        },
+      };
+    let night = night_amount(time.minutes, start, end);
+    let temperature =
+      NEUTRAL_TEMPERATURE as f64 + (self.temperature as f64 - NEUTRAL_TEMPERATURE as f64) * night;
+    (temperature / TEMPERATURE_STEP as f64).round() as u32 * TEMPERATURE_STEP
+  }
+}
+
+// Tanner Helland's fit of the colour of a black body, 0.0 ..= 1.0 per channel
+fn blackbody(kelvin: f64) -> [f64; 3] {
+  let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+  let red =
+    if t <= 66.0 {
+      255.0
+    } else {
+      329.698727446 * (t - 60.0).powf(-0.1332047592)
+    };
+  let green =
+    if t <= 66.0 {
+      99.4708025861 * t.ln() - 161.1195681661
+    } else {
+      288.1221695283 * (t - 60.0).powf(-0.0755148492)
+    };
+  let blue =
+    if t >= 66.0 {
+      255.0
+    } else if t <= 19.0 {
+      0.0
+    } else {
+      138.5177312231 * (t - 10.0).ln() - 305.0447927307
+    };
+  [red, green, blue].map(|c| (c / 255.0).clamp(0.0, 1.0))
+}
+
+/// Per channel gains that tint white to `kelvin`. All 1.0 at
+/// `NEUTRAL_TEMPERATURE`, and never above 1.0.
+pub fn temperature_gains(kelvin: u32) -> [f64; 3] {
+  let neutral = blackbody(NEUTRAL_TEMPERATURE as f64);
+  let white = blackbody(kelvin as f64);
+  let gains: [f64; 3] = std::array::from_fn(|i| white[i] / neutral[i]);
+  let max = gains.iter().cloned().fold(f64::EPSILON, f64::max);
+  gains.map(|gain| gain / max)
+}
+
+/// sRGB encoded to linear light
+pub fn srgb_decode(value: f64) -> f64 {
+  if value <= 0.04045 {
+    value / 12.92
+  } else {
+    ((value + 0.055) / 1.055).powf(2.4)
+  }
+}
+
+/// Linear light to sRGB encoded
+pub fn srgb_encode(value: f64) -> f64 {
+  if value <= 0.0031308 {
+    value * 12.92
+  } else {
+    1.055 * value.powf(1.0 / 2.4) - 0.055
+  }
+}
+
+/// Colour settings of a display from its config
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorSettings {
+  /// Scales the output, 1.0 leaves it
+  pub brightness: f64,
+  /// Stretches the output around mid grey, 1.0 leaves it
+  pub contrast: f64,
+  /// Exponent of the output curve, above 1.0 brightens the midtones
+  pub gamma: f64,
+  pub filter: ColorFilter,
+  pub night_light: NightLight,
+}
+
+impl Default for ColorSettings {
+  fn default() -> Self {
+    Self {
+      brightness: 1.0,
+      contrast: 1.0,
+      gamma: 1.0,
+      filter: ColorFilter::None,
+      night_light: NightLight::default(),
+    }
+  }
+}
+
+impl ColorSettings {
+  pub fn state_at(&self, time: &LocalTime) -> ColorState {
+    ColorState {
+      temperature: self.night_light.temperature_at(time),
+      brightness: self.brightness.clamp(0.0, 1.0),
+      contrast: self.contrast.max(0.0),
+      gamma: self.gamma.max(0.01),
+      filter: self.filter,
+    }
+  }
+}
+
+/// What the colour pipeline of a display is set to at one moment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorState {
+  pub temperature: u32,
+  pub brightness: f64,
+  pub contrast: f64,
+  pub gamma: f64,
+  pub filter: ColorFilter,
+}
+
+impl ColorState {
+  /// Leaves every colour as it is
+  pub fn is_identity(&self) -> bool {
+    self.temperature == NEUTRAL_TEMPERATURE && self.brightness == 1.0 && self.contrast == 1.0 &&
+      self.gamma == 1.0 && self.filter == ColorFilter::None
+  }
+
+  /// The output curve on sRGB encoded values, with `size` entries. If
+  /// `linear_input` the entries are for linear light instead, as after a
+  /// degamma LUT.
+  pub fn gamma_lut(&self, size: usize, linear_input: bool) -> Vec<[u16; 3]> {
+    let gains = temperature_gains(self.temperature);
+    (0 .. size)
+      .map(|i| {
+        let value = i as f64 / size.saturating_sub(1).max(1) as f64;
+        let value = if linear_input { srgb_encode(value) } else { value };
+        let value =
+          ((value - 0.5) * self.contrast + 0.5).clamp(0.0, 1.0).powf(1.0 / self.gamma) *
+            self.brightness;
+        gains.map(|gain| to_u16(value * gain))
+      })
+      .collect()
+  }
+}
+
+fn to_u16(value: f64) -> u16 {
+  (value.clamp(0.0, 1.0) * u16::MAX as f64).round() as u16
+}
+
+/// sRGB decoding for a degamma LUT with `size` entries
+pub fn degamma_lut(size: usize) -> Vec<[u16; 3]> {
+  (0 .. size)
+    .map(|i| {
+      let value = to_u16(srgb_decode(i as f64 / size.saturating_sub(1).max(1) as f64));
+      [value; 3]
+    })
+    .collect()
+}
+
+/// A `GAMMA_LUT`/`DEGAMMA_LUT` blob: `struct drm_color_lut` per entry
+pub fn lut_blob(lut: &[[u16; 3]]) -> Vec<u8> {
+  lut
+    .iter()
+    .flat_map(|&[red, green, blue]| [red, green, blue, 0])
+    .flat_map(|value| value.to_ne_bytes())
+    .collect()
+}
+
+/// A `CTM` blob: `struct drm_color_ctm`, row major S31.32 sign-magnitude
+pub fn ctm_blob(matrix: &Matrix) -> Vec<u8> {
+  matrix
+    .iter()
+    .flatten()
+    .map(|&value| {
+      let magnitude = ((value.abs() * (1u64 << 32) as f64) as u64) & !(1 << 63);
+      if value < 0.0 { magnitude | 1 << 63 } else { magnitude }
+    })
+    .flat_map(|value| value.to_ne_bytes())
+    .collect()
+}
+
+/// `struct hdr_output_metadata` for the connector's `HDR_OUTPUT_METADATA`,
+/// announcing PQ encoded BT.2020 mastered for the given luminances in nits
+pub fn hdr_output_metadata_blob(max_nits: f64, min_nits: f64, max_frame_average: f64) -> Vec<u8> {
+  // Primaries and white point in units of 0.00002
+  const BT2020: [[u16; 2]; 4] = [[35400, 14600], [8500, 39850], [6550, 2300], [15635, 16450]];
+  const EOTF_PQ: u8 = 2;
+  let mut blob = Vec::with_capacity(32);
+  blob.extend(0u32.to_ne_bytes());
+  blob.extend([EOTF_PQ, 0]);
+  blob.extend(BT2020.iter().flatten().flat_map(|value| value.to_ne_bytes()));
+  let max = max_nits.round().clamp(0.0, u16::MAX as f64) as u16;
+  let min = (min_nits * 10000.0).round().clamp(0.0, u16::MAX as f64) as u16;
+  let fall = max_frame_average.round().clamp(0.0, max as f64) as u16;
+
+  // Content light levels are those of what we draw, which is at most the
+  // mastering range
+  for value in [max, min, max, fall] {
+    blob.extend(value.to_ne_bytes());
+  }
+  blob.resize(32, 0);
+  blob
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn night(schedule: &str) -> NightLight {
+    NightLight {
+      schedule: schedule.parse().unwrap(),
+      temperature: 4000,
+    }
+  }
+
+  fn at(minutes: f64) -> LocalTime {
+    LocalTime {
+      day_of_year: 1,
+      minutes,
+      utc_offset: 0.0,
+    }
+  }
+
+  fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+    assert!(
+      (actual - expected).abs() <= tolerance,
+      "{actual} isn't within {tolerance} of {expected}"
+    );
+  }
+
+  #[test]
+  fn identity_luts_span_the_range() {
+    let state = ColorSettings::default().state_at(&at(0.0));
+    assert!(state.is_identity());
+    for linear_input in [false, true] {
+      let lut = state.gamma_lut(256, linear_input);
+      assert_eq!(lut.len(), 256);
+      assert_eq!(lut[0], [0; 3]);
+      assert_eq!(lut[255], [u16::MAX; 3]);
+    }
+    let lut = state.gamma_lut(256, false);
+    assert!(lut.iter().enumerate().all(|(i, entry)| *entry == [i as u16 * 257; 3]));
+    let degamma = degamma_lut(4096);
+    assert_eq!(degamma[0], [0; 3]);
+    assert_eq!(degamma[4095], [u16::MAX; 3]);
+    assert!(degamma.windows(2).all(|pair| pair[0][0] <= pair[1][0]));
+  }
+
+  #[test]
+  fn encodes_ctm_as_sign_magnitude() {
+    let matrix = [[1.0, -0.5, 0.0], [-1.0, 2.25, -0.0], [0.0, 0.0, -3.0]];
+    let blob = ctm_blob(&matrix);
+    let values =
+      blob
+        .chunks_exact(8)
+        .map(|chunk| u64::from_ne_bytes(chunk.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    let sign = 1 << 63;
+    assert_eq!(values, [
+      1 << 32,
+      sign | 1 << 31,
+      0,
+      sign | 1 << 32,
+      9 << 30,
+      0,
+      0,
+      0,
+      sign | 3 << 32,
+    ]);
+  }
+
+  #[test]
+  fn neutral_temperature_keeps_white() {
+    assert_eq!(temperature_gains(NEUTRAL_TEMPERATURE), [1.0; 3]);
+    let [red, green, blue] = temperature_gains(DEFAULT_NIGHT_TEMPERATURE);
+    assert_eq!(red, 1.0);
+    assert!(blue < green && green < red);
+  }
+
+  #[test]
+  fn finds_sunrise_and_sunset() {
+    // London at the summer solstice, in BST: 04:43 and 21:21
+    let Daylight::Sun { rise, set } = daylight(51.5074, -0.1278, 172, 60.0) else {
+      panic!["The sun sets in London"];
+    };
+    assert_near(rise, 4.0 * 60.0 + 43.0, 3.0);
+    assert_near(set, 21.0 * 60.0 + 21.0, 3.0);
+
+    // Tromsø has midnight sun in June and polar night in December
+    assert_eq!(daylight(69.65, 18.96, 172, 120.0), Daylight::PolarDay);
+    assert_eq!(daylight(69.65, 18.96, 355, 60.0), Daylight::PolarNight);
+  }
+
+  #[test]
+  fn fades_night_across_midnight() {
+    let light = night("22:00-06:00");
+    let temperature = |hours: f64| light.temperature_at(&at(hours * 60.0));
+    assert_eq!(temperature(12.0), NEUTRAL_TEMPERATURE);
+    assert_eq!(temperature(21.99), NEUTRAL_TEMPERATURE);
+    assert_eq!(temperature(22.25), 5250);
+    assert_eq!(temperature(23.0), 4000);
+    assert_eq!(temperature(0.0), 4000);
+    assert_eq!(temperature(3.0), 4000);
+    assert_eq!(temperature(5.75), 5250);
+    assert_eq!(temperature(6.0), NEUTRAL_TEMPERATURE);
+    assert_eq!(night("off").temperature_at(&at(0.0)), NEUTRAL_TEMPERATURE);
+    assert_eq!(night("on").temperature_at(&at(12.0 * 60.0)), 4000);
+  }
+
+  #[test]
+  fn parses_night_schedules() {
+    assert_eq!("off".parse(), Ok(NightSchedule::Off));
+    assert_eq!(" on ".parse(), Ok(NightSchedule::On));
+    assert_eq!("21:30-07:05".parse(), Ok(NightSchedule::Fixed(1290.0, 425.0)));
+    assert_eq!("sun 51.5, -0.13".parse(), Ok(NightSchedule::Sun(51.5, -0.13)));
+    for bad in [
+      "24:00-06:00",
+      "22:60-06:00",
+      "22-06",
+      "22:00-6",
+      "aa:00-06:00",
+      "-1:00-06:00",
+      "22:00",
+      "sun 51.5",
+      "sometimes",
+    ] {
+      assert!(bad.parse::<NightSchedule>().is_err(), "Accepted '{bad}'");
+    }
+  }
+}
@@ -0,0 +1,61 @@
+// Colour matrix on linear light, used if use_ctm isn't 0. If sdr_white
+// isn't 0 the output is PQ encoded BT.2020 with SDR white at that many nits.
+struct Params {
+   ctm: mat3x3<f32>,
+   use_ctm: u32,
+   sdr_white: f32,
+   swap_red_blue: u32,
+}
+@group(0) @binding(0) var source: texture_2d<f32>;
+// The output curve, one texel per 8 bit value of the source
+@group(0) @binding(1) var lut: texture_2d<f32>;
+@group(0) @binding(2) var<uniform> params: Params;
+// A triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> @builtin(position) vec4<f32> {
+   let corner = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+   return vec4<f32>(corner * 2.0 - 1.0, 0.0, 1.0);
+}
+fn srgb_decode(c: vec3<f32>) -> vec3<f32> {
+   return select(pow((c + 0.055) / 1.055, vec3<f32>(2.4)), c / 12.92, c <= vec3<f32>(0.04045));
+}
+fn srgb_encode(c: vec3<f32>) -> vec3<f32> {
+   return select(1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3<f32>(0.0031308));
+}
+// Linear BT.709 to linear BT.2020
+const BT709_TO_BT2020 = mat3x3<f32>(
+   vec3<f32>(0.6274, 0.0691, 0.0164),
+   vec3<f32>(0.3293, 0.9195, 0.0880),
+   vec3<f32>(0.0433, 0.0114, 0.8956),
+);
+// SMPTE ST 2084 of light relative to 10000 nits
+fn pq_encode(c: vec3<f32>) -> vec3<f32> {
+   let m1 = 0.1593017578125;
+   let m2 = 78.84375;
+   let c1 = 0.8359375;
+   let c2 = 18.8515625;
+   let c3 = 18.6875;
+   let p = pow(max(c, vec3<f32>(0.0)), vec3<f32>(m1));
+   return pow((c1 + c2 * p) / (1.0 + c3 * p), vec3<f32>(m2));
+}
+// Same order as the CRTC: degamma, matrix, gamma
+@fragment
+fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+   let texel = textureLoad(source, vec2<i32>(position.xy), 0);
+   var color = texel.rgb;
+   if params.use_ctm != 0u {
+      color = srgb_encode(clamp(params.ctm * srgb_decode(color), vec3<f32>(0.0), vec3<f32>(1.0)));
+   }
+   let index = vec3<i32>(round(clamp(color, vec3<f32>(0.0), vec3<f32>(1.0)) * 255.0));
+   let red = textureLoad(lut, vec2<i32>(index.r, 0), 0).r;
+   let green = textureLoad(lut, vec2<i32>(index.g, 0), 0).g;
+   let blue = textureLoad(lut, vec2<i32>(index.b, 0), 0).b;
+   var encoded = vec3<f32>(red, green, blue);
+   if params.sdr_white != 0.0 {
+      encoded = pq_encode(BT709_TO_BT2020 * srgb_decode(encoded) * (params.sdr_white / 10000.0));
+   }
+   if params.swap_red_blue != 0u {
+      encoded = encoded.bgr;
+   }
+   return vec4<f32>(encoded, texel.a);
+}
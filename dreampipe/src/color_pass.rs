@@ -0,0 +1,217 @@
+use crate::buffer::ScanoutFormat;
+use crate::color::ColorState;
+use crate::color::FALLBACK_LUT_SIZE;
+use crate::surface::Rect;
+use std::collections::HashMap;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindingResource;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsages;
+use wgpu::Extent3d;
+use wgpu::Origin3d;
+use wgpu::TexelCopyBufferLayout;
+use wgpu::TexelCopyTextureInfo;
+use wgpu::TextureAspect;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureUsages;
+use wgpu::TextureViewDescriptor;
+
+const COLOR_SHADER: &str = include_str!["color.wgsl"];
+// mat3x3<f32> with padded columns, a u32, an f32 and a u32, rounded up to 16
+// bytes
+const PARAMS_SIZE: u64 = 64;
+// Every format a display's primary plane can be drawn in
+const TARGET_FORMATS: [TextureFormat; 3] =
+  [TextureFormat::Bgra8Unorm, TextureFormat::Rgb10a2Unorm, TextureFormat::Rgba16Float];
+
+/// Applies colour settings in a shader, for CRTCs without the LUT or matrix
+/// properties to do it. Also the last step for displays that don't take what
+/// is composited as is: deep colour and HDR.
+pub struct ColorPass {
+  pipelines: HashMap<TextureFormat, wgpu::RenderPipeline>,
+}
+
+/// A display's colour state and output encoding uploaded for `ColorPass`
+#[derive(Debug)]
+pub struct ColorLut {
+  lut: wgpu::TextureView,
+  params: wgpu::Buffer,
+}
+
+impl ColorPass {
+  pub fn new(gpu: &wgpu::Device) -> Self {
+    let shader = gpu.create_shader_module(wgpu::ShaderModuleDescriptor {
+      label: Some("Color Shader"),
+      source: wgpu::ShaderSource::Wgsl(COLOR_SHADER.into()),
+    });
+    let pipelines =
+      TARGET_FORMATS
+        .into_iter()
+        .map(|format| (format, ColorPass::pipeline(gpu, &shader, format)))
+        .collect();
+    Self { pipelines }
+  }
+
+  fn pipeline(
+    gpu: &wgpu::Device,
+    shader: &wgpu::ShaderModule,
+    format: TextureFormat,
+  ) -> wgpu::RenderPipeline {
+    gpu.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+      label: Some("Color Pipeline"),
+      layout: None,
+      vertex: wgpu::VertexState {
+        module: shader,
+        entry_point: Some("vs_main"),
+        buffers: &[],
+        compilation_options: wgpu::PipelineCompilationOptions::default(),
+      },
+      fragment: Some(wgpu::FragmentState {
+        module: shader,
+        entry_point: Some("fs_main"),
+        targets: &[Some(wgpu::ColorTargetState {
+          format,
+          blend: None,
+          write_mask: wgpu::ColorWrites::ALL,
+        })],
+        compilation_options: wgpu::PipelineCompilationOptions::default(),
+      }),
+      primitive: wgpu::PrimitiveState::default(),
+      depth_stencil: None,
+      multisample: wgpu::MultisampleState::default(),
+      multiview: None,
+      cache: None,
+    })
+  }
+
+  /// Upload `state` for drawing into `format`. With `sdr_white` in nits the
+  /// output is PQ encoded BT.2020 for an HDR display.
+  pub fn lut(
+    &self,
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+    state: &ColorState,
+    format: ScanoutFormat,
+    sdr_white: Option<f64>,
+  ) -> ColorLut {
+    let size = Extent3d {
+      width: FALLBACK_LUT_SIZE as u32,
+      height: 1,
+      depth_or_array_layers: 1,
+    };
+    let texture = gpu.create_texture(&TextureDescriptor {
+      label: Some("Color LUT"),
+      size,
+      mip_level_count: 1,
+      sample_count: 1,
+      dimension: TextureDimension::D2,
+      format: TextureFormat::Rgba32Float,
+      usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+      view_formats: &[],
+    });
+    let texels =
+      state
+        .gamma_lut(FALLBACK_LUT_SIZE, false)
+        .iter()
+        .flat_map(|&[red, green, blue]| [red, green, blue, u16::MAX])
+        .flat_map(|value| (value as f32 / u16::MAX as f32).to_le_bytes())
+        .collect::<Vec<_>>();
+    queue.write_texture(TexelCopyTextureInfo {
+      texture: &texture,
+      mip_level: 0,
+      origin: Origin3d::ZERO,
+      aspect: TextureAspect::All,
+    }, &texels, TexelCopyBufferLayout {
+      offset: 0,
+      bytes_per_row: Some(16 * FALLBACK_LUT_SIZE as u32),
+      rows_per_image: Some(1),
+    }, size);
+
+    // The shader wants columns
+    let matrix = state.filter.matrix();
+    let mut params =
+      (0 .. 3)
+        .flat_map(|col| {
+          let column = matrix.map(|matrix| [matrix[0][col], matrix[1][col], matrix[2][col]]);
+          let [x, y, z] = column.unwrap_or_default();
+          [x as f32, y as f32, z as f32, 0.0]
+        })
+        .flat_map(|f| f.to_le_bytes())
+        .collect::<Vec<_>>();
+    params.extend((matrix.is_some() as u32).to_le_bytes());
+    params.extend((sdr_white.unwrap_or(0.0) as f32).to_le_bytes());
+    params.extend((format.swap_red_blue as u32).to_le_bytes());
+    params.resize(PARAMS_SIZE as usize, 0);
+    let buffer = gpu.create_buffer(&BufferDescriptor {
+      label: Some("Color Params"),
+      size: PARAMS_SIZE,
+      usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+      mapped_at_creation: false,
+    });
+    queue.write_buffer(&buffer, 0, &params);
+    ColorLut {
+      lut: texture.create_view(&TextureViewDescriptor::default()),
+      params: buffer,
+    }
+  }
+
+  /// Draw `rects` of `source` to `target` through `lut`
+  pub fn draw(
+    &self,
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+    lut: &ColorLut,
+    source: &wgpu::Texture,
+    target: &wgpu::Texture,
+    rects: &[Rect],
+  ) {
+    let Some(pipeline) = self.pipelines.get(&target.format()).filter(|_| !rects.is_empty()) else {
+      return;
+    };
+    let source_view = source.create_view(&TextureViewDescriptor::default());
+    let bind_group = gpu.create_bind_group(&BindGroupDescriptor {
+      label: Some("Color Bindgroup"),
+      layout: &pipeline.get_bind_group_layout(0),
+      entries: &[BindGroupEntry {
+        binding: 0,
+        resource: BindingResource::TextureView(&source_view),
+      }, BindGroupEntry {
+        binding: 1,
+        resource: BindingResource::TextureView(&lut.lut),
+      }, BindGroupEntry {
+        binding: 2,
+        resource: lut.params.as_entire_binding(),
+      }],
+    });
+    let view = target.create_view(&TextureViewDescriptor::default());
+    let mut encoder =
+      gpu.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Color Encoder") });
+    {
+      let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Color Pass"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+          view: &view,
+          depth_slice: None,
+          resolve_target: None,
+          ops: wgpu::Operations {
+            load: wgpu::LoadOp::Load,
+            store: wgpu::StoreOp::Store,
+          },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+      });
+      pass.set_pipeline(pipeline);
+      pass.set_bind_group(0, &bind_group, &[]);
+      for rect in rects {
+        pass.set_scissor_rect(rect.x as u32, rect.y as u32, rect.width, rect.height);
+        pass.draw(0 .. 3, 0 .. 1);
+      }
+    }
+    queue.submit([encoder.finish()]);
+  }
+}
@@ -0,0 +1,324 @@
+use crate::dmabuf::ClientTexture;
+use crate::fourcc::FourCc;
+use crate::gpu::create_pipeline;
+use crate::gpu::texture_bind_group_layout;
+use crate::scene::Scene;
+use crate::surface::Rect;
+use crate::surface::Transform;
+use crate::wallpaper::WallpaperImage;
+use crate::wallpaper::WallpaperMode;
+use crate::wallpaper::WallpaperSettings;
+use crate::wallpaper::Wallpapers;
+use crate::wallpaper::placement;
+use crate::yuv::YuvBlit;
+use crate::yuv::YuvPass;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindGroupLayoutDescriptor;
+use wgpu::BindGroupLayoutEntry;
+use wgpu::BindingResource;
+use wgpu::BindingType;
+use wgpu::BufferBindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsages;
+use wgpu::ShaderStages;
+use wgpu::TextureViewDescriptor;
+
+// Four vec2<f32>, a f32 and two u32, rounded up to 16 bytes
+const PARAMS_SIZE: u64 = 48;
+
+// Where a blit goes in its target and which part of the texture it shows
+struct Blit {
+  // Over what is there, or replacing it
+  blend: bool,
+  rect: Rect,
+  uv: [f32; 4],
+  alpha: f32,
+  transform: Transform,
+  // Ignore the texture's alpha channel, eg the X of XRGB
+  opaque: bool,
+}
+
+/// What of the scene a display shows
+pub struct View<'a> {
+  /// Where the display is in the virtual screen
+  pub rect: Rect,
+  pub wallpaper: &'a WallpaperSettings,
+  /// Surfaces on overlay planes, left out
+  pub offloaded: &'a [u64],
+}
+
+/// Draws the wallpaper and every surface that isn't on a plane of its own
+/// into a display's buffer
+pub struct Compositor {
+  texture_layout: wgpu::BindGroupLayout,
+  // Replaces what is in the buffer
+  background_pipeline: wgpu::RenderPipeline,
+  // Blends premultiplied surfaces over what is there
+  surface_pipeline: wgpu::RenderPipeline,
+  sampler: wgpu::Sampler,
+  params: wgpu::Buffer,
+  params_bind_group: wgpu::BindGroup,
+  wallpapers: Wallpapers,
+  yuv: YuvPass,
+}
+
+impl Compositor {
+  pub fn new(gpu: &wgpu::Device) -> Self {
+    let texture_layout = texture_bind_group_layout(gpu);
+    let params_layout = gpu.create_bind_group_layout(&BindGroupLayoutDescriptor {
+      label: Some("Blit Params Bindgroup Layout"),
+      entries: &[BindGroupLayoutEntry {
+        binding: 0,
+        visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+        ty: BindingType::Buffer {
+          ty: BufferBindingType::Uniform,
+          has_dynamic_offset: false,
+          min_binding_size: None,
+        },
+        count: None,
+      }],
+    });
+    let background_pipeline = create_pipeline(gpu, &texture_layout, &params_layout, None);
+    let surface_pipeline =
+      create_pipeline(
+        gpu,
+        &texture_layout,
+        &params_layout,
+        Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+      );
+    let sampler = gpu.create_sampler(&wgpu::SamplerDescriptor {
+      label: Some("Surface Sampler"),
+      mag_filter: wgpu::FilterMode::Linear,
+      min_filter: wgpu::FilterMode::Linear,
+      ..Default::default()
+    });
+    let params = gpu.create_buffer(&BufferDescriptor {
+      label: Some("Blit Params"),
+      size: PARAMS_SIZE,
+      usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+      mapped_at_creation: false,
+    });
+    let params_bind_group = gpu.create_bind_group(&BindGroupDescriptor {
+      label: Some("Blit Params Bindgroup"),
+      layout: &params_layout,
+      entries: &[BindGroupEntry {
+        binding: 0,
+        resource: params.as_entire_binding(),
+      }],
+    });
+    let wallpapers = Wallpapers::new(gpu, texture_layout.clone());
+    Self {
+      texture_layout,
+      background_pipeline,
+      surface_pipeline,
+      sampler,
+      params,
+      params_bind_group,
+      wallpapers,
+      yuv: YuvPass::new(gpu),
+    }
+  }
+
+  /// Upload or start decoding what `wallpaper` shows. Call before drawing
+  /// with it.
+  pub fn prepare_wallpaper(
+    &mut self,
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+    wallpaper: &WallpaperSettings,
+  ) {
+    self.wallpapers.prepare(gpu, queue, wallpaper);
+  }
+
+  /// Upload wallpapers that finished decoding. Returns the images that
+  /// changed, so displays showing them can be redrawn.
+  pub fn receive_wallpapers(
+    &mut self,
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+  ) -> Vec<WallpaperImage> {
+    self.wallpapers.receive(gpu, queue)
+  }
+
+  /// Decode wallpaper files again, eg after the config changed
+  pub fn reload_wallpapers(&mut self) {
+    self.wallpapers.reload();
+  }
+
+  /// Redraw `repaint` of what `view` shows: the wallpaper, then every
+  /// surface on it in z-order except those offloaded
+  pub fn draw(
+    &self,
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+    scene: &Scene,
+    view: &View,
+    canvas: &wgpu::Texture,
+    repaint: &[Rect],
+  ) {
+    if repaint.is_empty() {
+      return;
+    }
+    let display = view.rect;
+    let size = (display.width, display.height);
+    self.draw_wallpaper(gpu, queue, size, view.wallpaper, canvas, repaint);
+    let offset = (-display.x, -display.y);
+    for surface in scene.cull(display) {
+      if view.offloaded.contains(&surface.id) {
+        continue;
+      }
+      let (Some(texture), Some(visible)) = (surface.texture.as_ref(), surface.visible_rect())
+      else {
+        continue;
+      };
+      let visible = visible.translate(offset);
+      let clip =
+        repaint.iter().flat_map(|rect| rect.intersection(&visible)).collect::<Vec<_>>();
+      if clip.is_empty() {
+        continue;
+      }
+      let rect = surface.rect.translate(offset);
+      if YuvPass::supports(texture.format) {
+        self.yuv.draw(gpu, queue, &YuvBlit {
+          texture,
+          color: &surface.yuv,
+          alpha: surface.alpha,
+          transform: surface.transform,
+          rect,
+        }, canvas, &clip);
+        continue;
+      }
+      let bind_group = self.texture_bind_group(gpu, texture);
+      self.blit(gpu, queue, &bind_group, canvas, &Blit {
+        blend: true,
+        rect,
+        uv: [0.0, 0.0, 1.0, 1.0],
+        alpha: surface.alpha.clamp(0.0, 1.0),
+        transform: surface.transform,
+        opaque: surface.opaque || texture.format.info().is_some_and(|info| !info.alpha),
+      }, &clip);
+    }
+  }
+
+  // The colour over everything, then the image if it is loaded
+  fn draw_wallpaper(
+    &self,
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+    size: (u32, u32),
+    wallpaper: &WallpaperSettings,
+    canvas: &wgpu::Texture,
+    repaint: &[Rect],
+  ) {
+    if let Some(color) = self.wallpapers.color(wallpaper.color) {
+      self.blit(gpu, queue, color, canvas, &Blit {
+        blend: false,
+        rect: Rect::new(0, 0, size.0, size.1),
+        uv: [0.0, 0.0, 1.0, 1.0],
+        alpha: 1.0,
+        transform: Transform::Normal,
+        opaque: true,
+      }, repaint);
+    }
+    let texture = wallpaper.image.as_ref().and_then(|image| self.wallpapers.image(image));
+    let Some(texture) = texture.filter(|texture| texture.size.0 > 0 && texture.size.1 > 0) else {
+      return;
+    };
+    let (rect, uv) = placement(wallpaper.mode, texture.size, size);
+    let bind_group =
+      match wallpaper.mode {
+        WallpaperMode::Tile => &texture.tiled,
+        _ => &texture.clamped,
+      };
+    self.blit(gpu, queue, bind_group, canvas, &Blit {
+      blend: true,
+      rect,
+      uv,
+      alpha: 1.0,
+      transform: Transform::Normal,
+      opaque: false,
+    }, repaint);
+  }
+
+  fn texture_bind_group(&self, gpu: &wgpu::Device, texture: &ClientTexture) -> wgpu::BindGroup {
+    let view = texture.texture.create_view(&TextureViewDescriptor::default());
+    gpu.create_bind_group(&BindGroupDescriptor {
+      label: Some("Surface Bindgroup"),
+      layout: &self.texture_layout,
+      entries: &[BindGroupEntry {
+        binding: 0,
+        resource: BindingResource::TextureView(&view),
+      }, BindGroupEntry {
+        binding: 1,
+        resource: BindingResource::Sampler(&self.sampler),
+      }],
+    })
+  }
+
+  // Draw the texture bound by `texture` as `blit` says, touching only `clip`
+  fn blit(
+    &self,
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::BindGroup,
+    target: &wgpu::Texture,
+    blit: &Blit,
+    clip: &[Rect],
+  ) {
+    let (width, height) = (target.width() as f32, target.height() as f32);
+    let rect = blit.rect;
+    let mut params =
+      [
+        2.0 * rect.x as f32 / width - 1.0,
+        1.0 - 2.0 * rect.y as f32 / height,
+        2.0 * rect.width as f32 / width,
+        -2.0 * rect.height as f32 / height,
+      ]
+        .iter()
+        .chain(blit.uv.iter())
+        .chain([blit.alpha].iter())
+        .flat_map(|f| f.to_le_bytes())
+        .collect::<Vec<_>>();
+    params.extend(blit.transform.index().to_le_bytes());
+    params.extend((blit.opaque as u32).to_le_bytes());
+    params.resize(PARAMS_SIZE as usize, 0);
+    queue.write_buffer(&self.params, 0, &params);
+
+    let pipeline =
+      if blit.blend {
+        &self.surface_pipeline
+      } else {
+        &self.background_pipeline
+      };
+    let view = target.create_view(&TextureViewDescriptor::default());
+    let mut encoder =
+      gpu.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Blit Encoder") });
+    {
+      let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Blit Pass"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+          view: &view,
+          depth_slice: None,
+          resolve_target: None,
+          ops: wgpu::Operations {
+            load: wgpu::LoadOp::Load,
+            store: wgpu::StoreOp::Store,
+          },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+      });
+      pass.set_pipeline(pipeline);
+      pass.set_bind_group(0, texture, &[]);
+      pass.set_bind_group(1, &self.params_bind_group, &[]);
+      let bounds = Rect::new(0, 0, target.width(), target.height());
+      for rect in clip.iter().flat_map(|clip| clip.intersection(&bounds)) {
+        pass.set_scissor_rect(rect.x as u32, rect.y as u32, rect.width, rect.height);
+        pass.draw(0 .. 4, 0 .. 1);
+      }
+    }
+    queue.submit([encoder.finish()]);
+  }
+}
@@ -1,22 +1,47 @@
 pub use drm::control::Device as ControlDevice;
+use crate::color_pass::ColorPass;
+use crate::compositor::Compositor;
+use crate::compositor::View;
+use crate::cursor::Cursor;
+use crate::cursor::CursorFrame;
+use crate::cursor::SoftwareCursor;
 use crate::display::Display;
+use crate::fence::RenderFence;
+use crate::display::InUse;
 use crate::error::CompositorError;
 use crate::error::CompositorResult;
 use crate::gpu::init_gpu;
-use crate::gpu::load_default_bg;
+use crate::gpu::save_texture_png;
+use crate::kms::AtomicRequest;
+use crate::kms::KmsBackend;
+use crate::kms::monotonic_now;
+use crate::restore;
+use crate::scene::Scene;
+use crate::surface::BufferRelease;
+use crate::surface::Rect;
 use crate::util::DisplayPosition;
+use crate::util::local_time;
 use crate::util::config::CompositorConfig;
 use crate::util::config::Config;
 use drm::Device;
 use drm::control::AtomicCommitFlags;
-use drm::control::atomic;
-use drm::control::connector;
+use drm::control::ResourceHandles;
+use drm::control::crtc;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::os::fd::AsFd;
 use std::os::fd::BorrowedFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
+
+// Timers can fire this early, which shouldn't cost a frame
+const TIMER_SLACK: Duration = Duration::from_millis(1);
 
 // Throw this thing wherever you need it!
-pub struct Card(std::fs::File, u32);
+pub struct Card(std::fs::File, u32, OnceLock<ResourceHandles>);
 
 impl AsFd for Card {
   fn as_fd(&self) -> BorrowedFd<'_> {
@@ -34,6 +59,7 @@ impl Card {
       Card(
         options.open(path).map_err(|e| CompositorError::OpenCard(path.into(), e))?,
         card_num,
+        OnceLock::new(),
       ),
     )
   }
@@ -67,6 +93,21 @@ impl Card {
   pub fn num(&self) -> u32 {
     self.1
   }
+
+  pub fn try_clone(&self) -> CompositorResult<Card> {
+    let file = self.0.try_clone().map_err(|e| CompositorError::CloneCard(e))?;
+    Ok(Card(file, self.1, self.2.clone()))
+  }
+
+  /// The resources as they were the first time, to look CRTCs up in. Those
+  /// are fixed for the life of the card, unlike the connectors.
+  pub fn first_resources(&self) -> std::io::Result<&ResourceHandles> {
+    if let Some(resources) = self.2.get() {
+      return Ok(resources);
+    }
+    let resources = self.resource_handles()?;
+    Ok(self.2.get_or_init(|| resources))
+  }
 }
 
 impl Device for Card { }
@@ -74,17 +115,39 @@ impl Device for Card { }
 impl ControlDevice for Card { }
 
 pub struct AppContext {
-  pub card: Box<Card>,
-  pub gbm: gbm::Device<&'static Card>,
+  // Borrows the card owned by `kms`, so it has to be dropped first
+  pub gbm: Option<gbm::Device<&'static Card>>,
+  pub kms: Box<dyn KmsBackend>,
+  // Holds a semaphore of `gpu`, so it has to be dropped first
+  render_fence: Option<RenderFence>,
   pub gpu: wgpu::Device,
   pub adapter: wgpu::Adapter,
   pub queue: wgpu::Queue,
-  pub bg_bindgroup: wgpu::BindGroup,
   pub displays: Vec<Display>,
+  /// Where to write every flipped frame as a PNG, if anywhere
+  pub dump_dir: Option<PathBuf>,
+  /// The CRTC each display was last lit with, so it gets the same one back
+  /// after being unplugged
+  pub crtc_history: HashMap<String, crtc::Handle>,
+  pub cursor: Option<Arc<Cursor>>,
+  /// Cursor hotspot in the virtual screen
+  pub cursor_pos: (i32, i32),
+  // When the cursor was set, for animation
+  cursor_since: Instant,
+  software_cursor: SoftwareCursor,
+  color_pass: ColorPass,
+  compositor: Compositor,
+  /// Window ports to show, in virtual screen coordinates
+  pub scene: Scene,
 }
 
 impl AppContext {
   pub async fn init(card: Card) -> Self {
+    // Remember how the card was set up so it can be put back on exit
+    if let Err(e) = restore::save_state(&card) {
+      tracing::warn!["card{} will not be restored on exit: {e}", card.num()];
+    }
+
     // Put the opaque card pointer on the heap to allow us to move owned pointers to it without moving the card memory itself
     let card: Box<Card> = Box::new(card);
 
@@ -107,150 +170,405 @@ impl AppContext {
     // Use the static reference
     let gbm: gbm::Device<&'static Card> =
       gbm::Device::new(card_ref).expect("Failed to init GBM with device");
-    let (gpu, adapter, queue) =
-      init_gpu(card_ref).await.expect("Failed to init wgpu");
-    let bg_bindgroup = load_default_bg(card_ref, &gpu, &queue);
+    AppContext::with_backend(card, Some(gbm), None).await
+  }
+
+  /// A context for a device with no card behind it. Buffers are plain GPU
+  /// textures and are written to `dump_dir` after every page flip.
+  pub async fn headless(kms: impl KmsBackend + 'static, dump_dir: Option<PathBuf>) -> Self {
+    AppContext::with_backend(Box::new(kms), None, dump_dir).await
+  }
+
+  async fn with_backend(
+    kms: Box<dyn KmsBackend>,
+    gbm: Option<gbm::Device<&'static Card>>,
+    dump_dir: Option<PathBuf>,
+  ) -> Self {
+    let (gpu, adapter, queue) = init_gpu(&*kms).await.expect("Failed to init wgpu");
+    let compositor = Compositor::new(&gpu);
+    let software_cursor = SoftwareCursor::new(&gpu);
+    let color_pass = ColorPass::new(&gpu);
+    let render_fence = RenderFence::new(&gpu);
     let displays: Vec<Display> = Vec::new();
     AppContext {
-      card,
       gbm,
+      kms,
+      render_fence,
       gpu,
       adapter,
       queue,
-      bg_bindgroup,
       displays,
+      dump_dir,
+      crtc_history: HashMap::new(),
+      cursor: None,
+      cursor_pos: (0, 0),
+      cursor_since: Instant::now(),
+      software_cursor,
+      color_pass,
+      compositor,
+      scene: Scene::default(),
     }
   }
 
-  /// Returns true if display state was updated (eg a display disconnected)
-  pub fn update(&mut self) -> bool {
-    let events = match self.card.receive_events() {
-      Ok(events) => {
-        println!["Ready to receive events!"];
-        events.peekable()
-      },
-      Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-        println!["Would block"];
-        return false;
-      },
-      Err(e) => panic!["{e}"],
-    };
-    let mut disconnected = false;
-    events.for_each(
-      |event| {
-        match event {
-          drm::control::Event::PageFlip(event) => {
-            println!["Got page flip event"];
-            let mut to_remove: HashSet<String> = HashSet::new();
-            for display in self.displays.iter_mut() {
-              if display.crtc != event.crtc {
-                continue;
-              }
-
-              // Draw to the back buffer
-              println!["Blitting to the framebuffer for {}", display.name];
-
-              // Swap the buffers
-              //? SAFETY: This is safe here because we are calling it right after a page flip
-              //? event, indicating the hardware is no longer using it
-              match unsafe {
-                display.primary.swap(&self.card, display.crtc)
-              } {
-                Ok(()) => (),
-                // Probably disconnected: remove the display from the list
-                Err(e) => if let Ok(info) = self.card.get_connector(display.connector, false) {
-                  println!["Got an error: {e}"];
-                  if info.state() != connector::State::Connected {
-                    to_remove.insert(display.name.to_owned());
-                    disconnected = true;
-                  }
-                },
-              }
-            }
-            self
-              .displays
-              .retain_mut(
-                |display| if to_remove.contains(&display.name) {
-                  for fb in display
-                    .primary
-                    .buffers
-                    .fbs
-                    .iter()
-                    .chain(display.cursor.buffers.fbs.iter()) {
-                    self.card.destroy_framebuffer(*fb).ok();
-                  }
-                  for overlay in display.overlays.iter() {
-                    for fb in overlay.buffers.fbs.iter() {
-                      self.card.destroy_framebuffer(*fb).ok();
-                    }
-                  }
-                  true
-                } else {
-                  false
-                },
-              );
-          },
-          _ => (),
+  /// Show `cursor` on every display of this context, restarting its animation
+  pub fn set_cursor(&mut self, cursor: Arc<Cursor>) {
+    self.cursor = Some(cursor);
+    self.cursor_since = Instant::now();
+  }
+
+  /// Move the cursor hotspot. It follows on the next page flip of each display.
+  pub fn move_cursor(&mut self, pos: (i32, i32)) {
+    self.cursor_pos = pos;
+  }
+
+  /// The fd to wait on for events, None if the backend has no fd
+  pub fn fd(&self) -> Option<BorrowedFd<'_>> {
+    self.gbm.as_ref().map(|gbm| gbm.as_fd())
+  }
+
+  /// Handle all pending DRM events. Page flips are recorded and leave the
+  /// display waiting for `compose`.
+  pub fn update(&mut self) {
+    let kms: &dyn KmsBackend = &*self.kms;
+    let mut pending = Vec::new();
+    loop {
+      match kms.events() {
+        Ok(events) => pending.extend(events),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+        Err(e) => panic!["{e}"],
+      }
+    }
+    for event in pending.into_iter() {
+      if let drm::control::Event::PageFlip(event) = event {
+        for display in self.displays.iter_mut().filter(|display| display.crtc == event.crtc) {
+          display.flipped();
+          if let Some(release) = display.releases.pop_front() {
+            self.scene.release(release);
+          }
+          let late = display.scheduler.presented(event.frame, event.duration);
+          if late > 0 {
+            let (name, scheduler) = (&display.name, &display.scheduler);
+            tracing::warn![
+              "{name}: frame was {late} vblank(s) late, {} so far (refresh {:?}, composition {:?})",
+              scheduler.missed,
+              scheduler.refresh_interval(),
+              scheduler.render_time()
+            ];
+          }
+          display.needs_frame = true;
         }
-      },
-    );
-    disconnected
+      }
+    }
+  }
+
+  /// Hand the damage of every surface to the displays it is on, which each
+  /// redraw it with their next frame
+  pub fn flush_damage(&mut self) {
+    for rect in self.scene.take_damage() {
+      for display in self.displays.iter_mut() {
+        if let Some(rect) = rect.intersection(&display.rect()) {
+          display.damage.pending.push(rect);
+        }
+      }
+    }
+  }
+
+  /// When `compose` should be called next, None if no display is waiting for
+  /// a frame
+  pub fn next_composition(&self) -> Option<Instant> {
+    let now = monotonic_now();
+    self
+      .displays
+      .iter()
+      .filter(|display| display.needs_frame)
+      .map(|display| if display.tearing {
+        Instant::now()
+      } else {
+        Instant::now() + display.scheduler.composition_start(now).saturating_sub(now)
+      })
+      .min()
+  }
+
+  /// Compose and queue a frame on every display whose composition is due.
+  /// Returns true if display state was updated (eg a display disconnected).
+  pub fn compose(&mut self) -> bool {
+    self.flush_damage();
+    for image in self.compositor.receive_wallpapers(&self.gpu, &self.queue) {
+      for display in self.displays.iter_mut() {
+        if display.wallpaper.image.as_ref() == Some(&image) {
+          display.damage.reset();
+        }
+      }
+    }
+    let kms: &dyn KmsBackend = &*self.kms;
+    let mut to_remove: HashSet<String> = HashSet::new();
+    let local_time = local_time();
+    for display in self.displays.iter_mut() {
+      let now = monotonic_now();
+      // Tearing frames go out as soon as they can
+      let due = display.tearing || display.scheduler.composition_start(now) <= now + TIMER_SLACK;
+      if !display.needs_frame || !due {
+        continue;
+      }
+      let target = display.scheduler.target_vblank(now);
+
+      // Put the cursor where it belongs, leaving it to us to draw if the plane can't
+      let mut software_cursor = None;
+      if let Some(cursor) = self.cursor.as_ref() {
+        let frame = cursor.frame_at(self.cursor_since.elapsed());
+        let hotspot_pos = (self.cursor_pos.0 - display.pos.0, self.cursor_pos.1 - display.pos.1);
+        display.cursor.place(&self.queue, cursor, frame, hotspot_pos, display.size);
+        if display.cursor.software && display.cursor.visible {
+          let image = &cursor.frames[frame];
+          let rect = Rect::new(display.cursor.pos.0, display.cursor.pos.1, image.width, image.height);
+          software_cursor = Some((rect, cursor.id, frame));
+        }
+      }
+
+      // Skip composition for a fullscreen surface if we can, otherwise hand what we can to
+      // overlay planes and redraw what changed
+      display.update_color(&self.gpu, &self.queue, &self.color_pass, &local_time);
+      let scanout = display.plan_scanout(kms, self.scene.surfaces());
+      let damage = display.frame_damage(software_cursor);
+      if scanout {
+        display.damage.reset();
+        display.cursor.drawn = None;
+      } else {
+        display.plan_overlays(kms, self.scene.surfaces());
+        let repaint = display.repaint_region(&damage);
+        let offloaded =
+          display
+            .overlay_plan
+            .placements
+            .iter()
+            .map(|placement| placement.surface)
+            .collect::<Vec<_>>();
+        let view = View {
+          rect: display.rect(),
+          wallpaper: &display.wallpaper,
+          offloaded: &offloaded,
+        };
+        self.compositor.prepare_wallpaper(&self.gpu, &self.queue, &display.wallpaper);
+        if let Some(target) = display.primary.draw_texture() {
+          // With colour in a shader, frames are drawn in 8 bit first and converted at the end
+          let canvas = display.composite.as_ref().unwrap_or(target);
+          self.compositor.draw(&self.gpu, &self.queue, &self.scene, &view, canvas, &repaint);
+          if let (Some((_, _, frame)), Some(cursor)) = (software_cursor, self.cursor.as_ref()) {
+            let placed = CursorFrame {
+              cursor,
+              frame,
+              pos: display.cursor.pos,
+            };
+            self.software_cursor.draw(&self.gpu, &self.queue, &placed, canvas, &repaint);
+          }
+          let output = display.color_lut.as_ref().zip(display.composite.as_ref());
+          if let Some((lut, composite)) = output {
+            self.color_pass.draw(&self.gpu, &self.queue, lut, composite, target, &repaint);
+          }
+        }
+        display.damage.push(damage.clone());
+        display.cursor.drawn = software_cursor;
+      }
+
+      // Swap the buffers
+      //? SAFETY: This is safe here because the display only needs a frame after a page flip
+      //? event, indicating the hardware is no longer using it
+      let fence =
+        self.render_fence.as_ref().and_then(|fence| fence.signal(&self.gpu, &self.queue));
+      match unsafe {
+        display.swap(kms, fence.as_ref().map(|fence| fence.as_fd()), &damage)
+      } {
+        Ok(()) => {
+          display.needs_frame = false;
+          display.scheduler.committed(target, monotonic_now().saturating_sub(now));
+
+          // A scanned out client buffer is not in our textures
+          if let Some(dump_dir) = self.dump_dir.as_ref().filter(|_| display.scanout.is_none()) {
+            let frame = display.scheduler.last_frame();
+            let path = dump_dir.join(format!["{}-{frame}.png", display.name]);
+            save_texture_png(
+              &self.gpu,
+              &self.queue,
+              display.primary.scan_texture(),
+              &path,
+            ).unwrap_or_else(|e| {
+              tracing::warn!["Failed to dump frame: {e}"];
+            });
+          }
+        },
+        // Probably disconnected: remove the display from the list
+        Err(e) => {
+          println!["Got an error: {e}"];
+          if !display.is_connected(kms) {
+            to_remove.insert(display.name.to_owned());
+          }
+        },
+      }
+    }
+    let scene = &mut self.scene;
+    self
+      .displays
+      .retain_mut(
+        |display| if to_remove.contains(&display.name) {
+          display.release_all().for_each(|release| scene.release(release));
+          display.destroy_framebuffers(kms);
+          false
+        } else {
+          true
+        },
+      );
+    !to_remove.is_empty()
+  }
+
+  /// Free the framebuffers of every display. Only call this once the original
+  /// state is restored, removing a framebuffer that is being scanned out turns
+  /// its CRTC off.
+  pub fn destroy_displays(&mut self) {
+    for display in self.displays.drain(..) {
+      display.destroy_framebuffers(&*self.kms);
+    }
+  }
+
+  /// Drop displays whose monitor was unplugged. Returns true if there were
+  /// any.
+  pub fn remove_disconnected(&mut self) -> bool {
+    let kms: &dyn KmsBackend = &*self.kms;
+    let count = self.displays.len();
+    let scene = &mut self.scene;
+    self
+      .displays
+      .retain_mut(
+        |display| if display.is_connected(kms) {
+          true
+        } else {
+          println!["Lost display: {}", display.name];
+          display.release_all().for_each(|release| scene.release(release));
+          display.destroy_framebuffers(kms);
+          false
+        },
+      );
+    self.displays.len() != count
   }
 
   pub fn displays_mut(&mut self) -> impl Iterator<Item = &mut Display> {
     self.displays.iter_mut()
   }
 
+  /// Re-modeset displays whose configured mode changed. The display is torn
+  /// down and acquired again, since its buffers are sized for the old mode.
+  /// Returns true if any display changed.
+  pub fn apply_modes(&mut self, config: &Config) -> bool {
+    let kms: &dyn KmsBackend = &*self.kms;
+    let stale =
+      self
+        .displays
+        .extract_if(.., |display| match display.wanted_mode(kms, config) {
+          Some(mode) => {
+            println!["Switching {} to {}", display.name, mode.name().to_string_lossy()];
+            true
+          },
+          None => false,
+        })
+        .collect::<Vec<_>>();
+    if stale.is_empty() {
+      return false;
+    }
+    self.init_displays(config);
+
+    // The old buffers stay on screen until the new mode replaces them, so a
+    // rejected modeset leaves the display running as it was
+    let kms: &dyn KmsBackend = &*self.kms;
+    let scene = &mut self.scene;
+    for mut display in stale {
+      if self.displays.iter().any(|lit| lit.connector == display.connector) {
+        display.release_all().for_each(|release| scene.release(release));
+        display.destroy_framebuffers(kms);
+      } else {
+        let name = &display.name;
+        tracing::warn!["Keeping the old mode on {name}"];
+        self.displays.push(display);
+      }
+    }
+    true
+  }
+
+  /// Pick up settings that don't need a modeset, like VRR, colour and the
+  /// wallpaper
+  pub fn apply_settings(&mut self, config: &Config) {
+    for display in self.displays.iter_mut() {
+      display.apply_config(config);
+    }
+    self.compositor.reload_wallpapers();
+  }
+
   /// Returns true if any new displays were acquired
   pub fn init_displays(&mut self, config: &Config) -> bool {
     // Don't re-initialize displays we are already using
-    let ignore_list =
-      HashSet::<String>::from_iter(
-        self.displays.iter().map(|display| display.name.to_owned()),
-      );
-    let mut new_displays =
+    let in_use: InUse = self.displays.iter().collect();
+    let new_displays =
       Display::init_displays(
-        ignore_list,
-        &self.card,
-        &self.gbm,
+        &in_use,
+        &self.crtc_history,
+        config,
+        &*self.kms,
+        self.gbm.as_ref(),
         &self.gpu,
       ).unwrap_or_else(|e| {
         tracing::warn!["Failed to init displays: {e}"];
         Default::default()
       });
-    for display in new_displays.iter_mut() {
+    let mut lit = Vec::new();
+    for mut display in new_displays.into_iter() {
       println!["Found display: {} {:?}", display.name, display.size];
-      let mut atomic_req = atomic::AtomicModeReq::new();
-      display.init_req(&self.card, &mut atomic_req).expect("Failed to init display");
+      if let Some(edid) = display.edid.as_ref() {
+        println!["  {} ({:?} mm)", edid.identity(), edid.size];
+      }
+      let mut atomic_req = AtomicRequest::new();
+      display.init_req(&*self.kms, &mut atomic_req).expect("Failed to init display");
       display
         .primary
         .init_req(&mut atomic_req, display.crtc)
         .expect("Failed to init primary surface");
-      display
-        .cursor
-        .init_req(&mut atomic_req, display.crtc)
-        .expect("Failed to init primary surface");
-      for overlay in display.overlays.iter() {
-        overlay
-          .init_req(&mut atomic_req, display.crtc)
-          .expect("Failed to init primary surface");
+
+      // Let the driver veto the routing before anything changes on screen
+      let committed =
+        self
+          .kms
+          .commit(
+            AtomicCommitFlags::ALLOW_MODESET | AtomicCommitFlags::TEST_ONLY,
+            &atomic_req,
+          )
+          .and_then(|()| self.kms.commit(
+            AtomicCommitFlags::ALLOW_MODESET | AtomicCommitFlags::NONBLOCK |
+              AtomicCommitFlags::PAGE_FLIP_EVENT,
+            &atomic_req,
+          ));
+      match committed {
+        Ok(()) => {
+          // Its page flip comes like any other, with nothing to release
+          display.releases.push_back(BufferRelease::default());
+          self.crtc_history.insert(display.name.to_owned(), display.crtc);
+          lit.push(display);
+        },
+        Err(e) => {
+          let (name, crtc) = (&display.name, display.crtc);
+          tracing::warn!["Failed to set mode on {name} with {crtc:?}: {e}"];
+          display.destroy_framebuffers(&*self.kms);
+        },
       }
-      self
-        .card
-        .atomic_commit(
-          AtomicCommitFlags::ALLOW_MODESET | AtomicCommitFlags::NONBLOCK |
-            AtomicCommitFlags::PAGE_FLIP_EVENT,
-          atomic_req,
-        )
-        .expect("Failed to set mode");
     }
 
     // If we have new displays, add them to the display tree
-    if !new_displays.is_empty() {
-      self.displays.extend(new_displays.into_iter());
+    if !lit.is_empty() {
+      self.displays.extend(lit);
       for display in self.displays.iter_mut() {
-        let name = &display.name;
-        let pos = config.get::<DisplayPosition>(&CompositorConfig::offset_key(name));
+        let keys =
+          display
+            .config_names()
+            .iter()
+            .map(|name| CompositorConfig::offset_key(name))
+            .collect::<Vec<_>>();
+        let pos = config.get_first::<DisplayPosition>(&keys);
         if let Some(pos) = pos {
           display.pos = pos.into();
         }
@@ -261,3 +579,175 @@ impl AppContext {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::dmabuf::ClientTexture;
+  use crate::gpu::tests::assert_golden_png;
+  use crate::gpu::tests::has_gpu;
+  use crate::gpu::upload_rgba;
+  use crate::headless::HeadlessOutputs;
+  use crate::headless::virtual_device;
+  use crate::kms::make_mode;
+  use crate::kms::mock::MockDevice;
+  use crate::surface::Surface;
+  use crate::surface::Transform;
+  use drm::buffer::DrmFourcc;
+  use drm::buffer::DrmModifier;
+  use image::RgbaImage;
+  use std::str::FromStr;
+
+  // Lit virtual displays of `outputs` and the device behind them
+  fn headless(
+    outputs: &str,
+    config: &str,
+    dump_dir: Option<PathBuf>,
+  ) -> Option<(AppContext, MockDevice)> {
+    if !has_gpu() {
+      return None;
+    }
+    let device = virtual_device(0, &HeadlessOutputs::from_str(outputs).unwrap());
+    let mut context = futures::executor::block_on(AppContext::headless(device.clone(), dump_dir));
+    assert!(context.init_displays(&Config::from_str(config)));
+    Some((context, device))
+  }
+
+  fn context() -> Option<(AppContext, MockDevice)> {
+    headless("1920x1080@60 1280x720@60", "", None)
+  }
+
+  // A surface filling `rect` with `pixel`, premultiplied
+  fn solid(
+    context: &AppContext,
+    id: u64,
+    format: DrmFourcc,
+    pixel: [u8; 4],
+    rect: Rect,
+    z: i32,
+  ) -> Surface {
+    let image = RgbaImage::from_pixel(rect.width, rect.height, image::Rgba(pixel));
+    Surface {
+      id,
+      rect,
+      z,
+      buffer: None,
+      texture: Some(ClientTexture {
+        texture: upload_rgba(&context.gpu, &context.queue, "Test Surface", &image),
+        format,
+        modifier: DrmModifier::Linear,
+        size: image.dimensions(),
+      }),
+      clip: None,
+      opaque: false,
+      alpha: 1.0,
+      transform: Transform::Normal,
+      yuv: Default::default(),
+      damage: Vec::new(),
+      tearing: false,
+    }
+  }
+
+  fn names(context: &AppContext) -> Vec<&str> {
+    context.displays.iter().map(|display| display.name.as_str()).collect()
+  }
+
+  #[test]
+  fn drops_displays_whose_commit_failed_unplugged() {
+    let Some((mut context, device)) = context() else {
+      return;
+    };
+    assert_eq!(names(&context), ["card0-Virtual-1", "card0-Virtual-2"]);
+    for display in context.displays.iter_mut() {
+      display.needs_frame = true;
+    }
+
+    // The first is gone, the second is still there and tries again
+    device.set_connected(context.displays[0].connector, Vec::new());
+    device.fail_commit(std::io::ErrorKind::NotFound);
+    device.fail_commit(std::io::ErrorKind::InvalidInput);
+    assert!(context.compose());
+    assert_eq!(names(&context), ["card0-Virtual-2"]);
+    assert!(context.displays[0].needs_frame);
+    assert!(!context.compose());
+    assert!(!context.displays[0].needs_frame);
+  }
+
+  #[test]
+  fn removes_unplugged_displays() {
+    let Some((mut context, device)) = context() else {
+      return;
+    };
+    assert!(!context.remove_disconnected());
+    let connector = context.displays[1].connector;
+    device.set_connected(connector, Vec::new());
+    assert!(context.remove_disconnected());
+    assert_eq!(names(&context), ["card0-Virtual-1"]);
+    assert!(!context.remove_disconnected());
+
+    // Plugged back in it gets its CRTC back
+    let crtc = context.crtc_history["card0-Virtual-2"];
+    device.set_connected(connector, vec![make_mode(1280, 720, 60)]);
+    assert!(context.init_displays(&Config::default()));
+    assert_eq!(names(&context), ["card0-Virtual-1", "card0-Virtual-2"]);
+    assert_eq!(context.displays[1].crtc, crtc);
+  }
+
+  #[test]
+  fn keeps_the_old_mode_when_a_modeset_fails() {
+    let Some((mut context, device)) = context() else {
+      return;
+    };
+    let connector = context.displays[1].connector;
+    device.set_connected(connector, vec![make_mode(1280, 720, 60), make_mode(1024, 768, 60)]);
+    let config = Config::from_str("card0-Virtual-2.mode = 1024x768@60");
+
+    // The driver turns the new mode down, the display goes on as it was
+    device.fail_commit(std::io::ErrorKind::InvalidInput);
+    assert!(context.apply_modes(&config));
+    assert_eq!(names(&context), ["card0-Virtual-1", "card0-Virtual-2"]);
+    assert_eq!(context.displays[1].mode.size(), (1280, 720));
+
+    assert!(context.apply_modes(&config));
+    assert_eq!(names(&context), ["card0-Virtual-1", "card0-Virtual-2"]);
+    assert_eq!(context.displays[1].mode.size(), (1024, 768));
+    assert!(!context.apply_modes(&config));
+  }
+
+  #[test]
+  fn dumps_composited_frames() {
+    let dump_dir = std::env::temp_dir().join(format!["dreampipe-dump-{}", std::process::id()]);
+    std::fs::create_dir_all(&dump_dir).unwrap();
+    let config = "wallpaper = none\nwallpaper.color = 204080";
+    let Some((mut context, _)) = headless("64x48@60", config, Some(dump_dir.clone())) else {
+      return;
+    };
+
+    // Whatever cursor theme there is, drawn in software it would be in the frame
+    context.cursor = None;
+
+    // Opaque red with its X byte zero under translucent green
+    let red = solid(&context, 1, DrmFourcc::Xbgr8888, [255, 0, 0, 0], Rect::new(8, 8, 16, 16), 0);
+    let green =
+      solid(&context, 2, DrmFourcc::Abgr8888, [0, 128, 0, 128], Rect::new(16, 16, 24, 16), 1);
+    context.scene.insert(red);
+    context.scene.insert(green);
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let dump =
+      loop {
+        assert!(Instant::now() < deadline, "No frame was dumped");
+        context.update();
+        context.compose();
+        let dumped = std::fs::read_dir(&dump_dir).unwrap().next();
+        if let Some(entry) = dumped {
+          break entry.unwrap().path();
+        }
+        std::thread::sleep(Duration::from_millis(1));
+      };
+    assert!(dump.file_name().unwrap().to_string_lossy().starts_with("card0-Virtual-1-"));
+    let golden = include_bytes!["../golden/headless_scene.png"];
+    assert_golden_png(&dump, "headless_scene", golden, 2);
+    std::fs::remove_dir_all(&dump_dir).ok();
+  }
+}
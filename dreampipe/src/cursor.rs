@@ -0,0 +1,673 @@
+use crate::buffer::DrmCtx;
+use crate::kms::AtomicRequest;
+use crate::surface::Rect;
+use crate::util::config::CompositorConfig;
+use crate::util::config::Config;
+use drm::control::crtc;
+use std::os::fd::BorrowedFd;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindingResource;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsages;
+use wgpu::Extent3d;
+use wgpu::Origin3d;
+use wgpu::TexelCopyBufferLayout;
+use wgpu::TexelCopyTextureInfo;
+use wgpu::TextureAspect;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureUsages;
+use wgpu::TextureViewDescriptor;
+
+const CURSOR_SHADER: &str = include_str!["cursor.wgsl"];
+const XCURSOR_MAGIC: &[u8; 4] = b"Xcur";
+const XCURSOR_IMAGE_TYPE: u32 = 0xfffd0002;
+// Xcursor limits images to 0x7fff, anything near that is a broken file
+const XCURSOR_MAX_DIM: u32 = 0x800;
+const DEFAULT_THEME: &str = "default";
+const DEFAULT_CURSOR: &str = "left_ptr";
+const DEFAULT_SIZE: u32 = 24;
+// Deep enough for any real theme, shallow enough to stop on an inheritance loop
+const MAX_INHERIT_DEPTH: u32 = 8;
+
+static CURSOR_IDS: AtomicU64 = AtomicU64::new(0);
+
+/// One frame of a cursor, in premultiplied ARGB like the Xcursor file
+#[derive(Clone, Debug)]
+pub struct CursorImage {
+  pub width: u32,
+  pub height: u32,
+  pub hotspot: (u32, u32),
+  /// How long the frame is shown if the cursor is animated
+  pub delay: Duration,
+  pub pixels: Vec<u32>,
+}
+
+impl CursorImage {
+  /// BGRA bytes, little endian ARGB
+  pub fn bytes(&self) -> Vec<u8> {
+    self.pixels.iter().flat_map(|pixel| pixel.to_le_bytes()).collect()
+  }
+}
+
+/// A cursor image at a single size, possibly animated
+#[derive(Debug)]
+pub struct Cursor {
+  /// Tells apart cursors loaded at different times
+  pub id: u64,
+  pub frames: Vec<CursorImage>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+  data.get(offset .. offset + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn parse_image(data: &[u8], offset: usize) -> Option<CursorImage> {
+  let header = read_u32(data, offset)? as usize;
+  let width = read_u32(data, offset + 16)?;
+  let height = read_u32(data, offset + 20)?;
+  if width == 0 || height == 0 || width > XCURSOR_MAX_DIM || height > XCURSOR_MAX_DIM {
+    return None;
+  }
+  let xhot = read_u32(data, offset + 24)?.min(width - 1);
+  let yhot = read_u32(data, offset + 28)?.min(height - 1);
+  let delay = read_u32(data, offset + 32)?;
+  let start = offset + header;
+  let pixels =
+    (0 .. (width * height) as usize)
+      .map(|i| read_u32(data, start + 4 * i))
+      .collect::<Option<Vec<_>>>()?;
+  Some(CursorImage {
+    width,
+    height,
+    hotspot: (xhot, yhot),
+    delay: Duration::from_millis(delay as u64),
+    pixels,
+  })
+}
+
+/// Parse an Xcursor file, keeping only the images with the nominal size
+/// closest to `size`. There is more than one if the cursor is animated.
+pub fn parse_xcursor(data: &[u8], size: u32) -> Option<Vec<CursorImage>> {
+  if data.get(.. 4)? != XCURSOR_MAGIC {
+    return None;
+  }
+  let header = read_u32(data, 4)? as usize;
+  let ntoc = read_u32(data, 12)? as usize;
+  let mut images = Vec::new();
+  for i in 0 .. ntoc {
+    let entry = header + 12 * i;
+    if read_u32(data, entry)? == XCURSOR_IMAGE_TYPE {
+      images.push((read_u32(data, entry + 4)?, read_u32(data, entry + 8)? as usize));
+    }
+  }
+  let nominal = images.iter().map(|(nominal, _)| *nominal).min_by_key(|n| n.abs_diff(size))?;
+  images
+    .into_iter()
+    .filter(|(n, _)| *n == nominal)
+    .map(|(_, offset)| parse_image(data, offset))
+    .collect()
+}
+
+/// `$XCURSOR_PATH`, or where libXcursor looks by default
+fn search_path() -> Vec<PathBuf> {
+  if let Ok(path) = std::env::var("XCURSOR_PATH") {
+    return path.split(':').map(PathBuf::from).collect();
+  }
+  let mut dirs = Vec::new();
+  if let Ok(home) = std::env::var("HOME") {
+    dirs.push(PathBuf::from(&home).join(".local/share/icons"));
+    dirs.push(PathBuf::from(&home).join(".icons"));
+  }
+  dirs.push("/usr/share/icons".into());
+  dirs.push("/usr/share/pixmaps".into());
+  dirs
+}
+
+/// Themes listed after `Inherits=` in a theme's index.theme
+fn inherited_themes(dirs: &[PathBuf], theme: &str) -> Vec<String> {
+  dirs
+    .iter()
+    .flat_map(|dir| std::fs::read_to_string(dir.join(theme).join("index.theme")).ok())
+    .flat_map(|index| {
+      index
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Inherits")?.trim().strip_prefix('='))
+        .map(|parents| {
+          parents
+            .split([',', ';'])
+            .map(str::trim)
+            .filter(|parent| !parent.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>()
+        })
+    })
+    .flatten()
+    .collect()
+}
+
+fn find_cursor_file(dirs: &[PathBuf], theme: &str, name: &str, depth: u32) -> Option<PathBuf> {
+  let found =
+    dirs
+      .iter()
+      .map(|dir| dir.join(theme).join("cursors").join(name))
+      .find(|path| path.is_file());
+  if found.is_some() || depth == 0 {
+    return found;
+  }
+  inherited_themes(dirs, theme)
+    .iter()
+    .find_map(|parent| find_cursor_file(dirs, parent, name, depth - 1))
+}
+
+// Black outlined white arrow, for when no theme is installed
+fn default_arrow() -> CursorImage {
+  const SIZE: u32 = 16;
+  let inside = |x: i32, y: i32| {
+    x >= 0 && y >= 0 && (y as u32) < SIZE && x <= y && x + y / 2 < SIZE as i32 - 4
+  };
+  let mut pixels = Vec::with_capacity((SIZE * SIZE) as usize);
+  for y in 0 .. SIZE as i32 {
+    for x in 0 .. SIZE as i32 {
+      let edge = [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().any(|(dx, dy)| !inside(x + dx, y + dy));
+      pixels.push(match (inside(x, y), edge) {
+        (false, _) => 0x00000000,
+        (true, true) => 0xff000000,
+        (true, false) => 0xffffffff,
+      });
+    }
+  }
+  CursorImage {
+    width: SIZE,
+    height: SIZE,
+    hotspot: (0, 0),
+    delay: Duration::ZERO,
+    pixels,
+  }
+}
+
+impl Cursor {
+  fn new(frames: Vec<CursorImage>) -> Self {
+    Self {
+      id: CURSOR_IDS.fetch_add(1, Ordering::Relaxed),
+      frames,
+    }
+  }
+
+  /// Load `name` from `theme` or a theme it inherits from, then from the
+  /// default theme
+  pub fn load(theme: &str, name: &str, size: u32) -> Option<Self> {
+    let dirs = search_path();
+    let path =
+      find_cursor_file(&dirs, theme, name, MAX_INHERIT_DEPTH)
+        .or_else(|| find_cursor_file(&dirs, DEFAULT_THEME, name, MAX_INHERIT_DEPTH))?;
+    let data = std::fs::read(&path).inspect_err(|e| {
+      tracing::warn!["Failed to read {path:?}: {e}"];
+    }).ok()?;
+    let frames = parse_xcursor(&data, size).filter(|frames| !frames.is_empty());
+    if frames.is_none() {
+      tracing::warn!["{path:?} is not a valid Xcursor file"];
+    }
+    Some(Self::new(frames?))
+  }
+
+  /// The pointer from the configured theme and size, or a built in arrow
+  pub fn from_config(config: &Config) -> Self {
+    let theme =
+      config
+        .get::<String>(CompositorConfig::CURSOR_THEME_KEY)
+        .or_else(|| std::env::var("XCURSOR_THEME").ok())
+        .unwrap_or_else(|| DEFAULT_THEME.to_string());
+    let size =
+      config
+        .get::<u32>(CompositorConfig::CURSOR_SIZE_KEY)
+        .or_else(|| std::env::var("XCURSOR_SIZE").ok()?.parse().ok())
+        .unwrap_or(DEFAULT_SIZE);
+    Self::load(&theme, DEFAULT_CURSOR, size).unwrap_or_else(|| {
+      tracing::warn!["No {DEFAULT_CURSOR} cursor in theme {theme}, using the built in one"];
+      Self::new(vec![default_arrow()])
+    })
+  }
+
+  /// Index of the frame to show `elapsed` after the animation started
+  pub fn frame_at(&self, elapsed: Duration) -> usize {
+    let cycle: Duration = self.frames.iter().map(|frame| frame.delay).sum();
+    if self.frames.len() < 2 || cycle.is_zero() {
+      return 0;
+    }
+    let mut t = Duration::from_nanos((elapsed.as_nanos() % cycle.as_nanos()) as u64);
+    for (i, frame) in self.frames.iter().enumerate() {
+      if t < frame.delay {
+        return i;
+      }
+      t -= frame.delay;
+    }
+    0
+  }
+}
+
+/// The cursor as seen by one display. It goes on the cursor plane when there
+/// is one and the image fits, otherwise it is drawn into the primary buffer.
+#[derive(Debug)]
+pub struct DisplayCursor {
+  pub plane: Option<DrmCtx>,
+  /// Top left corner of the image relative to the display
+  pub pos: (i32, i32),
+  /// Some of the image is on this display
+  pub visible: bool,
+  /// Drawn into the primary buffer instead of using the plane
+  pub software: bool,
+  /// Cursor and frame in the draw buffer of the plane
+  uploaded: Option<(u64, usize)>,
+  /// The draw buffer has an image that has not been committed yet
+  image_changed: bool,
+  /// Where the plane was last committed to, None if off
+  committed: Option<(i32, i32)>,
+  /// Where the cursor was last drawn into the primary buffer, relative to
+  /// the display, along with the cursor id and frame
+  pub drawn: Option<(Rect, u64, usize)>,
+}
+
+impl DisplayCursor {
+  pub fn new(plane: Option<DrmCtx>) -> Self {
+    Self {
+      plane,
+      pos: (0, 0),
+      visible: false,
+      software: true,
+      uploaded: None,
+      image_changed: false,
+      committed: None,
+      drawn: None,
+    }
+  }
+
+  /// Move the hotspot of `cursor` to `hotspot_pos` on a display of
+  /// `display_size`, uploading `frame` to the plane if it changed
+  pub fn place(
+    &mut self,
+    queue: &wgpu::Queue,
+    cursor: &Cursor,
+    frame: usize,
+    hotspot_pos: (i32, i32),
+    display_size: (u32, u32),
+  ) {
+    let image = &cursor.frames[frame];
+    self.pos = (hotspot_pos.0 - image.hotspot.0 as i32, hotspot_pos.1 - image.hotspot.1 as i32);
+    self.visible =
+      self.pos.0 < display_size.0 as i32 && self.pos.1 < display_size.1 as i32 &&
+        self.pos.0 + image.width as i32 > 0 && self.pos.1 + image.height as i32 > 0;
+    let Some(plane) = self.plane.as_mut() else {
+      self.software = true;
+      return;
+    };
+    self.software = image.width > plane.size.0 || image.height > plane.size.1;
+    if self.software || !self.visible || self.uploaded == Some((cursor.id, frame)) {
+      return;
+    }
+
+    // Clear the rest of the buffer, the plane is always shown whole
+    let (width, height) = plane.size;
+    let mut bytes = vec![0u8; (width * height * 4) as usize];
+    let image_bytes = image.bytes();
+    for (y, row) in image_bytes.chunks((image.width * 4) as usize).enumerate() {
+      let start = y * (width * 4) as usize;
+      bytes[start .. start + row.len()].copy_from_slice(row);
+    }
+    let Some(texture) = plane.draw_texture() else {
+      return;
+    };
+    queue.write_texture(TexelCopyTextureInfo {
+      texture,
+      mip_level: 0,
+      origin: Origin3d::ZERO,
+      aspect: TextureAspect::All,
+    }, &bytes, TexelCopyBufferLayout {
+      offset: 0,
+      bytes_per_row: Some(4 * width),
+      rows_per_image: Some(height),
+    }, Extent3d {
+      width,
+      height,
+      depth_or_array_layers: 1,
+    });
+    plane.buffers.present();
+    self.uploaded = Some((cursor.id, frame));
+    self.image_changed = true;
+  }
+
+  fn target(&self) -> Option<(i32, i32)> {
+    (self.visible && !self.software).then_some(self.pos)
+  }
+
+  /// Add whatever changed about the plane since the last commit
+  /// The plane has to be updated by the next commit
+  pub fn changed(&self) -> bool {
+    self.plane.is_some() && (self.target() != self.committed || self.image_changed)
+  }
+
+  pub fn req(&self, atomic_req: &mut AtomicRequest, crtc: crtc::Handle) {
+    let Some(plane) = self.plane.as_ref() else {
+      return;
+    };
+    if !self.changed() {
+      return;
+    }
+    match self.target() {
+      Some(pos) => plane.plane_req(atomic_req, crtc, plane.next_fb(), pos),
+      None => plane.disable_req(atomic_req),
+    }
+  }
+
+  /// Have a new image wait for `fence`, the rendering that uploaded it
+  pub fn fence_req(&self, atomic_req: &mut AtomicRequest, fence: BorrowedFd) {
+    let Some(plane) = self.plane.as_ref() else {
+      return;
+    };
+    if self.image_changed && self.target().is_some() {
+      plane.fence_req(atomic_req, fence);
+    }
+  }
+
+  /// Call on every page flip of the display
+  pub fn flipped(&mut self) {
+    if let Some(plane) = self.plane.as_mut() {
+      plane.buffers.flipped();
+    }
+  }
+
+  /// Call once the request from `req` went through
+  pub fn committed(&mut self) {
+    self.committed = self.target();
+    if self.image_changed && self.committed.is_some() {
+      if let Some(plane) = self.plane.as_mut() {
+        plane.buffers.queued();
+      }
+      self.image_changed = false;
+    }
+  }
+}
+
+/// One frame of a cursor and where it goes
+pub struct CursorFrame<'a> {
+  pub cursor: &'a Cursor,
+  /// Index into `Cursor::frames`
+  pub frame: usize,
+  /// Top left corner, relative to the display
+  pub pos: (i32, i32),
+}
+
+/// Draws the cursor over a finished frame for displays that can't use a
+/// cursor plane
+pub struct SoftwareCursor {
+  pipeline: wgpu::RenderPipeline,
+  sampler: wgpu::Sampler,
+  rect: wgpu::Buffer,
+  // The uploaded frame and the bind group sampling it
+  image: Option<((u64, usize), wgpu::BindGroup)>,
+}
+
+impl SoftwareCursor {
+  pub fn new(gpu: &wgpu::Device) -> Self {
+    let shader = gpu.create_shader_module(wgpu::ShaderModuleDescriptor {
+      label: Some("Cursor Shader"),
+      source: wgpu::ShaderSource::Wgsl(CURSOR_SHADER.into()),
+    });
+    let pipeline = gpu.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+      label: Some("Cursor Pipeline"),
+      layout: None,
+      vertex: wgpu::VertexState {
+        module: &shader,
+        entry_point: Some("vs_main"),
+        buffers: &[],
+        compilation_options: wgpu::PipelineCompilationOptions::default(),
+      },
+      fragment: Some(wgpu::FragmentState {
+        module: &shader,
+        entry_point: Some("fs_main"),
+        targets: &[Some(wgpu::ColorTargetState {
+          format: TextureFormat::Bgra8Unorm,
+          blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+          write_mask: wgpu::ColorWrites::ALL,
+        })],
+        compilation_options: wgpu::PipelineCompilationOptions::default(),
+      }),
+      primitive: wgpu::PrimitiveState {
+        topology: wgpu::PrimitiveTopology::TriangleStrip,
+        ..Default::default()
+      },
+      depth_stencil: None,
+      multisample: wgpu::MultisampleState::default(),
+      multiview: None,
+      cache: None,
+    });
+    let sampler = gpu.create_sampler(&wgpu::SamplerDescriptor {
+      label: Some("Cursor Sampler"),
+      ..Default::default()
+    });
+    let rect = gpu.create_buffer(&BufferDescriptor {
+      label: Some("Cursor Rect"),
+      size: 16,
+      usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+      mapped_at_creation: false,
+    });
+    Self {
+      pipeline,
+      sampler,
+      rect,
+      image: None,
+    }
+  }
+
+  fn upload(&self, gpu: &wgpu::Device, queue: &wgpu::Queue, image: &CursorImage) -> wgpu::BindGroup {
+    let size = Extent3d {
+      width: image.width,
+      height: image.height,
+      depth_or_array_layers: 1,
+    };
+    let texture = gpu.create_texture(&TextureDescriptor {
+      label: Some("Cursor Texture"),
+      size,
+      mip_level_count: 1,
+      sample_count: 1,
+      dimension: TextureDimension::D2,
+      format: TextureFormat::Bgra8Unorm,
+      usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+      view_formats: &[],
+    });
+    queue.write_texture(TexelCopyTextureInfo {
+      texture: &texture,
+      mip_level: 0,
+      origin: Origin3d::ZERO,
+      aspect: TextureAspect::All,
+    }, &image.bytes(), TexelCopyBufferLayout {
+      offset: 0,
+      bytes_per_row: Some(4 * image.width),
+      rows_per_image: Some(image.height),
+    }, size);
+    let view = texture.create_view(&TextureViewDescriptor::default());
+    gpu.create_bind_group(&BindGroupDescriptor {
+      label: Some("Cursor Bindgroup"),
+      layout: &self.pipeline.get_bind_group_layout(0),
+      entries: &[BindGroupEntry {
+        binding: 0,
+        resource: BindingResource::TextureView(&view),
+      }, BindGroupEntry {
+        binding: 1,
+        resource: BindingResource::Sampler(&self.sampler),
+      }, BindGroupEntry {
+        binding: 2,
+        resource: self.rect.as_entire_binding(),
+      }],
+    })
+  }
+
+  /// Blend `placed` onto `target`, touching only `clip`
+  pub fn draw(
+    &mut self,
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+    placed: &CursorFrame,
+    target: &wgpu::Texture,
+    clip: &[Rect],
+  ) {
+    let CursorFrame { cursor, frame, pos } = *placed;
+    let image = &cursor.frames[frame];
+    let key = (cursor.id, frame);
+    if self.image.as_ref().is_none_or(|(uploaded, _)| *uploaded != key) {
+      self.image = Some((key, self.upload(gpu, queue, image)));
+    }
+    let (width, height) = (target.width() as f32, target.height() as f32);
+    let rect = [
+      2.0 * pos.0 as f32 / width - 1.0,
+      1.0 - 2.0 * pos.1 as f32 / height,
+      2.0 * image.width as f32 / width,
+      -2.0 * image.height as f32 / height,
+    ];
+    let rect_bytes = rect.iter().flat_map(|f| f.to_le_bytes()).collect::<Vec<_>>();
+    queue.write_buffer(&self.rect, 0, &rect_bytes);
+    let view = target.create_view(&TextureViewDescriptor::default());
+    let mut encoder =
+      gpu.create_command_encoder(
+        &wgpu::CommandEncoderDescriptor { label: Some("Cursor Encoder") },
+      );
+    {
+      let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Cursor Pass"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+          view: &view,
+          depth_slice: None,
+          resolve_target: None,
+          ops: wgpu::Operations {
+            load: wgpu::LoadOp::Load,
+            store: wgpu::StoreOp::Store,
+          },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+      });
+      pass.set_pipeline(&self.pipeline);
+      pass.set_bind_group(0, self.image.as_ref().map(|(_, bind_group)| bind_group), &[]);
+      let bounds = Rect::new(pos.0, pos.1, image.width, image.height);
+      for rect in clip.iter().flat_map(|rect| rect.intersection(&bounds)) {
+        pass.set_scissor_rect(rect.x as u32, rect.y as u32, rect.width, rect.height);
+        pass.draw(0 .. 4, 0 .. 1);
+      }
+    }
+    queue.submit([encoder.finish()]);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // An image chunk as Xcursor lays it out: nominal size, then the image
+  struct Chunk {
+    nominal: u32,
+    size: (u32, u32),
+    hotspot: (u32, u32),
+    delay: u32,
+  }
+
+  // An Xcursor file of `chunks`, each pixel being its index plus the
+  // chunk's number times 0x100
+  fn xcursor(chunks: &[Chunk]) -> Vec<u8> {
+    let words =
+      |words: &[u32]| words.iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<_>>();
+    let mut data = XCURSOR_MAGIC.to_vec();
+    data.extend(words(&[16, 0x1_0000, chunks.len() as u32]));
+    let mut position = 16 + 12 * chunks.len();
+    let mut images = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+      let (width, height) = chunk.size;
+      data.extend(words(&[XCURSOR_IMAGE_TYPE, chunk.nominal, position as u32]));
+      let mut image =
+        words(&[
+          36,
+          XCURSOR_IMAGE_TYPE,
+          chunk.nominal,
+          1,
+          width,
+          height,
+          chunk.hotspot.0,
+          chunk.hotspot.1,
+          chunk.delay,
+        ]);
+      image.extend(words(&(0 .. width * height).map(|j| j + 0x100 * i as u32).collect::<Vec<_>>()));
+      position += image.len();
+      images.extend(image);
+    }
+    data.extend(images);
+    data
+  }
+
+  fn chunks() -> Vec<Chunk> {
+    vec![
+      Chunk {
+        nominal: 24,
+        size: (2, 3),
+        hotspot: (1, 2),
+        delay: 50,
+      },
+      Chunk {
+        nominal: 48,
+        size: (4, 4),
+        hotspot: (9, 9),
+        delay: 0,
+      },
+      Chunk {
+        nominal: 24,
+        size: (2, 3),
+        hotspot: (0, 0),
+        delay: 70,
+      },
+    ]
+  }
+
+  #[test]
+  fn parses_the_closest_size() {
+    let data = xcursor(&chunks());
+    let frames = parse_xcursor(&data, 30).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!((frames[0].width, frames[0].height), (2, 3));
+    assert_eq!(frames[0].hotspot, (1, 2));
+    assert_eq!(frames[0].delay, Duration::from_millis(50));
+    assert_eq!(frames[0].pixels, [0, 1, 2, 3, 4, 5]);
+    assert_eq!(frames[1].delay, Duration::from_millis(70));
+    assert_eq!(frames[1].pixels[0], 0x200);
+
+    // A hotspot off the image is pulled onto its edge
+    let frames = parse_xcursor(&data, 40).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].hotspot, (3, 3));
+    assert_eq!(frames[0].pixels[15], 0x10f);
+  }
+
+  #[test]
+  fn rejects_malformed_files() {
+    let data = xcursor(&chunks());
+    let mut bad_magic = data.clone();
+    bad_magic[0] = b'x';
+    assert!(parse_xcursor(&bad_magic, 24).is_none());
+    assert!(parse_xcursor(&data[.. 3], 24).is_none());
+    assert!(parse_xcursor(&data[.. 30], 24).is_none());
+    assert!(parse_xcursor(&data[.. data.len() - 1], 24).is_none());
+    assert!(parse_xcursor(&xcursor(&[]), 24).is_none());
+
+    let empty = Chunk {
+      nominal: 24,
+      size: (0, 3),
+      hotspot: (0, 0),
+      delay: 0,
+    };
+    assert!(parse_xcursor(&xcursor(&[empty]), 24).is_none());
+  }
+}
@@ -0,0 +1,26 @@
+// Top left corner and size of the cursor in clip space
+struct Rect {
+   pos: vec2<f32>,
+   size: vec2<f32>,
+}
+struct VertexOutput {
+   @builtin(position) position: vec4<f32>,
+   @location(0) tex_coords: vec2<f32>,
+}
+@group(0) @binding(0) var tex: texture_2d<f32>;
+@group(0) @binding(1) var tex_sampler: sampler;
+@group(0) @binding(2) var<uniform> rect: Rect;
+// Drawn as a 4 vertex triangle strip
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+   var out: VertexOutput;
+   let corner = vec2<f32>(f32(vertex_index & 1u), f32(vertex_index >> 1u));
+   out.position = vec4<f32>(rect.pos + corner * rect.size, 0.0, 1.0);
+   out.tex_coords = corner;
+   return out;
+}
+// Xcursor images are premultiplied
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+   return textureSample(tex, tex_sampler, in.tex_coords);
+}
@@ -0,0 +1,138 @@
+use crate::surface::Rect;
+use std::collections::VecDeque;
+
+// Past this many rectangles a region is merged into its bounding box, which
+// is cheaper to redraw than lots of small pieces
+const MAX_RECTS: usize = 16;
+// Frames of damage kept, enough for any swapchain that is configured sanely
+const HISTORY: usize = 8;
+
+/// Add `rect` to `region`, dropping whatever it covers
+pub fn add(region: &mut Vec<Rect>, rect: Rect) {
+  if rect.is_empty() || region.iter().any(|old| old.contains(&rect)) {
+    return;
+  }
+  region.retain(|old| !rect.contains(old));
+  region.push(rect);
+  if region.len() > MAX_RECTS {
+    let bounds = region.iter().fold(region[0], |bounds, rect| bounds.union(rect));
+    *region = vec![bounds];
+  }
+}
+
+/// `rects` as the `struct drm_mode_rect` array `FB_DAMAGE_CLIPS` takes
+pub fn clips_blob(rects: &[Rect]) -> Vec<u8> {
+  rects
+    .iter()
+    .flat_map(|rect| [rect.x, rect.y, rect.right(), rect.bottom()])
+    .flat_map(|coord| coord.to_ne_bytes())
+    .collect()
+}
+
+/// What changed on a display in each of its last few frames, relative to
+/// the display
+#[derive(Debug, Default)]
+pub struct DamageHistory {
+  // Newest first
+  frames: VecDeque<Vec<Rect>>,
+  /// Damage not composed yet, in virtual screen coordinates
+  pub pending: Vec<Rect>,
+}
+
+impl DamageHistory {
+  /// Nothing has been drawn that later frames can build on
+  pub fn is_empty(&self) -> bool {
+    self.frames.is_empty()
+  }
+
+  /// Record the damage of a frame that was just drawn
+  pub fn push(&mut self, damage: Vec<Rect>) {
+    if self.frames.len() == HISTORY {
+      self.frames.pop_back();
+    }
+    self.frames.push_front(damage);
+  }
+
+  /// Forget everything, so the next frames are drawn whole
+  pub fn reset(&mut self) {
+    self.frames.clear();
+  }
+
+  /// The region to redraw in a buffer of `age` (see `Swapchain::age`) on
+  /// top of the new frame's own damage. None if the whole buffer has to be
+  /// redrawn.
+  pub fn since(&self, age: u64) -> Option<Vec<Rect>> {
+    if age == 0 || age as usize > self.frames.len() + 1 {
+      return None;
+    }
+    let mut region = Vec::new();
+    for rect in self.frames.iter().take(age as usize - 1).flatten() {
+      add(&mut region, *rect);
+    }
+    Some(region)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn merges_overlapping_damage() {
+    let mut region = Vec::new();
+    add(&mut region, Rect::new(10, 10, 10, 10));
+    add(&mut region, Rect::new(12, 12, 4, 4));
+    add(&mut region, Rect::new(0, 0, 0, 5));
+    assert_eq!(region, [Rect::new(10, 10, 10, 10)]);
+    add(&mut region, Rect::new(0, 0, 40, 40));
+    assert_eq!(region, [Rect::new(0, 0, 40, 40)]);
+  }
+
+  #[test]
+  fn collapses_to_the_bounding_box() {
+    let mut region = Vec::new();
+    for i in 0 .. MAX_RECTS as i32 {
+      add(&mut region, Rect::new(i * 10, 0, 5, 5));
+    }
+    assert_eq!(region.len(), MAX_RECTS);
+    add(&mut region, Rect::new(0, 100, 5, 5));
+    assert_eq!(region, [Rect::new(0, 0, MAX_RECTS as u32 * 10 - 5, 105)]);
+  }
+
+  #[test]
+  fn lays_out_damage_clips() {
+    let blob = clips_blob(&[Rect::new(-2, 3, 10, 20), Rect::new(5, 6, 1, 1)]);
+    let coords =
+      blob
+        .chunks_exact(4)
+        .map(|bytes| i32::from_ne_bytes(bytes.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(coords, [-2, 3, 8, 23, 5, 6, 6, 7]);
+  }
+
+  #[test]
+  fn redraws_damage_since_a_buffer_was_drawn() {
+    let mut history = DamageHistory::default();
+    assert!(history.is_empty());
+    assert_eq!(history.since(1), Some(Vec::new()));
+    assert_eq!(history.since(2), None);
+
+    for i in 0 .. HISTORY as i32 + 2 {
+      history.push(vec![Rect::new(i * 10, 0, 5, 5)]);
+    }
+    assert_eq!(history.since(0), None);
+    assert_eq!(history.since(1), Some(Vec::new()));
+    let newest = Rect::new((HISTORY as i32 + 1) * 10, 0, 5, 5);
+    assert_eq!(history.since(2), Some(vec![newest]));
+
+    // Only the last HISTORY frames are kept
+    let all = history.since(HISTORY as u64 + 1).unwrap();
+    assert_eq!(all.len(), HISTORY);
+    assert!(!all.contains(&Rect::new(10, 0, 5, 5)));
+    assert_eq!(history.since(HISTORY as u64 + 2), None);
+
+    history.reset();
+    assert!(history.is_empty());
+    assert_eq!(history.since(2), None);
+  }
+}
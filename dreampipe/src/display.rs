@@ -1,61 +1,407 @@
-pub use drm::control::Device as ControlDevice;
 use crate::buffer::CURSOR_DIM;
+use crate::buffer::DEFAULT_BUFFERS;
 use crate::buffer::DrmCtx;
+use crate::buffer::PresentMode;
+use crate::buffer::ScanoutFormat;
+use crate::buffer::SwapchainSpec;
+use crate::buffer::find_compatible_plane;
+use crate::color;
+use crate::color::ColorFilter;
+use crate::color::ColorSettings;
+use crate::color::ColorState;
+use crate::color::DEFAULT_NIGHT_TEMPERATURE;
+use crate::color::DEFAULT_SDR_WHITE;
+use crate::color::LocalTime;
+use crate::color::NightLight;
+use crate::color_pass::ColorLut;
+use crate::color_pass::ColorPass;
+use crate::cursor::DisplayCursor;
+use crate::damage;
+use crate::damage::DamageHistory;
+use crate::edid::Edid;
 use crate::context::Card;
 use crate::error::CompositorError;
 use crate::error::CompositorResult;
+use crate::formats::FormatTable;
+use crate::kms::AtomicRequest;
+use crate::kms::ConnectorInfo;
+use crate::kms::KmsBackend;
+use crate::kms::PropertyMap;
+use crate::overlay::OffloadStats;
+use crate::overlay::OverlayPlan;
+use crate::overlay::OverlayPlane;
+use crate::overlay::overlays_req;
+use crate::overlay::plan_overlays;
+use crate::overlay;
+use crate::scheduler::FrameScheduler;
+use crate::surface::BufferRelease;
+use crate::surface::Rect;
+use crate::surface::Surface;
+use crate::surface::Transform;
+use crate::util::ModePolicy;
+use crate::util::ModeSetting;
+use crate::util::config::CompositorConfig;
+use crate::util::config::Config;
+use crate::wallpaper::WallpaperSettings;
 use drm::ClientCapability;
-use drm::Device;
+use drm::control::AtomicCommitFlags;
+use drm::control::ModeTypeFlags;
 use drm::control::PlaneType;
-use drm::control::atomic;
 use drm::control::connector;
 use drm::control::crtc;
+use drm::control::framebuffer;
 use drm::control::plane;
 use drm::control::property;
 use drm::control;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::os::fd::BorrowedFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+use std::str::FromStr;
+use std::time::Duration;
 
-#[allow(unused)]
-fn print_formats(card: &Card, plane: plane::Handle) {
-  let prop_vals: HashMap<property::Handle, u64> =
-    card.get_properties(plane).unwrap().into_iter().collect();
-  let props = card.get_properties(plane).unwrap().as_hashmap(card).unwrap();
-  let formats_val = prop_vals[&props["IN_FORMATS"].handle()];
-  let blob = card.get_property_blob(formats_val).unwrap();
-  println!["formats: {:?}", blob.as_slice()];
+/// Most overlay planes one display claims, so others can have some too
+const MAX_OVERLAYS: usize = 3;
+/// Luminances in nits to announce for monitors that don't say
+const FALLBACK_MAX_NITS: f64 = 1000.0;
+const FALLBACK_MIN_NITS: f64 = 0.005;
+
+fn pick_mode(modes: &[control::Mode], policy: ModePolicy) -> Option<control::Mode> {
+  let area = |mode: &control::Mode| {
+    let (width, height) = mode.size();
+    width as u32 * height as u32
+  };
+  match policy {
+    ModePolicy::Preferred => modes
+      .iter()
+      .find(|mode| mode.mode_type().contains(ModeTypeFlags::PREFERRED))
+      .or_else(|| modes.first())
+      .copied(),
+    ModePolicy::HighestRefresh => modes
+      .iter()
+      .max_by_key(|mode| (mode.vrefresh(), area(mode)))
+      .copied(),
+    ModePolicy::HighestResolution => modes
+      .iter()
+      .max_by_key(|mode| (area(mode), mode.vrefresh()))
+      .copied(),
+  }
+}
+
+/// When a display refreshes as soon as a frame is ready instead of at a
+/// fixed rate
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum VrrMode {
+  #[default]
+  Off,
+  On,
+  /// Only while a fullscreen surface is scanned out, eg a game
+  FullscreenOnly,
+}
+
+impl FromStr for VrrMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim() {
+      "off" => Ok(Self::Off),
+      "on" => Ok(Self::On),
+      "fullscreen-only" => Ok(Self::FullscreenOnly),
+      other => Err(format!["Unknown VRR mode '{other}', expected off, on or fullscreen-only"]),
+    }
+  }
+}
+
+/// Colour settings of the display configured as the first of `names` that
+/// has them
+fn color_settings(config: &Config, names: &[String]) -> ColorSettings {
+  let keys = |key: fn(&str) -> String| names.iter().map(|name| key(name)).collect::<Vec<_>>();
+  let defaults = ColorSettings::default();
+  ColorSettings {
+    brightness: config
+      .get_first(&keys(CompositorConfig::brightness_key))
+      .unwrap_or(defaults.brightness),
+    contrast: config.get_first(&keys(CompositorConfig::contrast_key)).unwrap_or(defaults.contrast),
+    gamma: config.get_first(&keys(CompositorConfig::gamma_key)).unwrap_or(defaults.gamma),
+    filter: config.get_first(&keys(CompositorConfig::color_filter_key)).unwrap_or_default(),
+    night_light: NightLight {
+      schedule: config.get_first(&keys(CompositorConfig::night_light_key)).unwrap_or_default(),
+      temperature: config
+        .get_first(&keys(CompositorConfig::night_temperature_key))
+        .unwrap_or(DEFAULT_NIGHT_TEMPERATURE),
+    },
+  }
+}
+
+/// An 8 bit texture the size of a display to draw and copy around in
+fn canvas_texture(gpu: &wgpu::Device, label: &str, (width, height): (u32, u32)) -> wgpu::Texture {
+  gpu.create_texture(&wgpu::TextureDescriptor {
+    label: Some(label),
+    size: wgpu::Extent3d {
+      width,
+      height,
+      depth_or_array_layers: 1,
+    },
+    mip_level_count: 1,
+    sample_count: 1,
+    dimension: wgpu::TextureDimension::D2,
+    format: wgpu::TextureFormat::Bgra8Unorm,
+    usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST |
+      wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+    view_formats: &[],
+  })
+}
+
+/// Time between vblanks in `mode`
+fn refresh_interval(mode: &control::Mode) -> Duration {
+  let (hsync, vsync) = (mode.hsync(), mode.vsync());
+  let pixels = hsync.2 as u64 * vsync.2 as u64;
+  match mode.clock() as u64 * 1000 {
+    0 => Duration::from_secs(1) / mode.vrefresh().max(1),
+    clock => Duration::from_nanos(pixels * 1_000_000_000 / clock),
+  }
+}
+
+/// Names a display can be configured by, most specific first: its EDID
+/// identity if it has one, then its connector name
+pub fn config_names(name: &str, edid: Option<&Edid>) -> Vec<String> {
+  edid.map(|edid| edid.identity()).into_iter().chain([name.to_string()]).collect()
+}
+
+/// The mode `config` asks for on a display known by `names`, falling back to
+/// the configured policy (preferred by default) if the connector doesn't have
+/// it
+pub fn select_mode(
+  names: &[String],
+  modes: &[control::Mode],
+  config: &Config,
+) -> Option<control::Mode> {
+  let keys = |key: fn(&str) -> String| names.iter().map(|name| key(name)).collect::<Vec<_>>();
+  let name = &names[0];
+  let fallback =
+    config
+      .get_first::<ModePolicy>(&keys(CompositorConfig::mode_fallback_key))
+      .unwrap_or_default();
+  match config.get_first::<ModeSetting>(&keys(CompositorConfig::mode_key)) {
+    Some(ModeSetting::Exact(spec)) => {
+      let matching =
+        modes
+          .iter()
+          .filter(|mode| mode.size() == (spec.width, spec.height))
+          .filter(|mode| spec.refresh.is_none_or(|refresh| mode.vrefresh() == refresh))
+          .copied()
+          .collect::<Vec<_>>();
+
+      // Same size and refresh can come with different timings
+      let mode = pick_mode(&matching, ModePolicy::Preferred);
+      if mode.is_none() {
+        tracing::warn!["{name} has no mode {spec:?}, using {fallback:?} instead"];
+      }
+      mode.or_else(|| pick_mode(modes, fallback))
+    },
+    Some(ModeSetting::Policy(policy)) => pick_mode(modes, policy),
+    None => pick_mode(modes, fallback),
+  }
+}
+
+// Try to give connector `i` a CRTC, moving other connectors along if that
+// frees one up for it
+fn augment(
+  i: usize,
+  possible: &[Vec<crtc::Handle>],
+  owners: &mut HashMap<crtc::Handle, usize>,
+  visited: &mut HashSet<crtc::Handle>,
+) -> bool {
+  for &crtc in possible[i].iter() {
+    if !visited.insert(crtc) {
+      continue;
+    }
+    match owners.get(&crtc).copied() {
+      Some(owner) if !augment(owner, possible, owners, visited) => continue,
+      _ => {
+        owners.insert(crtc, i);
+        return true;
+      },
+    }
+  }
+  false
+}
+
+/// Assign each connector one of the CRTCs in `possible[i]` so that as many
+/// connectors as possible light up. `preferred` CRTCs are handed out first and
+/// only taken away if another connector can't be driven otherwise.
+pub fn match_crtcs(
+  possible: &[Vec<crtc::Handle>],
+  preferred: &[Option<crtc::Handle>],
+) -> Vec<Option<crtc::Handle>> {
+  let mut owners: HashMap<crtc::Handle, usize> = HashMap::new();
+  for (i, crtc) in preferred.iter().enumerate() {
+    if let Some(crtc) = crtc && possible[i].contains(crtc) && !owners.contains_key(crtc) {
+      owners.insert(*crtc, i);
+    }
+  }
+  for i in 0 .. possible.len() {
+    if !owners.values().any(|&owner| owner == i) {
+      augment(i, possible, &mut owners, &mut HashSet::new());
+    }
+  }
+  let mut crtcs = vec![None; possible.len()];
+  for (crtc, i) in owners {
+    crtcs[i] = Some(crtc);
+  }
+  crtcs
+}
+
+/// Names, CRTCs and planes already claimed by lit displays
+#[derive(Debug, Default)]
+pub struct InUse {
+  pub names: HashSet<String>,
+  pub crtcs: HashSet<crtc::Handle>,
+  pub planes: HashSet<plane::Handle>,
+}
+
+impl<'a> FromIterator<&'a Display> for InUse {
+  fn from_iter<T: IntoIterator<Item = &'a Display>>(displays: T) -> Self {
+    let mut in_use = InUse::default();
+    for display in displays {
+      in_use.names.insert(display.name.to_owned());
+      in_use.crtcs.insert(display.crtc);
+      in_use.planes.insert(display.primary.plane);
+      in_use.planes.extend(display.cursor.plane.as_ref().map(|cursor| cursor.plane));
+      in_use.planes.extend(display.overlays.iter().map(|overlay| overlay.plane));
+    }
+    in_use
+  }
+}
+
+/// Everything needed to light up a connector, short of buffers
+#[derive(Debug)]
+pub struct DisplayCandidate {
+  pub name: String,
+  pub connector: ConnectorInfo,
+  pub edid: Option<Edid>,
+  pub crtc: crtc::Handle,
+  pub mode: control::Mode,
+  pub primary: plane::Handle,
+  pub cursor: Option<plane::Handle>,
+  pub overlays: Vec<plane::Handle>,
+  pub connector_props: PropertyMap,
+  pub crtc_props: PropertyMap,
 }
 
 #[derive(Debug)]
 #[repr(C)]
 pub struct Display {
   pub name: String,
+  pub edid: Option<Edid>,
   pub size: (u32, u32),
   pub pos: (i32, i32),
   pub connector: connector::Handle,
   pub crtc: crtc::Handle,
-  pub connector_props: HashMap<String, property::Info>,
-  pub crtc_props: HashMap<String, property::Info>,
+  pub connector_props: PropertyMap,
+  pub crtc_props: PropertyMap,
   pub mode: control::Mode,
   pub primary: DrmCtx,
-  pub cursor: DrmCtx,
-  pub overlays: Vec<DrmCtx>,
+  pub cursor: DisplayCursor,
+  pub overlays: Vec<OverlayPlane>,
+  /// What goes on the overlays next frame
+  pub overlay_plan: OverlayPlan,
+  /// Client framebuffer to put on the primary plane instead of our own
+  /// buffers next frame
+  pub scanout: Option<framebuffer::Handle>,
+  /// Client framebuffers the last commit showed, on the primary plane or
+  /// overlays
+  pub client_fbs: Vec<framebuffer::Handle>,
+  /// What each commit still waiting for its page flip released, oldest first
+  pub releases: VecDeque<BufferRelease>,
+  pub scheduler: FrameScheduler,
+  /// The last frame was flipped and the next one can be composed
+  pub needs_frame: bool,
+  pub damage: DamageHistory,
+  /// The connector reports `vrr_capable` and the CRTC has `VRR_ENABLED`
+  pub vrr_capable: bool,
+  pub vrr_mode: VrrMode,
+  /// VRR was enabled by the last commit
+  pub vrr: bool,
+  /// Configured to allow async flips, and the driver can do them
+  pub tearing_allowed: bool,
+  /// The surface being scanned out asked for async flips and may have them
+  pub tearing: bool,
+  pub color: ColorSettings,
+  /// What is shown behind every surface
+  pub wallpaper: WallpaperSettings,
+  /// What the colour pipeline is set to, None until it first is
+  pub color_state: Option<ColorState>,
+  /// `color_state` still has to be committed to the CRTC
+  pub color_changed: bool,
+  /// Set while colour is applied in a shader because the CRTC can't
+  pub color_lut: Option<ColorLut>,
+  /// The monitor is sent PQ encoded BT.2020 along with HDR metadata
+  pub hdr: bool,
+  /// How bright SDR content is in HDR, in nits
+  pub sdr_white: f64,
+  /// What is composited into when colour is applied in a shader, for the
+  /// colour pass to convert in one go
+  pub composite: Option<wgpu::Texture>,
 }
 
 #[derive(Debug)]
 #[repr(C)]
 pub struct ReadonlyDisplay {
   name: String,
+  pub edid: Option<Edid>,
   pub size: (u32, u32),
   pub pos: (i32, i32),
   pub connector: connector::Handle,
   pub crtc: crtc::Handle,
-  pub connector_props: HashMap<String, property::Info>,
-  pub crtc_props: HashMap<String, property::Info>,
+  pub connector_props: PropertyMap,
+  pub crtc_props: PropertyMap,
   pub mode: control::Mode,
   pub primary: DrmCtx,
-  pub cursor: DrmCtx,
-  pub overlays: Vec<DrmCtx>,
+  pub cursor: DisplayCursor,
+  pub overlays: Vec<OverlayPlane>,
+  /// What goes on the overlays next frame
+  pub overlay_plan: OverlayPlan,
+  /// Client framebuffer to put on the primary plane instead of our own
+  /// buffers next frame
+  pub scanout: Option<framebuffer::Handle>,
+  /// Client framebuffers the last commit showed, on the primary plane or
+  /// overlays
+  pub client_fbs: Vec<framebuffer::Handle>,
+  /// What each commit still waiting for its page flip released, oldest first
+  pub releases: VecDeque<BufferRelease>,
+  pub scheduler: FrameScheduler,
+  /// The last frame was flipped and the next one can be composed
+  pub needs_frame: bool,
+  pub damage: DamageHistory,
+  /// The connector reports `vrr_capable` and the CRTC has `VRR_ENABLED`
+  pub vrr_capable: bool,
+  pub vrr_mode: VrrMode,
+  /// VRR was enabled by the last commit
+  pub vrr: bool,
+  /// Configured to allow async flips, and the driver can do them
+  pub tearing_allowed: bool,
+  /// The surface being scanned out asked for async flips and may have them
+  pub tearing: bool,
+  pub color: ColorSettings,
+  /// What is shown behind every surface
+  pub wallpaper: WallpaperSettings,
+  /// What the colour pipeline is set to, None until it first is
+  pub color_state: Option<ColorState>,
+  /// `color_state` still has to be committed to the CRTC
+  pub color_changed: bool,
+  /// Set while colour is applied in a shader because the CRTC can't
+  pub color_lut: Option<ColorLut>,
+  /// The monitor is sent PQ encoded BT.2020 along with HDR metadata
+  pub hdr: bool,
+  /// How bright SDR content is in HDR, in nits
+  pub sdr_white: f64,
+  /// What is composited into when colour is applied in a shader, for the
+  /// colour pass to convert in one go
+  pub composite: Option<wgpu::Texture>,
 }
 
 impl core::ops::Deref for Display {
@@ -85,31 +431,476 @@ impl PartialEq for Display {
 impl Display {
   pub fn init_req(
     &self,
-    card: &Card,
-    atomic_req: &mut atomic::AtomicModeReq,
+    kms: &dyn KmsBackend,
+    atomic_req: &mut AtomicRequest,
   ) -> CompositorResult<()> {
     atomic_req.add_property(
       self.connector,
       self.connector_props["CRTC_ID"].handle(),
       property::Value::CRTC(Some(self.crtc)),
     );
-    let blob = card.create_property_blob(&self.mode).expect("Failed to create a blob");
-    atomic_req.add_property(self.crtc, self.crtc_props["MODE_ID"].handle(), blob);
+    let blob = kms.create_mode_blob(&self.mode).expect("Failed to create a blob");
+    atomic_req.add_property(
+      self.crtc,
+      self.crtc_props["MODE_ID"].handle(),
+      property::Value::Blob(blob),
+    );
     atomic_req.add_property(
       self.crtc,
       self.crtc_props["ACTIVE"].handle(),
       property::Value::Boolean(true),
     );
+
+    // Also undoes HDR left on by whoever had the connector before
+    let colorspace = if self.hdr { "BT2020_RGB" } else { "Default" };
+    if let Some(prop) = self.connector_props.get("Colorspace") &&
+      let Some(value) = prop.enum_value(colorspace) {
+      atomic_req.add_property(self.connector, prop.handle(), property::Value::Unknown(value));
+    }
+    if let Some(prop) = self.connector_props.get("HDR_OUTPUT_METADATA") {
+      let hdr = self.edid.as_ref().and_then(|edid| edid.hdr).filter(|_| self.hdr);
+      let blob =
+        match hdr {
+          Some(hdr) => {
+            let max = hdr.max_nits().unwrap_or(FALLBACK_MAX_NITS);
+            let metadata =
+              color::hdr_output_metadata_blob(
+                max,
+                hdr.min_nits().unwrap_or(FALLBACK_MIN_NITS),
+                hdr.max_frame_average_nits().unwrap_or(max),
+              );
+            kms.create_blob(&metadata).expect("Failed to create a blob")
+          },
+          None => 0,
+        };
+      atomic_req.add_property(self.connector, prop.handle(), property::Value::Blob(blob));
+    }
+    if let Some(prop) = self.connector_props.get("max bpc").filter(|_| self.deep()) {
+      atomic_req.add_property(self.connector, prop.handle(), property::Value::UnsignedRange(10));
+    }
     Ok(())
   }
 
-  pub fn init_displays(
-    ignore_list: impl Into<Option<HashSet<String>>>,
-    card: &Card,
-    gbm: &gbm::Device<&'static Card>,
+  /// Call on every page flip, before drawing the next frame
+  pub fn flipped(&mut self) {
+    self.primary.buffers.flipped();
+    self.cursor.flipped();
+  }
+
+  /// Where the display is on the virtual screen
+  pub fn rect(&self) -> Rect {
+    Rect::new(self.pos.0, self.pos.1, self.size.0, self.size.1)
+  }
+
+  /// What changes on screen with the next frame, relative to the display:
+  /// pending surface damage, plus where the software cursor was and goes
+  /// to. Everything if there is no earlier frame to build on.
+  pub fn frame_damage(&mut self, cursor: Option<(Rect, u64, usize)>) -> Vec<Rect> {
+    let full = Rect::new(0, 0, self.size.0, self.size.1);
+    let pending = std::mem::take(&mut self.damage.pending);
+    if self.damage.is_empty() {
+      return vec![full];
+    }
+    let mut damage = Vec::new();
+    let origin = (-self.pos.0, -self.pos.1);
+    for rect in pending.iter().map(|rect| rect.translate(origin)) {
+      damage::add(&mut damage, rect);
+    }
+    if cursor != self.cursor.drawn {
+      for (rect, _, _) in cursor.iter().chain(self.cursor.drawn.iter()) {
+        damage::add(&mut damage, *rect);
+      }
+    }
+    damage.iter().flat_map(|rect| rect.intersection(&full)).collect()
+  }
+
+  /// The region of the draw buffer to redraw for a frame with
+  /// `frame_damage`, taking buffer age into account. Empty if there is no
+  /// buffer to draw into.
+  pub fn repaint_region(&mut self, frame_damage: &[Rect]) -> Vec<Rect> {
+    if self.primary.buffers.acquire().is_none() {
+      return Vec::new();
+    }
+    match self.damage.since(self.primary.buffers.age()) {
+      Some(mut region) => {
+        for rect in frame_damage {
+          damage::add(&mut region, *rect);
+        }
+        region
+      },
+      None => vec![Rect::new(0, 0, self.size.0, self.size.1)],
+    }
+  }
+
+  /// Whether the next commit should have VRR on
+  pub fn wants_vrr(&self) -> bool {
+    self.vrr_capable &&
+      match self.vrr_mode {
+        VrrMode::Off => false,
+        VrrMode::On => true,
+        VrrMode::FullscreenOnly => self.scanout.is_some(),
+      }
+  }
+
+  /// Pick up settings that can change without a modeset. They take effect
+  /// with the next frame.
+  pub fn apply_config(&mut self, config: &Config) {
+    let names = self.config_names();
+    let keys = names.iter().map(|name| CompositorConfig::vrr_key(name)).collect::<Vec<_>>();
+    self.vrr_mode = config.get_first(&keys).unwrap_or_default();
+    self.color = color_settings(config, &names);
+    let wallpaper = WallpaperSettings::from_config(config, &names);
+    if wallpaper != self.wallpaper {
+      self.wallpaper = wallpaper;
+      self.damage.reset();
+    }
+    let keys = names.iter().map(|name| CompositorConfig::sdr_white_key(name)).collect::<Vec<_>>();
+    let sdr_white = config.get_first(&keys).unwrap_or(DEFAULT_SDR_WHITE);
+    if sdr_white != self.sdr_white {
+      // Uploaded with the colour state
+      self.sdr_white = sdr_white;
+      self.color_state = None;
+    }
+  }
+
+  /// The primary plane is more than 8 bit, so frames are composited in 8 bit
+  /// and converted by the colour pass
+  pub fn deep(&self) -> bool {
+    self.primary.format != ScanoutFormat::XRGB8888
+  }
+
+  /// The CRTC can apply `state` by itself, and the colour pass isn't needed
+  /// anyway to convert to a deeper format
+  pub fn color_in_hardware(&self, state: &ColorState) -> bool {
+    !self.deep() && self.crtc_props.contains_key("GAMMA_LUT") &&
+      (state.filter == ColorFilter::None ||
+        ["DEGAMMA_LUT", "CTM"].iter().all(|name| self.crtc_props.contains_key(*name)))
+  }
+
+  /// Follow night light and changed settings. If the CRTC can't apply them
+  /// the whole display is redrawn through `color_pass`.
+  pub fn update_color(
+    &mut self,
     gpu: &wgpu::Device,
-  ) -> CompositorResult<Vec<Display>> {
-    let ignore_list = ignore_list.into();
+    queue: &wgpu::Queue,
+    color_pass: &ColorPass,
+    time: &LocalTime,
+  ) {
+    let state = self.color.state_at(time);
+    if self.color_state == Some(state) {
+      return;
+    }
+    self.color_state = Some(state);
+    self.color_changed = true;
+    let shader = self.deep() || (!self.color_in_hardware(&state) && !state.is_identity());
+    if shader || self.color_lut.is_some() {
+      let sdr_white = self.hdr.then_some(self.sdr_white);
+      self.color_lut =
+        shader.then(|| color_pass.lut(gpu, queue, &state, self.primary.format, sdr_white));
+      self.composite =
+        shader.then(|| {
+          self.composite.take().unwrap_or_else(|| canvas_texture(gpu, "Composite", self.size))
+        });
+      self.damage.reset();
+    }
+  }
+
+  // Set the CRTC's colour properties if they changed, to identity if the
+  // shader does the work. Returns the blobs to destroy after the commit.
+  fn color_req(&self, kms: &dyn KmsBackend, atomic_req: &mut AtomicRequest) -> Vec<u64> {
+    let Some(state) = self.color_state.filter(|_| self.color_changed) else {
+      return Vec::new();
+    };
+    let hardware = self.color_in_hardware(&state) && !state.is_identity();
+    let matrix = state.filter.matrix().filter(|_| hardware);
+    let lut_size = |name: &str| {
+      self.crtc_props.get(name).map(|prop| prop.value() as usize).filter(|&size| size > 1)
+    };
+    let mut blobs = Vec::new();
+    let mut blob = |data: Vec<u8>| {
+      kms.create_blob(&data).inspect(|&blob| blobs.push(blob)).unwrap_or_else(|e| {
+        tracing::warn!["Failed to create a color blob: {e}"];
+        0
+      })
+    };
+    let gamma =
+      match lut_size("GAMMA_LUT_SIZE") {
+        Some(size) if hardware => blob(color::lut_blob(&state.gamma_lut(size, matrix.is_some()))),
+        _ => 0,
+      };
+    let degamma =
+      match lut_size("DEGAMMA_LUT_SIZE") {
+        Some(size) if matrix.is_some() => blob(color::lut_blob(&color::degamma_lut(size))),
+        _ => 0,
+      };
+    let ctm = matrix.map(|matrix| blob(color::ctm_blob(&matrix))).unwrap_or(0);
+    for (name, value) in [("GAMMA_LUT", gamma), ("DEGAMMA_LUT", degamma), ("CTM", ctm)] {
+      if let Some(prop) = self.crtc_props.get(name) {
+        atomic_req.add_property(self.crtc, prop.handle(), property::Value::Blob(value));
+      }
+    }
+    blobs
+  }
+
+  /// Put the topmost surface straight on the primary plane if it covers the
+  /// whole display, so nothing needs composing this frame. Returns false if
+  /// it has to be composited: the buffer is the wrong size or format, it is
+  /// translucent, clipped or transformed, or the cursor is drawn in software.
+  pub fn plan_scanout(&mut self, kms: &dyn KmsBackend, surfaces: &[Surface]) -> bool {
+    let previous = self.scanout.take();
+    self.tearing = false;
+    let rect = Rect::new(self.pos.0, self.pos.1, self.size.0, self.size.1);
+    let visible = surfaces.iter().filter(|surface| surface.rect.intersects(&rect));
+    let Some(top) = visible.clone().max_by_key(|surface| surface.z) else {
+      return false;
+    };
+    let Some(buffer) = top.buffer.as_ref() else {
+      return false;
+    };
+    if top.rect != rect || !top.unclipped() || buffer.size != self.size || !top.opaque ||
+      top.alpha < 1.0 || top.transform != Transform::Normal ||
+      (self.cursor.software && self.cursor.visible) || self.color_lut.is_some() {
+      return false;
+    }
+    if !self.primary.formats.supports(buffer.format, buffer.modifier) {
+      return false;
+    }
+
+    // Overlays would only show things the surface covers
+    let mut atomic_req = AtomicRequest::new();
+    self.primary.plane_req(&mut atomic_req, self.crtc, buffer.fb, (0, 0));
+    self.cursor.req(&mut atomic_req, self.crtc);
+    overlays_req(&mut atomic_req, self.crtc, &self.overlays, &[]);
+    if kms.commit(AtomicCommitFlags::TEST_ONLY, &atomic_req).is_err() {
+      return false;
+    }
+    tracing::debug!["{}: scanning out surface {} directly", self.name, top.id];
+    self.scanout = Some(buffer.fb);
+
+    // Tearing only makes sense flipping one scanned out buffer to the next
+    self.tearing = self.tearing_allowed && top.tearing && previous.is_some();
+    self.overlay_plan = OverlayPlan {
+      stats: OffloadStats {
+        surfaces: visible.count(),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    true
+  }
+
+  /// Work out which of `surfaces` can skip the GPU this frame by going on an
+  /// overlay plane. Call after placing the cursor and before drawing, only
+  /// `overlay_plan.composited` needs drawing.
+  pub fn plan_overlays(&mut self, kms: &dyn KmsBackend, surfaces: &[Surface]) {
+    let mut base = AtomicRequest::new();
+    self.primary.flip_req(&mut base, self.crtc);
+    self.cursor.req(&mut base, self.crtc);
+    let rect = Rect::new(self.pos.0, self.pos.1, self.size.0, self.size.1);
+    let primary_zpos = self.primary.plane_props.get("zpos").map(|prop| prop.value());
+    self.overlay_plan =
+      plan_overlays(kms, self.crtc, rect, primary_zpos, &self.overlays, surfaces, &base);
+    let stats = self.overlay_plan.stats;
+    let offloaded =
+      self.overlay_plan.placements.iter().map(|placement| placement.surface).collect::<Vec<_>>();
+    tracing::debug![
+      "{}: {} of {} surfaces on overlays {:?}, {} rejected by the driver",
+      self.name,
+      stats.offloaded,
+      stats.surfaces,
+      offloaded,
+      stats.rejected
+    ];
+  }
+
+  /// Nothing but the buffer on the primary plane changes with the next
+  /// commit, as async flips require
+  fn only_primary_changes(&self) -> bool {
+    !self.cursor.changed() && self.overlay_plan.placements.is_empty() &&
+      !self.overlays.iter().any(|overlay| overlay.showing) && self.wants_vrr() == self.vrr &&
+      !self.color_changed
+  }
+
+  // Flip to the scanned out buffer without waiting for vblank. Returns false
+  // if that can't be done this frame, for a vsynced flip instead.
+  fn async_flip(&mut self, kms: &dyn KmsBackend) -> bool {
+    let Some(fb) = self.scanout.filter(|_| self.only_primary_changes()) else {
+      return false;
+    };
+    let mut atomic_req = AtomicRequest::new();
+    atomic_req.add_property(
+      self.primary.plane,
+      self.primary.plane_props["FB_ID"].handle(),
+      property::Value::Framebuffer(Some(fb)),
+    );
+    let flags =
+      AtomicCommitFlags::NONBLOCK | AtomicCommitFlags::PAGE_FLIP_EVENT |
+        AtomicCommitFlags::PAGE_FLIP_ASYNC;
+    match kms.commit(flags, &atomic_req) {
+      Ok(()) => {
+        self.release_client_fbs(None);
+        true
+      },
+      Err(e) => {
+        tracing::debug!["{}: async flip failed, waiting for vblank: {e}", self.name];
+        false
+      },
+    }
+  }
+
+  /// Queue the primary back buffer, or the client buffer being scanned out,
+  /// along with any cursor and overlay plane changes. Our buffers are shown
+  /// once `in_fence` signals, if there is one, and `damage` is passed on so
+  /// the driver can update just that. A surface allowed to tear is flipped
+  /// in without waiting for vblank when nothing else changes.
+  pub unsafe fn swap(
+    &mut self,
+    kms: &dyn KmsBackend,
+    in_fence: Option<BorrowedFd>,
+    damage: &[Rect],
+  ) -> CompositorResult<()> {
+    if self.tearing && self.async_flip(kms) {
+      return Ok(());
+    }
+    let mut atomic_req = AtomicRequest::new();
+    match self.scanout {
+      Some(fb) => self.primary.plane_req(&mut atomic_req, self.crtc, fb, (0, 0)),
+      None => {
+        self.primary.buffers.present();
+        self.primary.flip_req(&mut atomic_req, self.crtc);
+      },
+    }
+    self.cursor.req(&mut atomic_req, self.crtc);
+    overlays_req(&mut atomic_req, self.crtc, &self.overlays, &self.overlay_plan.placements);
+    if let Some(fence) = in_fence {
+      if self.scanout.is_none() {
+        self.primary.fence_req(&mut atomic_req, fence);
+      }
+      self.cursor.fence_req(&mut atomic_req, fence);
+    }
+
+    // Without clips the whole plane counts as damaged
+    let clips =
+      match self.primary.plane_props.get("FB_DAMAGE_CLIPS") {
+        Some(prop) if self.scanout.is_none() && !damage.is_empty() => kms
+          .create_blob(&damage::clips_blob(damage))
+          .inspect_err(|e| {
+            tracing::warn!["{}: failed to create damage clips: {e}", self.name];
+          })
+          .ok()
+          .map(|blob| (prop.handle(), blob)),
+        _ => None,
+      };
+    if let Some((handle, blob)) = clips {
+      atomic_req.add_property(self.primary.plane, handle, property::Value::Blob(blob));
+    }
+
+    let vrr = self.wants_vrr();
+    if vrr != self.vrr {
+      atomic_req.add_property(
+        self.crtc,
+        self.crtc_props["VRR_ENABLED"].handle(),
+        property::Value::Boolean(vrr),
+      );
+    }
+
+    let color_blobs = self.color_req(kms, &mut atomic_req);
+
+    // The kernel writes the fence fd here during the commit
+    let mut out_fence: RawFd = -1;
+    if let Some(prop) = self.crtc_props.get("OUT_FENCE_PTR") {
+      atomic_req.add_property(
+        self.crtc,
+        prop.handle(),
+        property::Value::UnsignedRange(&mut out_fence as *mut RawFd as u64),
+      );
+    }
+    let committed =
+      kms.commit(AtomicCommitFlags::NONBLOCK | AtomicCommitFlags::PAGE_FLIP_EVENT, &atomic_req);
+
+    // The commit holds on to the blobs for as long as it needs them
+    for blob in clips.map(|(_, blob)| blob).into_iter().chain(color_blobs) {
+      kms.destroy_blob(blob).ok();
+    }
+    committed.map_err(|err| CompositorError::AtomicCommitFailed(err))?;
+    self.color_changed = false;
+    if vrr != self.vrr {
+      println!["VRR {} on {}", if vrr { "enabled" } else { "disabled" }, self.name];
+      self.vrr = vrr;
+      self.scheduler.set_vrr(vrr);
+    }
+    self.release_client_fbs((out_fence >= 0).then(|| unsafe {
+      OwnedFd::from_raw_fd(out_fence)
+    }));
+    if self.scanout.is_none() {
+      self.primary.buffers.queued();
+    }
+    self.cursor.committed();
+    overlay::committed(&mut self.overlays, &self.overlay_plan.placements);
+    Ok(())
+  }
+
+  // Queue the client buffers the commit just made stopped showing, to be
+  // released with its page flip. Every commit queues one, even if empty, so
+  // each flip takes its own.
+  fn release_client_fbs(&mut self, out_fence: Option<OwnedFd>) {
+    let showing =
+      self
+        .scanout
+        .into_iter()
+        .chain(self.overlay_plan.placements.iter().map(|placement| placement.fb))
+        .collect::<Vec<_>>();
+    let fbs = self.client_fbs.iter().filter(|fb| !showing.contains(fb)).copied().collect();
+    self.client_fbs = showing;
+    self.releases.push_back(BufferRelease {
+      fbs,
+      fence: out_fence,
+    });
+  }
+
+  /// Everything the display still holds on to, for when it goes away
+  pub fn release_all(&mut self) -> impl Iterator<Item = BufferRelease> + '_ {
+    let showing = BufferRelease {
+      fbs: std::mem::take(&mut self.client_fbs),
+      fence: None,
+    };
+    self.releases.drain(..).chain([showing])
+  }
+
+  pub fn destroy_framebuffers(&self, kms: &dyn KmsBackend) {
+    self.primary.destroy_framebuffers(kms);
+    if let Some(cursor) = self.cursor.plane.as_ref() {
+      cursor.destroy_framebuffers(kms);
+    }
+  }
+
+  pub fn config_names(&self) -> Vec<String> {
+    config_names(&self.name, self.edid.as_ref())
+  }
+
+  /// The mode `config` asks for, if it differs from the current one
+  pub fn wanted_mode(&self, kms: &dyn KmsBackend, config: &Config) -> Option<control::Mode> {
+    let connector = kms.connector(self.connector, false).ok()?;
+    select_mode(&self.config_names(), &connector.modes, config).filter(|mode| *mode != self.mode)
+  }
+
+  /// False once the monitor is unplugged
+  pub fn is_connected(&self, kms: &dyn KmsBackend) -> bool {
+    kms
+      .connector(self.connector, false)
+      .map(|info| info.state == connector::State::Connected)
+      .unwrap_or(false)
+  }
+
+  /// Pick a CRTC, mode and planes for every connected connector that is not
+  /// already in use. CRTCs are matched to connectors through the encoders'
+  /// `possible_crtcs`, keeping the one in `history` where possible.
+  pub fn probe(
+    kms: &dyn KmsBackend,
+    in_use: &InUse,
+    history: &HashMap<String, crtc::Handle>,
+    config: &Config,
+  ) -> CompositorResult<Vec<DisplayCandidate>> {
     for (
       cap,
       enable,
@@ -117,102 +908,391 @@ impl Display {
       (ClientCapability::UniversalPlanes, true),
       (ClientCapability::Atomic, true),
     ].into_iter() {
-      card.set_client_capability(cap, enable).map_err(|err| {
+      kms.set_capability(cap, enable).map_err(|err| {
         CompositorError::ClientCapability(cap, err)
       })?;
     }
-    let resources = card.resource_handles().map_err(|err| {
+    let resources = kms.resources().map_err(|err| {
       CompositorError::ResourcesError(err)
     })?;
-    let connected: Vec<connector::Info> =
+    let connected: Vec<ConnectorInfo> =
       resources
-        .connectors()
+        .connectors
         .iter()
-        .flat_map(|con| card.get_connector(*con, true))
-        .filter(|i| i.state() == connector::State::Connected && !i.modes().is_empty())
+        .flat_map(|con| kms.connector(*con, true))
+        .filter(|i| i.state == connector::State::Connected && !i.modes.is_empty())
         .collect();
     if connected.is_empty() {
       Err(CompositorError::NoQualifiedConnectors)?;
     }
     let mut planes: HashSet<plane::Handle> =
-      card
-        .plane_handles()
+      kms
+        .planes()
         .map_err(|err| CompositorError::GetPlanes(err))?
         .into_iter()
+        .filter(|plane| !in_use.planes.contains(plane))
         .collect();
-    let max_displays = resources.crtcs().len().min(connected.len());
-    let mut displays = Vec::new();
-    for (
-      connector,
-      &crtc,
-    ) in connected.into_iter().take(max_displays).zip(resources.crtcs()) {
+    let mut wanted = Vec::new();
+    for connector in connected.into_iter() {
       let name =
         format![
           "card{}-{}-{}",
-          card.num(),
-          connector.interface().as_str(),
-          connector.interface_id()
+          kms.num(),
+          connector.interface.as_str(),
+          connector.interface_id
         ];
-      if let Some(ref ignore_list) = ignore_list {
-        if ignore_list.contains(&name) {
-          continue;
-        }
+      if in_use.names.contains(&name) {
+        continue;
       }
-      let mode = *connector.modes().first().unwrap();
-      let size = match mode.size() {
-        (width, height) => (width as u32, height as u32),
+      let edid =
+        kms
+          .properties(connector.handle.into())
+          .ok()
+          .and_then(|props| props.get("EDID").map(|prop| prop.value()))
+          .filter(|blob| *blob != 0)
+          .and_then(|blob| kms.blob(blob).ok())
+          .and_then(|blob| Edid::parse(&blob).inspect_err(|e| {
+            tracing::warn!["{name}: {e}"];
+          }).ok());
+      let names = config_names(&name, edid.as_ref());
+      let Some(mode) = select_mode(&names, &connector.modes, config) else {
+        continue;
+      };
+      let mut possible_crtcs = 0;
+      for &encoder in connector.encoders.iter() {
+        let info =
+          kms
+            .encoder(encoder)
+            .map_err(|err| CompositorError::GetEncoderInfo(encoder, err))?;
+        possible_crtcs |= info.possible_crtcs;
+      }
+      let crtcs =
+        resources
+          .filter_crtcs(possible_crtcs)
+          .into_iter()
+          .filter(|crtc| !in_use.crtcs.contains(crtc))
+          .collect::<Vec<_>>();
+      wanted.push((name, connector, edid, mode, crtcs));
+    }
+    let possible = wanted.iter().map(|(_, _, _, _, crtcs)| crtcs.clone()).collect::<Vec<_>>();
+    let preferred =
+      wanted.iter().map(|(name, _, _, _, _)| history.get(name).copied()).collect::<Vec<_>>();
+    let matched = match_crtcs(&possible, &preferred);
+    let mut candidates = Vec::new();
+    for ((name, connector, edid, mode, _), crtc) in wanted.into_iter().zip(matched) {
+      let Some(crtc) = crtc else {
+        tracing::warn!["No free CRTC can drive {name}"];
+        continue;
+      };
+      let primary = find_compatible_plane(kms, &resources, crtc, &mut planes, PlaneType::Primary);
+      let Some(primary) = primary else {
+        tracing::warn!["Skipping {name}: {}", CompositorError::NoCompatiblePlane(crtc)];
+        continue;
       };
-      let primary =
-        DrmCtx::from_connector(
-          card,
-          gbm,
-          gpu,
-          &resources,
-          crtc,
-          &mut planes,
-          PlaneType::Primary,
-          (size.0, size.1),
-        )?;
+      // Without one the cursor is drawn in software
       let cursor =
-        DrmCtx::from_connector(
-          card,
-          gbm,
-          gpu,
-          &resources,
-          crtc,
-          &mut planes,
-          PlaneType::Cursor,
-          (CURSOR_DIM, CURSOR_DIM),
-        )?;
+        find_compatible_plane(kms, &resources, crtc, &mut planes, PlaneType::Cursor);
+      let mut overlays = Vec::new();
+      while overlays.len() < MAX_OVERLAYS {
+        match find_compatible_plane(kms, &resources, crtc, &mut planes, PlaneType::Overlay) {
+          Some(plane) => overlays.push(plane),
+          None => break,
+        }
+      }
       let connector_props =
-        card
-          .get_properties(connector.handle())
-          .map_err(
-            |err| CompositorError::GetConnectorProperties(connector.handle(), err),
-          )?
-          .as_hashmap(card)
-          .map_err(|err| CompositorError::PropsToHashMap(err))?;
+        kms
+          .properties(connector.handle.into())
+          .map_err(|err| CompositorError::GetConnectorProperties(connector.handle, err))?;
       let crtc_props =
-        card
-          .get_properties(crtc)
-          .map_err(|err| CompositorError::GetCrtcProperties(crtc, err))?
-          .as_hashmap(card)
-          .map_err(|err| CompositorError::PropsToHashMap(err))?;
-      let size = size.into();
-      displays.push(Display {
+        kms
+          .properties(crtc.into())
+          .map_err(|err| CompositorError::GetCrtcProperties(crtc, err))?;
+      candidates.push(DisplayCandidate {
         name,
-        size,
-        pos: Default::default(),
-        connector: connector.handle(),
+        connector,
+        edid,
         crtc,
-        connector_props,
-        crtc_props,
         mode,
         primary,
         cursor,
-        overlays: vec![],
+        overlays,
+        connector_props,
+        crtc_props,
+      });
+    }
+    Ok(candidates)
+  }
+
+  pub fn init_displays(
+    in_use: &InUse,
+    history: &HashMap<String, crtc::Handle>,
+    config: &Config,
+    kms: &dyn KmsBackend,
+    gbm: Option<&gbm::Device<&'static Card>>,
+    gpu: &wgpu::Device,
+  ) -> CompositorResult<Vec<Display>> {
+    let mut displays = Vec::new();
+    let renderable = FormatTable::renderable(gpu);
+    for candidate in Display::probe(kms, in_use, history, config)? {
+      let (width, height) = candidate.mode.size();
+      let size = (width as u32, height as u32);
+      let names = config_names(&candidate.name, candidate.edid.as_ref());
+      let keys = |key: fn(&str) -> String| names.iter().map(|name| key(name)).collect::<Vec<_>>();
+      let count =
+        config.get_first::<usize>(&keys(CompositorConfig::buffers_key)).unwrap_or(DEFAULT_BUFFERS);
+      let count =
+        if count < 2 {
+          tracing::warn!["{}: at least 2 buffers are needed, not {count}", candidate.name];
+          2
+        } else {
+          count
+        };
+
+      // HDR needs 10 bits not to band. Deeper colour can be asked for without it too.
+      let hdr_wanted = config.get_first::<bool>(&keys(CompositorConfig::hdr_key)).unwrap_or(false);
+      let bits =
+        config
+          .get_first::<u32>(&keys(CompositorConfig::bit_depth_key))
+          .unwrap_or(if hdr_wanted { 10 } else { 8 });
+      let scanout = FormatTable::for_plane(kms, candidate.primary)?;
+      let formats = scanout.intersect(&renderable);
+      let format = ScanoutFormat::pick(bits, &formats);
+      if bits > 8 && format.bits() != bits {
+        tracing::warn!["{}: the primary plane has no {bits} bit format", candidate.name];
+      }
+      let pq =
+        candidate.edid.as_ref().and_then(|edid| edid.hdr).is_some_and(|hdr| hdr.supports_pq());
+      let signals =
+        candidate.connector_props.contains_key("HDR_OUTPUT_METADATA") &&
+          candidate
+            .connector_props
+            .get("Colorspace")
+            .is_some_and(|prop| prop.enum_value("BT2020_RGB").is_some());
+      let hdr = hdr_wanted && pq && signals && format.bits() >= 10;
+      if hdr_wanted && !hdr {
+        tracing::warn![
+          "{}: HDR needs a monitor that takes PQ and BT.2020, a driver that can signal it and \
+           a 10 bit format, staying in SDR",
+          candidate.name
+        ];
+      }
+      let spec = SwapchainSpec {
+        format,
+        formats: &formats,
+        size,
+        count,
+      };
+      let mut primary =
+        DrmCtx::new(kms, gbm, gpu, candidate.primary, PlaneType::Primary, scanout, spec)?;
+      primary.buffers.mode =
+        config.get_first(&keys(CompositorConfig::present_mode_key)).unwrap_or_default();
+      let vrr_capable =
+        candidate.connector_props.get("vrr_capable").is_some_and(|prop| prop.value() == 1) &&
+          candidate.crtc_props.contains_key("VRR_ENABLED");
+      let vrr_mode = config.get_first(&keys(CompositorConfig::vrr_key)).unwrap_or_default();
+      let tearing_allowed =
+        config.get_first(&keys(CompositorConfig::allow_tearing_key)).unwrap_or(false) &&
+          kms.async_page_flip();
+
+      // Whoever had the display before may have left VRR on
+      let vrr = candidate.crtc_props.get("VRR_ENABLED").is_some_and(|prop| prop.value() == 1);
+      let mut scheduler = FrameScheduler::new(refresh_interval(&candidate.mode));
+      scheduler.set_vrr(vrr);
+      let cursor_size = kms.cursor_size().unwrap_or((CURSOR_DIM, CURSOR_DIM));
+
+      // Cursor images and client buffers are SDR, which the plane would show
+      // as is. Drawn by us they go through the colour pass.
+      let cursor =
+        match candidate.cursor.filter(|_| !hdr) {
+          Some(plane) => {
+            let scanout = FormatTable::for_plane(kms, plane)?;
+            let formats = scanout.intersect(&renderable);
+            let spec = SwapchainSpec {
+              format: ScanoutFormat::ARGB8888,
+              formats: &formats,
+              size: cursor_size,
+              count: DEFAULT_BUFFERS,
+            };
+            let mut cursor = DrmCtx::new(kms, gbm, gpu, plane, PlaneType::Cursor, scanout, spec)?;
+
+            // Only the newest cursor image matters
+            cursor.buffers.mode = PresentMode::Mailbox;
+            Some(cursor)
+          },
+          None => None,
+        };
+      let overlays =
+        candidate
+          .overlays
+          .iter()
+          .filter(|_| !hdr)
+          .map(|plane| OverlayPlane::new(kms, *plane))
+          .collect::<CompositorResult<Vec<_>>>()?;
+      displays.push(Display {
+        name: candidate.name,
+        edid: candidate.edid,
+        size,
+        pos: Default::default(),
+        connector: candidate.connector.handle,
+        crtc: candidate.crtc,
+        connector_props: candidate.connector_props,
+        crtc_props: candidate.crtc_props,
+        mode: candidate.mode,
+        primary,
+        cursor: DisplayCursor::new(cursor),
+        overlays,
+        overlay_plan: Default::default(),
+        scanout: None,
+        client_fbs: Vec::new(),
+        releases: VecDeque::new(),
+        scheduler,
+        needs_frame: false,
+        damage: Default::default(),
+        vrr_capable,
+        vrr_mode,
+        vrr,
+        tearing_allowed,
+        tearing: false,
+        color: color_settings(config, &names),
+        wallpaper: WallpaperSettings::from_config(config, &names),
+        color_state: None,
+        color_changed: false,
+        color_lut: None,
+        hdr,
+        sdr_white:
+          config.get_first(&keys(CompositorConfig::sdr_white_key)).unwrap_or(DEFAULT_SDR_WHITE),
+        composite:
+          (format != ScanoutFormat::XRGB8888).then(|| canvas_texture(gpu, "Composite", size)),
       });
     }
     Ok(displays)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kms::make_mode;
+  use crate::kms::mock::MockDevice;
+
+  fn crtc(n: u32) -> crtc::Handle {
+    control::from_u32(n).unwrap()
+  }
+
+  // A connected connector whose encoder can drive `crtcs`
+  fn connect(
+    device: &MockDevice,
+    interface: connector::Interface,
+    crtcs: u32,
+  ) -> connector::Handle {
+    let encoder = device.add_encoder(crtcs);
+    device.add_connector(interface, vec![make_mode(1920, 1080, 60)], vec![encoder])
+  }
+
+  fn probe(
+    device: &MockDevice,
+    in_use: &InUse,
+    history: &HashMap<String, crtc::Handle>,
+  ) -> Vec<DisplayCandidate> {
+    Display::probe(device, in_use, history, &Config::default()).unwrap()
+  }
+
+  #[test]
+  fn matches_crtcs() {
+    let (a, b, c) = (crtc(1), crtc(2), crtc(3));
+
+    // The first connector is moved to its other CRTC for the second
+    assert_eq!(match_crtcs(&[vec![a, b], vec![a]], &[None, None]), [Some(b), Some(a)]);
+    assert_eq!(match_crtcs(&[vec![a, b], vec![a, b]], &[Some(b), Some(a)]), [Some(b), Some(a)]);
+
+    // A preferred CRTC is only taken away if nothing else works
+    assert_eq!(match_crtcs(&[vec![a, b], vec![b]], &[Some(b), None]), [Some(a), Some(b)]);
+    assert_eq!(match_crtcs(&[vec![a, c], vec![b]], &[Some(c), None]), [Some(c), Some(b)]);
+
+    // Preferring one that can't drive the connector changes nothing
+    assert_eq!(match_crtcs(&[vec![a]], &[Some(b)]), [Some(a)]);
+    assert_eq!(match_crtcs(&[vec![a], vec![a]], &[None, None]), [Some(a), None]);
+    assert_eq!(match_crtcs(&[vec![], vec![a]], &[None, None]), [None, Some(a)]);
+  }
+
+  #[test]
+  fn probes_crtcs_and_planes() {
+    let device = MockDevice::new();
+    let (a, b) = (device.add_crtc(), device.add_crtc());
+    let either = device.add_plane(PlaneType::Primary, 0b11, vec![]);
+    let first = device.add_plane(PlaneType::Primary, 0b01, vec![]);
+    let cursor = device.add_plane(PlaneType::Cursor, 0b01, vec![]);
+    let overlay = device.add_plane(PlaneType::Overlay, 0b11, vec![]);
+    let dp = connect(&device, connector::Interface::DisplayPort, 0b10);
+    let hdmi = connect(&device, connector::Interface::HDMIA, 0b11);
+    let unplugged = connect(&device, connector::Interface::DisplayPort, 0b11);
+    device.set_connected(unplugged, vec![]);
+
+    let candidates = probe(&device, &InUse::default(), &HashMap::new());
+    let picked =
+      candidates
+        .iter()
+        .map(|c| {
+          (c.name.as_str(), c.connector.handle, c.crtc, c.primary, c.cursor, c.overlays.clone())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(picked, [
+      ("card0-DP-1", dp, b, either, None, vec![overlay]),
+      ("card0-HDMI-A-1", hdmi, a, first, Some(cursor), vec![]),
+    ]);
+  }
+
+  #[test]
+  fn probes_around_displays_in_use() {
+    let device = MockDevice::new();
+    let (a, b) = (device.add_crtc(), device.add_crtc());
+    let primaries = [0, 1].map(|_| device.add_plane(PlaneType::Primary, 0b11, vec![]));
+    connect(&device, connector::Interface::HDMIA, 0b11);
+    connect(&device, connector::Interface::HDMIA, 0b11);
+
+    // The CRTCs go back to where they were
+    let history =
+      HashMap::from([("card0-HDMI-A-1".to_string(), b), ("card0-HDMI-A-2".to_string(), a)]);
+    let candidates = probe(&device, &InUse::default(), &history);
+    let crtcs = candidates.iter().map(|c| c.crtc).collect::<Vec<_>>();
+    assert_eq!(crtcs, [b, a]);
+
+    // Only what the lit display doesn't use is handed out
+    let in_use = InUse {
+      names: HashSet::from(["card0-HDMI-A-1".to_string()]),
+      crtcs: HashSet::from([a]),
+      planes: HashSet::from([primaries[0]]),
+    };
+    let candidates = probe(&device, &in_use, &history);
+    assert_eq!(candidates.len(), 1);
+    assert_eq!((candidates[0].name.as_str(), candidates[0].crtc), ("card0-HDMI-A-2", b));
+    assert_eq!(candidates[0].primary, primaries[1]);
+  }
+
+  #[test]
+  fn probes_nothing_unplugged() {
+    let device = MockDevice::new();
+    device.add_crtc();
+    device.add_plane(PlaneType::Primary, 0b1, vec![]);
+    let hdmi = connect(&device, connector::Interface::HDMIA, 0b1);
+    device.set_connected(hdmi, vec![]);
+    let probed = Display::probe(&device, &InUse::default(), &HashMap::new(), &Config::default());
+    assert!(matches!(probed, Err(CompositorError::NoQualifiedConnectors)));
+  }
+
+  #[test]
+  fn probes_past_connectors_without_a_plane() {
+    let device = MockDevice::new();
+    device.add_crtc();
+    let b = device.add_crtc();
+    let primary = device.add_plane(PlaneType::Primary, 0b11, vec![]);
+    connect(&device, connector::Interface::DisplayPort, 0b10);
+    connect(&device, connector::Interface::HDMIA, 0b01);
+
+    // The second connector is left out rather than failing the first
+    let candidates = probe(&device, &InUse::default(), &HashMap::new());
+    let picked =
+      candidates.iter().map(|c| (c.name.as_str(), c.crtc, c.primary)).collect::<Vec<_>>();
+    assert_eq!(picked, [("card0-DP-1", b, primary)]);
+  }
+}
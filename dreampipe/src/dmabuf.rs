@@ -0,0 +1,421 @@
+
+use ash::vk::ImportMemoryFdInfoKHR;
+use ash::vk::SubresourceLayout;
+use ash::vk;
+use crate::buffer::find_memory_type_index;
+use crate::error::CompositorError;
+use crate::error::CompositorResult;
+use crate::formats::vulkan_modifiers;
+use crate::fourcc::FourCc;
+use crate::surface::Dmabuf;
+use drm::buffer::DrmFourcc;
+use drm::buffer::DrmModifier;
+use std::os::fd::AsRawFd;
+use std::os::fd::IntoRawFd;
+use wgpu::Extent3d;
+use wgpu::TextureAspect;
+use wgpu::TextureDescriptor;
+use wgpu::TextureDimension;
+use wgpu::TextureFormat;
+use wgpu::TextureUses;
+use wgpu::wgc::api;
+use wgpu_hal::MemoryFlags;
+
+// Memory planes of an image laid out by a DRM format modifier, in order
+const MEMORY_PLANES: [vk::ImageAspectFlags; 4] = [
+  vk::ImageAspectFlags::MEMORY_PLANE_0_EXT,
+  vk::ImageAspectFlags::MEMORY_PLANE_1_EXT,
+  vk::ImageAspectFlags::MEMORY_PLANE_2_EXT,
+  vk::ImageAspectFlags::MEMORY_PLANE_3_EXT,
+];
+
+// A Vulkan image and the memory imported for it, freed together
+struct ImportedImage {
+  device: ash::Device,
+  image: vk::Image,
+  memories: Vec<vk::DeviceMemory>,
+}
+
+impl Drop for ImportedImage {
+  fn drop(&mut self) {
+    unsafe {
+      self.device.destroy_image(self.image, None);
+      for memory in self.memories.drain(..) {
+        self.device.free_memory(memory, None);
+      }
+    }
+  }
+}
+
+/// A client buffer the GPU can sample. wgpu frees the imported memory once
+/// the texture and every submission using it are done with, so this can be
+/// dropped as soon as it isn't drawn anymore.
+#[derive(Clone, Debug)]
+pub struct ClientTexture {
+  pub texture: wgpu::Texture,
+  pub format: DrmFourcc,
+  // What the texture was imported as, for ports to compare their next buffer
+  #[allow(dead_code)]
+  pub modifier: DrmModifier,
+  #[allow(dead_code)]
+  pub size: (u32, u32),
+}
+
+impl ClientTexture {
+  /// Fails if the GPU can't sample this format and modifier, or if the
+  /// planes aren't what the modifier needs
+  // Ports call this once they take client buffers, nothing does yet
+  #[allow(dead_code)]
+  pub fn import(gpu: &wgpu::Device, dmabuf: &Dmabuf) -> CompositorResult<Self> {
+    let (width, height) = dmabuf.size;
+    if width == 0 || height == 0 {
+      return Err(CompositorError::VulkanImageDim);
+    }
+    let (vk_format, wgpu_format, texel_width) =
+      sampled_format(dmabuf.format).ok_or(CompositorError::VulkanFormat(dmabuf.format))?;
+
+    // YUV formats are optional in wgpu, and so are the formats their planes
+    // are drawn through, eg 16 bit ones for P010
+    let features =
+      [TextureAspect::Plane0, TextureAspect::Plane1]
+        .into_iter()
+        .flat_map(|aspect| wgpu_format.aspect_specific_format(aspect))
+        .fold(wgpu_format.required_features(), |features, plane| {
+          features | plane.required_features()
+        });
+    if !gpu.features().contains(features) {
+      return Err(CompositorError::VulkanFormat(dmabuf.format));
+    }
+    let hal_device_guard = unsafe {
+      gpu.as_hal::<api::Vulkan>()
+    };
+    let Some(hal_device) = hal_device_guard else {
+      return Err(CompositorError::VulkanApi);
+    };
+
+    // Vulkan has no implicit layouts, so an Invalid modifier is never found.
+    // The modifier decides how many planes there are, eg compression adds
+    // some.
+    let disjoint = dmabuf.is_disjoint();
+    let mut wanted = vk::FormatFeatureFlags::SAMPLED_IMAGE;
+    if disjoint {
+      wanted |= vk::FormatFeatureFlags::DISJOINT;
+    }
+    let planes =
+      vulkan_modifiers(&hal_device, vk_format)
+        .into_iter()
+        .find(|modifier| {
+          DrmModifier::from(modifier.drm_format_modifier) == dmabuf.modifier &&
+            modifier.drm_format_modifier_tiling_features.contains(wanted)
+        })
+        .map(|modifier| modifier.drm_format_modifier_plane_count as usize)
+        .ok_or(CompositorError::VulkanModifier(dmabuf.modifier))?;
+    if dmabuf.planes.len() != planes {
+      return Err(CompositorError::DmabufPlanes(dmabuf.format, dmabuf.planes.len()));
+    }
+    if dmabuf.modifier == DrmModifier::Linear {
+      check_linear(dmabuf)?;
+    }
+    let extent = (width.div_ceil(texel_width), height);
+    let image = unsafe {
+      import_image(&hal_device, dmabuf, vk_format, wgpu_format, extent, disjoint)?
+    };
+    let label = format!["Client DMA-BUF {:?} {width}x{height}", dmabuf.format];
+    let size = Extent3d {
+      width: extent.0,
+      height,
+      depth_or_array_layers: 1,
+    };
+    let texture = unsafe {
+      let vk_image = image.image;
+      let hal_texture =
+        <api::Vulkan as wgpu::hal::Api>::Device::texture_from_raw(
+          &hal_device,
+          vk_image,
+          &wgpu::hal::TextureDescriptor {
+            label: Some(&label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: wgpu_format,
+            usage: TextureUses::RESOURCE,
+            memory_flags: MemoryFlags::empty(),
+            view_formats: vec![],
+          },
+          // wgpu calls this instead of destroying the image itself
+          Some(Box::new(move || drop(image))),
+        );
+      gpu.create_texture_from_hal::<api::Vulkan>(hal_texture, &TextureDescriptor {
+        label: Some(&label),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: wgpu_format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+      })
+    };
+    Ok(Self {
+      texture,
+      format: dmabuf.format,
+      modifier: dmabuf.modifier,
+      size: dmabuf.size,
+    })
+  }
+}
+
+// The Vulkan and wgpu formats a buffer is sampled as, and how many pixels
+// wide a texel is. Packed 4:2:2 has no wgpu format, so a pair of pixels is
+// read as one RGBA texel and the shader picks the luma out.
+fn sampled_format(format: DrmFourcc) -> Option<(vk::Format, TextureFormat, u32)> {
+  match format {
+    DrmFourcc::Yuyv | DrmFourcc::Uyvy => {
+      Some((vk::Format::R8G8B8A8_UNORM, TextureFormat::Rgba8Unorm, 2))
+    },
+    _ => {
+      let info = format.info()?;
+      Some((info.vk?, info.wgpu?, 1))
+    },
+  }
+}
+
+// The formats wgpu views the planes of a multi-planar texture as, which a
+// mutable format image with a modifier has to list up front
+fn plane_view_formats(format: TextureFormat) -> &'static [vk::Format] {
+  match format {
+    TextureFormat::NV12 => &[vk::Format::R8_UNORM, vk::Format::R8G8_UNORM],
+    TextureFormat::P010 => &[vk::Format::R16_UNORM, vk::Format::R16G16_UNORM],
+    _ => &[],
+  }
+}
+
+// Linear planes have to be at least as wide as the format needs, and one
+// buffer holding them all at least as big as them tightly packed. Drivers
+// don't all check, and read past the end otherwise.
+fn check_linear(dmabuf: &Dmabuf) -> CompositorResult<()> {
+  let Some(info) = dmabuf.format.info() else {
+    return Err(CompositorError::VulkanFormat(dmabuf.format));
+  };
+  let (width, height) = dmabuf.size;
+  for (i, plane) in dmabuf.planes.iter().enumerate().take(info.planes) {
+    if plane.stride < info.stride(i, width) {
+      return Err(CompositorError::DmabufLayout(dmabuf.format, i));
+    }
+  }
+  if dmabuf.is_disjoint() {
+    return Ok(());
+  }
+
+  // Seeking to the end is how a DMA-BUF tells its size
+  let Some(first) = dmabuf.planes.first() else {
+    return Ok(());
+  };
+  let end = unsafe {
+    libc::lseek(first.fd.as_raw_fd(), 0, libc::SEEK_END)
+  };
+  if end >= 0 && (end as u64) < info.size(width, height) as u64 {
+    return Err(CompositorError::DmabufLayout(dmabuf.format, dmabuf.planes.len() - 1));
+  }
+  Ok(())
+}
+
+// Create an image laid out as `dmabuf` says and import its memory, one
+// allocation per plane if they are in separate buffers
+unsafe fn import_image(
+  hal_device: &<api::Vulkan as wgpu::hal::Api>::Device,
+  dmabuf: &Dmabuf,
+  vk_format: vk::Format,
+  wgpu_format: TextureFormat,
+  (width, height): (u32, u32),
+  disjoint: bool,
+) -> CompositorResult<ImportedImage> {
+  let device = hal_device.raw_device();
+  let instance = hal_device.shared_instance().raw_instance();
+  let physical_device = hal_device.raw_physical_device();
+  let modifier = dmabuf.modifier.into();
+
+  // wgpu makes per plane views of YUV textures
+  let mut flags = vk::ImageCreateFlags::empty();
+  let view_formats = plane_view_formats(wgpu_format);
+  if wgpu_format.is_multi_planar_format() {
+    flags |= vk::ImageCreateFlags::MUTABLE_FORMAT;
+  }
+  if disjoint {
+    flags |= vk::ImageCreateFlags::DISJOINT;
+  }
+
+  // Check the driver can import this exact image before making it
+  let mut modifier_info =
+    vk::PhysicalDeviceImageDrmFormatModifierInfoEXT::default()
+      .drm_format_modifier(modifier)
+      .sharing_mode(vk::SharingMode::EXCLUSIVE);
+  let mut external_info =
+    vk::PhysicalDeviceExternalImageFormatInfo::default()
+      .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+  let mut format_info =
+    vk::PhysicalDeviceImageFormatInfo2::default()
+      .format(vk_format)
+      .ty(vk::ImageType::TYPE_2D)
+      .tiling(vk::ImageTiling::DRM_FORMAT_MODIFIER_EXT)
+      .usage(vk::ImageUsageFlags::SAMPLED)
+      .flags(flags)
+      .push_next(&mut external_info)
+      .push_next(&mut modifier_info);
+  let mut format_list = vk::ImageFormatListCreateInfo::default().view_formats(view_formats);
+  if !view_formats.is_empty() {
+    format_info = format_info.push_next(&mut format_list);
+  }
+  let mut external_properties = vk::ExternalImageFormatProperties::default();
+  let mut properties = vk::ImageFormatProperties2::default().push_next(&mut external_properties);
+  unsafe {
+    instance
+      .get_physical_device_image_format_properties2(physical_device, &format_info, &mut properties)
+      .map_err(|_| CompositorError::VulkanModifier(dmabuf.modifier))?
+  };
+  let max_extent = properties.image_format_properties.max_extent;
+  if width > max_extent.width || height > max_extent.height {
+    return Err(CompositorError::VulkanImageDim);
+  }
+  let importable =
+    external_properties
+      .external_memory_properties
+      .external_memory_features
+      .contains(vk::ExternalMemoryFeatureFlags::IMPORTABLE);
+  if !importable {
+    return Err(CompositorError::VulkanModifier(dmabuf.modifier));
+  }
+
+  // Offsets are within each plane's own buffer when disjoint, and within the
+  // one buffer otherwise
+  let plane_layouts =
+    dmabuf
+      .planes
+      .iter()
+      .map(|plane| SubresourceLayout {
+        offset: plane.offset as u64,
+        size: 0,
+        row_pitch: plane.stride as u64,
+        array_pitch: 0,
+        depth_pitch: 0,
+      })
+      .collect::<Vec<_>>();
+  let mut drm_format_modifier =
+    vk::ImageDrmFormatModifierExplicitCreateInfoEXT::default()
+      .drm_format_modifier(modifier)
+      .plane_layouts(&plane_layouts);
+  let mut external_memory_info =
+    vk::ExternalMemoryImageCreateInfo::default()
+      .handle_types(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+  let mut image_create_info =
+    vk::ImageCreateInfo::default()
+      .flags(flags)
+      .image_type(vk::ImageType::TYPE_2D)
+      .format(vk_format)
+      .extent(vk::Extent3D {
+        width,
+        height,
+        depth: 1,
+      })
+      .mip_levels(1)
+      .array_layers(1)
+      .samples(vk::SampleCountFlags::TYPE_1)
+      .tiling(vk::ImageTiling::DRM_FORMAT_MODIFIER_EXT)
+      .usage(vk::ImageUsageFlags::SAMPLED)
+      .sharing_mode(vk::SharingMode::EXCLUSIVE)
+      .initial_layout(vk::ImageLayout::UNDEFINED)
+      .push_next(&mut external_memory_info)
+      .push_next(&mut drm_format_modifier);
+  // Chaining the one above linked it into the other chain
+  let mut format_list = vk::ImageFormatListCreateInfo::default().view_formats(view_formats);
+  if !view_formats.is_empty() {
+    image_create_info = image_create_info.push_next(&mut format_list);
+  }
+  let image =
+    unsafe {
+      device
+        .create_image(&image_create_info, None)
+        .map_err(|e| CompositorError::VulkanImageCreate(e))?
+    };
+
+  // From here on dropping it cleans up whatever was made so far
+  let mut imported = ImportedImage {
+    device: device.clone(),
+    image,
+    memories: Vec::with_capacity(dmabuf.planes.len()),
+  };
+  let memory_properties = unsafe {
+    instance.get_physical_device_memory_properties(physical_device)
+  };
+  let allocations = if disjoint { dmabuf.planes.len() } else { 1 };
+  for (plane, aspect) in dmabuf.planes.iter().zip(MEMORY_PLANES).take(allocations) {
+    let mut plane_requirements =
+      vk::ImagePlaneMemoryRequirementsInfo::default().plane_aspect(aspect);
+    let mut requirements_info = vk::ImageMemoryRequirementsInfo2::default().image(image);
+    if disjoint {
+      requirements_info = requirements_info.push_next(&mut plane_requirements);
+    }
+    let mut requirements = vk::MemoryRequirements2::default();
+    unsafe {
+      device.get_image_memory_requirements2(&requirements_info, &mut requirements)
+    };
+    let requirements = requirements.memory_requirements;
+
+    // The buffer may not even be in GPU memory, eg from a camera
+    let memory_type_index =
+      find_memory_type_index(
+        requirements.memory_type_bits,
+        vk::MemoryPropertyFlags::empty(),
+        &memory_properties,
+      ).ok_or_else(|| CompositorError::VulkanMemoryTypeIndex)?;
+    let fd = plane.fd.try_clone().map_err(|e| CompositorError::DmabufFd(e))?;
+    let mut import_memory_fd =
+      ImportMemoryFdInfoKHR::default()
+        .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT)
+        .fd(fd.as_raw_fd());
+    let mut dedicated = vk::MemoryDedicatedAllocateInfo::default().image(image);
+    let mut allocate_info =
+      vk::MemoryAllocateInfo::default()
+        .allocation_size(requirements.size)
+        .memory_type_index(memory_type_index)
+        .push_next(&mut import_memory_fd);
+
+    // A disjoint image can't have dedicated memory
+    if !disjoint {
+      allocate_info = allocate_info.push_next(&mut dedicated);
+    }
+    let memory =
+      unsafe {
+        device
+          .allocate_memory(&allocate_info, None)
+          .map_err(|e| CompositorError::VulkanMemoryAlloc(e))?
+      };
+
+    // Vulkan owns the fd once the import succeeded
+    let _ = fd.into_raw_fd();
+    imported.memories.push(memory);
+  }
+
+  // Each plane of a disjoint image is bound separately
+  let mut plane_binds =
+    MEMORY_PLANES
+      .iter()
+      .map(|aspect| vk::BindImagePlaneMemoryInfo::default().plane_aspect(*aspect))
+      .collect::<Vec<_>>();
+  let binds =
+    imported
+      .memories
+      .iter()
+      .zip(plane_binds.iter_mut())
+      .map(|(memory, plane_bind)| {
+        let bind = vk::BindImageMemoryInfo::default().image(image).memory(*memory);
+        if disjoint { bind.push_next(plane_bind) } else { bind }
+      })
+      .collect::<Vec<_>>();
+  unsafe {
+    device.bind_image_memory2(&binds).map_err(|e| CompositorError::VulkanBindMemory(e))?;
+  }
+  drop(binds);
+  Ok(imported)
+}
@@ -0,0 +1,291 @@
+use crate::error::CompositorError;
+use crate::error::CompositorResult;
+
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+const BLOCK_SIZE: usize = 128;
+const DESCRIPTORS: [usize; 4] = [54, 72, 90, 108];
+const DESCRIPTOR_SIZE: usize = 18;
+const TAG_SERIAL: u8 = 0xff;
+const TAG_NAME: u8 = 0xfc;
+const TAG_CTA: u8 = 0x02;
+// CTA-861 data blocks that carry an extended tag in their first byte
+const BLOCK_EXTENDED: u8 = 7;
+const EXTENDED_COLORIMETRY: u8 = 5;
+const EXTENDED_HDR_STATIC: u8 = 6;
+const COLORIMETRY_BT2020_RGB: u8 = 1 << 7;
+const EOTF_PQ: u8 = 1 << 2;
+
+/// What a monitor says about HDR in its CTA-861 extension. Luminances are
+/// the raw code values, see the accessors for nits.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HdrInfo {
+  /// Supported transfer functions, one bit each: SDR, traditional HDR, PQ
+  /// and HLG
+  pub eotfs: u8,
+  pub bt2020: bool,
+  pub max_luminance: Option<u8>,
+  pub max_frame_average: Option<u8>,
+  pub min_luminance: Option<u8>,
+}
+
+impl HdrInfo {
+  /// The monitor takes PQ encoded BT.2020
+  pub fn supports_pq(&self) -> bool {
+    self.eotfs & EOTF_PQ != 0 && self.bt2020
+  }
+
+  /// Desired content max luminance in nits
+  pub fn max_nits(&self) -> Option<f64> {
+    self.max_luminance.map(|cv| 50.0 * 2f64.powf(cv as f64 / 32.0))
+  }
+
+  /// Desired content max frame average luminance in nits
+  pub fn max_frame_average_nits(&self) -> Option<f64> {
+    self.max_frame_average.map(|cv| 50.0 * 2f64.powf(cv as f64 / 32.0))
+  }
+
+  /// Desired content min luminance in nits, which is relative to the max
+  pub fn min_nits(&self) -> Option<f64> {
+    let max = self.max_nits()?;
+    self.min_luminance.map(|cv| max * (cv as f64 / 255.0).powi(2) / 100.0)
+  }
+}
+
+/// The parts of a monitor's EDID that identify it, and its HDR capabilities
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Edid {
+  /// Three letter PNP ID, eg `DEL`
+  pub manufacturer: String,
+  pub product: u16,
+  pub serial: u32,
+  /// Monitor name descriptor, eg `DELL U2720Q`
+  pub model: Option<String>,
+  /// Serial number descriptor, which is often set when `serial` is not
+  pub serial_string: Option<String>,
+  /// Physical size in millimetres
+  pub size: Option<(u32, u32)>,
+  /// None if the monitor has no HDR static metadata block
+  pub hdr: Option<HdrInfo>,
+}
+
+// Descriptor text is ASCII terminated by a newline and padded with spaces
+fn descriptor_text(descriptor: &[u8]) -> Option<String> {
+  let text =
+    descriptor[5 ..]
+      .iter()
+      .take_while(|byte| **byte != b'\n')
+      .map(|byte| *byte as char)
+      .collect::<String>();
+  let text = text.trim();
+  (!text.is_empty()).then(|| text.to_string())
+}
+
+// Walk the data block collection of a CTA-861 extension block for HDR
+// static metadata and colorimetry
+fn parse_cta(block: &[u8], hdr: &mut HdrInfo, has_metadata: &mut bool) {
+  // Detailed timings start at this offset, the data blocks come before them
+  let end = (block[2] as usize).min(BLOCK_SIZE - 1);
+  let mut offset = 4;
+  while offset < end {
+    let tag = block[offset] >> 5;
+    let len = (block[offset] & 0x1f) as usize;
+    let data = &block[offset + 1 .. (offset + 1 + len).min(end)];
+    offset += 1 + len;
+    if tag != BLOCK_EXTENDED || data.len() < 2 {
+      continue;
+    }
+    match data[0] {
+      EXTENDED_COLORIMETRY => hdr.bt2020 = data[1] & COLORIMETRY_BT2020_RGB != 0,
+      EXTENDED_HDR_STATIC => {
+        *has_metadata = true;
+        hdr.eotfs = data[1];
+        hdr.max_luminance = data.get(3).copied();
+        hdr.max_frame_average = data.get(4).copied();
+        hdr.min_luminance = data.get(5).copied();
+      },
+      _ => (),
+    }
+  }
+}
+
+impl Edid {
+  pub fn parse(blob: &[u8]) -> CompositorResult<Self> {
+    if blob.len() < BLOCK_SIZE {
+      return Err(CompositorError::InvalidEdid("Shorter than one block"));
+    }
+    let block = &blob[.. BLOCK_SIZE];
+    if block[.. 8] != HEADER {
+      return Err(CompositorError::InvalidEdid("Bad header"));
+    }
+    if block.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+      return Err(CompositorError::InvalidEdid("Bad checksum"));
+    }
+
+    // Three 5-bit letters, 1 is 'A'
+    let id = u16::from_be_bytes([block[8], block[9]]);
+    let manufacturer =
+      [10, 5, 0]
+        .iter()
+        .map(|shift| (b'A' - 1 + ((id >> shift) & 0x1f) as u8) as char)
+        .collect();
+    let product = u16::from_le_bytes([block[10], block[11]]);
+    let serial = u32::from_le_bytes([block[12], block[13], block[14], block[15]]);
+    let mut model = None;
+    let mut serial_string = None;
+    let mut size = None;
+    for offset in DESCRIPTORS {
+      let descriptor = &block[offset .. offset + DESCRIPTOR_SIZE];
+      let pixel_clock = u16::from_le_bytes([descriptor[0], descriptor[1]]);
+      if pixel_clock != 0 {
+        // A detailed timing, which has the size in millimetres
+        let width = descriptor[12] as u32 | ((descriptor[14] as u32 & 0xf0) << 4);
+        let height = descriptor[13] as u32 | ((descriptor[14] as u32 & 0x0f) << 8);
+        if size.is_none() && width != 0 && height != 0 {
+          size = Some((width, height));
+        }
+        continue;
+      }
+      match descriptor[3] {
+        TAG_NAME => model = descriptor_text(descriptor),
+        TAG_SERIAL => serial_string = descriptor_text(descriptor),
+        _ => (),
+      }
+    }
+
+    // Otherwise fall back to the size in centimetres, 0 for projectors
+    if size.is_none() && block[21] != 0 && block[22] != 0 {
+      size = Some((block[21] as u32 * 10, block[22] as u32 * 10));
+    }
+
+    // Extensions the kernel handed over too, a bad one is skipped
+    let mut hdr = HdrInfo::default();
+    let mut has_metadata = false;
+    for block in blob[BLOCK_SIZE ..].chunks_exact(BLOCK_SIZE) {
+      if block[0] == TAG_CTA && block.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0 {
+        parse_cta(block, &mut hdr, &mut has_metadata);
+      }
+    }
+    Ok(Self {
+      manufacturer,
+      product,
+      serial,
+      model,
+      serial_string,
+      size,
+      hdr: has_metadata.then_some(hdr),
+    })
+  }
+
+  /// Name for config keys that follows the monitor between ports, eg
+  /// `DEL-DELL_U2720Q-ABC1234`. Whitespace is replaced with underscores.
+  pub fn identity(&self) -> String {
+    let model = self.model.clone().unwrap_or_else(|| format!["{:04x}", self.product]);
+    let serial = self.serial_string.clone().unwrap_or_else(|| format!["{}", self.serial]);
+    format!["{}-{model}-{serial}", self.manufacturer].split_whitespace().collect::<Vec<_>>().join("_")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A 4K monitor with a CTA-861 extension declaring BT.2020 and PQ
+  const U2720Q: &str = concat![
+    "00ffffffffffff0010ac2ea1304c334c101e0104b53c22783afc7ea54e47ad26",
+    "0e5054a54b00d1c0a9c081808100714f01010101010108e80030f2705a80b058",
+    "8a0055502100001e000000fc0044454c4c205532373230510a20000000ff0041",
+    "4243313233340a2020202020000000fd00184b1e8c3c000a2020202020200129",
+    "02030f70e305c300e60605017350480000000000000000000000000000000000",
+    "0000000000000000000000000000000000000000000000000000000000000000",
+    "0000000000000000000000000000000000000000000000000000000000000000",
+    "00000000000000000000000000000000000000000000000000000000000000d4",
+  ];
+  // An old monitor with no text descriptors, no detailed timings and no
+  // extensions
+  const SYNCMASTER: &str = concat![
+    "00ffffffffffff004c2d7a0587d612000114010380301b782afc7ea54e47ad26",
+    "0e50540000000101010101010101010101010101010100000010000000000000",
+    "0000000000000000000000100000000000000000000000000000000000100000",
+    "0000000000000000000000000000001000000000000000000000000000000090",
+  ];
+
+  fn blob(hex: &str) -> Vec<u8> {
+    (0 .. hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i .. i + 2], 16).unwrap()).collect()
+  }
+
+  fn invalid(blob: &[u8]) -> &'static str {
+    match Edid::parse(blob) {
+      Err(CompositorError::InvalidEdid(reason)) => reason,
+      other => panic!["Expected an invalid EDID, got {other:?}"],
+    }
+  }
+
+  #[test]
+  fn identifies_monitor() {
+    let edid = Edid::parse(&blob(U2720Q)).unwrap();
+    assert_eq!(edid.manufacturer, "DEL");
+    assert_eq!(edid.product, 0xa12e);
+    assert_eq!(edid.serial, 0x4c334c30);
+    assert_eq!(edid.model.as_deref(), Some("DELL U2720Q"));
+    assert_eq!(edid.serial_string.as_deref(), Some("ABC1234"));
+    // From the detailed timing rather than the centimetres
+    assert_eq!(edid.size, Some((597, 336)));
+    assert_eq!(edid.identity(), "DEL-DELL_U2720Q-ABC1234");
+  }
+
+  #[test]
+  fn falls_back_without_descriptors() {
+    let edid = Edid::parse(&blob(SYNCMASTER)).unwrap();
+    assert_eq!(edid.manufacturer, "SAM");
+    assert_eq!(edid.model, None);
+    assert_eq!(edid.serial_string, None);
+    assert_eq!(edid.size, Some((480, 270)));
+    assert_eq!(edid.hdr, None);
+    assert_eq!(edid.identity(), "SAM-057a-1234567");
+  }
+
+  #[test]
+  fn reads_hdr_static_metadata() {
+    let hdr = Edid::parse(&blob(U2720Q)).unwrap().hdr.unwrap();
+    assert_eq!(hdr.eotfs, 0x05);
+    assert!(hdr.bt2020);
+    assert!(hdr.supports_pq());
+    assert_eq!((hdr.max_luminance, hdr.max_frame_average, hdr.min_luminance), (
+      Some(0x73),
+      Some(0x50),
+      Some(0x48),
+    ));
+    let max = hdr.max_nits().unwrap();
+    assert!((max - 603.6).abs() < 0.1, "{max}");
+    let min = hdr.min_nits().unwrap();
+    assert!((min - 0.4812).abs() < 0.001, "{min}");
+  }
+
+  #[test]
+  fn skips_bad_extension() {
+    let mut blob = blob(U2720Q);
+    blob[BLOCK_SIZE + 10] ^= 1;
+    assert_eq!(Edid::parse(&blob).unwrap().hdr, None);
+  }
+
+  #[test]
+  fn rejects_bad_header() {
+    let mut blob = blob(U2720Q);
+    blob[0] = 0xff;
+    assert_eq!(invalid(&blob), "Bad header");
+  }
+
+  #[test]
+  fn rejects_bad_checksum() {
+    let mut blob = blob(U2720Q);
+    blob[BLOCK_SIZE - 1] ^= 1;
+    assert_eq!(invalid(&blob), "Bad checksum");
+  }
+
+  #[test]
+  fn rejects_truncated_blob() {
+    let blob = blob(U2720Q);
+    assert_eq!(invalid(&blob[.. BLOCK_SIZE - 1]), "Shorter than one block");
+    assert_eq!(invalid(&[]), "Shorter than one block");
+  }
+}
@@ -3,6 +3,8 @@
 use ash::vk;
 use std::fmt::Display;
 use std::path::PathBuf;
+use drm::buffer::DrmFourcc;
+use drm::buffer::DrmModifier;
 use drm::control::connector;
 use drm::control::crtc;
 use drm::control::encoder;
@@ -17,6 +19,7 @@ pub type CompositorResult<T> = std::result::Result<T, CompositorError>;
 #[derive(Debug)]
 pub enum CompositorError {
   OpenCard(PathBuf, IoError),
+  CloneCard(IoError),
   GpuCard,
   VulkanApi,
   VulkanImageDim,
@@ -24,6 +27,11 @@ pub enum CompositorError {
   VulkanMemoryAlloc(vk::Result),
   VulkanMemoryTypeIndex,
   VulkanBindMemory(vk::Result),
+  VulkanModifier(DrmModifier),
+  VulkanFormat(DrmFourcc),
+  DmabufPlanes(DrmFourcc, usize),
+  DmabufLayout(DrmFourcc, usize),
+  DmabufFd(IoError),
   ClientCapability(ClientCapability, IoError),
   ResourcesError(IoError),
   NoQualifiedConnectors,
@@ -34,7 +42,7 @@ pub enum CompositorError {
   FrontBufferLock,
   AddFrameBuffer(IoError),
   GetPlanes(IoError),
-  NoCompatiblePrimaryPlane(crtc::Info),
+  NoCompatiblePlane(crtc::Handle),
   UnknownPlaneType(u64),
   PlaneNotFound(PlaneType),
   GetConnectorProperties(connector::Handle, IoError),
@@ -43,8 +51,14 @@ pub enum CompositorError {
   GetCrtcInfo(crtc::Handle, IoError),
   GetEncoderInfo(encoder::Handle, IoError),
   GetPlaneProperties(plane::Handle, IoError),
+  GetPlaneInfo(plane::Handle, IoError),
+  ImportDmabuf(IoError),
   PropsToHashMap(IoError),
   AtomicCommitFailed(IoError),
+  InvalidEdid(&'static str),
+  GpuPoll(wgpu::PollError),
+  BufferMap(wgpu::BufferAsyncError),
+  SavePng(PathBuf, image::ImageError),
   ConfigOpen(IoError),
   ConfigRead(IoError),
   ConfigMissing(String),
@@ -58,6 +72,7 @@ impl Display for CompositorError {
         Self::OpenCard(path, error) => format![
           "Unable to open card at {path:?}: {error:#?}"
         ],
+        Self::CloneCard(error) => format!["Unable to duplicate card fd: {error:#?}"],
         Self::GpuCard => format!["No matching card for selected GPU"],
         Self::VulkanApi => format!["Vulkan not supported"],
         Self::VulkanImageDim => format!["Invalid DMA-BUF dimensions"],
@@ -69,6 +84,17 @@ impl Display for CompositorError {
           "Failed to find suitable memory type for DMA-BUF"
         ],
         Self::VulkanBindMemory(e) => format!["Failed to bind vulkan memory: {e}"],
+        Self::VulkanModifier(modifier) => format![
+          "The GPU can't use buffers with modifier {modifier:?}"
+        ],
+        Self::VulkanFormat(format) => format!["The GPU can't sample {format:?} buffers"],
+        Self::DmabufPlanes(format, planes) => format![
+          "A {format:?} DMA-BUF can't have {planes} planes"
+        ],
+        Self::DmabufLayout(format, plane) => format![
+          "Plane {plane} of a linear {format:?} DMA-BUF doesn't fit the image"
+        ],
+        Self::DmabufFd(error) => format!["Failed to duplicate DMA-BUF fd: {error}"],
         Self::ClientCapability(client_capability, error) => format![
           "Unable to request {client_capability:#?}: {error:#?}"
         ],
@@ -89,8 +115,8 @@ impl Display for CompositorError {
           "Failed to add framebuffer to card: {error:#?}"
         ],
         Self::GetPlanes(error) => format!["Failed to get planes: {error:#?}"],
-        Self::NoCompatiblePrimaryPlane(info) => format![
-          "Failed to get compatible plane for CRTC. CRTC Info:\n{info:#?}"
+        Self::NoCompatiblePlane(handle) => format![
+          "No free plane is compatible with CRTC {handle:#?}"
         ],
         Self::UnknownPlaneType(val) => format!["Unkown plane type '{val:x}'"],
         Self::PlaneNotFound(planetype) => format![
@@ -114,12 +140,22 @@ impl Display for CompositorError {
         Self::GetPlaneProperties(handle, error) => format![
           "Failed to get properties for plane {handle:#?}: {error:#?}"
         ],
+        Self::GetPlaneInfo(handle, error) => format![
+          "Failed to get info for plane {handle:#?}: {error:#?}"
+        ],
+        Self::ImportDmabuf(error) => format![
+          "Failed to add a client DMA-BUF as a framebuffer: {error:#?}"
+        ],
         Self::PropsToHashMap(error) => format![
           "Failed to convert props to hashmap: {error:#?}"
         ],
         Self::AtomicCommitFailed(error) => format![
           "Failed to commit request to CRTC: {error:#?}"
         ],
+        Self::InvalidEdid(reason) => format!["Invalid EDID: {reason}"],
+        Self::GpuPoll(error) => format!["Failed to wait for the GPU: {error}"],
+        Self::BufferMap(error) => format!["Failed to map GPU buffer: {error}"],
+        Self::SavePng(path, error) => format!["Failed to save {path:?}: {error}"],
         Self::ConfigOpen(error) => format!["Failed to open configuration file: {error}"],
         Self::ConfigRead(error) => format!["Failed to read configuration file: {error}"],
         Self::ConfigMissing(k) => format!["Missing {k} in config"],
@@ -0,0 +1,186 @@
+use futures::future::select_all;
+use notify::Event as NotifyEvent;
+use notify::RecommendedWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+use std::os::fd::AsFd;
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::io::unix::AsyncFd;
+use tokio::signal::unix::Signal;
+use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::signal;
+use tokio::sync::mpsc;
+
+/// File descriptors the loop can wait on. Add a variant per kind of fd (input
+/// devices, PipeWire, ...) and register it with `EventLoop::add_fd`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Source {
+  Card(u32),
+  /// Kernel uevents, see `hotplug::UeventSocket`
+  Uevents,
+}
+
+/// One-shot timers. They are re-armed by whoever handles them.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Timer {
+  /// Look for newly connected displays
+  Reprobe,
+  /// A card without an fd has events due, see `KmsBackend::next_event_time`
+  Events(u32),
+  /// A display of a card is due to compose, see `AppContext::next_composition`
+  Compose(u32),
+}
+
+#[derive(Debug)]
+pub enum Wake {
+  /// The fd belonging to this source has data waiting. Readiness is already
+  /// cleared, so the handler must read until `WouldBlock`.
+  Readable(Source),
+  /// The config file was modified
+  Config,
+  Timer(Timer),
+  /// SIGTERM, SIGINT or SIGHUP was received
+  Shutdown,
+}
+
+/// Sleeps until a registered fd is readable, a timer expires or the config
+/// file changes.
+pub struct EventLoop {
+  fds: Vec<(Source, AsyncFd<OwnedFd>)>,
+  timers: Vec<(Instant, Timer)>,
+  config_path: PathBuf,
+  config_rx: mpsc::UnboundedReceiver<notify::Result<NotifyEvent>>,
+  config_watcher: Option<RecommendedWatcher>,
+  signals: Vec<Signal>,
+}
+
+fn watch_config(
+  config_path: &PathBuf,
+) -> (
+  mpsc::UnboundedReceiver<notify::Result<NotifyEvent>>,
+  Option<RecommendedWatcher>,
+) {
+  let (tx, rx) = mpsc::unbounded_channel();
+  let watcher =
+    notify::recommended_watcher(move |event| {
+      tx.send(event).ok();
+    }).ok().and_then(|mut watcher| {
+      watcher.watch(config_path, RecursiveMode::NonRecursive).ok()?;
+      Some(watcher)
+    });
+  if watcher.is_none() {
+    tracing::debug!["Unable to watch {config_path:?}"];
+  }
+  (rx, watcher)
+}
+
+impl EventLoop {
+  pub fn new(config_path: PathBuf) -> Self {
+    let (config_rx, config_watcher) = watch_config(&config_path);
+    let signals =
+      [SignalKind::terminate(), SignalKind::interrupt(), SignalKind::hangup()]
+        .into_iter()
+        .flat_map(|kind| signal(kind).inspect_err(|e| {
+          tracing::error!["Unable to handle {kind:?}: {e}"];
+        }))
+        .collect();
+    Self {
+      fds: Vec::new(),
+      timers: Vec::new(),
+      config_path,
+      config_rx,
+      config_watcher,
+      signals,
+    }
+  }
+
+  /// Retry watching the config file if it could not be watched before, eg
+  /// because it did not exist yet
+  pub fn rewatch_config(&mut self) {
+    if self.config_watcher.is_none() {
+      (self.config_rx, self.config_watcher) = watch_config(&self.config_path);
+    }
+  }
+
+  /// Wait on a duplicate of `fd`. The fd must already be non-blocking.
+  pub fn add_fd(&mut self, source: Source, fd: impl AsFd) -> std::io::Result<()> {
+    let fd = AsyncFd::new(fd.as_fd().try_clone_to_owned()?)?;
+    self.fds.push((source, fd));
+    Ok(())
+  }
+
+  /// Stop waiting on `source`, eg because its device went away
+  pub fn remove_fd(&mut self, source: Source) {
+    self.fds.retain(|(s, _)| *s != source);
+  }
+
+  /// Arm `timer` to fire at `at`, replacing any pending deadline for it
+  pub fn add_timer(&mut self, at: Instant, timer: Timer) {
+    self.timers.retain(|(_, t)| *t != timer);
+    self.timers.push((at, timer));
+  }
+
+  async fn readable(fds: &[(Source, AsyncFd<OwnedFd>)]) -> Source {
+    if fds.is_empty() {
+      return std::future::pending().await;
+    }
+    let waits = fds.iter().map(|(source, fd)| Box::pin(async move {
+      match fd.readable().await {
+        Ok(mut guard) => {
+          guard.clear_ready();
+          *source
+        },
+        Err(e) => {
+          tracing::error!["Polling {source:?} failed: {e}"];
+          std::future::pending().await
+        },
+      }
+    }));
+    select_all(waits).await.0
+  }
+
+  async fn signalled(signals: &mut [Signal]) {
+    if signals.is_empty() {
+      return std::future::pending().await;
+    }
+    select_all(signals.iter_mut().map(|signal| Box::pin(signal.recv()))).await;
+  }
+
+  /// Sleep until there is something to do
+  pub async fn next(&mut self) -> Wake {
+    loop {
+      let next_timer =
+        self
+          .timers
+          .iter()
+          .enumerate()
+          .min_by_key(|(_, (at, _))| *at)
+          .map(|(i, (at, _))| (i, *at));
+      let sleep = async {
+        match next_timer {
+          Some((_, at)) => tokio::time::sleep_until(at.into()).await,
+          None => std::future::pending().await,
+        }
+      };
+      tokio::select! {
+        event = self.config_rx.recv(), if self.config_watcher.is_some() => match event {
+          Some(Ok(_)) => return Wake::Config,
+          Some(Err(e)) => tracing::warn!["Config watcher error: {e}"],
+          None => {
+            tracing::warn!["Config watcher channel disconnected somehow. Reconnecting."];
+            (self.config_rx, self.config_watcher) = watch_config(&self.config_path);
+          },
+        },
+        () = Self::signalled(&mut self.signals) => return Wake::Shutdown,
+        source = Self::readable(&self.fds) => return Wake::Readable(source),
+        () = sleep => {
+          let (i, _) = next_timer.unwrap();
+          let (_, timer) = self.timers.swap_remove(i);
+          return Wake::Timer(timer);
+        },
+      }
+    }
+  }
+}
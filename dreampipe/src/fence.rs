@@ -0,0 +1,85 @@
+use ash::khr;
+use ash::vk;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use wgpu::CommandEncoderDescriptor;
+use wgpu::wgc::api;
+
+/// Turns GPU submissions into sync_files, so KMS can wait for rendering to
+/// finish instead of us. Needs `VK_KHR_external_semaphore_fd`, see `init_gpu`.
+pub struct RenderFence {
+  device: ash::Device,
+  fd_fns: khr::external_semaphore_fd::Device,
+  semaphore: vk::Semaphore,
+}
+
+impl std::fmt::Debug for RenderFence {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("RenderFence").field("semaphore", &self.semaphore).finish()
+  }
+}
+
+impl RenderFence {
+  /// None if the device was opened without sync_file export
+  pub fn new(gpu: &wgpu::Device) -> Option<Self> {
+    let hal_device = unsafe {
+      gpu.as_hal::<api::Vulkan>()
+    }?;
+    if !hal_device.enabled_device_extensions().contains(&khr::external_semaphore_fd::NAME) {
+      return None;
+    }
+    let device = hal_device.raw_device().clone();
+    let fd_fns =
+      khr::external_semaphore_fd::Device::new(hal_device.shared_instance().raw_instance(), &device);
+    let mut export =
+      vk::ExportSemaphoreCreateInfo::default().handle_types(
+        vk::ExternalSemaphoreHandleTypeFlags::SYNC_FD,
+      );
+    let semaphore = unsafe {
+      device.create_semaphore(&vk::SemaphoreCreateInfo::default().push_next(&mut export), None)
+    }.inspect_err(|e| {
+      tracing::warn!["Failed to create an exportable semaphore: {e}"];
+    }).ok()?;
+    Some(Self {
+      device,
+      fd_fns,
+      semaphore,
+    })
+  }
+
+  /// A sync_file that signals once everything submitted to `queue` so far
+  /// is done. None if the export failed or the work is already done.
+  pub fn signal(&self, gpu: &wgpu::Device, queue: &wgpu::Queue) -> Option<OwnedFd> {
+    unsafe {
+      queue.as_hal::<api::Vulkan>()?.add_signal_semaphore(self.semaphore, None);
+    }
+
+    // The semaphore goes out with the next submission, so make one
+    let encoder =
+      gpu.create_command_encoder(&CommandEncoderDescriptor { label: Some("Render Fence Encoder") });
+    queue.submit([encoder.finish()]);
+
+    // Exporting a sync_file resets the semaphore, so it can be signalled again
+    // next frame
+    let info =
+      vk::SemaphoreGetFdInfoKHR::default()
+        .semaphore(self.semaphore)
+        .handle_type(vk::ExternalSemaphoreHandleTypeFlags::SYNC_FD);
+    let fd = unsafe {
+      self.fd_fns.get_semaphore_fd(&info)
+    }.inspect_err(|e| {
+      tracing::warn!["Failed to export a render fence: {e}"];
+    }).ok()?;
+    (fd >= 0).then(|| unsafe {
+      OwnedFd::from_raw_fd(fd)
+    })
+  }
+}
+
+impl Drop for RenderFence {
+  fn drop(&mut self) {
+    unsafe {
+      self.device.destroy_semaphore(self.semaphore, None);
+    }
+  }
+}
@@ -0,0 +1,307 @@
+use ash::vk;
+use crate::buffer::DEEP_FORMATS;
+use crate::buffer::ScanoutFormat;
+use crate::error::CompositorError;
+use crate::error::CompositorResult;
+use crate::kms::KmsBackend;
+use drm::buffer::DrmFourcc;
+use drm::buffer::DrmModifier;
+use drm::control::plane;
+use std::collections::BTreeMap;
+use wgpu::wgc::api;
+
+// struct drm_format_modifier_blob
+const BLOB_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 24;
+// struct drm_format_modifier: a u64 mask, a u32 offset, padding and a u64
+const MODIFIER_SIZE: usize = 24;
+// What we can allocate and render to
+const RENDER_FORMATS: [ScanoutFormat; 5] = [
+  ScanoutFormat::XRGB8888,
+  ScanoutFormat::ARGB8888,
+  DEEP_FORMATS[0],
+  DEEP_FORMATS[1],
+  DEEP_FORMATS[2],
+];
+
+fn read_u32(blob: &[u8], offset: usize) -> Option<u32> {
+  Some(u32::from_ne_bytes(blob.get(offset .. offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(blob: &[u8], offset: usize) -> Option<u64> {
+  Some(u64::from_ne_bytes(blob.get(offset .. offset + 8)?.try_into().ok()?))
+}
+
+/// Every modifier Vulkan knows for `format`, with what it can be used for and
+/// how many memory planes it has
+pub fn vulkan_modifiers(
+  hal_device: &<api::Vulkan as wgpu::hal::Api>::Device,
+  format: vk::Format,
+) -> Vec<vk::DrmFormatModifierPropertiesEXT> {
+  let instance = hal_device.shared_instance().raw_instance();
+  let physical_device = hal_device.raw_physical_device();
+
+  // Asked twice, first for the count
+  let mut list = vk::DrmFormatModifierPropertiesListEXT::default();
+  let mut properties = vk::FormatProperties2::default().push_next(&mut list);
+  unsafe {
+    instance.get_physical_device_format_properties2(physical_device, format, &mut properties)
+  };
+  let count = list.drm_format_modifier_count as usize;
+  let mut modifiers = vec![vk::DrmFormatModifierPropertiesEXT::default(); count];
+  let mut list =
+    vk::DrmFormatModifierPropertiesListEXT::default()
+      .drm_format_modifier_properties(&mut modifiers);
+  let mut properties = vk::FormatProperties2::default().push_next(&mut list);
+  unsafe {
+    instance.get_physical_device_format_properties2(physical_device, format, &mut properties)
+  };
+  modifiers
+}
+
+/// Formats and the modifiers each can be used with, by a plane or by the
+/// GPU. `DrmModifier::Invalid` stands for an implicit layout, which the
+/// driver picks itself.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FormatTable {
+  // Ordered so that the table reads the same every time
+  formats: BTreeMap<u32, Vec<DrmModifier>>,
+}
+
+impl FormatTable {
+  /// Parse an `IN_FORMATS` blob, None if it is malformed
+  pub fn parse(blob: &[u8]) -> Option<Self> {
+    if read_u32(blob, 0)? != BLOB_VERSION {
+      return None;
+    }
+    let count_formats = read_u32(blob, 8)? as usize;
+    let formats_offset = read_u32(blob, 12)? as usize;
+    let count_modifiers = read_u32(blob, 16)? as usize;
+    let modifiers_offset = read_u32(blob, 20)? as usize;
+    if formats_offset < HEADER_SIZE || modifiers_offset < HEADER_SIZE {
+      return None;
+    }
+    let formats =
+      (0 .. count_formats)
+        .map(|i| read_u32(blob, formats_offset + 4 * i))
+        .collect::<Option<Vec<_>>>()?;
+    let mut table = FormatTable::default();
+    for &format in formats.iter() {
+      table.formats.insert(format, Vec::new());
+    }
+
+    // Each modifier applies to up to 64 formats, starting at its offset
+    for i in 0 .. count_modifiers {
+      let entry = modifiers_offset + MODIFIER_SIZE * i;
+      let mask = read_u64(blob, entry)?;
+      let offset = read_u32(blob, entry + 8)? as usize;
+      let modifier = DrmModifier::from(read_u64(blob, entry + 16)?);
+      for bit in (0 .. 64).filter(|bit| mask & (1 << bit) != 0) {
+        if let Some(format) = formats.get(offset + bit) {
+          table.formats.entry(*format).or_default().push(modifier);
+        }
+      }
+    }
+    Some(table)
+  }
+
+  /// `formats` with implicit layouts only
+  pub fn implicit(formats: &[u32]) -> Self {
+    Self {
+      formats: formats.iter().map(|&format| (format, vec![DrmModifier::Invalid])).collect(),
+    }
+  }
+
+  /// What `plane` can scan out. Without `IN_FORMATS` only implicit layouts are
+  /// known to work.
+  pub fn for_plane(kms: &dyn KmsBackend, plane: plane::Handle) -> CompositorResult<Self> {
+    let props =
+      kms
+        .properties(plane.into())
+        .map_err(|err| CompositorError::GetPlaneProperties(plane, err))?;
+    let in_formats =
+      props
+        .get("IN_FORMATS")
+        .map(|prop| prop.value())
+        .filter(|blob| *blob != 0)
+        .and_then(|blob| kms.blob(blob).ok())
+        .and_then(|blob| {
+          FormatTable::parse(&blob).or_else(|| {
+            tracing::warn!["Plane {plane:?} has a malformed IN_FORMATS blob"];
+            None
+          })
+        });
+    match in_formats {
+      Some(table) => Ok(table),
+      None => {
+        let info = kms.plane(plane).map_err(|err| CompositorError::GetPlaneInfo(plane, err))?;
+        Ok(FormatTable::implicit(&info.formats))
+      },
+    }
+  }
+
+  /// What the GPU can allocate, import and render to with
+  /// `VK_EXT_image_drm_format_modifier`. Implicit layouts are always in, as
+  /// they were all there was before.
+  pub fn renderable(gpu: &wgpu::Device) -> Self {
+    let mut table = FormatTable::implicit(&RENDER_FORMATS.map(|format| format.drm as u32));
+    let hal_device_guard = unsafe {
+      gpu.as_hal::<api::Vulkan>()
+    };
+    let Some(hal_device) = hal_device_guard else {
+      return table;
+    };
+    let wanted =
+      vk::FormatFeatureFlags::COLOR_ATTACHMENT | vk::FormatFeatureFlags::SAMPLED_IMAGE;
+    for format in RENDER_FORMATS {
+      let usable =
+        vulkan_modifiers(&hal_device, format.vk)
+          .into_iter()
+          .filter(|modifier| modifier.drm_format_modifier_tiling_features.contains(wanted))
+          .map(|modifier| DrmModifier::from(modifier.drm_format_modifier));
+      table.formats.entry(format.drm as u32).or_default().extend(usable);
+    }
+    table
+  }
+
+  /// For telling clients what to allocate
+  #[allow(unused)]
+  pub fn formats(&self) -> impl Iterator<Item = u32> + '_ {
+    self.formats.keys().copied()
+  }
+
+  pub fn contains(&self, format: DrmFourcc) -> bool {
+    self.formats.contains_key(&(format as u32))
+  }
+
+  /// Empty if `format` isn't in the table
+  pub fn modifiers(&self, format: DrmFourcc) -> &[DrmModifier] {
+    self.formats.get(&(format as u32)).map(|modifiers| modifiers.as_slice()).unwrap_or(&[])
+  }
+
+  /// Whether a buffer of `format` laid out as `modifier` can be used. An
+  /// implicit layout is up to the driver, so any listed format may work.
+  pub fn supports(&self, format: DrmFourcc, modifier: DrmModifier) -> bool {
+    match modifier {
+      DrmModifier::Invalid => self.contains(format),
+      _ => self.modifiers(format).contains(&modifier),
+    }
+  }
+
+  /// What is in both tables
+  pub fn intersect(&self, other: &FormatTable) -> FormatTable {
+    let formats =
+      self
+        .formats
+        .iter()
+        .map(|(format, modifiers)| {
+          let theirs = other.formats.get(format).map(|modifiers| modifiers.as_slice());
+          let shared =
+            modifiers
+              .iter()
+              .copied()
+              .filter(|modifier| theirs.unwrap_or(&[]).contains(modifier))
+              .collect::<Vec<_>>();
+          (*format, shared)
+        })
+        .filter(|(_, modifiers)| !modifiers.is_empty())
+        .collect();
+    FormatTable { formats }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gpu::tests::device;
+
+  // An IN_FORMATS blob of `formats` and (mask, offset, modifier) entries
+  fn blob(version: u32, formats: &[u32], modifiers: &[(u64, u32, u64)]) -> Vec<u8> {
+    let formats_offset = HEADER_SIZE;
+    let modifiers_offset = (formats_offset + 4 * formats.len()).next_multiple_of(8);
+    let mut blob = Vec::new();
+    for word in [
+      version,
+      0,
+      formats.len() as u32,
+      formats_offset as u32,
+      modifiers.len() as u32,
+      modifiers_offset as u32,
+    ] {
+      blob.extend(word.to_ne_bytes());
+    }
+    for format in formats {
+      blob.extend(format.to_ne_bytes());
+    }
+    blob.resize(modifiers_offset, 0);
+    for &(mask, offset, modifier) in modifiers {
+      blob.extend(mask.to_ne_bytes());
+      blob.extend(offset.to_ne_bytes());
+      blob.extend([0; 4]);
+      blob.extend(modifier.to_ne_bytes());
+    }
+    blob
+  }
+
+  #[test]
+  fn parses_modifiers_by_mask_and_offset() {
+    // The mask's top bit reaches 63 formats past the offset
+    let formats = (0 .. 70).map(|i| DrmFourcc::Xrgb8888 as u32 + i).collect::<Vec<_>>();
+    let linear = u64::from(DrmModifier::Linear);
+    let tiled = 0x0100_0000_0000_0001;
+    let modifiers = [(1 | (1 << 63), 5, linear), (0b10, 0, tiled)];
+    let table = FormatTable::parse(&blob(1, &formats, &modifiers)).unwrap();
+    assert_eq!(table.formats.len(), 70);
+    assert_eq!(table.formats[&formats[5]], [DrmModifier::Linear]);
+    assert_eq!(table.formats[&formats[68]], [DrmModifier::Linear]);
+    assert_eq!(table.formats[&formats[1]], [DrmModifier::from(tiled)]);
+    assert!(table.formats[&formats[0]].is_empty());
+    assert!(table.formats[&formats[69]].is_empty());
+  }
+
+  #[test]
+  fn rejects_malformed_blobs() {
+    let formats = [DrmFourcc::Xrgb8888 as u32, DrmFourcc::Argb8888 as u32];
+    let good = blob(1, &formats, &[(0b11, 0, 0)]);
+    assert!(FormatTable::parse(&good).is_some());
+    assert_eq!(FormatTable::parse(&good[.. HEADER_SIZE - 4]), None);
+    assert_eq!(FormatTable::parse(&good[.. good.len() - 8]), None);
+    assert_eq!(FormatTable::parse(&blob(2, &formats, &[(0b11, 0, 0)])), None);
+  }
+
+  #[test]
+  fn intersects_implicit_and_explicit_layouts() {
+    let plane =
+      FormatTable::parse(&blob(
+        1,
+        &[DrmFourcc::Xrgb8888 as u32, DrmFourcc::Argb8888 as u32],
+        &[(0b11, 0, u64::from(DrmModifier::Linear))],
+      ))
+      .unwrap()
+      .intersect(&FormatTable::implicit(&[DrmFourcc::Xrgb8888 as u32]));
+    assert!(plane.formats.is_empty());
+
+    // An implicit layout only matches another implicit layout
+    let mut gpu = FormatTable::implicit(&[DrmFourcc::Xrgb8888 as u32]);
+    gpu.formats.entry(DrmFourcc::Xrgb8888 as u32).or_default().push(DrmModifier::Linear);
+    let shared =
+      FormatTable::implicit(&[DrmFourcc::Xrgb8888 as u32, DrmFourcc::Argb8888 as u32])
+        .intersect(&gpu);
+    assert_eq!(shared, FormatTable::implicit(&[DrmFourcc::Xrgb8888 as u32]));
+    assert!(shared.supports(DrmFourcc::Xrgb8888, DrmModifier::Invalid));
+    assert!(!shared.supports(DrmFourcc::Xrgb8888, DrmModifier::Linear));
+    assert!(!shared.supports(DrmFourcc::Argb8888, DrmModifier::Invalid));
+  }
+
+  #[test]
+  fn renders_implicit_layouts() {
+    let Some((gpu, _)) = device(wgpu::Features::empty()) else {
+      return;
+    };
+    let table = FormatTable::renderable(&gpu);
+    for format in RENDER_FORMATS {
+      assert_eq!(table.modifiers(format.drm)[0], DrmModifier::Invalid);
+      assert!(table.supports(format.drm, DrmModifier::Invalid));
+    }
+  }
+}
@@ -1,8 +1,108 @@
+use ash::vk;
 use drm::buffer::DrmFourcc;
+use wgpu::TextureFormat;
+
+/// How a format lays out its pixels in memory, after the kernel's
+/// `drm_format_info`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FormatInfo {
+   /// Each plane has its own stride and offset
+   pub planes: usize,
+   /// Bytes per block in each plane. 0 if the format only exists compressed,
+   /// and has no linear layout.
+   pub block_bytes: [u32; 3],
+   /// Block width and height in each plane, in pixels of that plane
+   pub block_size: [(u32, u32); 3],
+   /// Planes after the first are this many times narrower and shorter
+   pub subsampling: (u32, u32),
+   pub alpha: bool,
+   pub yuv: bool,
+   pub vk: Option<vk::Format>,
+   pub wgpu: Option<TextureFormat>,
+}
+
+impl FormatInfo {
+   const fn rgb(bytes: u32) -> Self {
+      Self {
+         planes: 1,
+         block_bytes: [bytes, 0, 0],
+         block_size: [(1, 1); 3],
+         subsampling: (1, 1),
+         alpha: false,
+         yuv: false,
+         vk: None,
+         wgpu: None,
+      }
+   }
+
+   const fn yuv(block_bytes: [u32; 3], planes: usize, subsampling: (u32, u32)) -> Self {
+      Self {
+         planes,
+         block_bytes,
+         block_size: [(1, 1); 3],
+         subsampling,
+         alpha: false,
+         yuv: true,
+         vk: None,
+         wgpu: None,
+      }
+   }
+
+   const fn alpha(self) -> Self {
+      Self { alpha: true, ..self }
+   }
+
+   const fn blocks(self, block_size: [(u32, u32); 3]) -> Self {
+      Self { block_size, ..self }
+   }
+
+   const fn vk(self, format: vk::Format) -> Self {
+      Self { vk: Some(format), ..self }
+   }
+
+   const fn wgpu(self, format: TextureFormat) -> Self {
+      Self { wgpu: Some(format), ..self }
+   }
+
+   /// Width and height of `plane` in its own pixels for a `width` by `height`
+   /// image
+   pub fn plane_size(&self, plane: usize, width: u32, height: u32) -> (u32, u32) {
+      match plane {
+         0 => (width, height),
+         _ => (width.div_ceil(self.subsampling.0), height.div_ceil(self.subsampling.1)),
+      }
+   }
+
+   /// Smallest stride of `plane` in bytes. Rows of blocks are counted in
+   /// rows of pixels, as the kernel does.
+   pub fn stride(&self, plane: usize, width: u32) -> u32 {
+      let (plane_width, _) = self.plane_size(plane, width, 0);
+      let (block_width, block_height) = self.block_size[plane];
+      (plane_width * self.block_bytes[plane]).div_ceil(block_width * block_height)
+   }
+
+   /// Bytes taken by `plane`, tightly packed
+   pub fn plane_bytes(&self, plane: usize, width: u32, height: u32) -> u32 {
+      let (_, plane_height) = self.plane_size(plane, width, height);
+      self.stride(plane, width) * plane_height
+   }
+
+   /// Where `plane` starts if the planes follow each other tightly packed
+   pub fn offset(&self, plane: usize, width: u32, height: u32) -> u32 {
+      (0 .. plane).map(|plane| self.plane_bytes(plane, width, height)).sum()
+   }
+
+   /// Bytes taken by all planes, tightly packed
+   pub fn size(&self, width: u32, height: u32) -> u32 {
+      self.offset(self.planes, width, height)
+   }
+}
 
 pub trait FourCc {
    fn depth(&self) -> u32;
    fn bpp(&self) -> u32;
+   /// None for `Big_endian`, which is a flag and not a format
+   fn info(&self) -> Option<FormatInfo>;
 }
 
 // depth is bits used for encoding
@@ -208,4 +308,225 @@ impl FourCc for DrmFourcc {
          DrmFourcc::Xbgr16161616f => 64,
       }
    }
+
+   fn info(&self) -> Option<FormatInfo> {
+      let rgb = FormatInfo::rgb;
+      let yuv = FormatInfo::yuv;
+      let info = match self {
+         DrmFourcc::Big_endian => return None,
+         DrmFourcc::C8 | DrmFourcc::Rgb332 | DrmFourcc::Bgr233 => rgb(1),
+         DrmFourcc::R8 => rgb(1).vk(vk::Format::R8_UNORM).wgpu(TextureFormat::R8Unorm),
+         DrmFourcc::R16 => rgb(2).vk(vk::Format::R16_UNORM).wgpu(TextureFormat::R16Unorm),
+         DrmFourcc::Gr88 => rgb(2).vk(vk::Format::R8G8_UNORM).wgpu(TextureFormat::Rg8Unorm),
+         DrmFourcc::Rg88 => rgb(2),
+         DrmFourcc::Gr1616 => rgb(4).vk(vk::Format::R16G16_UNORM).wgpu(TextureFormat::Rg16Unorm),
+         DrmFourcc::Rg1616 => rgb(4),
+         DrmFourcc::Argb4444 => rgb(2).alpha().vk(vk::Format::A4R4G4B4_UNORM_PACK16),
+         DrmFourcc::Xrgb4444 => rgb(2).vk(vk::Format::A4R4G4B4_UNORM_PACK16),
+         DrmFourcc::Abgr4444 => rgb(2).alpha().vk(vk::Format::A4B4G4R4_UNORM_PACK16),
+         DrmFourcc::Xbgr4444 => rgb(2).vk(vk::Format::A4B4G4R4_UNORM_PACK16),
+         DrmFourcc::Rgba4444 => rgb(2).alpha().vk(vk::Format::R4G4B4A4_UNORM_PACK16),
+         DrmFourcc::Rgbx4444 => rgb(2).vk(vk::Format::R4G4B4A4_UNORM_PACK16),
+         DrmFourcc::Bgra4444 => rgb(2).alpha().vk(vk::Format::B4G4R4A4_UNORM_PACK16),
+         DrmFourcc::Bgrx4444 => rgb(2).vk(vk::Format::B4G4R4A4_UNORM_PACK16),
+         DrmFourcc::Argb1555 => rgb(2).alpha().vk(vk::Format::A1R5G5B5_UNORM_PACK16),
+         DrmFourcc::Xrgb1555 => rgb(2).vk(vk::Format::A1R5G5B5_UNORM_PACK16),
+         DrmFourcc::Abgr1555 => rgb(2).alpha(),
+         DrmFourcc::Xbgr1555 => rgb(2),
+         DrmFourcc::Rgba5551 => rgb(2).alpha().vk(vk::Format::R5G5B5A1_UNORM_PACK16),
+         DrmFourcc::Rgbx5551 => rgb(2).vk(vk::Format::R5G5B5A1_UNORM_PACK16),
+         DrmFourcc::Bgra5551 => rgb(2).alpha().vk(vk::Format::B5G5R5A1_UNORM_PACK16),
+         DrmFourcc::Bgrx5551 => rgb(2).vk(vk::Format::B5G5R5A1_UNORM_PACK16),
+         DrmFourcc::Rgb565 => rgb(2).vk(vk::Format::R5G6B5_UNORM_PACK16),
+         DrmFourcc::Bgr565 => rgb(2).vk(vk::Format::B5G6R5_UNORM_PACK16),
+         DrmFourcc::Rgb888 => rgb(3).vk(vk::Format::B8G8R8_UNORM),
+         DrmFourcc::Bgr888 => rgb(3).vk(vk::Format::R8G8B8_UNORM),
+         DrmFourcc::Argb8888 => {
+            rgb(4).alpha().vk(vk::Format::B8G8R8A8_UNORM).wgpu(TextureFormat::Bgra8Unorm)
+         },
+         DrmFourcc::Xrgb8888 => {
+            rgb(4).vk(vk::Format::B8G8R8A8_UNORM).wgpu(TextureFormat::Bgra8Unorm)
+         },
+         DrmFourcc::Abgr8888 => {
+            rgb(4).alpha().vk(vk::Format::R8G8B8A8_UNORM).wgpu(TextureFormat::Rgba8Unorm)
+         },
+         DrmFourcc::Xbgr8888 => {
+            rgb(4).vk(vk::Format::R8G8B8A8_UNORM).wgpu(TextureFormat::Rgba8Unorm)
+         },
+         DrmFourcc::Rgba8888 | DrmFourcc::Bgra8888 => rgb(4).alpha(),
+         DrmFourcc::Rgbx8888 | DrmFourcc::Bgrx8888 => rgb(4),
+         DrmFourcc::Argb2101010 => rgb(4).alpha().vk(vk::Format::A2R10G10B10_UNORM_PACK32),
+         DrmFourcc::Xrgb2101010 => rgb(4).vk(vk::Format::A2R10G10B10_UNORM_PACK32),
+         DrmFourcc::Abgr2101010 => {
+            rgb(4)
+               .alpha()
+               .vk(vk::Format::A2B10G10R10_UNORM_PACK32)
+               .wgpu(TextureFormat::Rgb10a2Unorm)
+         },
+         DrmFourcc::Xbgr2101010 => {
+            rgb(4).vk(vk::Format::A2B10G10R10_UNORM_PACK32).wgpu(TextureFormat::Rgb10a2Unorm)
+         },
+         DrmFourcc::Rgba1010102 | DrmFourcc::Bgra1010102 => rgb(4).alpha(),
+         DrmFourcc::Rgbx1010102 | DrmFourcc::Bgrx1010102 => rgb(4),
+         DrmFourcc::Argb16161616f => rgb(8).alpha(),
+         DrmFourcc::Xrgb16161616f => rgb(8),
+         DrmFourcc::Abgr16161616f => {
+            rgb(8).alpha().vk(vk::Format::R16G16B16A16_SFLOAT).wgpu(TextureFormat::Rgba16Float)
+         },
+         DrmFourcc::Xbgr16161616f => {
+            rgb(8).vk(vk::Format::R16G16B16A16_SFLOAT).wgpu(TextureFormat::Rgba16Float)
+         },
+         DrmFourcc::Axbxgxrx106106106106 => rgb(8).alpha(),
+         // RGB with a separate alpha plane
+         DrmFourcc::Rgb565_a8 | DrmFourcc::Bgr565_a8 => {
+            FormatInfo { planes: 2, block_bytes: [2, 1, 0], ..rgb(2) }.alpha()
+         },
+         DrmFourcc::Rgb888_a8 | DrmFourcc::Bgr888_a8 => {
+            FormatInfo { planes: 2, block_bytes: [3, 1, 0], ..rgb(3) }.alpha()
+         },
+         DrmFourcc::Xrgb8888_a8 |
+         DrmFourcc::Xbgr8888_a8 |
+         DrmFourcc::Rgbx8888_a8 |
+         DrmFourcc::Bgrx8888_a8 => {
+            FormatInfo { planes: 2, block_bytes: [4, 1, 0], ..rgb(4) }.alpha()
+         },
+         // Packed YUV, a block of two pixels sharing chroma takes 4 or 8 bytes
+         DrmFourcc::Yuyv => {
+            yuv([2, 0, 0], 1, (2, 1)).vk(vk::Format::G8B8G8R8_422_UNORM)
+         },
+         DrmFourcc::Uyvy => {
+            yuv([2, 0, 0], 1, (2, 1)).vk(vk::Format::B8G8R8G8_422_UNORM)
+         },
+         DrmFourcc::Yvyu | DrmFourcc::Vyuy => yuv([2, 0, 0], 1, (2, 1)),
+         DrmFourcc::Y210 => {
+            yuv([4, 0, 0], 1, (2, 1)).vk(vk::Format::G10X6B10X6G10X6R10X6_422_UNORM_4PACK16)
+         },
+         DrmFourcc::Y212 => {
+            yuv([4, 0, 0], 1, (2, 1)).vk(vk::Format::G12X4B12X4G12X4R12X4_422_UNORM_4PACK16)
+         },
+         DrmFourcc::Y216 => {
+            yuv([4, 0, 0], 1, (2, 1)).vk(vk::Format::G16B16G16R16_422_UNORM)
+         },
+         DrmFourcc::Ayuv => yuv([4, 0, 0], 1, (1, 1)).alpha(),
+         DrmFourcc::Xyuv8888 | DrmFourcc::Xvyu2101010 => yuv([4, 0, 0], 1, (1, 1)),
+         DrmFourcc::Vuy888 => yuv([3, 0, 0], 1, (1, 1)),
+         DrmFourcc::Y410 => yuv([4, 0, 0], 1, (1, 1)).alpha(),
+         DrmFourcc::Y412 | DrmFourcc::Y416 => yuv([8, 0, 0], 1, (1, 1)).alpha(),
+         DrmFourcc::Xvyu12_16161616 | DrmFourcc::Xvyu16161616 => yuv([8, 0, 0], 1, (1, 1)),
+         // 2x2 tiles of 8 bytes
+         DrmFourcc::Y0l0 | DrmFourcc::Y0l2 => {
+            yuv([8, 0, 0], 1, (2, 2)).blocks([(2, 2), (1, 1), (1, 1)]).alpha()
+         },
+         DrmFourcc::X0l0 | DrmFourcc::X0l2 => {
+            yuv([8, 0, 0], 1, (2, 2)).blocks([(2, 2), (1, 1), (1, 1)])
+         },
+         // Only ever compressed
+         DrmFourcc::Vuy101010 => yuv([0, 0, 0], 1, (1, 1)),
+         DrmFourcc::Yuv420_8bit | DrmFourcc::Yuv420_10bit => yuv([0, 0, 0], 1, (2, 2)),
+         // Luma plane and an interleaved chroma plane
+         DrmFourcc::Nv12 => {
+            yuv([1, 2, 0], 2, (2, 2))
+               .vk(vk::Format::G8_B8R8_2PLANE_420_UNORM)
+               .wgpu(TextureFormat::NV12)
+         },
+         DrmFourcc::Nv21 => yuv([1, 2, 0], 2, (2, 2)),
+         DrmFourcc::Nv16 => yuv([1, 2, 0], 2, (2, 1)).vk(vk::Format::G8_B8R8_2PLANE_422_UNORM),
+         DrmFourcc::Nv61 => yuv([1, 2, 0], 2, (2, 1)),
+         DrmFourcc::Nv24 => yuv([1, 2, 0], 2, (1, 1)).vk(vk::Format::G8_B8R8_2PLANE_444_UNORM),
+         DrmFourcc::Nv42 => yuv([1, 2, 0], 2, (1, 1)),
+         // 10 bit samples packed four into 5 bytes
+         DrmFourcc::Nv15 => yuv([5, 5, 0], 2, (2, 2)).blocks([(4, 1), (2, 1), (1, 1)]),
+         // 10 to 16 bit samples in the high bits of 16
+         DrmFourcc::P010 => {
+            yuv([2, 4, 0], 2, (2, 2))
+               .vk(vk::Format::G10X6_B10X6R10X6_2PLANE_420_UNORM_3PACK16)
+               .wgpu(TextureFormat::P010)
+         },
+         DrmFourcc::P012 => {
+            yuv([2, 4, 0], 2, (2, 2)).vk(vk::Format::G12X4_B12X4R12X4_2PLANE_420_UNORM_3PACK16)
+         },
+         DrmFourcc::P016 => yuv([2, 4, 0], 2, (2, 2)).vk(vk::Format::G16_B16R16_2PLANE_420_UNORM),
+         DrmFourcc::P210 => {
+            yuv([2, 4, 0], 2, (2, 1)).vk(vk::Format::G10X6_B10X6R10X6_2PLANE_422_UNORM_3PACK16)
+         },
+         // A plane each for Y, U and V, or Y, V and U
+         DrmFourcc::Yuv410 | DrmFourcc::Yvu410 => yuv([1, 1, 1], 3, (4, 4)),
+         DrmFourcc::Yuv411 | DrmFourcc::Yvu411 => yuv([1, 1, 1], 3, (4, 1)),
+         DrmFourcc::Yuv420 => yuv([1, 1, 1], 3, (2, 2)).vk(vk::Format::G8_B8_R8_3PLANE_420_UNORM),
+         DrmFourcc::Yuv422 => yuv([1, 1, 1], 3, (2, 1)).vk(vk::Format::G8_B8_R8_3PLANE_422_UNORM),
+         DrmFourcc::Yuv444 => yuv([1, 1, 1], 3, (1, 1)).vk(vk::Format::G8_B8_R8_3PLANE_444_UNORM),
+         DrmFourcc::Yvu420 => yuv([1, 1, 1], 3, (2, 2)),
+         DrmFourcc::Yvu422 => yuv([1, 1, 1], 3, (2, 1)),
+         DrmFourcc::Yvu444 => yuv([1, 1, 1], 3, (1, 1)),
+         DrmFourcc::Q410 | DrmFourcc::Q401 => yuv([2, 2, 2], 3, (1, 1)),
+      };
+      Some(info)
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   // Stride, offset and size of each plane
+   type Planes = &'static [(u32, u32, u32)];
+
+   const LAYOUTS: &[(DrmFourcc, u32, u32, Planes)] = &[
+      (DrmFourcc::Xrgb8888, 1920, 1080, &[(7680, 0, 8294400)]),
+      (DrmFourcc::Xrgb8888, 1, 1, &[(4, 0, 4)]),
+      (DrmFourcc::Nv12, 1920, 1080, &[(1920, 0, 2073600), (1920, 2073600, 1036800)]),
+      (DrmFourcc::Nv12, 5, 3, &[(5, 0, 15), (6, 15, 12)]),
+      (DrmFourcc::Nv12, 1, 1, &[(1, 0, 1), (2, 1, 2)]),
+      (DrmFourcc::P010, 1920, 1080, &[(3840, 0, 4147200), (3840, 4147200, 2073600)]),
+      (DrmFourcc::P010, 5, 3, &[(10, 0, 30), (12, 30, 24)]),
+      (
+         DrmFourcc::Yuv420,
+         1920,
+         1080,
+         &[(1920, 0, 2073600), (960, 2073600, 518400), (960, 2592000, 518400)],
+      ),
+      (DrmFourcc::Yuv420, 7, 5, &[(7, 0, 35), (4, 35, 12), (4, 47, 12)]),
+      (DrmFourcc::Nv15, 1920, 1080, &[(2400, 0, 2592000), (2400, 2592000, 1296000)]),
+      (DrmFourcc::Nv15, 6, 2, &[(8, 0, 16), (8, 16, 8)]),
+      (DrmFourcc::Y0l0, 1920, 1080, &[(3840, 0, 4147200)]),
+      (DrmFourcc::Y0l0, 3, 3, &[(6, 0, 18)]),
+      (DrmFourcc::Yuyv, 5, 2, &[(10, 0, 20)]),
+   ];
+
+   #[test]
+   fn lays_out_planes() {
+      for &(format, width, height, planes) in LAYOUTS {
+         let info = format.info().unwrap();
+         assert_eq!(info.planes, planes.len(), "{format:?}");
+         for (plane, &(stride, offset, bytes)) in planes.iter().enumerate() {
+            let layout =
+               (
+                  info.stride(plane, width),
+                  info.offset(plane, width, height),
+                  info.plane_bytes(plane, width, height),
+               );
+            let name = format!["{format:?} {width}x{height} plane {plane}"];
+            assert_eq!(layout, (stride, offset, bytes), "{name}");
+         }
+         let (_, offset, bytes) = planes[planes.len() - 1];
+         assert_eq!(info.size(width, height), offset + bytes, "{format:?} {width}x{height}");
+      }
+   }
+
+   #[test]
+   fn describes_formats() {
+      let nv12 = DrmFourcc::Nv12.info().unwrap();
+      assert!(nv12.yuv && !nv12.alpha);
+      assert_eq!(nv12.plane_size(1, 5, 3), (3, 2));
+      assert_eq!(nv12.vk, Some(vk::Format::G8_B8R8_2PLANE_420_UNORM));
+      assert_eq!(nv12.wgpu, Some(TextureFormat::NV12));
+
+      let argb = DrmFourcc::Argb8888.info().unwrap();
+      assert!(argb.alpha && !argb.yuv);
+      assert!(!DrmFourcc::Xrgb8888.info().unwrap().alpha);
+      assert_eq!(argb.wgpu, Some(TextureFormat::Bgra8Unorm));
+
+      assert_eq!(DrmFourcc::Big_endian.info(), None);
+      assert_eq!(DrmFourcc::Yuv420_8bit.info().unwrap().stride(0, 1920), 0);
+   }
 }
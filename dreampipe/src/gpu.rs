@@ -1,20 +1,21 @@
-use crate::context::Card;
+use crate::error::CompositorError;
 use crate::error::CompositorResult;
-use image::GenericImageView;
-use wgpu::AddressMode;
-use wgpu::BindGroup;
-use wgpu::BindGroupDescriptor;
-use wgpu::BindGroupEntry;
+use ash::ext;
+use ash::khr;
+use crate::kms::KmsBackend;
+use std::path::Path;
+use wgpu::BindGroupLayout;
 use wgpu::BindGroupLayoutDescriptor;
 use wgpu::BindGroupLayoutEntry;
-use wgpu::BindingResource;
 use wgpu::BindingType;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsages;
+use wgpu::CommandEncoderDescriptor;
 use wgpu::Extent3d;
-use wgpu::FilterMode;
 use wgpu::Origin3d;
 use wgpu::SamplerBindingType;
-use wgpu::SamplerDescriptor;
 use wgpu::ShaderStages;
+use wgpu::TexelCopyBufferInfo;
 use wgpu::TexelCopyBufferLayout;
 use wgpu::TexelCopyTextureInfo;
 use wgpu::TextureAspect;
@@ -23,39 +24,61 @@ use wgpu::TextureDimension;
 use wgpu::TextureFormat;
 use wgpu::TextureSampleType;
 use wgpu::TextureUsages;
-use wgpu::TextureViewDescriptor;
 use wgpu::TextureViewDimension;
+use wgpu::hal::vulkan::CreateDeviceCallback;
+use wgpu::hal::vulkan::CreateDeviceCallbackArgs;
+use wgpu::wgc::api;
 
-const TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba8Uint;
 const BLIT_SHADER: &str = include_str!["blit.wgsl"];
-#[cfg(not(feature = "expanding"))]
-const BG_BYTES: &'static [u8] = include_bytes!["../mambutt.png"];
-#[cfg(feature = "expanding")]
-const BG_BYTES: &'static [u8] = &[];
+// NV12 and P010 textures, and the 16 bit views of P010's planes
+const YUV_FEATURES: wgpu::Features =
+  wgpu::Features::TEXTURE_FORMAT_NV12
+    .union(wgpu::Features::TEXTURE_FORMAT_P010)
+    .union(wgpu::Features::TEXTURE_FORMAT_16BIT_NORM);
 
-pub fn load_default_bg(
-  card: &Card,
+/// What every textured draw binds first: a texture and a filtering sampler
+pub fn texture_bind_group_layout(gpu: &wgpu::Device) -> BindGroupLayout {
+  gpu.create_bind_group_layout(&BindGroupLayoutDescriptor {
+    label: Some("Texture Bindgroup Layout"),
+    entries: &[BindGroupLayoutEntry {
+      binding: 0,
+      visibility: ShaderStages::FRAGMENT,
+      ty: BindingType::Texture {
+        sample_type: TextureSampleType::Float { filterable: true },
+        view_dimension: TextureViewDimension::D2,
+        multisampled: false,
+      },
+      count: None,
+    }, BindGroupLayoutEntry {
+      binding: 1,
+      visibility: ShaderStages::FRAGMENT,
+      ty: BindingType::Sampler(SamplerBindingType::Filtering),
+      count: None,
+    }],
+  })
+}
+
+/// Upload `image` as a texture to sample from. The pixels are taken as they
+/// are, as the buffers drawn into are sRGB encoded already.
+pub fn upload_rgba(
   gpu: &wgpu::Device,
   queue: &wgpu::Queue,
-) -> BindGroup {
-  // This cfg is for display purposes when concatenating the entire project together.
-  let bg =
-    image::load_from_memory_with_format(BG_BYTES, image::ImageFormat::Png).unwrap();
-  let rgba = bg.to_rgba8();
-  let (width, height) = bg.dimensions();
+  label: &str,
+  image: &image::RgbaImage,
+) -> wgpu::Texture {
+  let (width, height) = image.dimensions();
   let size = Extent3d {
     width,
     height,
     depth_or_array_layers: 1,
   };
-  let pci_ids = card.pci_ids();
   let texture = gpu.create_texture(&TextureDescriptor {
-    label: Some(&format!["Default BG {pci_ids:x?}"]),
+    label: Some(label),
     size,
     mip_level_count: 1,
     sample_count: 1,
     dimension: TextureDimension::D2,
-    format: TextureFormat::Bgra8UnormSrgb,
+    format: TextureFormat::Rgba8Unorm,
     usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
     view_formats: &[],
   });
@@ -64,59 +87,54 @@ pub fn load_default_bg(
     mip_level: 0,
     origin: Origin3d::ZERO,
     aspect: TextureAspect::All,
-  }, &rgba, TexelCopyBufferLayout {
+  }, image.as_raw(), TexelCopyBufferLayout {
     offset: 0,
     bytes_per_row: Some(4 * width),
     rows_per_image: Some(height),
   }, size);
-  let texture_view = texture.create_view(&TextureViewDescriptor::default());
-  let sampler = gpu.create_sampler(&SamplerDescriptor {
-    label: Some(&format!["Default BG Sampler {pci_ids:x?}"]),
-    address_mode_u: AddressMode::ClampToEdge,
-    address_mode_v: AddressMode::ClampToEdge,
-    address_mode_w: AddressMode::ClampToEdge,
-    mag_filter: FilterMode::Linear,
-    min_filter: FilterMode::Nearest,
-    mipmap_filter: FilterMode::Nearest,
-    ..Default::default()
-  });
-  let bindgroup_layout = gpu.create_bind_group_layout(&BindGroupLayoutDescriptor {
-    label: Some(&format!["Default Bg Bindgroup Layout {pci_ids:x?}"]),
-    entries: &[BindGroupLayoutEntry {
-      binding: 0,
-      visibility: ShaderStages::FRAGMENT,
-      ty: BindingType::Texture {
-        sample_type: TextureSampleType::Float { filterable: true },
-        view_dimension: TextureViewDimension::D2,
-        multisampled: false,
-      },
-      count: None,
-    }, BindGroupLayoutEntry {
-      binding: 1,
-      visibility: ShaderStages::FRAGMENT,
-      ty: BindingType::Sampler(SamplerBindingType::Filtering),
-      count: None,
-    }],
-  });
-  let bind_group = gpu.create_bind_group(&BindGroupDescriptor {
-    label: Some(&format!["Default BG Bindgroup {pci_ids:x?}"]),
-    layout: &bindgroup_layout,
-    entries: &[BindGroupEntry {
-      binding: 0,
-      resource: BindingResource::TextureView(&texture_view),
-    }, BindGroupEntry {
-      binding: 1,
-      resource: BindingResource::Sampler(&sampler),
-    }],
+  texture
+}
+
+// Open the device with VK_KHR_external_semaphore_fd enabled, which wgpu
+// doesn't ask for, so render fences can be handed to KMS. Buffers with DRM
+// format modifiers need VK_EXT_image_drm_format_modifier too.
+unsafe fn open_with_sync_fd(
+  adapter: &wgpu::Adapter,
+  descriptor: &wgpu::DeviceDescriptor,
+) -> Option<wgpu::hal::OpenDevice<api::Vulkan>> {
+  let hal_adapter = unsafe {
+    adapter.as_hal::<api::Vulkan>()
+  }?;
+  let extension = khr::external_semaphore_fd::NAME;
+  if !hal_adapter.physical_device_capabilities().supports_extension(extension) {
+    return None;
+  }
+  let modifiers =
+    hal_adapter
+      .physical_device_capabilities()
+      .supports_extension(ext::image_drm_format_modifier::NAME);
+  let callback: Box<CreateDeviceCallback> = Box::new(move |args: CreateDeviceCallbackArgs| {
+    args.extensions.push(extension);
+    if modifiers && !args.extensions.contains(&ext::image_drm_format_modifier::NAME) {
+      args.extensions.push(ext::image_drm_format_modifier::NAME);
+    }
   });
-  bind_group
+  unsafe {
+    hal_adapter.open_with_callback(
+      descriptor.required_features,
+      &descriptor.memory_hints,
+      Some(callback),
+    )
+  }.inspect_err(|e| {
+    tracing::warn!["Failed to open the device with sync_file export: {e}"];
+  }).ok()
 }
 
 pub async fn init_gpu(
-  card: &Card,
+  kms: &dyn KmsBackend,
 ) -> CompositorResult<(wgpu::Device, wgpu::Adapter, wgpu::Queue)> {
-  if let Some((vendor_id, device_id)) = card.pci_ids() {
-    let card_num = card.num();
+  if let Some((vendor_id, device_id)) = kms.pci_ids() {
+    let card_num = kms.num();
     println!["Opend card{card_num}: vendor=0x{vendor_id:x}, device=0x{device_id:x}"];
   }
   let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
@@ -143,30 +161,111 @@ pub async fn init_gpu(
       .expect("No suitable adapter found");
   let info = adapter.get_info();
   println!["Selected GPU {} ({:?})", info.name, info.backend];
-  let (device, queue) = adapter.request_device(&wgpu::DeviceDescriptor {
+  let descriptor = wgpu::DeviceDescriptor {
     label: Some("DMA-BUF Device"),
-    required_features: wgpu::Features::empty(),
+    // For sampling client video buffers, where the GPU can
+    required_features: adapter.features() & YUV_FEATURES,
     required_limits: wgpu::Limits::defaults(),
     memory_hints: wgpu::MemoryHints::default(),
     experimental_features: wgpu::ExperimentalFeatures::disabled(),
     trace: wgpu::Trace::Off,
-  }).await.expect("Failed to create device");
+  };
+  let (device, queue) =
+    match unsafe {
+      open_with_sync_fd(&adapter, &descriptor)
+    } {
+      Some(hal_device) => unsafe {
+        adapter.create_device_from_hal(hal_device, &descriptor)
+      },
+      None => {
+        println!["No sync_file export, falling back to implicit sync"];
+        adapter.request_device(&descriptor).await
+      },
+    }.expect("Failed to create device");
   println!["Card and GPU successfully initialized in tandem."];
   Ok((device, adapter, queue))
 }
 
-pub fn create_pipeline(device: &wgpu::Device) {
+/// Read `texture` back from the GPU and write it to `path` as a PNG. Blocks
+/// until the copy is done. Only BGRA textures are supported.
+pub fn save_texture_png(
+  gpu: &wgpu::Device,
+  queue: &wgpu::Queue,
+  texture: &wgpu::Texture,
+  path: &Path,
+) -> CompositorResult<()> {
+  let (width, height) = (texture.width(), texture.height());
+  let bytes_per_row = (4 * width).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+  let buffer = gpu.create_buffer(&BufferDescriptor {
+    label: Some("PNG Readback Buffer"),
+    size: bytes_per_row as u64 * height as u64,
+    usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+    mapped_at_creation: false,
+  });
   let mut encoder =
-    device.create_command_encoder(
-      &wgpu::CommandEncoderDescriptor { label: Some("Compositor Render Encoder") },
+    gpu.create_command_encoder(
+      &CommandEncoderDescriptor { label: Some("PNG Readback Encoder") },
     );
+  encoder.copy_texture_to_buffer(TexelCopyTextureInfo {
+    texture,
+    mip_level: 0,
+    origin: Origin3d::ZERO,
+    aspect: TextureAspect::All,
+  }, TexelCopyBufferInfo {
+    buffer: &buffer,
+    layout: TexelCopyBufferLayout {
+      offset: 0,
+      bytes_per_row: Some(bytes_per_row),
+      rows_per_image: Some(height),
+    },
+  }, texture.size());
+  queue.submit([encoder.finish()]);
+  let slice = buffer.slice(..);
+  let (tx, rx) = std::sync::mpsc::channel();
+  slice.map_async(wgpu::MapMode::Read, move |result| {
+    tx.send(result).ok();
+  });
+  gpu
+    .poll(wgpu::PollType::wait_indefinitely())
+    .map_err(|e| CompositorError::GpuPoll(e))?;
+  rx
+    .recv()
+    .map_err(|_| CompositorError::BufferMap(wgpu::BufferAsyncError))?
+    .map_err(|e| CompositorError::BufferMap(e))?;
+  let mut rgba = Vec::with_capacity(4 * width as usize * height as usize);
+  for row in slice.get_mapped_range().chunks(bytes_per_row as usize) {
+    for pixel in row[.. 4 * width as usize].chunks(4) {
+      rgba.extend_from_slice(&[pixel[2], pixel[1], pixel[0], 0xff]);
+    }
+  }
+  buffer.unmap();
+  image::RgbaImage::from_raw(width, height, rgba)
+    .expect("Readback buffer is the size of the texture")
+    .save(path)
+    .map_err(|e| CompositorError::SavePng(path.into(), e))
+}
+
+/// The pipeline drawing textures into composited frames, with `blend` or
+/// replacing what is there. Binds a texture and sampler in group 0 and
+/// where to draw in group 1.
+pub fn create_pipeline(
+  device: &wgpu::Device,
+  texture_layout: &BindGroupLayout,
+  params_layout: &BindGroupLayout,
+  blend: Option<wgpu::BlendState>,
+) -> wgpu::RenderPipeline {
   let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
     label: Some("Blit Shader"),
     source: wgpu::ShaderSource::Wgsl(BLIT_SHADER.into()),
   });
-  let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+  let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+    label: Some("Blit Pipeline Layout"),
+    bind_group_layouts: &[texture_layout, params_layout],
+    push_constant_ranges: &[],
+  });
+  device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
     label: Some("Blit Pipeline"),
-    layout: None,
+    layout: Some(&layout),
     vertex: wgpu::VertexState {
       module: &shader,
       entry_point: Some("vs_main"),
@@ -177,31 +276,114 @@ pub fn create_pipeline(device: &wgpu::Device) {
       module: &shader,
       entry_point: Some("fs_main"),
       targets: &[Some(wgpu::ColorTargetState {
-        format: TEXTURE_FORMAT,
-        blend: None,
+        format: TextureFormat::Bgra8Unorm,
+        blend,
         write_mask: wgpu::ColorWrites::ALL,
       })],
       compilation_options: wgpu::PipelineCompilationOptions::default(),
     }),
-    primitive: wgpu::PrimitiveState::default(),
+    primitive: wgpu::PrimitiveState {
+      topology: wgpu::PrimitiveTopology::TriangleStrip,
+      ..Default::default()
+    },
     depth_stencil: None,
     multisample: wgpu::MultisampleState::default(),
     multiview: None,
     cache: None,
-  });
-  // let render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-  //    label: Some("Composite Pass"),
-  //    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-  //       view: &output_view,
-  //       depth_slice: None,
-  //       resolve_target: None,
-  //       ops: wgpu::Operations {
-  //          load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-  //          store: wgpu::StoreOp::Store,
-  //       },
-  //    })],
-  //    depth_stencil_attachment: None,
-  //    timestamp_writes: None,
-  //    occlusion_query_set: None,
-  // });
+  })
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+
+  /// Whether there is a Vulkan adapter, for tests that set up a whole
+  /// `AppContext`, which insists on one
+  pub fn has_gpu() -> bool {
+    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
+      backends: wgpu::Backends::VULKAN,
+      ..Default::default()
+    });
+    let found = !instance.enumerate_adapters(wgpu::Backends::VULKAN).is_empty();
+    if !found {
+      eprintln!["No Vulkan adapter, skipping"];
+    }
+    found
+  }
+
+  /// A Vulkan device with `features` to render tests on. None without one,
+  /// eg on CI machines without a GPU, where the test should pass vacuously.
+  pub fn device(features: wgpu::Features) -> Option<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
+      backends: wgpu::Backends::VULKAN,
+      ..Default::default()
+    });
+    let adapter =
+      instance
+        .enumerate_adapters(wgpu::Backends::VULKAN)
+        .into_iter()
+        .find(|adapter| adapter.features().contains(features));
+    let Some(adapter) = adapter else {
+      eprintln!["No Vulkan adapter with {features:?}, skipping"];
+      return None;
+    };
+    let descriptor = wgpu::DeviceDescriptor {
+      required_features: features,
+      ..Default::default()
+    };
+    Some(futures::executor::block_on(adapter.request_device(&descriptor)).unwrap())
+  }
+
+  /// A BGRA texture to draw into and read back, cleared to transparent
+  pub fn target(gpu: &wgpu::Device, (width, height): (u32, u32)) -> wgpu::Texture {
+    gpu.create_texture(&TextureDescriptor {
+      label: Some("Test Target"),
+      size: Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+      },
+      mip_level_count: 1,
+      sample_count: 1,
+      dimension: TextureDimension::D2,
+      format: TextureFormat::Bgra8Unorm,
+      usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+      view_formats: &[],
+    })
+  }
+
+  /// Read `texture` back and compare it with the PNG `golden`, allowing
+  /// channels to be off by `tolerance` as GPUs filter a little differently.
+  /// What was read is left in the temp dir if it doesn't match.
+  pub fn assert_golden(
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    name: &str,
+    golden: &[u8],
+    tolerance: u8,
+  ) {
+    let path = std::env::temp_dir().join(format!["dreampipe-{name}-{}.png", std::process::id()]);
+    save_texture_png(gpu, queue, texture, &path).unwrap();
+    assert_golden_png(&path, name, golden, tolerance);
+    std::fs::remove_file(&path).ok();
+  }
+
+  /// Compare the PNG at `path` to `golden`, off by at most `tolerance` in
+  /// any channel
+  pub fn assert_golden_png(path: &Path, name: &str, golden: &[u8], tolerance: u8) {
+    let actual = image::open(path).unwrap().to_rgba8();
+    let expected =
+      image::load_from_memory_with_format(golden, image::ImageFormat::Png).unwrap().to_rgba8();
+    assert_eq!(actual.dimensions(), expected.dimensions(), "{name}");
+    let worst =
+      actual
+        .as_raw()
+        .iter()
+        .zip(expected.as_raw())
+        .map(|(actual, expected)| actual.abs_diff(*expected))
+        .max()
+        .unwrap_or(0);
+    assert!(worst <= tolerance, "{name} is off by up to {worst}, see {}", path.display());
+  }
 }
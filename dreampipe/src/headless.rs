@@ -0,0 +1,60 @@
+use crate::buffer::CURSOR_FORMAT;
+use crate::buffer::DRM_FORMAT;
+use crate::kms::make_mode;
+use crate::kms::mock::MockDevice;
+use crate::util::ModeSpec;
+use drm::control::PlaneType;
+use drm::control::connector;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_REFRESH: u32 = 60;
+
+/// The `headless.outputs` config value
+#[derive(Clone, Debug)]
+pub struct HeadlessOutputs(pub Vec<ModeSpec>);
+
+impl Default for HeadlessOutputs {
+  fn default() -> Self {
+    Self(vec![ModeSpec {
+      width: 1920,
+      height: 1080,
+      refresh: Some(DEFAULT_REFRESH),
+    }])
+  }
+}
+
+impl FromStr for HeadlessOutputs {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let outputs = s.split_whitespace().map(ModeSpec::from_str).collect::<Result<Vec<_>, _>>()?;
+    if outputs.is_empty() {
+      return Err(String::from("No headless outputs listed"));
+    }
+    Ok(Self(outputs))
+  }
+}
+
+/// A KMS device with one virtual connector per output. Page flips complete at
+/// the refresh rate of the output, so the normal page flip handling drives
+/// composition without any hardware.
+pub fn virtual_device(num: u32, outputs: &HeadlessOutputs) -> MockDevice {
+  let device = MockDevice::with_num(num);
+  device.record_commits(false);
+  for (i, output) in outputs.0.iter().enumerate() {
+    let crtc = device.add_crtc();
+    let possible_crtcs = 1 << i;
+    device.add_plane(PlaneType::Primary, possible_crtcs, vec![DRM_FORMAT as u32]);
+    device.add_plane(PlaneType::Cursor, possible_crtcs, vec![CURSOR_FORMAT as u32]);
+    let encoder = device.add_encoder(possible_crtcs);
+    let refresh = output.refresh.unwrap_or(DEFAULT_REFRESH).max(1);
+    device.add_connector(
+      connector::Interface::Virtual,
+      vec![make_mode(output.width, output.height, refresh)],
+      vec![encoder],
+    );
+    device.set_refresh(crtc, Duration::from_secs(1) / refresh);
+  }
+  device
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+#[cfg(test)]
+use std::os::unix::net::UnixDatagram;
+
+// Uevents are at most a few kilobytes
+const UEVENT_BUFFER_SIZE: usize = 8192;
+// Multicast group the kernel sends uevents to (udev re-broadcasts on 2)
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+/// What happened to a card, and the number of the card it happened to
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HotplugEvent {
+  CardAdded(u32),
+  CardRemoved(u32),
+  /// A connector on the card was plugged or unplugged
+  Changed(u32),
+}
+
+/// A kernel uevent, `ACTION@DEVPATH` followed by `KEY=VALUE` pairs
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Uevent {
+  pub action: String,
+  pub devpath: String,
+  pub props: HashMap<String, String>,
+}
+
+impl Uevent {
+  /// Parse a datagram as sent by the kernel. Messages re-broadcast by udev
+  /// have a binary header and are not understood.
+  pub fn parse(buf: &[u8]) -> Option<Self> {
+    let mut fields =
+      buf
+        .split(|byte| *byte == 0)
+        .filter(|field| !field.is_empty())
+        .map(|field| String::from_utf8_lossy(field));
+    let header = fields.next()?;
+    let (action, devpath) = header.split_once('@')?;
+    let props =
+      fields
+        .filter_map(|field| {
+          field.split_once('=').map(|(k, v)| (k.to_string(), v.to_string()))
+        })
+        .collect();
+    Some(Self {
+      action: action.to_string(),
+      devpath: devpath.to_string(),
+      props,
+    })
+  }
+
+  /// Inverse of `parse`
+  #[cfg(test)]
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut buf = format!["{}@{}\0", self.action, self.devpath].into_bytes();
+    for (k, v) in self.props.iter() {
+      buf.extend_from_slice(format!["{k}={v}\0"].as_bytes());
+    }
+    buf
+  }
+
+  /// Only card devices are interesting. Connectors show up as `change` events
+  /// on their card with `HOTPLUG=1`.
+  pub fn hotplug(&self) -> Option<HotplugEvent> {
+    if self.props.get("SUBSYSTEM").map(String::as_str) != Some("drm") {
+      return None;
+    }
+    let num = self.props.get("DEVNAME")?.strip_prefix("dri/card")?.parse().ok()?;
+    match self.action.as_str() {
+      "add" => Some(HotplugEvent::CardAdded(num)),
+      "remove" => Some(HotplugEvent::CardRemoved(num)),
+      "change" if self.props.get("HOTPLUG").map(String::as_str) == Some("1") => Some(
+        HotplugEvent::Changed(num),
+      ),
+      _ => None,
+    }
+  }
+}
+
+/// Non-blocking datagram socket that uevents arrive on. Normally this is the
+/// kernel's netlink socket, but any datagram socket works, see `pair`.
+pub struct UeventSocket(OwnedFd);
+
+impl AsFd for UeventSocket {
+  fn as_fd(&self) -> BorrowedFd<'_> {
+    self.0.as_fd()
+  }
+}
+
+impl UeventSocket {
+  pub fn netlink() -> io::Result<Self> {
+    let fd =
+      unsafe {
+        libc::socket(
+          libc::AF_NETLINK,
+          libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+          libc::NETLINK_KOBJECT_UEVENT,
+        )
+      };
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe {
+      OwnedFd::from_raw_fd(fd)
+    };
+    let mut addr: libc::sockaddr_nl = unsafe {
+      std::mem::zeroed()
+    };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = KERNEL_UEVENT_GROUP;
+    let res =
+      unsafe {
+        libc::bind(
+          fd.as_raw_fd(),
+          &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+          std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+      };
+    if res < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(Self(fd))
+  }
+
+  /// A socket fed by the returned injector instead of the kernel, for
+  /// scripting hotplugs
+  #[cfg(test)]
+  pub fn pair() -> io::Result<(UeventInjector, Self)> {
+    let (tx, rx) = UnixDatagram::pair()?;
+    rx.set_nonblocking(true)?;
+    Ok((UeventInjector(tx), Self(rx.into())))
+  }
+
+  /// Every hotplug event waiting on the socket, reading until `WouldBlock`.
+  /// Unrelated and unparseable uevents are skipped.
+  pub fn hotplug_events(&self) -> io::Result<Vec<HotplugEvent>> {
+    let mut events = Vec::new();
+    let mut buf = [0u8; UEVENT_BUFFER_SIZE];
+    loop {
+      let len =
+        unsafe {
+          libc::recv(self.0.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+        };
+      if len < 0 {
+        let err = io::Error::last_os_error();
+        return match err.kind() {
+          io::ErrorKind::WouldBlock => Ok(events),
+          io::ErrorKind::Interrupted => continue,
+          _ => Err(err),
+        };
+      }
+      if let Some(event) = Uevent::parse(&buf[.. len as usize]).and_then(|u| u.hotplug()) {
+        events.push(event);
+      }
+    }
+  }
+}
+
+/// Sends synthetic uevents to a `UeventSocket` made with `UeventSocket::pair`
+#[cfg(test)]
+pub struct UeventInjector(UnixDatagram);
+
+#[cfg(test)]
+impl UeventInjector {
+  pub fn send(&self, event: &Uevent) -> io::Result<()> {
+    self.0.send(&event.to_bytes()).map(|_| ())
+  }
+
+  /// Send the uevent the kernel sends for `action` on `/dev/dri/card{num}`
+  pub fn send_card(&self, action: &str, num: u32, hotplug: bool) -> io::Result<()> {
+    let mut props = HashMap::from([
+      ("ACTION".to_string(), action.to_string()),
+      ("SUBSYSTEM".to_string(), "drm".to_string()),
+      ("DEVNAME".to_string(), format!["dri/card{num}"]),
+      ("DEVTYPE".to_string(), "drm_minor".to_string()),
+    ]);
+    if hotplug {
+      props.insert("HOTPLUG".to_string(), "1".to_string());
+    }
+    self.send(&Uevent {
+      action: action.to_string(),
+      devpath: format!["/devices/virtual/drm/card{num}"],
+      props,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // As sent by i915 when a monitor is plugged into card0
+  const CONNECTOR_HOTPLUG: &[u8] =
+    b"change@/devices/pci0000:00/0000:00:02.0/drm/card0\0\
+    ACTION=change\0\
+    DEVPATH=/devices/pci0000:00/0000:00:02.0/drm/card0\0\
+    SUBSYSTEM=drm\0\
+    HOTPLUG=1\0\
+    CONNECTOR=95\0\
+    PROPERTY=96\0\
+    DEVNAME=dri/card0\0\
+    DEVTYPE=drm_minor\0\
+    SEQNUM=4121\0\
+    MAJOR=226\0\
+    MINOR=0\0";
+
+  // Sent when a lease on card1 is revoked, nothing was plugged
+  const LEASE_CHANGE: &[u8] =
+    b"change@/devices/pci0000:00/0000:01:00.0/drm/card1\0\
+    ACTION=change\0\
+    DEVPATH=/devices/pci0000:00/0000:01:00.0/drm/card1\0\
+    SUBSYSTEM=drm\0\
+    LEASE=1\0\
+    DEVNAME=dri/card1\0\
+    DEVTYPE=drm_minor\0\
+    SEQNUM=4130\0\
+    MAJOR=226\0\
+    MINOR=1\0";
+
+  fn hotplug(buf: &[u8]) -> Option<HotplugEvent> {
+    Uevent::parse(buf).and_then(|uevent| uevent.hotplug())
+  }
+
+  #[test]
+  fn parses_kernel_uevents() {
+    let uevent = Uevent::parse(CONNECTOR_HOTPLUG).unwrap();
+    assert_eq!(uevent.action, "change");
+    assert_eq!(uevent.devpath, "/devices/pci0000:00/0000:00:02.0/drm/card0");
+    assert_eq!(uevent.props.len(), 11);
+    assert_eq!(uevent.props["CONNECTOR"], "95");
+    assert_eq!(uevent.hotplug(), Some(HotplugEvent::Changed(0)));
+    assert_eq!(Uevent::parse(&uevent.to_bytes()), Some(uevent));
+
+    let lease = Uevent::parse(LEASE_CHANGE).unwrap();
+    assert_eq!(lease.props["LEASE"], "1");
+    assert_eq!(lease.hotplug(), None);
+  }
+
+  #[test]
+  fn reports_cards_coming_and_going() {
+    let card = |action: &str, devname: &str| {
+      let buf =
+        format![
+          "{action}@/devices/pci0000:00/0000:01:00.0/drm/card1\0ACTION={action}\0\
+          SUBSYSTEM=drm\0DEVNAME={devname}\0DEVTYPE=drm_minor\0"
+        ];
+      hotplug(buf.as_bytes())
+    };
+    assert_eq!(card("add", "dri/card1"), Some(HotplugEvent::CardAdded(1)));
+    assert_eq!(card("remove", "dri/card1"), Some(HotplugEvent::CardRemoved(1)));
+    // Not a hotplug without HOTPLUG=1
+    assert_eq!(card("change", "dri/card1"), None);
+    assert_eq!(card("bind", "dri/card1"), None);
+    // Render nodes have no connectors
+    assert_eq!(card("add", "dri/renderD128"), None);
+  }
+
+  #[test]
+  fn ignores_other_uevents() {
+    // Another subsystem
+    assert_eq!(
+      hotplug(
+        b"add@/devices/platform/i8042/serio0/input/input5\0ACTION=add\0\
+        SUBSYSTEM=input\0DEVNAME=input/event5\0HOTPLUG=1\0",
+      ),
+      None,
+    );
+    // A connector's own sysfs device, which has no device node
+    assert_eq!(
+      hotplug(
+        b"add@/devices/pci0000:00/0000:00:02.0/drm/card0/card0-DP-1\0ACTION=add\0\
+        SUBSYSTEM=drm\0",
+      ),
+      None,
+    );
+    // Re-broadcast by udev, behind a binary header
+    assert_eq!(hotplug(b"libudev\0\xfe\xed\xca\xfe\x28\0\0\0ACTION=change\0"), None);
+    // Malformed
+    assert_eq!(Uevent::parse(b""), None);
+    assert_eq!(Uevent::parse(b"\0\0"), None);
+    assert_eq!(
+      hotplug(b"change@/devices/virtual/drm/card0\0SUBSYSTEM=drm\0DEVNAME=dri/cardX\0HOTPLUG=1\0"),
+      None,
+    );
+    assert_eq!(hotplug(b"change /devices/virtual/drm/card0\0SUBSYSTEM=drm\0HOTPLUG=1\0"), None);
+  }
+
+  #[test]
+  fn reports_injected_hotplugs() {
+    let (injector, socket) = UeventSocket::pair().unwrap();
+    assert_eq!(socket.hotplug_events().unwrap(), []);
+
+    injector.send_card("add", 1, false).unwrap();
+    injector.send_card("change", 0, true).unwrap();
+    injector.send(&Uevent::parse(LEASE_CHANGE).unwrap()).unwrap();
+    injector.send(&Uevent::parse(CONNECTOR_HOTPLUG).unwrap()).unwrap();
+    injector.send_card("remove", 1, false).unwrap();
+    assert_eq!(socket.hotplug_events().unwrap(), [
+      HotplugEvent::CardAdded(1),
+      HotplugEvent::Changed(0),
+      HotplugEvent::Changed(0),
+      HotplugEvent::CardRemoved(1),
+    ]);
+    assert_eq!(socket.hotplug_events().unwrap(), []);
+  }
+}
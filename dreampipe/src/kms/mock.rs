@@ -0,0 +1,705 @@
+use crate::kms::AtomicRequest;
+use crate::kms::ConnectorInfo;
+use crate::kms::EncoderInfo;
+use crate::kms::KmsBackend;
+use crate::kms::Object;
+use crate::kms::PlaneInfo;
+use crate::kms::Property;
+use crate::kms::PropertyMap;
+use crate::kms::Resources;
+use crate::kms::monotonic_now;
+use crate::surface::Dmabuf;
+use drm::ClientCapability;
+use drm::control::AtomicCommitFlags;
+use drm::control::Event;
+use drm::control::Mode;
+use drm::control::PageFlipEvent;
+use drm::control::PlaneType;
+use drm::control::RawResourceHandle;
+use drm::control::connector;
+use drm::control::crtc;
+use drm::control::encoder;
+use drm::control::framebuffer;
+use drm::control::from_u32;
+use drm::control::plane;
+#[cfg(test)]
+use drm::control::property;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+const CONNECTOR_PROPS: &[&str] = &["CRTC_ID", "DPMS", "EDID"];
+const CRTC_PROPS: &[&str] = &["ACTIVE", "MODE_ID"];
+const PLANE_PROPS: &[&str] = &[
+  "FB_ID",
+  "CRTC_ID",
+  "SRC_X",
+  "SRC_Y",
+  "SRC_W",
+  "SRC_H",
+  "CRTC_X",
+  "CRTC_Y",
+  "CRTC_W",
+  "CRTC_H",
+];
+
+#[derive(Default)]
+struct MockState {
+  num: u32,
+  next_id: u32,
+  crtcs: Vec<crtc::Handle>,
+  encoders: Vec<EncoderInfo>,
+  connectors: Vec<ConnectorInfo>,
+  planes: Vec<PlaneInfo>,
+  props: HashMap<RawResourceHandle, PropertyMap>,
+  blobs: HashMap<u64, Vec<u8>>,
+  // Events are held back until their deadline, if they have one
+  events: VecDeque<(Option<Instant>, Event)>,
+  commit_failures: VecDeque<io::ErrorKind>,
+  test_failures: VecDeque<io::ErrorKind>,
+  commits: Vec<(AtomicCommitFlags, AtomicRequest)>,
+  record_commits: bool,
+  cursor_size: Option<(u32, u32)>,
+  async_page_flip: bool,
+  frames: HashMap<crtc::Handle, u32>,
+  flip_interval: Duration,
+  refresh: HashMap<crtc::Handle, Duration>,
+  last_flip: HashMap<crtc::Handle, Instant>,
+}
+
+impl MockState {
+  fn next_id(&mut self) -> u32 {
+    self.next_id += 1;
+    self.next_id
+  }
+
+  fn handle<T: From<RawResourceHandle>>(&mut self) -> T {
+    from_u32(self.next_id()).unwrap()
+  }
+
+  fn add_props(&mut self, object: RawResourceHandle, names: &[&str]) {
+    for name in names {
+      let handle = self.handle();
+      self.props.entry(object).or_default().insert(name.to_string(), Property {
+        handle,
+        value: 0,
+        enums: Vec::new(),
+      });
+    }
+  }
+
+  fn prop_value(&self, object: impl Into<RawResourceHandle>, name: &str) -> u64 {
+    self
+      .props
+      .get(&object.into())
+      .and_then(|props| props.get(name))
+      .map(|prop| prop.value)
+      .unwrap_or(0)
+  }
+
+  fn encoder_crtcs(&self, connector: &ConnectorInfo) -> u32 {
+    self
+      .encoders
+      .iter()
+      .filter(|encoder| connector.encoders.contains(&encoder.handle))
+      .fold(0, |mask, encoder| mask | encoder.possible_crtcs)
+  }
+
+  fn crtc_mask(&self, crtc: u64) -> u32 {
+    self
+      .crtcs
+      .iter()
+      .position(|handle| u32::from(*handle) as u64 == crtc)
+      .map(|i| 1 << i)
+      .unwrap_or(0)
+  }
+
+  /// Reject what a real driver would reject with EINVAL
+  fn check(&self, req: &AtomicRequest) -> io::Result<()> {
+    let invalid = || Err(io::Error::from_raw_os_error(libc::EINVAL));
+    for &(object, property, value) in req.iter() {
+      let Some(props) = self.props.get(&object) else {
+        return invalid();
+      };
+      let Some((name, _)) = props.iter().find(|(_, p)| p.handle == property) else {
+        return invalid();
+      };
+      if name != "CRTC_ID" || value == 0 {
+        continue;
+      }
+      let possible_crtcs =
+        if let Some(connector) = self
+          .connectors
+          .iter()
+          .find(|c| RawResourceHandle::from(c.handle) == object) {
+          self.encoder_crtcs(connector)
+        } else if let Some(plane) = self
+          .planes
+          .iter()
+          .find(|p| RawResourceHandle::from(p.handle) == object) {
+          plane.possible_crtcs
+        } else {
+          0
+        };
+      if possible_crtcs & self.crtc_mask(value) == 0 {
+        return invalid();
+      }
+    }
+
+    // A CRTC can only be routed to one connector at a time
+    let mut routed = HashSet::new();
+    for connector in self.connectors.iter() {
+      let crtc = req
+        .iter()
+        .find(|(object, property, _)| {
+          *object == connector.handle.into() &&
+            self.props[object].get("CRTC_ID").map(|p| p.handle) == Some(*property)
+        })
+        .map(|(_, _, value)| *value)
+        .unwrap_or_else(|| self.prop_value(connector.handle, "CRTC_ID"));
+      if crtc != 0 && !routed.insert(crtc) {
+        return invalid();
+      }
+    }
+    Ok(())
+  }
+
+  fn apply(&mut self, req: &AtomicRequest) {
+    for &(object, property, value) in req.iter() {
+      let Some(props) = self.props.get_mut(&object) else {
+        continue;
+      };
+      if let Some(prop) = props.values_mut().find(|p| p.handle == property) {
+        prop.value = value;
+      }
+    }
+    for plane in self.planes.iter_mut() {
+      let props = &self.props[&plane.handle.into()];
+      plane.crtc = from_u32(props["CRTC_ID"].value as u32);
+      plane.framebuffer = from_u32(props["FB_ID"].value as u32);
+    }
+  }
+
+  /// CRTCs affected by a request, either directly or through a plane
+  fn touched_crtcs(&self, req: &AtomicRequest) -> Vec<crtc::Handle> {
+    let mut crtcs = Vec::new();
+    for &(object, _, _) in req.iter() {
+      let crtc =
+        if let Some(crtc) = self.crtcs.iter().find(|c| RawResourceHandle::from(**c) == object) {
+          Some(*crtc)
+        } else if let Some(plane) = self
+          .planes
+          .iter()
+          .find(|p| RawResourceHandle::from(p.handle) == object) {
+          plane.crtc
+        } else {
+          None
+        };
+      if let Some(crtc) = crtc && !crtcs.contains(&crtc) {
+        crtcs.push(crtc);
+      }
+    }
+    crtcs
+  }
+}
+
+/// In-memory KMS device that can be scripted with connectors, encoders,
+/// planes and failures. Commits are checked for the routing mistakes a driver
+/// would reject, then applied so that later reads see the new values. Clones
+/// are the same device, so a test can keep scripting one it handed over.
+#[derive(Clone)]
+pub struct MockDevice {
+  state: Arc<Mutex<MockState>>,
+}
+
+impl Default for MockDevice {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl MockDevice {
+  pub fn new() -> Self {
+    Self::with_num(0)
+  }
+
+  pub fn with_num(num: u32) -> Self {
+    Self {
+      state: Arc::new(Mutex::new(MockState {
+        num,
+        flip_interval: Duration::from_micros(16_667),
+        record_commits: true,
+        ..Default::default()
+      })),
+    }
+  }
+
+  fn state(&self) -> MutexGuard<'_, MockState> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  pub fn add_crtc(&self) -> crtc::Handle {
+    let mut state = self.state();
+    let handle: crtc::Handle = state.handle();
+    state.crtcs.push(handle);
+    state.add_props(handle.into(), CRTC_PROPS);
+    handle
+  }
+
+  /// `possible_crtcs` is a bitmask over the CRTCs in the order they were added
+  pub fn add_encoder(&self, possible_crtcs: u32) -> encoder::Handle {
+    let mut state = self.state();
+    let handle = state.handle();
+    state.encoders.push(EncoderInfo {
+      handle,
+      possible_crtcs,
+    });
+    handle
+  }
+
+  pub fn add_connector(
+    &self,
+    interface: connector::Interface,
+    modes: Vec<Mode>,
+    encoders: Vec<encoder::Handle>,
+  ) -> connector::Handle {
+    let mut state = self.state();
+    let handle: connector::Handle = state.handle();
+    let interface_id =
+      state.connectors.iter().filter(|c| c.interface == interface).count() as u32 + 1;
+    state.connectors.push(ConnectorInfo {
+      handle,
+      interface,
+      interface_id,
+      state: if modes.is_empty() {
+        connector::State::Disconnected
+      } else {
+        connector::State::Connected
+      },
+      modes,
+      encoders,
+    });
+    state.add_props(handle.into(), CONNECTOR_PROPS);
+    handle
+  }
+
+  pub fn add_plane(
+    &self,
+    planetype: PlaneType,
+    possible_crtcs: u32,
+    formats: Vec<u32>,
+  ) -> plane::Handle {
+    let mut state = self.state();
+    let handle: plane::Handle = state.handle();
+    state.planes.push(PlaneInfo {
+      handle,
+      crtc: None,
+      framebuffer: None,
+      possible_crtcs,
+      formats,
+    });
+    state.add_props(handle.into(), PLANE_PROPS);
+    let type_handle = state.handle();
+    state.props.entry(handle.into()).or_default().insert("type".into(), Property {
+      handle: type_handle,
+      value: planetype as u64,
+      enums: vec![
+        ("Overlay".into(), PlaneType::Overlay as u64),
+        ("Primary".into(), PlaneType::Primary as u64),
+        ("Cursor".into(), PlaneType::Cursor as u64),
+      ],
+    });
+    handle
+  }
+
+  /// Hold page flips on `crtc` back until `interval` after the previous one,
+  /// like a real vblank would, and stamp them with the actual time
+  pub fn set_refresh(&self, crtc: crtc::Handle, interval: Duration) {
+    self.state().refresh.insert(crtc, interval);
+  }
+
+  /// Stop keeping commits around for `take_commits`, for devices that run for
+  /// longer than a test
+  pub fn record_commits(&self, record: bool) {
+    self.state().record_commits = record;
+  }
+}
+
+// Scripting only tests need
+#[cfg(test)]
+impl MockDevice {
+  /// Add or overwrite a property on an object
+  pub fn set_property(&self, object: Object, name: &str, value: property::RawValue) {
+    let mut state = self.state();
+    let object = object.into();
+    if !state.props.get(&object).is_some_and(|props| props.contains_key(name)) {
+      state.add_props(object, &[name]);
+    }
+    state.props.get_mut(&object).unwrap().get_mut(name).unwrap().value = value;
+  }
+
+  /// Store `data` in a new blob and point the property `name` at it
+  pub fn set_blob_property(&self, object: Object, name: &str, data: &[u8]) -> u64 {
+    let blob = self.create_blob(data).unwrap();
+    self.set_property(object, name, blob);
+    blob
+  }
+
+  /// Plug or unplug a monitor. Unplugged connectors report no modes.
+  pub fn set_connected(&self, handle: connector::Handle, modes: Vec<Mode>) {
+    let mut state = self.state();
+    if let Some(connector) = state.connectors.iter_mut().find(|c| c.handle == handle) {
+      connector.state = if modes.is_empty() {
+        connector::State::Disconnected
+      } else {
+        connector::State::Connected
+      };
+      connector.modes = modes;
+    }
+  }
+
+  /// Fail the next non test-only commit with `kind`. Queued failures are used
+  /// up in order.
+  pub fn fail_commit(&self, kind: io::ErrorKind) {
+    self.state().commit_failures.push_back(kind);
+  }
+
+  /// Fail the next test-only commit with `kind`, like a driver turning down a
+  /// configuration it can't do
+  pub fn fail_test_commit(&self, kind: io::ErrorKind) {
+    self.state().test_failures.push_back(kind);
+  }
+
+  pub fn queue_event(&self, event: Event) {
+    self.state().events.push_back((None, event));
+  }
+
+  /// Timestamp spacing of generated page flip events
+  pub fn set_flip_interval(&self, interval: Duration) {
+    self.state().flip_interval = interval;
+  }
+
+  /// What `DRM_CAP_CURSOR_WIDTH/HEIGHT` report
+  pub fn set_cursor_size(&self, size: Option<(u32, u32)>) {
+    self.state().cursor_size = size;
+  }
+
+  /// What `DRM_CAP_ATOMIC_ASYNC_PAGE_FLIP` reports. Async commits are
+  /// rejected without it.
+  pub fn set_async_page_flip(&self, supported: bool) {
+    self.state().async_page_flip = supported;
+  }
+
+  /// Every commit that went through, including test-only ones
+  pub fn take_commits(&self) -> Vec<(AtomicCommitFlags, AtomicRequest)> {
+    std::mem::take(&mut self.state().commits)
+  }
+
+  pub fn property_value(&self, object: Object, name: &str) -> property::RawValue {
+    self.state().prop_value(object, name)
+  }
+}
+
+impl KmsBackend for MockDevice {
+  fn num(&self) -> u32 {
+    self.state().num
+  }
+
+  fn set_capability(&self, _cap: ClientCapability, _enable: bool) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn cursor_size(&self) -> Option<(u32, u32)> {
+    self.state().cursor_size
+  }
+
+  fn async_page_flip(&self) -> bool {
+    self.state().async_page_flip
+  }
+
+  fn resources(&self) -> io::Result<Resources> {
+    let state = self.state();
+    Ok(Resources {
+      crtcs: state.crtcs.clone(),
+      connectors: state.connectors.iter().map(|c| c.handle).collect(),
+    })
+  }
+
+  fn connector(&self, handle: connector::Handle, _probe: bool) -> io::Result<ConnectorInfo> {
+    let state = self.state();
+    state.connectors.iter().find(|c| c.handle == handle).cloned().ok_or_else(|| {
+      io::Error::from_raw_os_error(libc::ENOENT)
+    })
+  }
+
+  fn encoder(&self, handle: encoder::Handle) -> io::Result<EncoderInfo> {
+    let state = self.state();
+    state.encoders.iter().find(|e| e.handle == handle).cloned().ok_or_else(|| {
+      io::Error::from_raw_os_error(libc::ENOENT)
+    })
+  }
+
+  fn planes(&self) -> io::Result<Vec<plane::Handle>> {
+    Ok(self.state().planes.iter().map(|p| p.handle).collect())
+  }
+
+  fn plane(&self, handle: plane::Handle) -> io::Result<PlaneInfo> {
+    let state = self.state();
+    state.planes.iter().find(|p| p.handle == handle).cloned().ok_or_else(|| {
+      io::Error::from_raw_os_error(libc::ENOENT)
+    })
+  }
+
+  fn properties(&self, object: Object) -> io::Result<PropertyMap> {
+    let state = self.state();
+    state.props.get(&object.into()).cloned().ok_or_else(|| {
+      io::Error::from_raw_os_error(libc::ENOENT)
+    })
+  }
+
+  fn blob(&self, blob: u64) -> io::Result<Vec<u8>> {
+    let state = self.state();
+    state.blobs.get(&blob).cloned().ok_or_else(|| {
+      io::Error::from_raw_os_error(libc::ENOENT)
+    })
+  }
+
+  fn create_blob(&self, data: &[u8]) -> io::Result<u64> {
+    let mut state = self.state();
+    let blob = state.next_id() as u64;
+    state.blobs.insert(blob, data.to_vec());
+    Ok(blob)
+  }
+
+  fn destroy_blob(&self, blob: u64) -> io::Result<()> {
+    self.state().blobs.remove(&blob).map(|_| ()).ok_or_else(|| {
+      io::Error::from_raw_os_error(libc::ENOENT)
+    })
+  }
+
+  fn remove_framebuffer(&self, _fb: framebuffer::Handle) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn import_dmabuf(&self, _dmabuf: &Dmabuf) -> io::Result<framebuffer::Handle> {
+    Ok(self.state().handle())
+  }
+
+  fn commit(&self, flags: AtomicCommitFlags, req: &AtomicRequest) -> io::Result<()> {
+    let mut state = self.state();
+    if state.record_commits {
+      state.commits.push((flags, req.clone()));
+    }
+    state.check(req)?;
+    if flags.contains(AtomicCommitFlags::PAGE_FLIP_ASYNC) && !state.async_page_flip {
+      return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    if flags.contains(AtomicCommitFlags::TEST_ONLY) {
+      return match state.test_failures.pop_front() {
+        Some(kind) => Err(kind.into()),
+        None => Ok(()),
+      };
+    }
+    if let Some(kind) = state.commit_failures.pop_front() {
+      return Err(kind.into());
+    }
+    state.apply(req);
+    if flags.contains(AtomicCommitFlags::PAGE_FLIP_EVENT) {
+      for crtc in state.touched_crtcs(req) {
+        let frame = {
+          let frame = state.frames.entry(crtc).or_default();
+          *frame += 1;
+          *frame
+        };
+        let (due, duration) =
+          match state.refresh.get(&crtc) {
+            // Async flips don't wait for vblank
+            Some(_) if flags.contains(AtomicCommitFlags::PAGE_FLIP_ASYNC) => {
+              (Some(Instant::now()), monotonic_now())
+            },
+            Some(&interval) => {
+              let now = Instant::now();
+              let due =
+                state
+                  .last_flip
+                  .get(&crtc)
+                  .map(|last| (*last + interval).max(now))
+                  .unwrap_or(now + interval);
+              state.last_flip.insert(crtc, due);
+              (Some(due), monotonic_now() + (due - now))
+            },
+            None => (None, state.flip_interval * frame),
+          };
+        let event = PageFlipEvent {
+          frame,
+          duration,
+          crtc,
+        };
+        state.events.push_back((due, Event::PageFlip(event)));
+      }
+    }
+    Ok(())
+  }
+
+  fn events(&self) -> io::Result<Vec<Event>> {
+    let mut state = self.state();
+    let now = Instant::now();
+    let (ready, held): (VecDeque<_>, VecDeque<_>) =
+      std::mem::take(&mut state.events)
+        .into_iter()
+        .partition(|(due, _)| due.is_none_or(|due| due <= now));
+    state.events = held;
+    if ready.is_empty() {
+      return Err(io::ErrorKind::WouldBlock.into());
+    }
+    Ok(ready.into_iter().map(|(_, event)| event).collect())
+  }
+
+  fn next_event_time(&self) -> Option<Instant> {
+    let state = self.state();
+    state.events.iter().map(|(due, _)| due.unwrap_or_else(Instant::now)).min()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kms::make_mode;
+  use drm::control::ResourceHandle;
+
+  // Two CRTCs, a connector only the first can drive and a primary plane
+  // either can use
+  fn device() -> (MockDevice, [crtc::Handle; 2], connector::Handle, plane::Handle) {
+    let device = MockDevice::new();
+    let crtcs = [device.add_crtc(), device.add_crtc()];
+    let encoder = device.add_encoder(0b01);
+    let connector =
+      device.add_connector(
+        connector::Interface::HDMIA,
+        vec![make_mode(1920, 1080, 60)],
+        vec![encoder],
+      );
+    let plane = device.add_plane(PlaneType::Primary, 0b11, vec![]);
+    (device, crtcs, connector, plane)
+  }
+
+  fn set<H: ResourceHandle + Into<Object>>(
+    device: &MockDevice,
+    req: &mut AtomicRequest,
+    handle: H,
+    name: &str,
+    value: u64,
+  ) {
+    let props = device.properties(handle.into()).unwrap();
+    req.add_property(handle, props[name].handle(), property::Value::UnsignedRange(value));
+  }
+
+  fn raw(crtc: crtc::Handle) -> u64 {
+    u32::from(crtc) as u64
+  }
+
+  fn flips(device: &MockDevice) -> Vec<(u32, Duration, crtc::Handle)> {
+    device
+      .events()
+      .unwrap()
+      .into_iter()
+      .map(|event| match event {
+        Event::PageFlip(flip) => (flip.frame, flip.duration, flip.crtc),
+        _ => panic!["Only page flips are made up"],
+      })
+      .collect()
+  }
+
+  #[test]
+  fn routes_only_to_possible_crtcs() {
+    let (device, [a, b], connector, _) = device();
+    let mut req = AtomicRequest::new();
+    set(&device, &mut req, connector, "CRTC_ID", raw(b));
+    let rejected = device.commit(AtomicCommitFlags::ALLOW_MODESET, &req).unwrap_err();
+    assert_eq!(rejected.raw_os_error(), Some(libc::EINVAL));
+    assert_eq!(device.property_value(connector.into(), "CRTC_ID"), 0);
+
+    set(&device, &mut req, connector, "CRTC_ID", raw(a));
+    device.commit(AtomicCommitFlags::ALLOW_MODESET, &req).unwrap();
+    assert_eq!(device.property_value(connector.into(), "CRTC_ID"), raw(a));
+    assert_eq!(device.take_commits().len(), 2);
+    assert!(device.take_commits().is_empty());
+
+    // The CRTC is taken
+    let encoder = device.add_encoder(0b11);
+    let other =
+      device.add_connector(connector::Interface::DisplayPort, Vec::new(), vec![encoder]);
+    let mut req = AtomicRequest::new();
+    set(&device, &mut req, other, "CRTC_ID", raw(a));
+    assert!(device.commit(AtomicCommitFlags::ALLOW_MODESET, &req).is_err());
+  }
+
+  #[test]
+  fn fails_scripted_commits() {
+    let (device, [a, _], connector, _) = device();
+    let mut req = AtomicRequest::new();
+    set(&device, &mut req, connector, "CRTC_ID", raw(a));
+    device.fail_commit(io::ErrorKind::NotFound);
+
+    // Test-only commits don't use the failure up
+    device.commit(AtomicCommitFlags::TEST_ONLY, &req).unwrap();
+    let failed = device.commit(AtomicCommitFlags::empty(), &req).unwrap_err();
+    assert_eq!(failed.kind(), io::ErrorKind::NotFound);
+    assert_eq!(device.property_value(connector.into(), "CRTC_ID"), 0);
+    device.commit(AtomicCommitFlags::empty(), &req).unwrap();
+    assert_eq!(device.property_value(connector.into(), "CRTC_ID"), raw(a));
+  }
+
+  #[test]
+  fn flips_what_commits_touch() {
+    let (device, [a, b], _, plane) = device();
+    device.set_flip_interval(Duration::from_millis(10));
+    let mut req = AtomicRequest::new();
+    set(&device, &mut req, plane, "CRTC_ID", raw(a));
+    set(&device, &mut req, plane, "FB_ID", 1);
+    for _ in 0 .. 2 {
+      device.commit(AtomicCommitFlags::PAGE_FLIP_EVENT, &req).unwrap();
+    }
+    assert_eq!(flips(&device), [
+      (1, Duration::from_millis(10), a),
+      (2, Duration::from_millis(20), a),
+    ]);
+    assert!(device.events().is_err_and(|e| e.kind() == io::ErrorKind::WouldBlock));
+
+    device.queue_event(Event::PageFlip(PageFlipEvent {
+      frame: 7,
+      duration: Duration::from_secs(1),
+      crtc: b,
+    }));
+    assert_eq!(flips(&device), [(7, Duration::from_secs(1), b)]);
+
+    // Async flips need the capability
+    let flags = AtomicCommitFlags::PAGE_FLIP_EVENT | AtomicCommitFlags::PAGE_FLIP_ASYNC;
+    assert!(!device.async_page_flip());
+    assert!(device.commit(flags, &req).is_err());
+    device.set_async_page_flip(true);
+    assert!(device.async_page_flip());
+    device.commit(flags, &req).unwrap();
+    assert_eq!(flips(&device), [(3, Duration::from_millis(30), a)]);
+  }
+
+  #[test]
+  fn keeps_blobs_and_caps() {
+    let (device, _, connector, _) = device();
+    let blob = device.set_blob_property(connector.into(), "EDID", b"edid");
+    assert_eq!(device.properties(connector.into()).unwrap()["EDID"].value(), blob);
+    assert_eq!(device.blob(blob).unwrap(), b"edid");
+    device.destroy_blob(blob).unwrap();
+    assert!(device.blob(blob).is_err());
+
+    assert_eq!(device.cursor_size(), None);
+    device.set_cursor_size(Some((256, 256)));
+    assert_eq!(device.cursor_size(), Some((256, 256)));
+  }
+}
@@ -0,0 +1,505 @@
+pub mod mock;
+
+use crate::context::Card;
+use crate::surface::Dmabuf;
+use drm::ClientCapability;
+use drm::Device;
+use drm::buffer::DrmFourcc;
+use drm::buffer::DrmModifier;
+use drm::buffer::PlanarBuffer;
+use drm::buffer;
+use drm::DriverCapability;
+use drm::control::AtomicCommitFlags;
+use drm::control::CrtcListFilter;
+use drm::control::Device as ControlDevice;
+use drm::control::Event;
+use drm::control::FbCmd2Flags;
+use drm::control::Mode;
+use drm::control::RawResourceHandle;
+use drm::control::ResourceHandle;
+use drm::control::ResourceHandles;
+use drm::control::atomic;
+use drm::control::connector;
+use drm::control::crtc;
+use drm::control::encoder;
+use drm::control::framebuffer;
+use drm::control::plane;
+use drm::control::property;
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::AsFd;
+use std::time::Duration;
+use std::time::Instant;
+
+pub type PropertyMap = HashMap<String, Property>;
+
+/// A property of a KMS object along with the value it had when it was read
+#[derive(Clone, Debug)]
+pub struct Property {
+  pub handle: property::Handle,
+  pub value: property::RawValue,
+  /// Names and values if this is an enum property
+  pub enums: Vec<(String, property::RawValue)>,
+}
+
+impl Property {
+  pub fn handle(&self) -> property::Handle {
+    self.handle
+  }
+
+  pub fn value(&self) -> property::RawValue {
+    self.value
+  }
+
+  pub fn enum_value(&self, name: &str) -> Option<property::RawValue> {
+    self.enums.iter().find(|(n, _)| n == name).map(|(_, value)| *value)
+  }
+}
+
+/// Objects that have properties
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Object {
+  Connector(connector::Handle),
+  Crtc(crtc::Handle),
+  Plane(plane::Handle),
+}
+
+impl From<connector::Handle> for Object {
+  fn from(handle: connector::Handle) -> Self {
+    Self::Connector(handle)
+  }
+}
+
+impl From<crtc::Handle> for Object {
+  fn from(handle: crtc::Handle) -> Self {
+    Self::Crtc(handle)
+  }
+}
+
+impl From<plane::Handle> for Object {
+  fn from(handle: plane::Handle) -> Self {
+    Self::Plane(handle)
+  }
+}
+
+impl From<Object> for RawResourceHandle {
+  fn from(object: Object) -> Self {
+    match object {
+      Object::Connector(handle) => handle.into(),
+      Object::Crtc(handle) => handle.into(),
+      Object::Plane(handle) => handle.into(),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Resources {
+  pub crtcs: Vec<crtc::Handle>,
+  pub connectors: Vec<connector::Handle>,
+}
+
+impl Resources {
+  /// CRTCs whose index is set in a `possible_crtcs` bitmask
+  pub fn filter_crtcs(&self, possible_crtcs: u32) -> Vec<crtc::Handle> {
+    self
+      .crtcs
+      .iter()
+      .enumerate()
+      .filter(|(i, _)| possible_crtcs & (1 << i) != 0)
+      .map(|(_, crtc)| *crtc)
+      .collect()
+  }
+}
+
+impl From<ResourceHandles> for Resources {
+  fn from(resources: ResourceHandles) -> Self {
+    Self {
+      crtcs: resources.crtcs().to_vec(),
+      connectors: resources.connectors().to_vec(),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectorInfo {
+  pub handle: connector::Handle,
+  pub interface: connector::Interface,
+  pub interface_id: u32,
+  pub state: connector::State,
+  pub modes: Vec<Mode>,
+  pub encoders: Vec<encoder::Handle>,
+}
+
+#[derive(Clone, Debug)]
+pub struct EncoderInfo {
+  pub handle: encoder::Handle,
+  pub possible_crtcs: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlaneInfo {
+  pub handle: plane::Handle,
+  pub crtc: Option<crtc::Handle>,
+  pub framebuffer: Option<framebuffer::Handle>,
+  pub possible_crtcs: u32,
+  pub formats: Vec<u32>,
+}
+
+/// Property changes for a single atomic commit. Unlike `AtomicModeReq` it can
+/// be inspected, which is what backends other than a real card need.
+#[derive(Clone, Debug, Default)]
+pub struct AtomicRequest {
+  props: Vec<(RawResourceHandle, property::Handle, property::RawValue)>,
+}
+
+impl AtomicRequest {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Set a property, replacing any earlier value for it
+  pub fn add_property<H: ResourceHandle>(
+    &mut self,
+    handle: H,
+    property: property::Handle,
+    value: property::Value,
+  ) {
+    let object: RawResourceHandle = handle.into();
+    let value: property::RawValue = value.into();
+    match self.props.iter_mut().find(|(o, p, _)| *o == object && *p == property) {
+      Some((_, _, old)) => *old = value,
+      None => self.props.push((object, property, value)),
+    }
+  }
+
+  pub fn iter(
+    &self,
+  ) -> impl Iterator<Item = &(RawResourceHandle, property::Handle, property::RawValue)> {
+    self.props.iter()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.props.is_empty()
+  }
+}
+
+impl From<&AtomicRequest> for atomic::AtomicModeReq {
+  fn from(req: &AtomicRequest) -> Self {
+    let mut atomic_req = atomic::AtomicModeReq::new();
+    for &(object, property, value) in req.iter() {
+      atomic_req.add_raw_property(object, property, value);
+    }
+    atomic_req
+  }
+}
+
+/// Everything the compositor needs from a KMS device. `Card` is the real
+/// thing, `mock::MockDevice` can be scripted to stand in for it.
+pub trait KmsBackend {
+  /// Card number, used to name displays
+  fn num(&self) -> u32;
+
+  /// PCI vendor and device of the GPU behind the device, if there is one
+  fn pci_ids(&self) -> Option<(u32, u32)> {
+    None
+  }
+
+  fn set_capability(&self, cap: ClientCapability, enable: bool) -> io::Result<()>;
+
+  /// Largest cursor plane buffer the driver supports, if it says
+  fn cursor_size(&self) -> Option<(u32, u32)> {
+    None
+  }
+
+  /// Atomic commits can flip without waiting for vblank
+  fn async_page_flip(&self) -> bool {
+    false
+  }
+
+  fn resources(&self) -> io::Result<Resources>;
+  fn connector(&self, handle: connector::Handle, probe: bool) -> io::Result<ConnectorInfo>;
+  fn encoder(&self, handle: encoder::Handle) -> io::Result<EncoderInfo>;
+  fn planes(&self) -> io::Result<Vec<plane::Handle>>;
+  fn plane(&self, handle: plane::Handle) -> io::Result<PlaneInfo>;
+  fn properties(&self, object: Object) -> io::Result<PropertyMap>;
+  fn blob(&self, blob: u64) -> io::Result<Vec<u8>>;
+  fn create_blob(&self, data: &[u8]) -> io::Result<u64>;
+  fn destroy_blob(&self, blob: u64) -> io::Result<()>;
+  fn remove_framebuffer(&self, fb: framebuffer::Handle) -> io::Result<()>;
+
+  /// Add a client buffer as a framebuffer. The driver rejects formats and
+  /// modifiers none of its planes can scan out.
+  fn import_dmabuf(&self, dmabuf: &Dmabuf) -> io::Result<framebuffer::Handle>;
+  fn commit(&self, flags: AtomicCommitFlags, req: &AtomicRequest) -> io::Result<()>;
+
+  /// Pending events. Fails with `WouldBlock` once there are none left.
+  fn events(&self) -> io::Result<Vec<Event>>;
+
+  /// Backends without an fd to poll say when `events` has something next
+  fn next_event_time(&self) -> Option<Instant> {
+    None
+  }
+
+  fn create_mode_blob(&self, mode: &Mode) -> io::Result<u64> {
+    let raw: drm_ffi::drm_mode_modeinfo = (*mode).into();
+    let data =
+      unsafe {
+        std::slice::from_raw_parts(
+          &raw as *const drm_ffi::drm_mode_modeinfo as *const u8,
+          std::mem::size_of_val(&raw),
+        )
+      };
+    self.create_blob(data)
+  }
+}
+
+// `possible_crtcs` as a mask of indices into `resources.crtcs`
+fn crtc_mask(resources: &ResourceHandles, possible_crtcs: CrtcListFilter) -> u32 {
+  let crtcs = resources.filter_crtcs(possible_crtcs);
+  resources
+    .crtcs()
+    .iter()
+    .enumerate()
+    .filter(|(_, crtc)| crtcs.contains(crtc))
+    .fold(0, |mask, (i, _)| mask | (1 << i))
+}
+
+impl KmsBackend for Card {
+  fn num(&self) -> u32 {
+    Card::num(self)
+  }
+
+  fn pci_ids(&self) -> Option<(u32, u32)> {
+    Card::pci_ids(self)
+  }
+
+  fn set_capability(&self, cap: ClientCapability, enable: bool) -> io::Result<()> {
+    self.set_client_capability(cap, enable)
+  }
+
+  fn cursor_size(&self) -> Option<(u32, u32)> {
+    let width = self.get_driver_capability(DriverCapability::CursorWidth).ok()?;
+    let height = self.get_driver_capability(DriverCapability::CursorHeight).ok()?;
+    Some((width as u32, height as u32))
+  }
+
+  fn async_page_flip(&self) -> bool {
+    // The legacy capability alone only covers the legacy page flip ioctl
+    [DriverCapability::ASyncPageFlip, DriverCapability::AtomicASyncPageFlip]
+      .into_iter()
+      .all(|cap| self.get_driver_capability(cap).is_ok_and(|value| value != 0))
+  }
+
+  fn resources(&self) -> io::Result<Resources> {
+    Ok(self.resource_handles()?.into())
+  }
+
+  fn connector(&self, handle: connector::Handle, probe: bool) -> io::Result<ConnectorInfo> {
+    let info = self.get_connector(handle, probe)?;
+    Ok(ConnectorInfo {
+      handle,
+      interface: info.interface(),
+      interface_id: info.interface_id(),
+      state: info.state(),
+      modes: info.modes().to_vec(),
+      encoders: info.encoders().to_vec(),
+    })
+  }
+
+  fn encoder(&self, handle: encoder::Handle) -> io::Result<EncoderInfo> {
+    let info = self.get_encoder(handle)?;
+    Ok(EncoderInfo {
+      handle,
+      possible_crtcs: crtc_mask(self.first_resources()?, info.possible_crtcs()),
+    })
+  }
+
+  fn planes(&self) -> io::Result<Vec<plane::Handle>> {
+    self.plane_handles()
+  }
+
+  fn plane(&self, handle: plane::Handle) -> io::Result<PlaneInfo> {
+    let info = self.get_plane(handle)?;
+    Ok(PlaneInfo {
+      handle,
+      crtc: info.crtc(),
+      framebuffer: info.framebuffer(),
+      possible_crtcs: crtc_mask(self.first_resources()?, info.possible_crtcs()),
+      formats: info.formats().to_vec(),
+    })
+  }
+
+  fn properties(&self, object: Object) -> io::Result<PropertyMap> {
+    let set =
+      match object {
+        Object::Connector(handle) => self.get_properties(handle)?,
+        Object::Crtc(handle) => self.get_properties(handle)?,
+        Object::Plane(handle) => self.get_properties(handle)?,
+      };
+    let mut props = PropertyMap::new();
+    for (&handle, &value) in set.iter() {
+      let info = self.get_property(handle)?;
+      let enums = match info.value_type() {
+        property::ValueType::Enum(values) => values
+          .values()
+          .1
+          .iter()
+          .map(|e| (e.name().to_string_lossy().into_owned(), e.value()))
+          .collect(),
+        _ => Vec::new(),
+      };
+      props.insert(info.name().to_string_lossy().into_owned(), Property {
+        handle,
+        value,
+        enums,
+      });
+    }
+    Ok(props)
+  }
+
+  fn blob(&self, blob: u64) -> io::Result<Vec<u8>> {
+    self.get_property_blob(blob)
+  }
+
+  fn create_blob(&self, data: &[u8]) -> io::Result<u64> {
+    let mut data = data.to_vec();
+    let blob = drm_ffi::mode::create_property_blob(self.as_fd(), &mut data)?;
+    Ok(blob.blob_id as u64)
+  }
+
+  fn destroy_blob(&self, blob: u64) -> io::Result<()> {
+    self.destroy_property_blob(blob)
+  }
+
+  fn remove_framebuffer(&self, fb: framebuffer::Handle) -> io::Result<()> {
+    self.destroy_framebuffer(fb)
+  }
+
+  fn import_dmabuf(&self, dmabuf: &Dmabuf) -> io::Result<framebuffer::Handle> {
+    if dmabuf.planes.is_empty() || dmabuf.planes.len() > 4 {
+      return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    // Planes in the same buffer get the same handle
+    let mut handles = [None; 4];
+    for (i, plane) in dmabuf.planes.iter().enumerate() {
+      match self.prime_fd_to_buffer(plane.fd.as_fd()) {
+        Ok(handle) => handles[i] = Some(handle),
+        Err(err) => {
+          close_handles(self, &handles);
+          return Err(err);
+        },
+      }
+    }
+    let imported = ImportedDmabuf {
+      dmabuf,
+      handles,
+    };
+    let flags =
+      match imported.modifier() {
+        Some(_) => FbCmd2Flags::MODIFIERS,
+        None => FbCmd2Flags::empty(),
+      };
+    let fb = self.add_planar_framebuffer(&imported, flags);
+
+    // The framebuffer keeps the buffers alive without the handles
+    close_handles(self, &handles);
+    fb
+  }
+
+  fn commit(&self, flags: AtomicCommitFlags, req: &AtomicRequest) -> io::Result<()> {
+    self.atomic_commit(flags, req.into())
+  }
+
+  fn events(&self) -> io::Result<Vec<Event>> {
+    Ok(self.receive_events()?.collect())
+  }
+}
+
+// Each distinct handle once, closing one twice could close another buffer
+// that got the number in between
+fn close_handles(card: &Card, handles: &[Option<buffer::Handle>; 4]) {
+  let mut closed = Vec::with_capacity(4);
+  for handle in handles.iter().flatten() {
+    if !closed.contains(handle) {
+      card.close_buffer(*handle).ok();
+      closed.push(*handle);
+    }
+  }
+}
+
+// A DMA-BUF along with the GEM handle each plane got on this card
+struct ImportedDmabuf<'a> {
+  dmabuf: &'a Dmabuf,
+  handles: [Option<buffer::Handle>; 4],
+}
+
+impl PlanarBuffer for ImportedDmabuf<'_> {
+  fn size(&self) -> (u32, u32) {
+    self.dmabuf.size
+  }
+
+  fn format(&self) -> DrmFourcc {
+    self.dmabuf.format
+  }
+
+  fn modifier(&self) -> Option<DrmModifier> {
+    Some(self.dmabuf.modifier).filter(|modifier| *modifier != DrmModifier::Invalid)
+  }
+
+  fn pitches(&self) -> [u32; 4] {
+    let mut pitches = [0; 4];
+    for (pitch, plane) in pitches.iter_mut().zip(self.dmabuf.planes.iter()) {
+      *pitch = plane.stride;
+    }
+    pitches
+  }
+
+  fn handles(&self) -> [Option<buffer::Handle>; 4] {
+    self.handles
+  }
+
+  fn offsets(&self) -> [u32; 4] {
+    let mut offsets = [0; 4];
+    for (offset, plane) in offsets.iter_mut().zip(self.dmabuf.planes.iter()) {
+      *offset = plane.offset;
+    }
+    offsets
+  }
+}
+
+/// CLOCK_MONOTONIC, the clock page flip timestamps are taken from
+pub fn monotonic_now() -> Duration {
+  let mut ts = libc::timespec {
+    tv_sec: 0,
+    tv_nsec: 0,
+  };
+  unsafe {
+    libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+  }
+  Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Build a mode with CVT reduced blanking style timings
+pub fn make_mode(width: u16, height: u16, refresh: u32) -> Mode {
+  let htotal = width + 160;
+  let vtotal = height + height / 30 + 6;
+  let mut name = [0; 32];
+  for (dst, src) in name.iter_mut().zip(format!["{width}x{height}"].bytes()) {
+    *dst = src as std::ffi::c_char;
+  }
+  drm_ffi::drm_mode_modeinfo {
+    clock: (htotal as u32 * vtotal as u32 * refresh).div_ceil(1000),
+    hdisplay: width,
+    hsync_start: width + 48,
+    hsync_end: width + 80,
+    htotal,
+    vdisplay: height,
+    vsync_start: height + 3,
+    vsync_end: height + 9,
+    vtotal,
+    vrefresh: refresh,
+    name,
+    ..Default::default()
+  }.into()
+}
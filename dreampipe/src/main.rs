@@ -1,24 +1,52 @@
 mod buffer;
+mod color;
+mod color_pass;
+mod compositor;
 mod context;
+mod cursor;
+mod damage;
 mod display;
+mod dmabuf;
+mod edid;
 mod error;
+mod event_loop;
+mod fence;
+mod formats;
 mod fourcc;
 mod gpu;
+mod headless;
+mod hotplug;
+mod kms;
+mod overlay;
+mod restore;
+mod scene;
+mod scheduler;
+mod surface;
 mod util;
+mod wallpaper;
+mod yuv;
 
 use crate::context::AppContext;
 use crate::context::Card;
+use crate::cursor::Cursor;
 use crate::display::Display;
+use crate::event_loop::EventLoop;
+use crate::event_loop::Source;
+use crate::event_loop::Timer;
+use crate::event_loop::Wake;
+use crate::headless::HeadlessOutputs;
+use crate::headless::virtual_device;
+use crate::hotplug::HotplugEvent;
+use crate::hotplug::UeventSocket;
 use crate::util::config::CompositorConfig;
 use crate::util::config::Config;
 use crate::util::layout_displays;
 use nix::fcntl::FcntlArg;
 use nix::fcntl::OFlag;
 use nix::fcntl::fcntl;
-use notify::Event as NotifyEvent;
-use notify::RecursiveMode;
-use notify::Watcher;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 use taffy::NodeId;
@@ -26,71 +54,103 @@ use taffy::TaffyTree;
 
 // 65536x65536
 pub const VIRTUAL_SCREEN_EXTENTS: (i32, i32) = (0x10000, 0x10000);
+const REPROBE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Set up a context for `card` and start waiting on its events
+async fn open_card(card: Card, event_loop: &mut EventLoop) -> Option<AppContext> {
+  let flags = fcntl(&card, FcntlArg::F_GETFL).expect("Failed to get card FD flags");
+  let new_flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+  fcntl(&card, FcntlArg::F_SETFL(new_flags)).expect("Failed to set new flags");
+  let context: AppContext = AppContext::init(card).await;
+  let num = context.kms.num();
+  if let Some(Err(e)) = context.fd().map(|fd| event_loop.add_fd(Source::Card(num), fd)) {
+    tracing::error!["Unable to poll card{num}: {e}"];
+    return None;
+  }
+  Some(context)
+}
 
 #[tokio::main]
 async fn main() {
+  restore::install_panic_hook();
+
   // Initialize config watcher
   let config_path = CompositorConfig::config_path().unwrap_or("/dev/null".into());
-  let (mut tx, mut rx) = crossbeam::channel::bounded(2);
-  tx.send(Ok(NotifyEvent::default())).unwrap();
-  let mut config_watcher = notify::recommended_watcher(tx).ok();
-  config_watcher.map(
-    |mut watcher| watcher.watch(&config_path, RecursiveMode::NonRecursive).ok(),
-  );
+  let mut event_loop = EventLoop::new(config_path.clone());
   let mut config = Config::new(&config_path).unwrap_or_default();
 
+  // Virtual displays replace the cards if asked for, or if there are no cards
+  let headless_outputs =
+    config.get::<HeadlessOutputs>(CompositorConfig::HEADLESS_OUTPUTS_KEY);
+
   // Open all the cards! Why not?
-  let cards = Card::open_all().into_iter().map(|card| {
-    let flags = fcntl(&card, FcntlArg::F_GETFL).expect("Failed to get card FD flags");
-    let new_flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
-    fcntl(&card, FcntlArg::F_SETFL(new_flags)).expect("Failed to set new flags");
-    card
-  }).collect::<Vec<_>>();
+  let cards = match headless_outputs {
+    Some(_) => Vec::new(),
+    None => Card::open_all(),
+  };
 
   // Create gbm devices and default bg buffers
   let mut contexts: Vec<AppContext> = Vec::new();
 
   // Create gbm contexts and copy bg into gpu memory
   for card in cards.into_iter() {
-    let context: AppContext = AppContext::init(card).await;
-    contexts.push(context);
+    if let Some(context) = open_card(card, &mut event_loop).await {
+      contexts.push(context);
+    }
+  }
+
+  // Cards and monitors coming and going. Without this they are polled for.
+  let uevents = if contexts.is_empty() {
+    None
+  } else {
+    UeventSocket::netlink().inspect_err(|e| {
+      tracing::warn!["Unable to listen for hotplug events, polling instead: {e}"];
+    }).ok()
+  };
+  if let Some(Err(e)) = uevents.as_ref().map(|socket| event_loop.add_fd(Source::Uevents, socket)) {
+    tracing::error!["Unable to poll for hotplug events: {e}"];
+  }
+  if contexts.is_empty() {
+    let outputs = headless_outputs.unwrap_or_default();
+    let dump_dir = config.get::<PathBuf>(CompositorConfig::HEADLESS_DUMP_KEY);
+    println!["Running headless with {} virtual display(s)", outputs.0.len()];
+    contexts.push(AppContext::headless(virtual_device(0, &outputs), dump_dir).await);
   }
-  let end = Instant::now() + Duration::from_secs(5);
-  let mut frame = 0usize;
   let mut layout: TaffyTree<String> = TaffyTree::new();
   let mut leaf_ids: Vec<NodeId> = Vec::new();
-  loop {
-    let mut displays_changed = false;
+  let mut displays_changed = false;
+  for context in contexts.iter_mut() {
+    displays_changed |= context.init_displays(&config);
+  }
 
-    // Update configuration and such
-    match rx.try_recv() {
-      Ok(Ok(NotifyEvent { .. })) => {
-        if let Ok(new_config) = Config::new(&config_path) {
-          config = new_config;
-          // displays = todo![];
-        }
-      },
-      Ok(Err(_)) => { },
-      Err(crossbeam::channel::TryRecvError::Empty) => (),
-      Err(_) => {
-        if frame % 120 == 0 {
-          tracing::warn!["Config watcher channel disconnected somehow. Reconnecting."];
-          (tx, rx) = crossbeam::channel::bounded(2);
-          config_watcher = notify::recommended_watcher(tx).ok();
-          config_watcher.map(
-            |mut watcher| watcher.watch(&config_path, RecursiveMode::NonRecursive).ok(),
-          );
-        }
-      },
-    }
-    for context in contexts.iter_mut() {
-      displays_changed |= context.update();
-      displays_changed |= context.init_displays(&config);
+  // Until there is input the cursor sits in the middle of the first display
+  let mut cursor = Arc::new(Cursor::from_config(&config));
+  let cursor_pos =
+    contexts
+      .iter()
+      .flat_map(|context| context.displays.first())
+      .next()
+      .map(|display| {
+        (display.pos.0 + display.size.0 as i32 / 2, display.pos.1 + display.size.1 as i32 / 2)
+      })
+      .unwrap_or_default();
+  for context in contexts.iter_mut() {
+    context.set_cursor(cursor.clone());
+    context.move_cursor(cursor_pos);
+  }
+  event_loop.add_timer(Instant::now() + REPROBE_INTERVAL, Timer::Reprobe);
+  loop {
+    // Backends without an fd are woken by a timer instead
+    for context in contexts.iter().filter(|context| context.fd().is_none()) {
+      if let Some(at) = context.kms.next_event_time() {
+        event_loop.add_timer(at, Timer::Events(context.kms.num()));
+      }
     }
-    if Instant::now().checked_duration_since(end).is_some() {
-      break;
+    for context in contexts.iter() {
+      if let Some(at) = context.next_composition() {
+        event_loop.add_timer(at, Timer::Compose(context.kms.num()));
+      }
     }
-    frame += 1;
     if displays_changed {
       let displays: HashMap<String, &mut Display> =
         contexts
@@ -101,5 +161,95 @@ async fn main() {
           .collect();
       (layout, leaf_ids) = layout_displays(displays);
     }
+    displays_changed = false;
+    match event_loop.next().await {
+      Wake::Readable(Source::Card(num)) | Wake::Timer(Timer::Events(num)) => {
+        for context in contexts.iter_mut().filter(|context| context.kms.num() == num) {
+          context.update();
+        }
+      },
+      Wake::Timer(Timer::Compose(num)) => {
+        for context in contexts.iter_mut().filter(|context| context.kms.num() == num) {
+          displays_changed |= context.compose();
+        }
+      },
+      Wake::Config => {
+        if let Ok(new_config) = Config::new(&config_path) {
+          config = new_config;
+          cursor = Arc::new(Cursor::from_config(&config));
+          for context in contexts.iter_mut() {
+            displays_changed |= context.apply_modes(&config);
+            context.apply_settings(&config);
+            context.set_cursor(cursor.clone());
+          }
+        }
+      },
+      Wake::Readable(Source::Uevents) => {
+        let events =
+          uevents.as_ref().map(|socket| socket.hotplug_events()).unwrap_or(Ok(Vec::new()));
+        for event in events.unwrap_or_else(|e| {
+          tracing::warn!["Failed to read hotplug events: {e}"];
+          Vec::new()
+        }) {
+          match event {
+            HotplugEvent::CardAdded(num) => {
+              if contexts.iter().any(|context| context.kms.num() == num) {
+                continue;
+              }
+              let card = match Card::open(num) {
+                Ok(card) => card,
+                Err(e) => {
+                  tracing::warn!["{e}"];
+                  continue;
+                },
+              };
+              println!["Added card{num}"];
+              if let Some(mut context) = open_card(card, &mut event_loop).await {
+                displays_changed |= context.init_displays(&config);
+                context.set_cursor(cursor.clone());
+                context.move_cursor(cursor_pos);
+                contexts.push(context);
+              }
+            },
+            HotplugEvent::CardRemoved(num) => {
+              println!["Removed card{num}"];
+              event_loop.remove_fd(Source::Card(num));
+              restore::forget(num);
+              contexts.retain_mut(|context| if context.kms.num() == num {
+                context.destroy_displays();
+                displays_changed = true;
+                false
+              } else {
+                true
+              });
+            },
+            HotplugEvent::Changed(num) => {
+              for context in contexts.iter_mut().filter(|context| context.kms.num() == num) {
+                displays_changed |= context.remove_disconnected();
+                displays_changed |= context.init_displays(&config);
+              }
+            },
+          }
+        }
+      },
+      Wake::Timer(Timer::Reprobe) => {
+        event_loop.rewatch_config();
+
+        // New displays are only found by polling if there are no uevents
+        if uevents.is_none() {
+          for context in contexts.iter_mut() {
+            displays_changed |= context.init_displays(&config);
+          }
+        }
+        event_loop.add_timer(Instant::now() + REPROBE_INTERVAL, Timer::Reprobe);
+      },
+      Wake::Shutdown => break,
+    }
+  }
+
+  // Hand the displays back in the state we found them
+  restore::restore_all();
+  for context in contexts.iter_mut() {
+    context.destroy_displays();
   }
 }
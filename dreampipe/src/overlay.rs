@@ -0,0 +1,375 @@
+use crate::error::CompositorError;
+use crate::error::CompositorResult;
+use crate::formats::FormatTable;
+use crate::kms::AtomicRequest;
+use crate::kms::KmsBackend;
+use crate::kms::PropertyMap;
+use crate::surface::ClientBuffer;
+use crate::surface::Rect;
+use crate::surface::Surface;
+use crate::surface::Transform;
+use drm::control::AtomicCommitFlags;
+use drm::control::crtc;
+use drm::control::framebuffer;
+use drm::control::plane;
+use drm::control::property;
+
+/// An overlay plane owned by a display. It never has buffers of its own, it
+/// only ever scans out client buffers.
+#[derive(Debug)]
+pub struct OverlayPlane {
+  pub plane: plane::Handle,
+  pub props: PropertyMap,
+  pub formats: FormatTable,
+  /// Was enabled by the last commit
+  pub showing: bool,
+}
+
+/// A surface put on an overlay plane
+#[derive(Clone, Debug)]
+pub struct Placement {
+  /// Index into `Display::overlays`
+  pub overlay: usize,
+  pub surface: u64,
+  pub fb: framebuffer::Handle,
+  pub src: (u32, u32),
+  /// Relative to the display
+  pub dst: Rect,
+  pub zpos: Option<u64>,
+  /// Plane alpha, if the surface is translucent
+  pub alpha: Option<u16>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OffloadStats {
+  /// Surfaces on the display
+  pub surfaces: usize,
+  /// Surfaces scanned out from an overlay plane
+  pub offloaded: usize,
+  /// Assignments the driver turned down in a test commit
+  pub rejected: usize,
+}
+
+/// Which surfaces go on overlays for one frame, and which are left for the GPU
+#[derive(Debug, Default)]
+pub struct OverlayPlan {
+  pub placements: Vec<Placement>,
+  /// Surfaces to composite, bottom first
+  pub composited: Vec<u64>,
+  pub stats: OffloadStats,
+}
+
+impl OverlayPlane {
+  pub fn new(kms: &dyn KmsBackend, plane: plane::Handle) -> CompositorResult<Self> {
+    let props =
+      kms
+        .properties(plane.into())
+        .map_err(|err| CompositorError::GetPlaneProperties(plane, err))?;
+    let formats = FormatTable::for_plane(kms, plane)?;
+    Ok(Self {
+      plane,
+      props,
+      formats,
+      showing: false,
+    })
+  }
+
+  fn supports(&self, surface: &Surface, buffer: &ClientBuffer) -> bool {
+    self.formats.supports(buffer.format, buffer.modifier) &&
+      (surface.opaque || self.props.contains_key("pixel blend mode")) &&
+      (surface.alpha >= 1.0 || self.props.contains_key("alpha"))
+  }
+
+  pub fn req(&self, atomic_req: &mut AtomicRequest, crtc: crtc::Handle, placement: &Placement) {
+    let plane = self.plane;
+    let props = &self.props;
+    let values = [
+      ("FB_ID", property::Value::Framebuffer(Some(placement.fb))),
+      ("CRTC_ID", property::Value::CRTC(Some(crtc))),
+      ("SRC_X", property::Value::UnsignedRange(0)),
+      ("SRC_Y", property::Value::UnsignedRange(0)),
+      ("SRC_W", property::Value::UnsignedRange((placement.src.0 as u64) << 16)),
+      ("SRC_H", property::Value::UnsignedRange((placement.src.1 as u64) << 16)),
+      ("CRTC_X", property::Value::SignedRange(placement.dst.x as i64)),
+      ("CRTC_Y", property::Value::SignedRange(placement.dst.y as i64)),
+      ("CRTC_W", property::Value::UnsignedRange(placement.dst.width as u64)),
+      ("CRTC_H", property::Value::UnsignedRange(placement.dst.height as u64)),
+    ];
+    for (name, value) in values {
+      atomic_req.add_property(plane, props[name].handle(), value);
+    }
+    if let (Some(zpos), Some(prop)) = (placement.zpos, props.get("zpos")) {
+      atomic_req.add_property(plane, prop.handle(), property::Value::UnsignedRange(zpos));
+    }
+    if let Some(prop) = props.get("alpha") {
+      let alpha = placement.alpha.unwrap_or(u16::MAX);
+      atomic_req.add_property(plane, prop.handle(), property::Value::UnsignedRange(alpha as u64));
+    }
+  }
+
+  pub fn disable_req(&self, atomic_req: &mut AtomicRequest) {
+    atomic_req.add_property(
+      self.plane,
+      self.props["FB_ID"].handle(),
+      property::Value::Framebuffer(None),
+    );
+    atomic_req.add_property(
+      self.plane,
+      self.props["CRTC_ID"].handle(),
+      property::Value::CRTC(None),
+    );
+  }
+}
+
+/// Add the placements, and turn off whatever overlay isn't in them
+pub fn overlays_req(
+  atomic_req: &mut AtomicRequest,
+  crtc: crtc::Handle,
+  overlays: &[OverlayPlane],
+  placements: &[Placement],
+) {
+  for (i, overlay) in overlays.iter().enumerate() {
+    match placements.iter().find(|placement| placement.overlay == i) {
+      Some(placement) => overlay.req(atomic_req, crtc, placement),
+      None if overlay.showing => overlay.disable_req(atomic_req),
+      None => (),
+    }
+  }
+}
+
+/// Call once the request from `overlays_req` went through
+pub fn committed(overlays: &mut [OverlayPlane], placements: &[Placement]) {
+  for (i, overlay) in overlays.iter_mut().enumerate() {
+    overlay.showing = placements.iter().any(|placement| placement.overlay == i);
+  }
+}
+
+/// Put as many of `surfaces` on free overlays as the driver accepts, top
+/// first. A surface qualifies if its buffer is in a format the plane takes,
+/// it is untransformed and unclipped, entirely on the display and not
+/// covered by anything that has to be composited, and it is opaque unless the
+/// plane can blend.
+/// Every assignment is checked with a test commit on top of `base`, the rest
+/// of the frame's commit.
+pub fn plan_overlays(
+  kms: &dyn KmsBackend,
+  crtc: crtc::Handle,
+  display: Rect,
+  primary_zpos: Option<u64>,
+  overlays: &[OverlayPlane],
+  surfaces: &[Surface],
+  base: &AtomicRequest,
+) -> OverlayPlan {
+  let mut visible =
+    surfaces
+      .iter()
+      .filter(|surface| surface.rect.intersects(&display) && surface.alpha > 0.0)
+      .collect::<Vec<_>>();
+  visible.sort_by_key(|surface| std::cmp::Reverse(surface.z));
+  let mut plan = OverlayPlan::default();
+  plan.stats.surfaces = visible.len();
+  let mut composited_above: Vec<Rect> = Vec::new();
+  let mut free: Vec<usize> = (0 .. overlays.len()).collect();
+  for surface in visible {
+    let qualifies =
+      surface.transform == Transform::Normal && surface.unclipped() &&
+        display.contains(&surface.rect) &&
+        !composited_above.iter().any(|rect| rect.intersects(&surface.rect));
+    let buffer = surface.buffer.as_ref().filter(|_| qualifies);
+    let mut placed = false;
+    for (i, &overlay) in free.iter().enumerate() {
+      let Some(buffer) = buffer else {
+        break;
+      };
+      if !overlays[overlay].supports(surface, buffer) {
+        continue;
+      }
+      let mut placements = plan.placements.clone();
+      placements.push(Placement {
+        overlay,
+        surface: surface.id,
+        fb: buffer.fb,
+        src: buffer.size,
+        dst: surface.rect.translate((-display.x, -display.y)),
+        zpos: None,
+        alpha: (surface.alpha < 1.0).then(|| (surface.alpha.max(0.0) * u16::MAX as f32) as u16),
+      });
+
+      // Stack them above the primary plane, topmost surface highest
+      let count = placements.len() as u64;
+      for (rank, placement) in placements.iter_mut().enumerate() {
+        placement.zpos = primary_zpos.map(|zpos| zpos + count - rank as u64);
+      }
+      let mut atomic_req = base.clone();
+      overlays_req(&mut atomic_req, crtc, overlays, &placements);
+      if kms.commit(AtomicCommitFlags::TEST_ONLY, &atomic_req).is_ok() {
+        plan.placements = placements;
+        free.remove(i);
+        placed = true;
+        break;
+      }
+      plan.stats.rejected += 1;
+    }
+    if !placed {
+      composited_above.push(surface.rect);
+      plan.composited.push(surface.id);
+    }
+  }
+  plan.composited.reverse();
+  plan.stats.offloaded = plan.placements.len();
+  plan
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kms::mock::MockDevice;
+  use drm::buffer::DrmFourcc;
+  use drm::buffer::DrmModifier;
+  use drm::control::PlaneType;
+  use drm::control::from_u32;
+
+  const DISPLAY: Rect = Rect {
+    x: 1920,
+    y: 0,
+    width: 1280,
+    height: 720,
+  };
+
+  // A CRTC with `count` overlays that take XRGB8888 and can blend and stack
+  fn overlays(count: usize) -> (MockDevice, crtc::Handle, Vec<OverlayPlane>) {
+    let device = MockDevice::new();
+    let crtc = device.add_crtc();
+    let overlays =
+      (0 .. count)
+        .map(|_| {
+          let plane = device.add_plane(PlaneType::Overlay, 0b1, vec![DrmFourcc::Xrgb8888 as u32]);
+          for name in ["zpos", "alpha", "pixel blend mode"] {
+            device.set_property(plane.into(), name, 0);
+          }
+          OverlayPlane::new(&device, plane).unwrap()
+        })
+        .collect();
+    (device, crtc, overlays)
+  }
+
+  // An opaque surface with a buffer any of the overlays can scan out
+  fn surface(id: u64, rect: Rect, z: i32) -> Surface {
+    Surface {
+      id,
+      rect,
+      z,
+      buffer: Some(ClientBuffer {
+        fb: from_u32(100 + id as u32).unwrap(),
+        format: DrmFourcc::Xrgb8888,
+        modifier: DrmModifier::Invalid,
+        size: (rect.width, rect.height),
+      }),
+      texture: None,
+      clip: None,
+      opaque: true,
+      alpha: 1.0,
+      transform: Transform::Normal,
+      yuv: Default::default(),
+      damage: Vec::new(),
+      tearing: false,
+    }
+  }
+
+  fn offload(
+    device: &MockDevice,
+    crtc: crtc::Handle,
+    overlays: &[OverlayPlane],
+    surfaces: &[Surface],
+  ) -> OverlayPlan {
+    plan_overlays(device, crtc, DISPLAY, Some(0), overlays, surfaces, &AtomicRequest::new())
+  }
+
+  fn placed(plan: &OverlayPlan) -> Vec<(usize, u64)> {
+    plan.placements.iter().map(|placement| (placement.overlay, placement.surface)).collect()
+  }
+
+  #[test]
+  fn stacks_surfaces_top_first() {
+    let (device, crtc, overlays) = overlays(3);
+    let surfaces = [
+      surface(1, Rect::new(1920, 0, 100, 100), 0),
+      surface(2, Rect::new(2020, 0, 100, 100), 2),
+      surface(3, Rect::new(2120, 0, 100, 100), 1),
+    ];
+    let plan = offload(&device, crtc, &overlays, &surfaces);
+    assert_eq!(placed(&plan), [(0, 2), (1, 3), (2, 1)]);
+    assert!(plan.composited.is_empty());
+    assert_eq!((plan.stats.surfaces, plan.stats.offloaded), (3, 3));
+
+    // Relative to the display, and counting down to just above the primary
+    let zpos = plan.placements.iter().map(|placement| placement.zpos).collect::<Vec<_>>();
+    assert_eq!(zpos, [Some(3), Some(2), Some(1)]);
+    assert_eq!(plan.placements[0].dst, Rect::new(100, 0, 100, 100));
+    assert!(
+      device
+        .take_commits()
+        .iter()
+        .all(|(flags, _)| *flags == AtomicCommitFlags::TEST_ONLY)
+    );
+  }
+
+  #[test]
+  fn composites_transformed_clipped_and_partly_shown_surfaces() {
+    let (device, crtc, overlays) = overlays(3);
+    let mut rotated = surface(1, Rect::new(1920, 0, 100, 100), 0);
+    rotated.transform = Transform::Rotate90;
+    let mut clipped = surface(2, Rect::new(2020, 0, 100, 100), 1);
+    clipped.clip = Some(Rect::new(2020, 0, 50, 100));
+    let half_off = surface(3, Rect::new(1870, 200, 100, 100), 2);
+    let plan = offload(&device, crtc, &overlays, &[rotated, clipped, half_off]);
+    assert!(plan.placements.is_empty());
+    assert_eq!(plan.composited, [1, 2, 3]);
+    assert_eq!((plan.stats.surfaces, plan.stats.offloaded), (3, 0));
+  }
+
+  #[test]
+  fn keeps_surfaces_under_composited_ones_off_overlays() {
+    let (device, crtc, overlays) = overlays(3);
+    let mut rotated = surface(1, Rect::new(1950, 50, 100, 100), 2);
+    rotated.transform = Transform::Rotate180;
+    let under = surface(2, Rect::new(1920, 0, 100, 100), 1);
+    let beside = surface(3, Rect::new(2320, 0, 100, 100), 0);
+    let plan = offload(&device, crtc, &overlays, &[rotated, under, beside]);
+    assert_eq!(placed(&plan), [(0, 3)]);
+    assert_eq!(plan.composited, [2, 1]);
+  }
+
+  #[test]
+  fn falls_back_when_the_driver_rejects_an_overlay() {
+    let (device, crtc, overlays) = overlays(2);
+    let surfaces = [surface(1, Rect::new(1920, 0, 100, 100), 0)];
+
+    // The next overlay is tried, and with none left the surface is composited
+    device.fail_test_commit(std::io::ErrorKind::InvalidInput);
+    let plan = offload(&device, crtc, &overlays, &surfaces);
+    assert_eq!(placed(&plan), [(1, 1)]);
+    assert_eq!(plan.stats.rejected, 1);
+
+    device.fail_test_commit(std::io::ErrorKind::InvalidInput);
+    device.fail_test_commit(std::io::ErrorKind::InvalidInput);
+    let plan = offload(&device, crtc, &overlays, &surfaces);
+    assert!(plan.placements.is_empty());
+    assert_eq!(plan.composited, [1]);
+    assert_eq!((plan.stats.offloaded, plan.stats.rejected), (0, 2));
+  }
+
+  #[test]
+  fn leaves_out_invisible_surfaces() {
+    let (device, crtc, overlays) = overlays(2);
+    let mut invisible = surface(1, Rect::new(1920, 0, 100, 100), 1);
+    invisible.alpha = 0.0;
+    let mut translucent = surface(2, Rect::new(2020, 0, 100, 100), 0);
+    translucent.alpha = 0.5;
+    let plan = offload(&device, crtc, &overlays, &[invisible, translucent]);
+    assert_eq!(placed(&plan), [(0, 2)]);
+    assert!(plan.composited.is_empty());
+    assert_eq!(plan.stats.surfaces, 1);
+    assert_eq!(plan.placements[0].alpha, Some(u16::MAX / 2));
+  }
+}
@@ -0,0 +1,244 @@
+use crate::context::Card;
+use crate::error::CompositorError;
+use crate::error::CompositorResult;
+use drm::ClientCapability;
+use drm::Device;
+use drm::control::AtomicCommitFlags;
+use drm::control::Device as ControlDevice;
+use drm::control::ResourceHandle;
+use drm::control::atomic::AtomicModeReq;
+use drm::control::connector;
+use drm::control::crtc;
+use drm::control::framebuffer;
+use drm::control::plane;
+use drm::control::property;
+use std::os::fd::AsRawFd;
+use std::sync::Mutex;
+
+/// KMS state of a card from before we touched it
+pub struct SavedState {
+  crtcs: Vec<(crtc::Handle, crtc::Info)>,
+  connectors: Vec<(connector::Handle, Option<crtc::Handle>)>,
+  planes: Vec<(plane::Handle, Option<framebuffer::Handle>)>,
+  // Colour properties of each CRTC, which the legacy calls don't touch
+  colors: Vec<(crtc::Handle, Vec<(property::Handle, property::RawValue)>)>,
+  // What each connector tells the monitor about the signal, for HDR
+  signals: Vec<(connector::Handle, Vec<(property::Handle, property::RawValue)>)>,
+}
+
+const COLOR_PROPERTIES: [&str; 3] = ["GAMMA_LUT", "DEGAMMA_LUT", "CTM"];
+const SIGNAL_PROPERTIES: [&str; 3] = ["Colorspace", "HDR_OUTPUT_METADATA", "max bpc"];
+
+// Cards are cloned into here so the state can be restored from the panic hook
+static SAVED_STATES: Mutex<Vec<(Card, SavedState)>> = Mutex::new(Vec::new());
+
+// Current values of the properties of `handle` named in `names`
+fn saved_properties<T: ResourceHandle>(
+  card: &Card,
+  handle: T,
+  names: &[&str],
+) -> std::io::Result<Vec<(property::Handle, property::RawValue)>> {
+  let props = card.get_properties(handle)?;
+  let values =
+    props
+      .iter()
+      .filter(|&(&handle, _)| {
+        card.get_property(handle).is_ok_and(|info| {
+          names.contains(&info.name().to_string_lossy().as_ref())
+        })
+      })
+      .map(|(&handle, &value)| (handle, value))
+      .collect();
+  Ok(values)
+}
+
+fn get_current_state(card: &Card) -> CompositorResult<SavedState> {
+  let res = card.resource_handles().map_err(|e| CompositorError::ResourcesError(e))?;
+  let mut crtcs = Vec::new();
+  for &crtc_handle in res.crtcs() {
+    let crtc_info =
+      card
+        .get_crtc(crtc_handle)
+        .map_err(|e| CompositorError::GetCrtcInfo(crtc_handle, e))?;
+    crtcs.push((crtc_handle, crtc_info));
+  }
+  let mut colors = Vec::new();
+  for &crtc_handle in res.crtcs() {
+    let values =
+      saved_properties(card, crtc_handle, &COLOR_PROPERTIES)
+        .map_err(|e| CompositorError::GetCrtcProperties(crtc_handle, e))?;
+    colors.push((crtc_handle, values));
+  }
+  let mut connectors = Vec::new();
+  let mut signals = Vec::new();
+  for &conn_handle in res.connectors() {
+    let conn_info =
+      card
+        .get_connector(conn_handle, false)
+        .map_err(|e| CompositorError::GetConnectorInfo(conn_handle, e))?;
+    let mut crtc_handle = None;
+    if let Some(encoder_handle) = conn_info.current_encoder() {
+      let encoder_info =
+        card
+          .get_encoder(encoder_handle)
+          .map_err(|e| CompositorError::GetEncoderInfo(encoder_handle, e))?;
+      crtc_handle = encoder_info.crtc();
+    }
+    connectors.push((conn_handle, crtc_handle));
+    let values =
+      saved_properties(card, conn_handle, &SIGNAL_PROPERTIES)
+        .map_err(|e| CompositorError::GetConnectorProperties(conn_handle, e))?;
+    signals.push((conn_handle, values));
+  }
+  // Without universal planes only overlays are listed, and we need cursors too
+  card
+    .set_client_capability(ClientCapability::UniversalPlanes, true)
+    .map_err(|e| CompositorError::ClientCapability(ClientCapability::UniversalPlanes, e))?;
+  let mut planes = Vec::new();
+  for plane_handle in card.plane_handles().map_err(|e| CompositorError::GetPlanes(e))? {
+    let plane_info =
+      card
+        .get_plane(plane_handle)
+        .map_err(|e| CompositorError::GetPlaneInfo(plane_handle, e))?;
+    planes.push((plane_handle, plane_info.framebuffer()));
+  }
+  Ok(SavedState {
+    crtcs,
+    connectors,
+    planes,
+    colors,
+    signals,
+  })
+}
+
+fn reset_tty() {
+  if let Ok(tty) = std::fs::File::open("/dev/tty") {
+    const VT_GETSTATE: libc::Ioctl = 0x5603;
+    const VT_ACTIVATE: libc::Ioctl = 0x5606;
+
+    #[repr(C)]
+    struct VtStat {
+      v_active: u16,
+      v_signal: u16,
+      v_state: u16,
+    }
+
+    let mut vt_stat = VtStat {
+      v_active: 0,
+      v_signal: 0,
+      v_state: 0,
+    };
+    unsafe {
+      libc::ioctl(tty.as_raw_fd(), VT_GETSTATE, &mut vt_stat);
+      libc::ioctl(tty.as_raw_fd(), VT_ACTIVATE, vt_stat.v_active as i32);
+    }
+  }
+}
+
+fn restore_state(card: &Card, state: &SavedState) {
+  // Night light and such would outlast us otherwise
+  let mut atomic_req = AtomicModeReq::new();
+  for (crtc_handle, values) in state.colors.iter() {
+    for &(property, value) in values.iter() {
+      atomic_req.add_raw_property((*crtc_handle).into(), property, value);
+    }
+  }
+  if state.colors.iter().any(|(_, values)| !values.is_empty()) {
+    card.atomic_commit(AtomicCommitFlags::empty(), atomic_req).unwrap_or_else(|e| {
+      tracing::warn!["Failed to restore color properties: {e}"];
+    });
+  }
+
+  // Left in HDR, monitors show everything washed out. This is separate as
+  // the metadata blob may be gone with whoever made it.
+  let mut atomic_req = AtomicModeReq::new();
+  for (conn_handle, values) in state.signals.iter() {
+    for &(property, value) in values.iter() {
+      atomic_req.add_raw_property((*conn_handle).into(), property, value);
+    }
+  }
+  if state.signals.iter().any(|(_, values)| !values.is_empty()) {
+    card.atomic_commit(AtomicCommitFlags::ALLOW_MODESET, atomic_req).unwrap_or_else(|e| {
+      tracing::warn!["Failed to restore connector signal properties: {e}"];
+    });
+  }
+
+  // Turn off planes that were off before we started (cursors, overlays)
+  for &(plane_handle, fb) in state.planes.iter() {
+    if fb.is_some() {
+      continue;
+    }
+    let crtc_handle = card.get_plane(plane_handle).ok().and_then(|info| info.crtc());
+    if let Some(crtc_handle) = crtc_handle {
+      card
+        .set_plane(plane_handle, crtc_handle, None, 0, (0, 0, 0, 0), (0, 0, 0, 0))
+        .unwrap_or_else(|e| {
+          tracing::warn!["Failed to disable plane {plane_handle:?}: {e}"];
+        });
+    }
+  }
+  for (crtc_handle, crtc_info) in state.crtcs.iter() {
+    let connectors =
+      state
+        .connectors
+        .iter()
+        .flat_map(
+          |(conn_handle, conn_crtc_handle)| if (*conn_crtc_handle)? == *crtc_handle {
+            Some(*conn_handle)
+          } else {
+            None
+          },
+        )
+        .collect::<Vec<_>>();
+    card
+      .set_crtc(
+        *crtc_handle,
+        crtc_info.framebuffer(),
+        crtc_info.position(),
+        &connectors,
+        crtc_info.mode(),
+      )
+      .unwrap_or_else(|e| {
+        tracing::warn!["Failed to restore CRTC {crtc_handle:?}: {e}"];
+      });
+  }
+}
+
+/// Snapshot the CRTC/connector state of `card` so it can be put back on exit
+pub fn save_state(card: &Card) -> CompositorResult<()> {
+  let state = get_current_state(card)?;
+  let card = card.try_clone()?;
+  SAVED_STATES.lock().unwrap_or_else(|e| e.into_inner()).push((card, state));
+  Ok(())
+}
+
+/// Drop the saved state of a card that was unplugged
+pub fn forget(card_num: u32) {
+  SAVED_STATES
+    .lock()
+    .unwrap_or_else(|e| e.into_inner())
+    .retain(|(card, _)| card.num() != card_num);
+}
+
+/// Put every saved card back the way we found it and kick the VT to redraw.
+/// Each card is only restored once.
+pub fn restore_all() {
+  let saved =
+    std::mem::take(&mut *SAVED_STATES.lock().unwrap_or_else(|e| e.into_inner()));
+  if saved.is_empty() {
+    return;
+  }
+  for (card, state) in saved.iter() {
+    restore_state(card, state);
+  }
+  reset_tty();
+}
+
+/// Restore the saved state before the default panic message is printed
+pub fn install_panic_hook() {
+  let default_hook = std::panic::take_hook();
+  std::panic::set_hook(Box::new(move |info| {
+    restore_all();
+    default_hook(info);
+  }));
+}
@@ -0,0 +1,81 @@
+
+use crate::surface::BufferRelease;
+use crate::surface::Rect;
+use crate::surface::Surface;
+
+/// Every surface on the virtual screen
+#[derive(Debug, Default)]
+pub struct Scene {
+  surfaces: Vec<Surface>,
+  // Where removed surfaces were, to be redrawn without them
+  removed: Vec<Rect>,
+  // Client buffers no display shows any more, to hand back to their clients
+  released: Vec<BufferRelease>,
+}
+
+impl Scene {
+  pub fn surfaces(&self) -> &[Surface] {
+    &self.surfaces
+  }
+
+  /// Add to the surface's damage whatever is changed through this
+  // Ports update, add and remove their surfaces, nothing else does
+  #[allow(dead_code)]
+  pub fn get_mut(&mut self, id: u64) -> Option<&mut Surface> {
+    self.surfaces.iter_mut().find(|surface| surface.id == id)
+  }
+
+  /// Add `surface`, replacing the one with the same ID. Damages both.
+  #[allow(dead_code)]
+  pub fn insert(&mut self, mut surface: Surface) {
+    self.remove(surface.id);
+    surface.damage.extend(surface.visible_rect());
+    self.surfaces.push(surface);
+  }
+
+  #[allow(dead_code)]
+  pub fn remove(&mut self, id: u64) -> Option<Surface> {
+    let i = self.surfaces.iter().position(|surface| surface.id == id)?;
+    let surface = self.surfaces.remove(i);
+    self.removed.extend(surface.visible_rect());
+    Some(surface)
+  }
+
+  /// Take the damage of every surface and of those removed, in virtual
+  /// screen coordinates
+  pub fn take_damage(&mut self) -> Vec<Rect> {
+    let mut damage = std::mem::take(&mut self.removed);
+    for surface in self.surfaces.iter_mut() {
+      damage.append(&mut surface.damage);
+    }
+    damage
+  }
+
+  /// Hand `release` back to the clients with the next `take_released`
+  pub fn release(&mut self, release: BufferRelease) {
+    if !release.fbs.is_empty() {
+      self.released.push(release);
+    }
+  }
+
+  /// Client buffers released since the last call, oldest first
+  // For ports to hand the buffers back, none does yet
+  #[allow(dead_code)]
+  pub fn take_released(&mut self) -> Vec<BufferRelease> {
+    std::mem::take(&mut self.released)
+  }
+
+  /// The surfaces that can be seen on `rect`, bottom first. Surfaces at the
+  /// same z stay in the order they were inserted.
+  pub fn cull(&self, rect: Rect) -> Vec<&Surface> {
+    let mut visible =
+      self
+        .surfaces
+        .iter()
+        .filter(|surface| surface.visible_rect().is_some_and(|visible| visible.intersects(&rect)))
+        .filter(|surface| surface.alpha > 0.0)
+        .collect::<Vec<_>>();
+    visible.sort_by_key(|surface| surface.z);
+    visible
+  }
+}
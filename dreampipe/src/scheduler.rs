@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// Presentations kept to estimate the refresh interval from
+const HISTORY: usize = 32;
+/// Head room between composition being done and the vblank it is for
+pub const SAFETY_MARGIN: Duration = Duration::from_millis(1);
+// Composition time to plan for before any has been measured
+const INITIAL_RENDER_TIME: Duration = Duration::from_millis(2);
+
+/// Predicts the vblanks of one display from its page flips and decides when
+/// to start composing the next frame. It never reads a clock: all times are
+/// offsets on CLOCK_MONOTONIC, like page flip timestamps, passed in by the
+/// caller.
+///
+/// With variable refresh rate there is no fixed interval to predict: the
+/// display refreshes when a frame arrives, but no sooner than the refresh
+/// interval of the mode after the last one.
+#[derive(Clone, Debug)]
+pub struct FrameScheduler {
+  // Refresh interval of the mode, used until flips have been measured
+  nominal: Duration,
+  // Vblank sequence number and timestamp of recent flips
+  history: VecDeque<(u64, Duration)>,
+  // Frame counter of the last flip, to extend the 32 bit counter
+  last_frame: Option<u32>,
+  sequence: u64,
+  interval: Duration,
+  render_time: Duration,
+  // The vblank the frame in flight was composed for
+  target: Option<Duration>,
+  vrr: bool,
+  /// Frames that reached the screen after the vblank they were composed for
+  pub missed: u64,
+}
+
+impl FrameScheduler {
+  pub fn new(nominal: Duration) -> Self {
+    Self {
+      nominal,
+      history: VecDeque::with_capacity(HISTORY),
+      last_frame: None,
+      sequence: 0,
+      interval: nominal,
+      render_time: INITIAL_RENDER_TIME,
+      target: None,
+      vrr: false,
+      missed: 0,
+    }
+  }
+
+  /// Follow a change of variable refresh rate. Flips measured before don't
+  /// tell anything about the refresh rate after.
+  pub fn set_vrr(&mut self, vrr: bool) {
+    if vrr != self.vrr {
+      self.vrr = vrr;
+      self.history.clear();
+      self.interval = self.nominal;
+      self.target = None;
+    }
+  }
+
+  /// Record a page flip that happened at `time` on vblank `frame`, as in the
+  /// page flip event. Returns how many vblanks late the frame was.
+  pub fn presented(&mut self, frame: u32, time: Duration) -> u32 {
+    // Drivers without a vblank counter always report 0
+    let step =
+      match (self.last_frame, self.history.back()) {
+        (Some(last), _) if frame != 0 && last != 0 => frame.wrapping_sub(last) as u64,
+        (_, Some(&(_, last_time))) => self.periods(time.saturating_sub(last_time)).max(1),
+        (_, None) => 0,
+      };
+    self.sequence += step;
+    self.last_frame = Some(frame);
+
+    // Flips come whenever frames do, so only the last one means anything, and
+    // no frame can be late
+    if self.vrr {
+      self.history.clear();
+      self.history.push_back((self.sequence, time));
+      self.target = None;
+      return 0;
+    }
+    if self.history.len() == HISTORY {
+      self.history.pop_front();
+    }
+    self.history.push_back((self.sequence, time));
+    self.interval = self.estimate_interval();
+    let late =
+      match self.target.take() {
+        Some(target) if time > target + self.interval / 2 => {
+          self.periods(time - target) as u32
+        },
+        _ => 0,
+      };
+    self.missed += late as u64;
+    late
+  }
+
+  // `duration` in refresh intervals, rounded
+  fn periods(&self, duration: Duration) -> u64 {
+    (duration.as_secs_f64() / self.interval.as_secs_f64()).round() as u64
+  }
+
+  // Least squares slope of timestamps over vblank sequence numbers, which
+  // evens out jitter in when the events were timestamped
+  fn estimate_interval(&self) -> Duration {
+    let Some(&(first_sequence, first_time)) = self.history.front() else {
+      return self.nominal;
+    };
+    let points =
+      self
+        .history
+        .iter()
+        .map(|&(sequence, time)| {
+          ((sequence - first_sequence) as f64, (time - first_time).as_secs_f64())
+        })
+        .collect::<Vec<_>>();
+    let count = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+    let covariance = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum::<f64>();
+    let variance = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum::<f64>();
+    if variance == 0.0 {
+      return self.nominal;
+    }
+
+    // Way off the mode means the samples are bad, eg the counter stalled
+    let slope = covariance / variance;
+    let nominal = self.nominal.as_secs_f64();
+    if slope < nominal / 2.0 || slope > nominal * 2.0 {
+      return self.nominal;
+    }
+    Duration::from_secs_f64(slope)
+  }
+
+  /// Vblank counter of the last flip, 0 if unknown
+  pub fn last_frame(&self) -> u32 {
+    self.last_frame.unwrap_or(0)
+  }
+
+  pub fn refresh_interval(&self) -> Duration {
+    self.interval
+  }
+
+  /// Composition time planned for
+  pub fn render_time(&self) -> Duration {
+    self.render_time
+  }
+
+  /// The first vblank after `now`, None until there has been a flip. With
+  /// VRR the earliest the display can refresh.
+  pub fn next_vblank(&self, now: Duration) -> Option<Duration> {
+    let &(_, last) = self.history.back()?;
+    if self.vrr {
+      return Some((last + self.nominal).max(now));
+    }
+    let elapsed = now.saturating_sub(last);
+    let periods = elapsed.as_nanos() / self.interval.as_nanos().max(1) + 1;
+    Some(last + self.interval * periods as u32)
+  }
+
+  /// The next vblank there is still time to compose a frame for
+  pub fn target_vblank(&self, now: Duration) -> Option<Duration> {
+    if self.vrr {
+      return self.next_vblank(now + self.render_time + SAFETY_MARGIN);
+    }
+    let vblank = self.next_vblank(now)?;
+    if vblank < now + self.render_time + SAFETY_MARGIN {
+      Some(vblank + self.interval)
+    } else {
+      Some(vblank)
+    }
+  }
+
+  /// When to start composing: as late as possible while still making
+  /// `target_vblank`, which keeps latency low. `now` before the first flip.
+  pub fn composition_start(&self, now: Duration) -> Duration {
+    match self.target_vblank(now) {
+      Some(vblank) => vblank.saturating_sub(self.render_time + SAFETY_MARGIN).max(now),
+      None => now,
+    }
+  }
+
+  /// A frame composed for `target` was committed after composition took
+  /// `took`
+  pub fn committed(&mut self, target: Option<Duration>, took: Duration) {
+    self.target = target;
+
+    // Follow slow frames at once and fast ones gradually, so one quick frame
+    // doesn't make the next one miss
+    self.render_time =
+      if took > self.render_time {
+        took
+      } else {
+        (self.render_time * 7 + took) / 8
+      };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // 60 Hz
+  const PERIOD: Duration = Duration::from_nanos(16_666_667);
+  // What a 60 Hz mode claims, a little off, to show the estimate moving
+  const NOMINAL: Duration = Duration::from_micros(16_600);
+  const START: Duration = Duration::from_secs(100);
+
+  // Up to 300µs either way, the same every run
+  fn jitter(i: u32) -> i64 {
+    [120, -300, 40, 280, -90, -210, 0, 170][i as usize % 8] * 1000
+  }
+
+  fn at(vblank: u32, jitter: i64) -> Duration {
+    Duration::from_nanos(((START + PERIOD * vblank).as_nanos() as i64 + jitter) as u64)
+  }
+
+  fn assert_near(actual: Duration, expected: Duration, tolerance: Duration) {
+    let difference = actual.abs_diff(expected);
+    assert!(difference <= tolerance, "{actual:?} is not within {tolerance:?} of {expected:?}");
+  }
+
+  // A scheduler that saw `count` flips exactly on time
+  fn steady(count: u32) -> FrameScheduler {
+    let mut scheduler = FrameScheduler::new(PERIOD);
+    for i in 0 .. count {
+      assert_eq!(scheduler.presented(1000 + i, at(i, 0)), 0);
+    }
+    scheduler
+  }
+
+  #[test]
+  fn converges_with_jitter() {
+    let mut scheduler = FrameScheduler::new(NOMINAL);
+    assert_eq!(scheduler.refresh_interval(), NOMINAL);
+    for i in 0 .. HISTORY as u32 * 2 {
+      assert_eq!(scheduler.presented(500 + i, at(i, jitter(i))), 0);
+    }
+    assert_near(scheduler.refresh_interval(), PERIOD, Duration::from_micros(20));
+    assert_eq!(scheduler.missed, 0);
+  }
+
+  #[test]
+  fn follows_wrapping_counter() {
+    let mut scheduler = FrameScheduler::new(NOMINAL);
+    for i in 0 .. 8 {
+      let frame = (u32::MAX - 3).wrapping_add(i);
+      assert_eq!(scheduler.presented(frame, at(i, 0)), 0);
+    }
+    assert_eq!(scheduler.last_frame(), 3);
+    // A wrap taken as a jump of 2^32 vblanks would leave the nominal interval
+    assert_near(scheduler.refresh_interval(), PERIOD, Duration::from_micros(1));
+  }
+
+  #[test]
+  fn counts_vblanks_without_counter() {
+    let mut scheduler = FrameScheduler::new(NOMINAL);
+    // Every other vblank has a flip after the fourth
+    let vblanks = [0, 1, 2, 3, 5, 7, 9, 11, 13];
+    for vblank in vblanks {
+      assert_eq!(scheduler.presented(0, at(vblank, 0)), 0);
+    }
+    assert_eq!(scheduler.last_frame(), 0);
+    assert_near(scheduler.refresh_interval(), PERIOD, Duration::from_micros(1));
+  }
+
+  #[test]
+  fn counts_missed_frames() {
+    let mut scheduler = steady(8);
+    let now = at(7, 0) + Duration::from_millis(5);
+    let target = scheduler.target_vblank(now);
+    assert_eq!(target, Some(at(8, 0)));
+    scheduler.committed(target, Duration::from_millis(3));
+
+    // Shown two vblanks after the one it was composed for
+    assert_eq!(scheduler.presented(1010, at(10, 0)), 2);
+    assert_eq!(scheduler.missed, 2);
+
+    // On time, and flips without a target are never late
+    scheduler.committed(Some(at(11, 0)), Duration::from_millis(3));
+    assert_eq!(scheduler.presented(1011, at(11, 0)), 0);
+    assert_eq!(scheduler.presented(1012, at(12, 0)), 0);
+    assert_eq!(scheduler.missed, 2);
+  }
+
+  #[test]
+  fn targets_next_vblank_with_margin() {
+    let scheduler = steady(4);
+    let budget = scheduler.render_time() + SAFETY_MARGIN;
+    assert_eq!(budget, INITIAL_RENDER_TIME + SAFETY_MARGIN);
+
+    // Exactly enough time left for the next vblank
+    let latest = at(4, 0) - budget;
+    assert_eq!(scheduler.target_vblank(latest), Some(at(4, 0)));
+    assert_eq!(scheduler.composition_start(latest), latest);
+
+    // Any later and it's the one after
+    let late = latest + Duration::from_nanos(1);
+    assert_eq!(scheduler.target_vblank(late), Some(at(5, 0)));
+    assert_eq!(scheduler.composition_start(late), at(5, 0) - budget);
+
+    // Early on, composition waits
+    let early = at(3, 0) + Duration::from_millis(1);
+    assert_eq!(scheduler.composition_start(early), latest);
+  }
+
+  #[test]
+  fn composes_at_once_before_first_flip() {
+    let scheduler = FrameScheduler::new(PERIOD);
+    assert_eq!(scheduler.target_vblank(START), None);
+    assert_eq!(scheduler.composition_start(START), START);
+  }
+
+  #[test]
+  fn vrr_resets_history() {
+    let mut scheduler = FrameScheduler::new(NOMINAL);
+    for i in 0 .. 8 {
+      scheduler.presented(100 + i, at(i, 0));
+    }
+    assert_ne!(scheduler.refresh_interval(), NOMINAL);
+    scheduler.committed(Some(at(8, 0)), Duration::from_millis(3));
+
+    scheduler.set_vrr(true);
+    assert_eq!(scheduler.refresh_interval(), NOMINAL);
+    assert_eq!(scheduler.next_vblank(at(8, 0)), None);
+
+    // Flips whenever a frame is ready, which is never late
+    assert_eq!(scheduler.presented(120, at(20, 0)), 0);
+    assert_eq!(scheduler.missed, 0);
+    assert_eq!(scheduler.next_vblank(at(20, 0)), Some(at(20, 0) + NOMINAL));
+    let later = at(30, 0);
+    assert_eq!(scheduler.next_vblank(later), Some(later));
+
+    // And back, with nothing carried over
+    scheduler.set_vrr(false);
+    assert_eq!(scheduler.next_vblank(later), None);
+    assert_eq!(scheduler.refresh_interval(), NOMINAL);
+  }
+}
@@ -0,0 +1,236 @@
+
+use crate::dmabuf::ClientTexture;
+use crate::error::CompositorError;
+use crate::error::CompositorResult;
+use crate::kms::KmsBackend;
+use crate::yuv::YuvColor;
+use drm::buffer::DrmFourcc;
+use drm::buffer::DrmModifier;
+use drm::control::framebuffer;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
+use std::os::fd::OwnedFd;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Rect {
+  pub x: i32,
+  pub y: i32,
+  pub width: u32,
+  pub height: u32,
+}
+
+impl Rect {
+  pub fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+    Self {
+      x,
+      y,
+      width,
+      height,
+    }
+  }
+
+  pub fn right(&self) -> i32 {
+    self.x + self.width as i32
+  }
+
+  pub fn bottom(&self) -> i32 {
+    self.y + self.height as i32
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.width == 0 || self.height == 0
+  }
+
+  pub fn intersects(&self, other: &Rect) -> bool {
+    !self.is_empty() && !other.is_empty() && self.x < other.right() &&
+      other.x < self.right() && self.y < other.bottom() && other.y < self.bottom()
+  }
+
+  pub fn contains(&self, other: &Rect) -> bool {
+    self.x <= other.x && self.y <= other.y && self.right() >= other.right() &&
+      self.bottom() >= other.bottom()
+  }
+
+  /// The overlap of both, None if they don't overlap
+  pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+    if !self.intersects(other) {
+      return None;
+    }
+    let (x, y) = (self.x.max(other.x), self.y.max(other.y));
+    let (right, bottom) = (self.right().min(other.right()), self.bottom().min(other.bottom()));
+    Some(Rect::new(x, y, (right - x) as u32, (bottom - y) as u32))
+  }
+
+  /// The smallest rectangle covering both
+  pub fn union(&self, other: &Rect) -> Rect {
+    let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+    let (right, bottom) = (self.right().max(other.right()), self.bottom().max(other.bottom()));
+    Rect::new(x, y, (right - x) as u32, (bottom - y) as u32)
+  }
+
+  pub fn translate(&self, (dx, dy): (i32, i32)) -> Rect {
+    Rect::new(self.x + dx, self.y + dy, self.width, self.height)
+  }
+}
+
+/// How a client buffer is rotated/flipped before being shown
+// Only ports set anything but Normal, and none hands a transform over yet
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Transform {
+  #[default]
+  Normal,
+  Rotate90,
+  Rotate180,
+  Rotate270,
+  Flipped,
+  Flipped90,
+  Flipped180,
+  Flipped270,
+}
+
+impl Transform {
+  /// As the shaders take it: quarter turns clockwise, plus 4 if flipped
+  /// horizontally first
+  pub fn index(&self) -> u32 {
+    *self as u32
+  }
+}
+
+/// One plane of a DMA-BUF. Planes may share an fd at different offsets.
+#[derive(Debug)]
+pub struct DmabufPlane {
+  pub fd: OwnedFd,
+  pub offset: u32,
+  pub stride: u32,
+}
+
+/// A DMA-BUF as a client hands it over, eg from PipeWire
+#[derive(Debug)]
+pub struct Dmabuf {
+  pub size: (u32, u32),
+  pub format: DrmFourcc,
+  /// `DrmModifier::Invalid` if the layout is implicit
+  pub modifier: DrmModifier,
+  /// In order, as many as the format and modifier have
+  pub planes: Vec<DmabufPlane>,
+}
+
+impl Dmabuf {
+  /// Whether the planes are in separate buffers, each of which has to be
+  /// imported on its own
+  pub fn is_disjoint(&self) -> bool {
+    self.planes.iter().skip(1).any(|plane| {
+      !same_file(plane.fd.as_fd(), self.planes[0].fd.as_fd())
+    })
+  }
+}
+
+// Two fds can refer to the same DMA-BUF, which is a file like any other
+fn same_file(a: BorrowedFd, b: BorrowedFd) -> bool {
+  match (fstat(a), fstat(b)) {
+    (Some(a), Some(b)) => a.st_dev == b.st_dev && a.st_ino == b.st_ino,
+    _ => false,
+  }
+}
+
+fn fstat(fd: BorrowedFd) -> Option<libc::stat> {
+  let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+  let ret = unsafe {
+    libc::fstat(fd.as_raw_fd(), stat.as_mut_ptr())
+  };
+  (ret == 0).then(|| unsafe {
+    stat.assume_init()
+  })
+}
+
+/// A client buffer that has been added to the card as a framebuffer, so a
+/// plane can scan it out directly
+#[derive(Clone, Debug)]
+pub struct ClientBuffer {
+  pub fb: framebuffer::Handle,
+  pub format: DrmFourcc,
+  pub modifier: DrmModifier,
+  pub size: (u32, u32),
+}
+
+impl ClientBuffer {
+  /// Fails if the card can't scan out this format and modifier at all, in
+  /// which case the buffer can only be composited
+  // Called by ports once they take client buffers, none does yet
+  #[allow(dead_code)]
+  pub fn import(kms: &dyn KmsBackend, dmabuf: &Dmabuf) -> CompositorResult<Self> {
+    let fb = kms.import_dmabuf(dmabuf).map_err(|err| CompositorError::ImportDmabuf(err))?;
+    Ok(Self {
+      fb,
+      format: dmabuf.format,
+      modifier: dmabuf.modifier,
+      size: dmabuf.size,
+    })
+  }
+
+  /// Only call once a page flip showed something else on every plane that
+  /// had this buffer
+  #[allow(dead_code)]
+  pub fn release(&self, kms: &dyn KmsBackend) {
+    kms.remove_framebuffer(self.fb).ok();
+  }
+}
+
+/// Client buffers a commit stopped showing. Their clients can reuse them
+/// once `fence` signals, or once the commit's page flip arrives if there is
+/// no fence.
+#[derive(Debug, Default)]
+pub struct BufferRelease {
+  /// The `ClientBuffer::fb` of each buffer
+  pub fbs: Vec<framebuffer::Handle>,
+  /// The commit's OUT_FENCE, None if the driver has none
+  // For the port handing the buffers back to wait on
+  #[allow(dead_code)]
+  pub fence: Option<OwnedFd>,
+}
+
+/// One window port: a client buffer and where it goes on the virtual screen
+#[derive(Clone, Debug)]
+pub struct Surface {
+  pub id: u64,
+  /// Where the buffer is shown, in virtual screen coordinates
+  pub rect: Rect,
+  /// Higher is on top
+  pub z: i32,
+  pub buffer: Option<ClientBuffer>,
+  /// The same buffer imported for the GPU, for when it is composited
+  pub texture: Option<ClientTexture>,
+  /// Only this part of `rect` is shown, in virtual screen coordinates
+  pub clip: Option<Rect>,
+  /// The buffer has no transparent pixels
+  pub opaque: bool,
+  /// Opacity of the whole surface, 0.0 ..= 1.0
+  pub alpha: f32,
+  pub transform: Transform,
+  /// How to turn a YUV buffer into colours, from the port's metadata.
+  /// Ignored for RGB buffers.
+  pub yuv: YuvColor,
+  /// What changed since it was last composed, in virtual screen coordinates.
+  /// Moving or resizing a surface damages both the old and the new rect.
+  pub damage: Vec<Rect>,
+  /// The client would rather tear than wait for vblank, eg a game. Only
+  /// honoured while the surface is scanned out fullscreen.
+  pub tearing: bool,
+}
+
+impl Surface {
+  /// What of the surface can be seen, None if it is clipped away entirely
+  pub fn visible_rect(&self) -> Option<Rect> {
+    match self.clip {
+      Some(clip) => self.rect.intersection(&clip),
+      None => Some(self.rect).filter(|rect| !rect.is_empty()),
+    }
+  }
+
+  /// Whether the clip leaves the whole surface showing
+  pub fn unclipped(&self) -> bool {
+    self.clip.is_none_or(|clip| clip.contains(&self.rect))
+  }
+}
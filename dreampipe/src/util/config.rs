@@ -16,6 +16,24 @@ use tracing::warn;
 pub struct CompositorConfig;
 
 impl CompositorConfig {
+   /// Space separated modes of virtual displays to create instead of opening
+   /// cards, eg `1920x1080@60 1280x720@30`
+   pub const HEADLESS_OUTPUTS_KEY: &str = "headless.outputs";
+   /// Directory to write every frame presented on a virtual display to
+   pub const HEADLESS_DUMP_KEY: &str = "headless.dump";
+   /// Xcursor theme name, defaults to `$XCURSOR_THEME`
+   pub const CURSOR_THEME_KEY: &str = "cursor.theme";
+   /// Nominal cursor size in pixels, defaults to `$XCURSOR_SIZE`
+   pub const CURSOR_SIZE_KEY: &str = "cursor.size";
+   /// Path of the wallpaper of displays without their own, `none` for just
+   /// the colour or `default` for the built-in one
+   pub const WALLPAPER_KEY: &str = "wallpaper";
+   /// How the wallpaper is scaled: `fill`, `fit`, `stretch`, `center` or `tile`
+   pub const WALLPAPER_MODE_KEY: &str = "wallpaper.mode";
+   /// `RRGGBB` shown wherever the wallpaper doesn't cover, or if it can't be
+   /// loaded. No `#`, that starts a comment.
+   pub const WALLPAPER_COLOR_KEY: &str = "wallpaper.color";
+
    pub fn config_path() -> Option<PathBuf> {
       let xdg = Xdg::new().ok();
       let config_dir = xdg.map(|xdg| xdg.config().ok()).flatten();
@@ -25,6 +43,100 @@ impl CompositorConfig {
    pub fn offset_key(display_name: &str) -> String {
       format!["{display_name}.offset"]
    }
+
+   /// `WIDTHxHEIGHT[@REFRESH]` or a mode policy
+   pub fn mode_key(display_name: &str) -> String {
+      format!["{display_name}.mode"]
+   }
+
+   /// Mode policy to use when the configured mode is not available
+   pub fn mode_fallback_key(display_name: &str) -> String {
+      format!["{display_name}.mode_fallback"]
+   }
+
+   /// Number of buffers to render into, at least 2
+   pub fn buffers_key(display_name: &str) -> String {
+      format!["{display_name}.buffers"]
+   }
+
+   /// `fifo` to show every frame, `mailbox` to let newer frames replace
+   /// older ones still waiting to be shown
+   pub fn present_mode_key(display_name: &str) -> String {
+      format!["{display_name}.present_mode"]
+   }
+
+   /// Variable refresh rate: `off`, `on`, or `fullscreen-only` to enable it
+   /// only while a fullscreen surface is scanned out
+   pub fn vrr_key(display_name: &str) -> String {
+      format!["{display_name}.vrr"]
+   }
+
+   /// `true` to let fullscreen surfaces that ask for it flip without waiting
+   /// for vblank
+   pub fn allow_tearing_key(display_name: &str) -> String {
+      format!["{display_name}.allow_tearing"]
+   }
+
+   /// Output scale, 0.0 ..= 1.0
+   pub fn brightness_key(display_name: &str) -> String {
+      format!["{display_name}.brightness"]
+   }
+
+   /// Output stretch around mid grey, 1.0 leaves it
+   pub fn contrast_key(display_name: &str) -> String {
+      format!["{display_name}.contrast"]
+   }
+
+   /// Exponent of the output curve, 1.0 leaves it
+   pub fn gamma_key(display_name: &str) -> String {
+      format!["{display_name}.gamma"]
+   }
+
+   /// `none`, `protanopia`, `deuteranopia`, `tritanopia` or `grayscale`
+   pub fn color_filter_key(display_name: &str) -> String {
+      format!["{display_name}.color_filter"]
+   }
+
+   /// `off`, `on`, `HH:MM-HH:MM`, or `sun LATITUDE,LONGITUDE` for sunset
+   /// until sunrise
+   pub fn night_light_key(display_name: &str) -> String {
+      format!["{display_name}.night_light"]
+   }
+
+   /// Night light colour temperature in kelvin
+   pub fn night_temperature_key(display_name: &str) -> String {
+      format!["{display_name}.night_temperature"]
+   }
+
+   /// Drive the display in HDR if it supports it, true or false
+   pub fn hdr_key(display_name: &str) -> String {
+      format!["{display_name}.hdr"]
+   }
+
+   /// Bits per colour channel to scan out: 8, 10 or 16
+   pub fn bit_depth_key(display_name: &str) -> String {
+      format!["{display_name}.bit_depth"]
+   }
+
+   /// How bright SDR white is in HDR, in nits
+   pub fn sdr_white_key(display_name: &str) -> String {
+      format!["{display_name}.sdr_white"]
+   }
+
+   /// Wallpaper path of this display alone, as `WALLPAPER_KEY`
+   pub fn wallpaper_key(display_name: &str) -> String {
+      format!["{display_name}.wallpaper"]
+   }
+
+   /// As `WALLPAPER_MODE_KEY`, for this display alone
+   pub fn wallpaper_mode_key(display_name: &str) -> String {
+      format!["{display_name}.wallpaper.mode"]
+   }
+
+   /// As `WALLPAPER_COLOR_KEY`, for this display alone
+   pub fn wallpaper_color_key(display_name: &str) -> String {
+      format!["{display_name}.wallpaper.color"]
+   }
 }
 
 /// Format is: key = value. Comments are with '#' or just write anything. Errors
@@ -71,6 +183,13 @@ impl Config {
       Ok(Self::from_str(&buf))
    }
 
+   /// Get the value of the first of `keys` that is set
+   pub fn get_first<N: FromStr>(&self, keys: &[String]) -> Option<N>
+   where
+      <N as FromStr>::Err: std::fmt::Display {
+      keys.iter().find(|k| self.data.contains_key(k.as_str())).and_then(|k| self.get(k))
+   }
+
    /// Get a config value
    pub fn get<N: FromStr>(&self, k: &str) -> Option<N>
    where
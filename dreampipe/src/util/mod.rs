@@ -1,5 +1,6 @@
 pub mod config;
 
+use crate::color::LocalTime;
 use crate::display::Display;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
@@ -60,6 +61,82 @@ impl From<DisplayPosition> for (i32, i32) {
   }
 }
 
+/// `WIDTHxHEIGHT`, optionally followed by `@REFRESH`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ModeSpec {
+  pub width: u16,
+  pub height: u16,
+  pub refresh: Option<u32>,
+}
+
+impl FromStr for ModeSpec {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (size, refresh) = match s.trim().split_once('@') {
+      Some((size, refresh)) => (size, Some(refresh)),
+      None => (s.trim(), None),
+    };
+    let Some((width, height)) = size.split_once('x') else {
+      return Err(format!["Cannot parse mode '{s}': Missing 'x' separator"]);
+    };
+    let parse_err = |e| format!["Failed to parse mode '{s}': {e}"];
+    Ok(
+      Self {
+        width: width.trim().parse().map_err(parse_err)?,
+        height: height.trim().parse().map_err(parse_err)?,
+        refresh: refresh.map(|r| r.trim().parse()).transpose().map_err(parse_err)?,
+      },
+    )
+  }
+}
+
+/// How to pick a mode when none is asked for, or the one asked for is missing
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ModePolicy {
+  /// The mode the monitor marks as preferred, usually its native resolution
+  #[default]
+  Preferred,
+  HighestRefresh,
+  HighestResolution,
+}
+
+impl FromStr for ModePolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim() {
+      "preferred" => Ok(Self::Preferred),
+      "highest-refresh" => Ok(Self::HighestRefresh),
+      "highest-resolution" => Ok(Self::HighestResolution),
+      other => Err(
+        format![
+          "Unknown mode policy '{other}', expected preferred, highest-refresh or highest-resolution"
+        ],
+      ),
+    }
+  }
+}
+
+/// The `<display>.mode` config value: an exact mode or a policy
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ModeSetting {
+  Exact(ModeSpec),
+  Policy(ModePolicy),
+}
+
+impl FromStr for ModeSetting {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s.trim().starts_with(|ch: char| ch.is_ascii_digit()) {
+      Ok(Self::Exact(s.parse()?))
+    } else {
+      Ok(Self::Policy(s.parse()?))
+    }
+  }
+}
+
 #[derive(Eq, PartialEq)]
 pub enum Direction {
   East,
@@ -82,6 +159,25 @@ pub struct BackgroundImage {
   pub height: i32,
 }
 
+/// The wall clock in the local timezone
+pub fn local_time() -> LocalTime {
+  let mut tm: libc::tm = unsafe {
+    std::mem::zeroed()
+  };
+  let mut ts: libc::timespec = unsafe {
+    std::mem::zeroed()
+  };
+  unsafe {
+    libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts);
+    libc::localtime_r(&ts.tv_sec, &mut tm);
+  }
+  LocalTime {
+    day_of_year: tm.tm_yday as u32 + 1,
+    minutes: tm.tm_hour as f64 * 60.0 + tm.tm_min as f64 + tm.tm_sec as f64 / 60.0,
+    utc_offset: tm.tm_gmtoff as f64 / 60.0,
+  }
+}
+
 pub fn layout_displays(
   mut displays: HashMap<String, &mut Display>,
 ) -> (TaffyTree<String>, Vec<NodeId>) {
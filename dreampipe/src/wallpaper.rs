@@ -0,0 +1,362 @@
+use crate::gpu::upload_rgba;
+use crate::surface::Rect;
+use crate::util::config::CompositorConfig;
+use crate::util::config::Config;
+use image::ImageResult;
+use image::RgbaImage;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindingResource;
+use wgpu::TextureViewDescriptor;
+
+// This cfg is for display purposes when concatenating the entire project together.
+#[cfg(not(feature = "expanding"))]
+const BG_BYTES: &[u8] = include_bytes!["../mambutt.png"];
+#[cfg(feature = "expanding")]
+const BG_BYTES: &[u8] = &[];
+
+/// How a wallpaper is scaled to the display
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum WallpaperMode {
+  /// Covers the display, cropping what doesn't fit
+  Fill,
+  /// As big as fits whole, the colour around it
+  Fit,
+  /// Covers the display, whatever that does to the aspect ratio
+  #[default]
+  Stretch,
+  /// Unscaled in the middle, the colour around it
+  Center,
+  /// Unscaled and repeated from the top left corner
+  Tile,
+}
+
+impl FromStr for WallpaperMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim() {
+      "fill" => Ok(Self::Fill),
+      "fit" => Ok(Self::Fit),
+      "stretch" => Ok(Self::Stretch),
+      "center" => Ok(Self::Center),
+      "tile" => Ok(Self::Tile),
+      other => {
+        Err(format![
+          "Unknown wallpaper mode '{other}', expected fill, fit, stretch, center or tile"
+        ])
+      },
+    }
+  }
+}
+
+/// An opaque colour, `RRGGBB` in the config, where `#` starts a comment
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct SolidColor(pub [u8; 3]);
+
+impl FromStr for SolidColor {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let hex = s.trim().trim_start_matches('#');
+    let channel = |i: usize| hex.get(i .. i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+      (6, Some(r), Some(g), Some(b)) => Ok(Self([r, g, b])),
+      _ => Err(format!["Invalid colour '{s}', expected RRGGBB"]),
+    }
+  }
+}
+
+/// Where a wallpaper image is decoded from
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum WallpaperImage {
+  /// Built into the binary
+  Default,
+  File(PathBuf),
+}
+
+impl WallpaperImage {
+  // Premultiplied, like everything else drawn, and scaled down to fit in
+  // `max_size` squared. Slow for big images, so only call off the render
+  // thread.
+  fn decode(&self, max_size: u32) -> ImageResult<RgbaImage> {
+    let decoded =
+      match self {
+        Self::Default => image::load_from_memory_with_format(BG_BYTES, image::ImageFormat::Png),
+        Self::File(path) => image::open(path),
+      }?;
+    let decoded =
+      if decoded.width() > max_size || decoded.height() > max_size {
+        tracing::warn![
+          "Wallpaper {self:?} is {}x{}, more than the GPU takes, scaling it down to fit {max_size}",
+          decoded.width(),
+          decoded.height()
+        ];
+        decoded.resize(max_size, max_size, image::imageops::FilterType::Triangle)
+      } else {
+        decoded
+      };
+    let mut rgba = decoded.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+      let alpha = pixel[3] as u32;
+      for channel in pixel.0[.. 3].iter_mut() {
+        *channel = ((*channel as u32 * alpha + 127) / 255) as u8;
+      }
+    }
+    Ok(rgba)
+  }
+}
+
+/// What a display shows behind every surface
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WallpaperSettings {
+  /// None for just the colour
+  pub image: Option<WallpaperImage>,
+  pub mode: WallpaperMode,
+  /// Wherever the image doesn't cover, or all of the display until it is
+  /// loaded or if it can't be
+  pub color: SolidColor,
+}
+
+impl WallpaperSettings {
+  /// The settings of the display configured as the first of `names` that has
+  /// them, falling back to those of every display
+  pub fn from_config(config: &Config, names: &[String]) -> Self {
+    let keys = |key: fn(&str) -> String, global: &str| {
+      names.iter().map(|name| key(name)).chain([global.to_owned()]).collect::<Vec<_>>()
+    };
+    let path =
+      config.get_first::<String>(
+        &keys(CompositorConfig::wallpaper_key, CompositorConfig::WALLPAPER_KEY),
+      );
+    let image =
+      match path.as_deref() {
+        None | Some("default") => Some(WallpaperImage::Default),
+        Some("none") => None,
+        Some(path) => Some(WallpaperImage::File(path.into())),
+      };
+    Self {
+      image,
+      mode: config
+        .get_first(
+          &keys(CompositorConfig::wallpaper_mode_key, CompositorConfig::WALLPAPER_MODE_KEY),
+        )
+        .unwrap_or_default(),
+      color: config
+        .get_first(
+          &keys(CompositorConfig::wallpaper_color_key, CompositorConfig::WALLPAPER_COLOR_KEY),
+        )
+        .unwrap_or_default(),
+    }
+  }
+}
+
+/// Where an image of `image` pixels goes on a display of `display` pixels,
+/// relative to the display, and which part of it in texture coordinates as
+/// `[x, y, width, height]`
+pub fn placement(
+  mode: WallpaperMode,
+  (width, height): (u32, u32),
+  display: (u32, u32),
+) -> (Rect, [f32; 4]) {
+  let full = Rect::new(0, 0, display.0, display.1);
+  let (x_scale, y_scale) = (display.0 as f32 / width as f32, display.1 as f32 / height as f32);
+  let centered = |(w, h): (u32, u32)| {
+    Rect::new((display.0 as i32 - w as i32) / 2, (display.1 as i32 - h as i32) / 2, w, h)
+  };
+  match mode {
+    WallpaperMode::Fill => {
+      let scale = x_scale.max(y_scale);
+      let (u, v) = (x_scale / scale, y_scale / scale);
+      (full, [(1.0 - u) / 2.0, (1.0 - v) / 2.0, u, v])
+    },
+    WallpaperMode::Fit => {
+      let scale = x_scale.min(y_scale);
+      let size = ((width as f32 * scale).round() as u32, (height as f32 * scale).round() as u32);
+      (centered(size), [0.0, 0.0, 1.0, 1.0])
+    },
+    WallpaperMode::Stretch => (full, [0.0, 0.0, 1.0, 1.0]),
+    WallpaperMode::Center => (centered((width, height)), [0.0, 0.0, 1.0, 1.0]),
+    WallpaperMode::Tile => (full, [0.0, 0.0, x_scale, y_scale]),
+  }
+}
+
+/// A decoded wallpaper on the GPU
+pub struct WallpaperTexture {
+  pub size: (u32, u32),
+  /// Clamped to the edges
+  pub clamped: wgpu::BindGroup,
+  /// Repeated, for tiling
+  pub tiled: wgpu::BindGroup,
+}
+
+/// Wallpaper images and colours uploaded for a GPU. Images are decoded on
+/// threads of their own and show up a few frames after they are asked for.
+pub struct Wallpapers {
+  layout: wgpu::BindGroupLayout,
+  clamped: wgpu::Sampler,
+  tiled: wgpu::Sampler,
+  // None if the image failed to load
+  images: HashMap<WallpaperImage, Option<WallpaperTexture>>,
+  colors: HashMap<SolidColor, wgpu::BindGroup>,
+  // Being decoded
+  loading: HashSet<WallpaperImage>,
+  // To decode again on the next `prepare`
+  stale: HashSet<WallpaperImage>,
+  // Asked for since the last `reload`, to drop the rest
+  used: HashSet<WallpaperImage>,
+  sender: mpsc::Sender<(WallpaperImage, ImageResult<RgbaImage>)>,
+  receiver: mpsc::Receiver<(WallpaperImage, ImageResult<RgbaImage>)>,
+}
+
+impl Wallpapers {
+  /// Bind groups are made with `layout`, a texture and a sampler
+  pub fn new(gpu: &wgpu::Device, layout: wgpu::BindGroupLayout) -> Self {
+    let sampler = |label, address_mode| {
+      gpu.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some(label),
+        address_mode_u: address_mode,
+        address_mode_v: address_mode,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+      })
+    };
+    let (sender, receiver) = mpsc::channel();
+    Self {
+      layout,
+      clamped: sampler("Wallpaper Sampler", wgpu::AddressMode::ClampToEdge),
+      tiled: sampler("Tiled Wallpaper Sampler", wgpu::AddressMode::Repeat),
+      images: HashMap::new(),
+      colors: HashMap::new(),
+      loading: HashSet::new(),
+      stale: HashSet::new(),
+      used: HashSet::new(),
+      sender,
+      receiver,
+    }
+  }
+
+  fn bind_group(
+    &self,
+    gpu: &wgpu::Device,
+    label: &str,
+    texture: &wgpu::Texture,
+    sampler: &wgpu::Sampler,
+  ) -> wgpu::BindGroup {
+    let view = texture.create_view(&TextureViewDescriptor::default());
+    gpu.create_bind_group(&BindGroupDescriptor {
+      label: Some(label),
+      layout: &self.layout,
+      entries: &[BindGroupEntry {
+        binding: 0,
+        resource: BindingResource::TextureView(&view),
+      }, BindGroupEntry {
+        binding: 1,
+        resource: BindingResource::Sampler(sampler),
+      }],
+    })
+  }
+
+  /// Make sure what `settings` shows is uploaded or on its way
+  pub fn prepare(
+    &mut self,
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+    settings: &WallpaperSettings,
+  ) {
+    let color = settings.color;
+    if !self.colors.contains_key(&color) {
+      let [r, g, b] = color.0;
+      let pixel = RgbaImage::from_raw(1, 1, vec![r, g, b, 0xff]).expect("One pixel");
+      let texture = upload_rgba(gpu, queue, "Wallpaper Colour", &pixel);
+      let bind_group =
+        self.bind_group(gpu, "Wallpaper Colour Bindgroup", &texture, &self.clamped);
+      self.colors.insert(color, bind_group);
+    }
+    let Some(image) = settings.image.as_ref() else {
+      return;
+    };
+    self.used.insert(image.clone());
+    let missing = !self.images.contains_key(image) || self.stale.remove(image);
+    if missing && self.loading.insert(image.clone()) {
+      let (image, sender) = (image.clone(), self.sender.clone());
+      let max_size = gpu.limits().max_texture_dimension_2d;
+      std::thread::spawn(move || {
+        let decoded = image.decode(max_size);
+        sender.send((image, decoded)).ok();
+      });
+    }
+  }
+
+  /// Upload whatever finished decoding. Returns the images that changed.
+  pub fn receive(&mut self, gpu: &wgpu::Device, queue: &wgpu::Queue) -> Vec<WallpaperImage> {
+    let mut changed = Vec::new();
+    while let Ok((image, decoded)) = self.receiver.try_recv() {
+      self.loading.remove(&image);
+      match decoded {
+        Ok(rgba) => {
+          let texture = upload_rgba(gpu, queue, "Wallpaper", &rgba);
+          let texture = WallpaperTexture {
+            size: rgba.dimensions(),
+            clamped: self.bind_group(gpu, "Wallpaper Bindgroup", &texture, &self.clamped),
+            tiled: self.bind_group(gpu, "Tiled Wallpaper Bindgroup", &texture, &self.tiled),
+          };
+          self.images.insert(image.clone(), Some(texture));
+        },
+        Err(e) => {
+          tracing::warn!["Failed to load wallpaper {image:?}, showing its colour instead: {e}"];
+          self.images.insert(image.clone(), None);
+        },
+      }
+      changed.push(image);
+    }
+    changed
+  }
+
+  /// Decode image files again the next time they are asked for, showing the
+  /// old image until then. Images not asked for since the last reload are
+  /// dropped.
+  pub fn reload(&mut self) {
+    let used = std::mem::take(&mut self.used);
+    self.colors.clear();
+    self.images.retain(|image, _| used.contains(image));
+    self.stale =
+      self
+        .images
+        .keys()
+        .filter(|image| matches!(image, WallpaperImage::File(_)))
+        .cloned()
+        .collect();
+  }
+
+  pub fn color(&self, color: SolidColor) -> Option<&wgpu::BindGroup> {
+    self.colors.get(&color)
+  }
+
+  pub fn image(&self, image: &WallpaperImage) -> Option<&WallpaperTexture> {
+    self.images.get(image).and_then(Option::as_ref)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn scales_down_what_the_gpu_cannot_take() {
+    let full = WallpaperImage::Default.decode(u32::MAX).unwrap();
+    let (width, height) = full.dimensions();
+    assert_eq!(WallpaperImage::Default.decode(width.max(height)).unwrap(), full);
+
+    let scaled = WallpaperImage::Default.decode(64).unwrap();
+    assert_eq!(scaled.width().max(scaled.height()), 64);
+    let aspect = |(w, h): (u32, u32)| w as f32 / h as f32;
+    assert!((aspect(scaled.dimensions()) - aspect((width, height))).abs() < 0.05);
+  }
+}
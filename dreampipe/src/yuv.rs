@@ -0,0 +1,519 @@
+
+use crate::dmabuf::ClientTexture;
+use crate::fourcc::FourCc;
+use crate::surface::Rect;
+use crate::surface::Transform;
+use drm::buffer::DrmFourcc;
+use wgpu::BindGroupDescriptor;
+use wgpu::BindGroupEntry;
+use wgpu::BindingResource;
+use wgpu::BufferDescriptor;
+use wgpu::BufferUsages;
+use wgpu::TextureAspect;
+use wgpu::TextureFormat;
+use wgpu::TextureViewDescriptor;
+
+const YUV_SHADER: &str = include_str!["yuv.wgsl"];
+// Two vec2<f32>, a mat3x3<f32> with padded columns, two vec3<f32> each
+// followed by a 4 byte scalar, a vec2<f32> and a u32, rounded up to 16 bytes
+const PARAMS_SIZE: u64 = 112;
+
+/// The Y'CbCr to R'G'B' matrix a video buffer was encoded with
+// The rest come from a port's colour metadata, which none sends yet
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum YuvMatrix {
+  /// SD video
+  Bt601,
+  /// HD video
+  #[default]
+  Bt709,
+  /// UHD video. The primaries are left as they are.
+  Bt2020,
+}
+
+impl YuvMatrix {
+  // Luma weights of red and blue
+  fn kr_kb(&self) -> (f64, f64) {
+    match self {
+      Self::Bt601 => (0.299, 0.114),
+      Self::Bt709 => (0.2126, 0.0722),
+      Self::Bt2020 => (0.2627, 0.0593),
+    }
+  }
+
+  /// Columns of the matrix taking Y' in 0 ..= 1 and Cb, Cr in -0.5 ..= 0.5
+  /// to R'G'B'
+  pub fn to_rgb(self) -> [[f64; 3]; 3] {
+    let (kr, kb) = self.kr_kb();
+    let kg = 1.0 - kr - kb;
+    [
+      [1.0, 1.0, 1.0],
+      [0.0, -2.0 * kb * (1.0 - kb) / kg, 2.0 * (1.0 - kb)],
+      [2.0 * (1.0 - kr), -2.0 * kr * (1.0 - kr) / kg, 0.0],
+    ]
+  }
+}
+
+/// Which codes a video buffer uses for black to white
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum YuvRange {
+  /// 16 ..= 235 for luma and 16 ..= 240 for chroma at 8 bits
+  #[default]
+  Limited,
+  /// Every code
+  Full,
+}
+
+/// Where chroma samples are relative to the luma samples they are shared by
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ChromaSiting {
+  /// With the left luma sample, and halfway down. MPEG-2 and H.264.
+  #[default]
+  Left,
+  /// In the middle, as in JPEG
+  Center,
+  /// With the top left luma sample, as in BT.2020
+  TopLeft,
+}
+
+/// How a YUV buffer's samples turn into colours, from the port's colour
+/// metadata
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct YuvColor {
+  pub matrix: YuvMatrix,
+  pub range: YuvRange,
+  pub siting: ChromaSiting,
+}
+
+// How a YUV format is laid out for the shader
+#[derive(Clone, Copy, Debug)]
+struct YuvLayout {
+  // As in the shader: 0 planar, 1 YUYV, 2 UYVY
+  packed: u32,
+  bits: u32,
+  // Codes a texel value of 1.0 stands for
+  texel_max: f64,
+  // Plane views for planar formats
+  planes: Option<(TextureFormat, TextureFormat)>,
+}
+
+impl YuvLayout {
+  fn new(format: DrmFourcc) -> Option<Self> {
+    let (packed, bits, texel_max, planes) =
+      match format {
+        DrmFourcc::Nv12 => (0, 8, 255.0, Some((TextureFormat::R8Unorm, TextureFormat::Rg8Unorm))),
+        // 10 bits in the high bits of 16
+        DrmFourcc::P010 => {
+          (0, 10, 65535.0 / 64.0, Some((TextureFormat::R16Unorm, TextureFormat::Rg16Unorm)))
+        },
+        DrmFourcc::Yuyv => (1, 8, 255.0, None),
+        DrmFourcc::Uyvy => (2, 8, 255.0, None),
+        _ => return None,
+      };
+    Some(Self {
+      packed,
+      bits,
+      texel_max,
+      planes,
+    })
+  }
+
+  // Per channel scale and offset taking texel values to Y' in 0 ..= 1 and
+  // Cb, Cr in -0.5 ..= 0.5
+  fn scale_offset(&self, range: YuvRange) -> ([f64; 3], [f64; 3]) {
+    let max = self.texel_max;
+    match range {
+      YuvRange::Limited => {
+        let step = (1 << (self.bits - 8)) as f64;
+        let (luma, chroma) = (max / (219.0 * step), max / (224.0 * step));
+        ([luma, chroma, chroma], [16.0 / 219.0, 128.0 / 224.0, 128.0 / 224.0])
+      },
+      YuvRange::Full => {
+        let codes = ((1 << self.bits) - 1) as f64;
+        let half = (1 << (self.bits - 1)) as f64 / codes;
+        ([max / codes; 3], [0.0, half, half])
+      },
+    }
+  }
+}
+
+// Plain sampling expects each chroma sample in the middle of the luma
+// samples sharing it. One sited with the first of `subsampling` luma samples
+// is this many chroma texels earlier.
+fn siting_offset(siting: ChromaSiting, (x, y): (u32, u32)) -> [f32; 2] {
+  let cosited = |subsampling: u32| (subsampling - 1) as f32 / (2 * subsampling) as f32;
+  match siting {
+    ChromaSiting::Left => [cosited(x), 0.0],
+    ChromaSiting::Center => [0.0, 0.0],
+    ChromaSiting::TopLeft => [cosited(x), cosited(y)],
+  }
+}
+
+/// A YUV texture and where `YuvPass::draw` puts it
+pub struct YuvBlit<'a> {
+  pub texture: &'a ClientTexture,
+  pub color: &'a YuvColor,
+  /// Opacity
+  pub alpha: f32,
+  pub transform: Transform,
+  /// Where the whole texture goes in the target, scaled to fit
+  pub rect: Rect,
+}
+
+/// Draws NV12, P010, YUYV and UYVY client textures, converting to RGB as it
+/// samples them
+pub struct YuvPass {
+  pipeline: wgpu::RenderPipeline,
+  sampler: wgpu::Sampler,
+  params: wgpu::Buffer,
+}
+
+impl YuvPass {
+  pub fn new(gpu: &wgpu::Device) -> Self {
+    let shader = gpu.create_shader_module(wgpu::ShaderModuleDescriptor {
+      label: Some("YUV Shader"),
+      source: wgpu::ShaderSource::Wgsl(YUV_SHADER.into()),
+    });
+    let pipeline = gpu.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+      label: Some("YUV Pipeline"),
+      layout: None,
+      vertex: wgpu::VertexState {
+        module: &shader,
+        entry_point: Some("vs_main"),
+        buffers: &[],
+        compilation_options: wgpu::PipelineCompilationOptions::default(),
+      },
+      fragment: Some(wgpu::FragmentState {
+        module: &shader,
+        entry_point: Some("fs_main"),
+        targets: &[Some(wgpu::ColorTargetState {
+          format: TextureFormat::Bgra8Unorm,
+          blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+          write_mask: wgpu::ColorWrites::ALL,
+        })],
+        compilation_options: wgpu::PipelineCompilationOptions::default(),
+      }),
+      primitive: wgpu::PrimitiveState {
+        topology: wgpu::PrimitiveTopology::TriangleStrip,
+        ..Default::default()
+      },
+      depth_stencil: None,
+      multisample: wgpu::MultisampleState::default(),
+      multiview: None,
+      cache: None,
+    });
+
+    // Chroma is upsampled by filtering
+    let sampler = gpu.create_sampler(&wgpu::SamplerDescriptor {
+      label: Some("YUV Sampler"),
+      mag_filter: wgpu::FilterMode::Linear,
+      min_filter: wgpu::FilterMode::Linear,
+      ..Default::default()
+    });
+    let params = gpu.create_buffer(&BufferDescriptor {
+      label: Some("YUV Params"),
+      size: PARAMS_SIZE,
+      usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+      mapped_at_creation: false,
+    });
+    Self {
+      pipeline,
+      sampler,
+      params,
+    }
+  }
+
+  /// Whether `format` can be drawn
+  pub fn supports(format: DrmFourcc) -> bool {
+    YuvLayout::new(format).is_some()
+  }
+
+  /// Draw the texture of `blit` on `target` as `blit` says, touching only
+  /// `clip`. Does nothing if the texture isn't YUV.
+  pub fn draw(
+    &self,
+    gpu: &wgpu::Device,
+    queue: &wgpu::Queue,
+    blit: &YuvBlit,
+    target: &wgpu::Texture,
+    clip: &[Rect],
+  ) {
+    let YuvBlit { texture, color, alpha, transform, rect } = *blit;
+    let Some(layout) = YuvLayout::new(texture.format) else {
+      return;
+    };
+    let subsampling = texture.format.info().map(|info| info.subsampling).unwrap_or((1, 1));
+    let (luma, chroma) =
+      match layout.planes {
+        Some((luma, chroma)) => {
+          let plane = |format, aspect| {
+            texture.texture.create_view(&TextureViewDescriptor {
+              format: Some(format),
+              aspect,
+              ..Default::default()
+            })
+          };
+          (plane(luma, TextureAspect::Plane0), plane(chroma, TextureAspect::Plane1))
+        },
+        None => {
+          let view = texture.texture.create_view(&TextureViewDescriptor::default());
+          (view.clone(), view)
+        },
+      };
+
+    let (width, height) = (target.width() as f32, target.height() as f32);
+    let (scale, offset) = layout.scale_offset(color.range);
+    let floats = |values: &[f32]| values.iter().flat_map(|f| f.to_le_bytes()).collect::<Vec<_>>();
+    let mut params =
+      floats(&[
+        2.0 * rect.x as f32 / width - 1.0,
+        1.0 - 2.0 * rect.y as f32 / height,
+        2.0 * rect.width as f32 / width,
+        -2.0 * rect.height as f32 / height,
+      ]);
+    for column in color.matrix.to_rgb() {
+      params.extend(floats(&[column[0] as f32, column[1] as f32, column[2] as f32, 0.0]));
+    }
+    params.extend(floats(&scale.map(|f| f as f32)));
+    params.extend(alpha.to_le_bytes());
+    params.extend(floats(&offset.map(|f| f as f32)));
+    params.extend(layout.packed.to_le_bytes());
+    params.extend(floats(&siting_offset(color.siting, subsampling)));
+    params.extend(transform.index().to_le_bytes());
+    params.resize(PARAMS_SIZE as usize, 0);
+    queue.write_buffer(&self.params, 0, &params);
+
+    let bind_group = gpu.create_bind_group(&BindGroupDescriptor {
+      label: Some("YUV Bindgroup"),
+      layout: &self.pipeline.get_bind_group_layout(0),
+      entries: &[BindGroupEntry {
+        binding: 0,
+        resource: BindingResource::TextureView(&luma),
+      }, BindGroupEntry {
+        binding: 1,
+        resource: BindingResource::TextureView(&chroma),
+      }, BindGroupEntry {
+        binding: 2,
+        resource: BindingResource::Sampler(&self.sampler),
+      }, BindGroupEntry {
+        binding: 3,
+        resource: self.params.as_entire_binding(),
+      }],
+    });
+    let view = target.create_view(&TextureViewDescriptor::default());
+    let mut encoder =
+      gpu.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("YUV Encoder") });
+    {
+      let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("YUV Pass"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+          view: &view,
+          depth_slice: None,
+          resolve_target: None,
+          ops: wgpu::Operations {
+            load: wgpu::LoadOp::Load,
+            store: wgpu::StoreOp::Store,
+          },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+      });
+      pass.set_pipeline(&self.pipeline);
+      pass.set_bind_group(0, &bind_group, &[]);
+      for rect in clip.iter().flat_map(|clip| clip.intersection(&rect)) {
+        pass.set_scissor_rect(rect.x as u32, rect.y as u32, rect.width, rect.height);
+        pass.draw(0 .. 4, 0 .. 1);
+      }
+    }
+    queue.submit([encoder.finish()]);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gpu::tests::assert_golden;
+  use crate::gpu::tests::device;
+  use crate::gpu::tests::target;
+  use drm::buffer::DrmModifier;
+
+  // Y'CbCr codes of 75% white, yellow, cyan, green, magenta, red, blue and
+  // black, as in ITU-R BT.601 and SMPTE RP 219 for limited range and ITU-T
+  // H.273 for full range
+  const BARS: &[(YuvMatrix, u32, YuvRange, [[u16; 3]; 8])] = &[
+    (YuvMatrix::Bt601, 8, YuvRange::Limited, [
+      [180, 128, 128], [162, 44, 142], [131, 156, 44], [112, 72, 58],
+      [84, 184, 198], [65, 100, 212], [35, 212, 114], [16, 128, 128],
+    ]),
+    (YuvMatrix::Bt601, 8, YuvRange::Full, [
+      [191, 128, 128], [169, 32, 144], [134, 160, 32], [112, 65, 48],
+      [79, 191, 208], [57, 96, 224], [22, 224, 112], [0, 128, 128],
+    ]),
+    (YuvMatrix::Bt601, 10, YuvRange::Limited, [
+      [721, 512, 512], [646, 176, 567], [525, 625, 176], [450, 289, 231],
+      [335, 735, 793], [260, 399, 848], [139, 848, 457], [64, 512, 512],
+    ]),
+    (YuvMatrix::Bt601, 10, YuvRange::Full, [
+      [767, 512, 512], [680, 128, 574], [538, 641, 128], [450, 258, 191],
+      [317, 766, 833], [229, 383, 896], [87, 896, 450], [0, 512, 512],
+    ]),
+    (YuvMatrix::Bt709, 8, YuvRange::Limited, [
+      [180, 128, 128], [168, 44, 136], [145, 147, 44], [133, 63, 52],
+      [63, 193, 204], [51, 109, 212], [28, 212, 120], [16, 128, 128],
+    ]),
+    (YuvMatrix::Bt709, 8, YuvRange::Full, [
+      [191, 128, 128], [177, 32, 137], [151, 150, 32], [137, 54, 41],
+      [54, 202, 215], [41, 106, 224], [14, 224, 119], [0, 128, 128],
+    ]),
+    (YuvMatrix::Bt709, 10, YuvRange::Limited, [
+      [721, 512, 512], [674, 176, 543], [581, 589, 176], [534, 253, 207],
+      [251, 771, 817], [204, 435, 848], [111, 848, 481], [64, 512, 512],
+    ]),
+    (YuvMatrix::Bt709, 10, YuvRange::Full, [
+      [767, 512, 512], [712, 128, 547], [604, 600, 128], [549, 216, 164],
+      [219, 808, 860], [163, 424, 896], [55, 896, 477], [0, 512, 512],
+    ]),
+    (YuvMatrix::Bt2020, 8, YuvRange::Limited, [
+      [180, 128, 128], [171, 44, 135], [137, 151, 44], [127, 67, 51],
+      [69, 189, 205], [59, 105, 212], [26, 212, 121], [16, 128, 128],
+    ]),
+    (YuvMatrix::Bt2020, 8, YuvRange::Full, [
+      [191, 128, 128], [180, 32, 136], [141, 155, 32], [130, 59, 40],
+      [62, 197, 216], [50, 101, 224], [11, 224, 120], [0, 128, 128],
+    ]),
+    (YuvMatrix::Bt2020, 10, YuvRange::Limited, [
+      [721, 512, 512], [682, 176, 539], [548, 606, 176], [509, 270, 203],
+      [276, 754, 821], [237, 418, 848], [103, 848, 485], [64, 512, 512],
+    ]),
+    (YuvMatrix::Bt2020, 10, YuvRange::Full, [
+      [767, 512, 512], [722, 128, 543], [566, 619, 128], [520, 236, 159],
+      [247, 788, 865], [202, 405, 896], [45, 896, 481], [0, 512, 512],
+    ]),
+  ];
+
+  // R'G'B' of each bar
+  const BAR_RGB: [[f64; 3]; 8] = [
+    [0.75, 0.75, 0.75],
+    [0.75, 0.75, 0.0],
+    [0.0, 0.75, 0.75],
+    [0.0, 0.75, 0.0],
+    [0.75, 0.0, 0.75],
+    [0.75, 0.0, 0.0],
+    [0.0, 0.0, 0.75],
+    [0.0, 0.0, 0.0],
+  ];
+
+  // What the shader makes of a pixel with these codes
+  fn decode(format: DrmFourcc, color: YuvColor, codes: [f64; 3]) -> [f64; 3] {
+    let layout = YuvLayout::new(format).unwrap();
+    let (scale, offset) = layout.scale_offset(color.range);
+    let ycbcr: [f64; 3] =
+      std::array::from_fn(|i| codes[i] / layout.texel_max * scale[i] - offset[i]);
+    let matrix = color.matrix.to_rgb();
+    std::array::from_fn(|row| {
+      (0 .. 3).map(|column| matrix[column][row] * ycbcr[column]).sum::<f64>().clamp(0.0, 1.0)
+    })
+  }
+
+  #[test]
+  fn decodes_colour_bars() {
+    for &(matrix, bits, range, bars) in BARS {
+      let format = if bits == 8 { DrmFourcc::Nv12 } else { DrmFourcc::P010 };
+      let color = YuvColor { matrix, range, ..Default::default() };
+
+      // Codes are rounded, which is up to about a code off in R'G'B'
+      let tolerance = 1.5 / ((1 << bits) - 1) as f64;
+      let near = |a: f64, b: f64| (a - b).abs() < tolerance;
+      for (codes, expected) in bars.iter().zip(BAR_RGB) {
+        let rgb = decode(format, color, codes.map(f64::from));
+        assert!(
+          rgb.iter().zip(expected).all(|(&rgb, expected)| near(rgb, expected)),
+          "{matrix:?} {range:?} {bits} bit {codes:?} gave {rgb:?}, not {expected:?}"
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn sites_chroma() {
+    assert_eq!(siting_offset(ChromaSiting::Left, (2, 2)), [0.25, 0.0]);
+    assert_eq!(siting_offset(ChromaSiting::TopLeft, (2, 2)), [0.25, 0.25]);
+    assert_eq!(siting_offset(ChromaSiting::TopLeft, (2, 1)), [0.25, 0.0]);
+    assert_eq!(siting_offset(ChromaSiting::Center, (2, 2)), [0.0, 0.0]);
+    assert_eq!(siting_offset(ChromaSiting::Left, (1, 1)), [0.0, 0.0]);
+  }
+
+  // BT.709 limited range bars 8 pixels wide, so with left siting every other
+  // pixel on a border is filtered half and half from both bars' chroma
+  #[test]
+  fn renders_nv12_bars() {
+    let Some((gpu, queue)) = device(wgpu::Features::TEXTURE_FORMAT_NV12) else {
+      return;
+    };
+    let (width, height) = (64, 16);
+    let (_, _, _, bars) = BARS[4];
+    let luma =
+      (0 .. width * height).map(|i| bars[(i % width / 8) as usize][0] as u8).collect::<Vec<_>>();
+    let chroma =
+      (0 .. width / 2 * height / 2)
+        .flat_map(|i| {
+          let [_, cb, cr] = bars[(i % (width / 2) / 4) as usize];
+          [cb as u8, cr as u8]
+        })
+        .collect::<Vec<_>>();
+    let texture = gpu.create_texture(&wgpu::TextureDescriptor {
+      label: Some("NV12 Bars"),
+      size: wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+      },
+      mip_level_count: 1,
+      sample_count: 1,
+      dimension: wgpu::TextureDimension::D2,
+      format: TextureFormat::NV12,
+      usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+      view_formats: &[],
+    });
+    for (aspect, data, (plane_width, plane_height), texel_bytes) in [
+      (TextureAspect::Plane0, &luma, (width, height), 1),
+      (TextureAspect::Plane1, &chroma, (width / 2, height / 2), 2),
+    ] {
+      queue.write_texture(wgpu::TexelCopyTextureInfo {
+        texture: &texture,
+        mip_level: 0,
+        origin: wgpu::Origin3d::ZERO,
+        aspect,
+      }, data, wgpu::TexelCopyBufferLayout {
+        offset: 0,
+        bytes_per_row: Some(plane_width * texel_bytes),
+        rows_per_image: Some(plane_height),
+      }, wgpu::Extent3d {
+        width: plane_width,
+        height: plane_height,
+        depth_or_array_layers: 1,
+      });
+    }
+    let texture = ClientTexture {
+      texture,
+      format: DrmFourcc::Nv12,
+      modifier: DrmModifier::Linear,
+      size: (width, height),
+    };
+    let canvas = target(&gpu, (width, height));
+    let rect = Rect::new(0, 0, width, height);
+    let blit = YuvBlit {
+      texture: &texture,
+      color: &YuvColor::default(),
+      alpha: 1.0,
+      transform: Transform::Normal,
+      rect,
+    };
+    YuvPass::new(&gpu).draw(&gpu, &queue, &blit, &canvas, &[rect]);
+    assert_golden(&gpu, &queue, &canvas, "nv12_bars", include_bytes!["../golden/nv12_bars.png"], 2);
+  }
+}
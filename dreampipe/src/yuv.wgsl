@@ -0,0 +1,97 @@
+// Where the buffer goes and how its samples turn into colours
+struct Params {
+   // Top left corner and size of the destination in clip space
+   pos: vec2<f32>,
+   size: vec2<f32>,
+   // Y'CbCr to R'G'B', after scale and offset
+   matrix: mat3x3<f32>,
+   // Texel values to Y' in 0 ..= 1 and Cb, Cr in -0.5 ..= 0.5
+   scale: vec3<f32>,
+   // Opacity of the whole surface
+   alpha: f32,
+   offset: vec3<f32>,
+   // 0 for separate luma and chroma planes, 1 for YUYV, 2 for UYVY
+   packed: u32,
+   // How far the chroma samples are from where plain sampling expects them,
+   // in chroma texels
+   siting: vec2<f32>,
+   // Rotated clockwise in quarter turns, flipped horizontally first if 4 or
+   // more, as in `Transform`
+   transform: u32,
+}
+struct VertexOutput {
+   @builtin(position) position: vec4<f32>,
+   @location(0) tex_coords: vec2<f32>,
+}
+// The same texture twice for packed formats, where a texel is two pixels
+@group(0) @binding(0) var luma: texture_2d<f32>;
+@group(0) @binding(1) var chroma: texture_2d<f32>;
+@group(0) @binding(2) var tex_sampler: sampler;
+@group(0) @binding(3) var<uniform> params: Params;
+// Where the destination corner `corner` samples the buffer
+fn transformed(corner: vec2<f32>, transform: u32) -> vec2<f32> {
+   var c = corner;
+   switch transform & 3u {
+      case 1u: {
+         c = vec2<f32>(corner.y, 1.0 - corner.x);
+      }
+      case 2u: {
+         c = vec2<f32>(1.0 - corner.x, 1.0 - corner.y);
+      }
+      case 3u: {
+         c = vec2<f32>(1.0 - corner.y, corner.x);
+      }
+      default: {
+      }
+   }
+   if transform >= 4u {
+      c.x = 1.0 - c.x;
+   }
+   return c;
+}
+// Drawn as a 4 vertex triangle strip
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+   var out: VertexOutput;
+   let corner = vec2<f32>(f32(vertex_index & 1u), f32(vertex_index >> 1u));
+   out.position = vec4<f32>(params.pos + corner * params.size, 0.0, 1.0);
+   out.tex_coords = transformed(corner, params.transform);
+   return out;
+}
+// Luma of pixel `pixel` of a packed buffer
+fn packed_luma(pixel: vec2<i32>) -> f32 {
+   let size = vec2<i32>(textureDimensions(luma));
+   let clamped = clamp(pixel, vec2<i32>(0), vec2<i32>(size.x * 2 - 1, size.y - 1));
+   let texel = textureLoad(luma, vec2<i32>(clamped.x / 2, clamped.y), 0);
+   let pair = select(texel.ga, texel.rb, params.packed == 1u);
+   return select(pair.x, pair.y, (clamped.x & 1) == 1);
+}
+// Packed luma is two to a texel, so it is filtered here
+fn packed_luma_bilinear(tex_coords: vec2<f32>) -> f32 {
+   let size = vec2<f32>(textureDimensions(luma)) * vec2<f32>(2.0, 1.0);
+   let position = tex_coords * size - 0.5;
+   let base = vec2<i32>(floor(position));
+   let t = fract(position);
+   let top = mix(packed_luma(base), packed_luma(base + vec2<i32>(1, 0)), t.x);
+   let bottom =
+      mix(packed_luma(base + vec2<i32>(0, 1)), packed_luma(base + vec2<i32>(1, 1)), t.x);
+   return mix(top, bottom, t.y);
+}
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+   let chroma_coords = in.tex_coords + params.siting / vec2<f32>(textureDimensions(chroma));
+   let chroma_texel = textureSample(chroma, tex_sampler, chroma_coords);
+   var y: f32;
+   var cbcr: vec2<f32>;
+   if params.packed == 0u {
+      y = textureSample(luma, tex_sampler, in.tex_coords).r;
+      cbcr = chroma_texel.rg;
+   } else {
+      y = packed_luma_bilinear(in.tex_coords);
+      cbcr = select(chroma_texel.rb, chroma_texel.ga, params.packed == 1u);
+   }
+   let ycbcr = vec3<f32>(y, cbcr) * params.scale - params.offset;
+   let rgb = clamp(params.matrix * ycbcr, vec3<f32>(0.0), vec3<f32>(1.0));
+   // Premultiplied, like everything else drawn
+   return vec4<f32>(rgb * params.alpha, params.alpha);
+}